@@ -1,11 +1,15 @@
-use fs1541::validate::{validate_mountpoint, ValidationType};
+use fs1541::validate::{
+    validate_memory_read, validate_memory_write, validate_mountpoint, validate_new_device_number,
+    ValidationType,
+};
 use rs1541::{validate_device, DeviceValidation};
 
 use fs1541::error::{Error, Fs1541Error};
 
 use clap::{ArgAction, Parser, Subcommand};
-use log::debug;
+use log::{debug, LevelFilter};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 #[derive(Subcommand, Clone, Debug)]
 pub enum ClientOperation {
@@ -13,16 +17,57 @@ pub enum ClientOperation {
     #[clap(alias = "busreset")]
     Resetbus,
 
+    /// Identify every device on the IEC (or IEEE-488) bus
+    #[clap(alias = "scan")]
+    Scanbus,
+
     /// Mount the filesystem
     Mount {
         /// Device number (default: 8)
         #[arg(short = 'd', long = "device", default_value = "8")]
         device: u8,
 
-        /// Don't actually format the disk if requested
+        /// Don't actually format the disk, or restore disk images to it, if
+        /// requested - the format command is logged instead
         #[arg(short = 'f', long = "dummy-formats", action = ArgAction::SetTrue)]
         dummy_formats: bool,
 
+        /// Re-read the directory every N seconds, so changes made to the disk
+        /// by another computer on the bus are picked up (default: disabled)
+        #[arg(long = "auto-refresh-secs", value_parser = clap::value_parser!(u64).range(1..))]
+        auto_refresh_secs: Option<u64>,
+
+        /// Mount read-write, allowing files to be written to the disk
+        /// (default: read-only)
+        #[arg(long = "rw", action = ArgAction::SetTrue)]
+        read_write: bool,
+
+        /// Replace existing files by scratching them and then writing them
+        /// afresh, rather than using the drive's save-with-replace (@:),
+        /// which can corrupt disks in some drives, including the 1541
+        #[arg(long = "safe-replace", action = ArgAction::SetTrue)]
+        safe_replace: bool,
+
+        /// Read each file back after writing it, and check it matches what
+        /// was written, to catch data lost on the bus
+        #[arg(long = "verify-writes", action = ArgAction::SetTrue)]
+        verify_writes: bool,
+
+        /// Sector interleave for the drive to use when writing files, on
+        /// drives which support changing it (default: the drive's own)
+        #[arg(long = "interleave", value_parser = clap::value_parser!(u8).range(1..))]
+        interleave: Option<u8>,
+
+        /// Create the mountpoint if it doesn't exist, and remove it again on
+        /// unmount
+        #[arg(long = "mkdir", action = ArgAction::SetTrue)]
+        mkdir: bool,
+
+        /// Serve the mount from a .d64 disk image file, rather than the
+        /// drive.  The mount is read-only.
+        #[arg(long = "image")]
+        image: Option<PathBuf>,
+
         /// Mountpoint path
         mountpoint: String,
 
@@ -61,31 +106,200 @@ pub enum ClientOperation {
         device: u8,
     },
 
+    /// Report on the daemon's health
+    Health,
+
+    /// Report timings of the daemon's background operations
+    Metrics {
+        /// Reset the metrics after reporting them
+        #[arg(long = "reset", action = ArgAction::SetTrue)]
+        reset: bool,
+    },
+
+    /// List the daemon's current mounts
+    #[clap(alias = "list")]
+    Mounts,
+
+    /// Watch the selected device, reporting status changes, disk changes,
+    /// mounts and unmounts as they happen, until interrupted
+    Watch {
+        /// Device number (default: 8)
+        #[arg(short = 'd', long = "device", default_value = "8")]
+        device: u8,
+    },
+
+    /// Change the selected device's number, until the drive is reset or
+    /// powered off.  Supported by 1541, 1571 and 1581 drives - to change
+    /// the number permanently, or on other drives, set the drive's jumpers
+    /// or switches instead
+    #[clap(alias = "renumber")]
+    Setdevice {
+        /// Device number (default: 8)
+        #[arg(short = 'd', long = "device", default_value = "8")]
+        device: u8,
+
+        /// New device number (8-15)
+        new_device: u8,
+    },
+
+    /// Re-read the directory of the mounted device, returning once the new
+    /// listing has been read, for example after the disk has been changed
+    #[clap(alias = "refresh")]
+    Refreshdir {
+        /// Device number (default: 8)
+        #[arg(short = 'd', long = "device", default_value = "8")]
+        device: u8,
+
+        /// Drive to refresh, on dual drive units (default: 0)
+        #[arg(long = "drive")]
+        drive: Option<u8>,
+    },
+
+    /// Get or set the daemon's log level
+    Loglevel {
+        /// New log level (off, error, warn, info, debug or trace).  If
+        /// omitted, the current level is reported
+        level: Option<String>,
+    },
+
+    /// Read a file from the selected device, without mounting it, and write
+    /// it to stdout
+    #[clap(alias = "cat")]
+    Read {
+        /// Device number (default: 8)
+        #[arg(short = 'd', long = "device", default_value = "8")]
+        device: u8,
+
+        /// Strip the 2 byte load address from the start of the file (for
+        /// PRG files).  By default the file is output raw
+        #[arg(short = 's', long = "strip-load-addr", action = ArgAction::SetTrue)]
+        strip_load_addr: bool,
+
+        /// Name of the file on the disk
+        filename: String,
+    },
+
+    /// Read the selected device's memory, using M-R, and print it in hex
+    #[clap(alias = "mr")]
+    Memread {
+        /// Device number (default: 8)
+        #[arg(short = 'd', long = "device", default_value = "8")]
+        device: u8,
+
+        /// Address to read from, in hex (for example 0300 or $0300)
+        #[arg(value_parser = parse_hex_addr)]
+        addr: u16,
+
+        /// Number of bytes to read, up to 255 (default: 16)
+        #[arg(default_value = "16")]
+        len: usize,
+    },
+
+    /// Write to the selected device's memory, using M-W.  Writing to the
+    /// wrong locations can crash the drive, which then needs to be reset
+    #[clap(alias = "mw")]
+    Memwrite {
+        /// Device number (default: 8)
+        #[arg(short = 'd', long = "device", default_value = "8")]
+        device: u8,
+
+        /// Address to write to, in hex (for example 0500 or $0500)
+        #[arg(value_parser = parse_hex_addr)]
+        addr: u16,
+
+        /// Bytes to write, in hex (for example a9 00 60)
+        #[arg(value_parser = parse_hex_byte, required = true)]
+        data: Vec<u8>,
+    },
+
+    /// Have the selected device run the code in its memory at an address,
+    /// using M-E.  Code which doesn't return hangs the drive, and possibly
+    /// the bus, until the drive is reset
+    #[clap(alias = "me")]
+    Memexec {
+        /// Device number (default: 8)
+        #[arg(short = 'd', long = "device", default_value = "8")]
+        device: u8,
+
+        /// Address of the code, in hex (for example 0500 or $0500)
+        #[arg(value_parser = parse_hex_addr)]
+        addr: u16,
+    },
+
     /// Kill the 1541fs daemon (1541fsd)
     Kill,
 }
 
+/// Strips any $ or 0x prefix from a hex number
+fn hex_digits(value: &str) -> &str {
+    value
+        .strip_prefix('$')
+        .or_else(|| value.strip_prefix("0x"))
+        .unwrap_or(value)
+}
+
+fn parse_hex_addr(value: &str) -> Result<u16, String> {
+    u16::from_str_radix(hex_digits(value), 16)
+        .map_err(|e| format!("Invalid address {}: {}", value, e))
+}
+
+fn parse_hex_byte(value: &str) -> Result<u8, String> {
+    u8::from_str_radix(hex_digits(value), 16).map_err(|e| format!("Invalid byte {}: {}", value, e))
+}
+
 impl ClientOperation {
     pub fn log(&self) {
         match self {
             Self::Resetbus => {
                 debug!("Operation: Reset Bus");
             }
+            Self::Scanbus => {
+                debug!("Operation: Scan Bus");
+            }
             Self::Mount {
                 device,
                 mountpoint,
                 dummy_formats,
+                auto_refresh_secs,
+                read_write,
+                safe_replace,
+                verify_writes,
+                interleave,
+                mkdir,
+                image,
                 ..
             } => {
                 debug!(
-                    "Operation: Mount device {} at '{}'{}",
+                    "Operation: Mount device {} at '{}'{}{}{}{}{}{}{}{}",
                     device,
                     mountpoint,
+                    if *read_write { " read-write" } else { "" },
+                    if *safe_replace {
+                        " with safe replace"
+                    } else {
+                        ""
+                    },
+                    if *verify_writes {
+                        " verifying writes"
+                    } else {
+                        ""
+                    },
                     if *dummy_formats {
                         " with dummy formats"
                     } else {
                         ""
-                    }
+                    },
+                    auto_refresh_secs
+                        .map(|secs| format!(" auto-refreshing every {}s", secs))
+                        .unwrap_or_default(),
+                    interleave
+                        .map(|interleave| format!(" with interleave {}", interleave))
+                        .unwrap_or_default(),
+                    if *mkdir { " creating mountpoint" } else { "" },
+                    image
+                        .as_ref()
+                        .map(|image| format!(" from image {}", image.display()))
+                        .unwrap_or_default()
                 );
             }
             Self::Unmount {
@@ -103,6 +317,65 @@ impl ClientOperation {
             Self::Getstatus { device } => {
                 debug!("Operation: Get status of device {}", device);
             }
+            Self::Health => {
+                debug!("Operation: Get daemon health");
+            }
+            Self::Metrics { reset } => {
+                debug!("Operation: Get daemon metrics (reset: {})", reset);
+            }
+            Self::Mounts => {
+                debug!("Operation: List daemon mounts");
+            }
+            Self::Watch { device } => {
+                debug!("Operation: Watch device {}", device);
+            }
+            Self::Setdevice { device, new_device } => {
+                debug!("Operation: Set device {} number to {}", device, new_device);
+            }
+            Self::Refreshdir { device, drive } => {
+                debug!(
+                    "Operation: Refresh directory of device {} drive {}",
+                    device,
+                    drive.unwrap_or(0)
+                );
+            }
+            Self::Loglevel { level } => match level {
+                Some(level) => debug!("Operation: Set daemon log level to {}", level),
+                None => debug!("Operation: Get daemon log level"),
+            },
+            Self::Read {
+                device,
+                strip_load_addr,
+                filename,
+            } => {
+                debug!(
+                    "Operation: Read file '{}' from device {}{}",
+                    filename,
+                    device,
+                    if *strip_load_addr {
+                        " stripping load address"
+                    } else {
+                        ""
+                    }
+                );
+            }
+            Self::Memread { device, addr, len } => {
+                debug!(
+                    "Operation: Read {} bytes of device {} memory from ${:04X}",
+                    len, device, addr
+                );
+            }
+            Self::Memwrite { device, addr, data } => {
+                debug!(
+                    "Operation: Write {} bytes to device {} memory at ${:04X}",
+                    data.len(),
+                    device,
+                    addr
+                );
+            }
+            Self::Memexec { device, addr } => {
+                debug!("Operation: Execute device {} code at ${:04X}", device, addr);
+            }
             Self::Kill => {
                 debug!("Operation: Kill daemon");
             }
@@ -121,6 +394,16 @@ impl ClientOperation {
 pub struct Args {
     #[command(subcommand)]
     pub operation: ClientOperation,
+
+    /// Only log warnings and errors, so only the operation's output is
+    /// printed
+    #[arg(short = 'q', long = "quiet", global = true, action = ArgAction::SetTrue)]
+    pub quiet: bool,
+
+    /// If the running daemon speaks an incompatible protocol version, kill
+    /// it and start a new one
+    #[arg(long = "upgrade-daemon", global = true, action = ArgAction::SetTrue)]
+    pub upgrade_daemon: bool,
 }
 
 impl Args {
@@ -129,6 +412,8 @@ impl Args {
             ClientOperation::Mount {
                 device,
                 mountpoint,
+                mkdir,
+                image,
                 path,
                 ..
             } => {
@@ -139,10 +424,29 @@ impl Args {
                     }
                 })?;
 
-                let new_path =
-                    validate_mountpoint(Path::new(mountpoint), ValidationType::Mount, true)?;
+                // A mountpoint the daemon is going to create can't be
+                // validated yet, so just make it absolute
+                let new_path = if *mkdir && !Path::new(mountpoint).exists() {
+                    std::env::current_dir()
+                        .map_err(|e| Error::Io {
+                            message: "Failed to get current directory".into(),
+                            error: e.to_string(),
+                        })?
+                        .join(mountpoint.as_str())
+                } else {
+                    validate_mountpoint(Path::new(mountpoint), ValidationType::Mount, true)?
+                };
                 *path = Some(new_path.clone());
                 *mountpoint = new_path.display().to_string();
+
+                // The daemon has its own working directory, so needs the
+                // image's absolute path
+                if let Some(image) = image {
+                    *image = image.canonicalize().map_err(|e| Error::Io {
+                        message: format!("Failed to find disk image {}", image.display()),
+                        error: e.to_string(),
+                    })?;
+                }
             }
             ClientOperation::Unmount {
                 device,
@@ -185,15 +489,101 @@ impl Args {
                     *mountpoint = Some(new_path.display().to_string());
                 }
             }
-            ClientOperation::Identify { device } | ClientOperation::Getstatus { device } => {
+            ClientOperation::Identify { device }
+            | ClientOperation::Getstatus { device }
+            | ClientOperation::Watch { device } => {
+                validate_device(Some(*device), DeviceValidation::Required).map_err(|e| {
+                    Error::Rs1541 {
+                        message: "Device validation failed".into(),
+                        error: e,
+                    }
+                })?;
+            }
+            ClientOperation::Setdevice { device, new_device } => {
+                validate_device(Some(*device), DeviceValidation::Required).map_err(|e| {
+                    Error::Rs1541 {
+                        message: "Device validation failed".into(),
+                        error: e,
+                    }
+                })?;
+                validate_new_device_number(*device, *new_device)?;
+            }
+            ClientOperation::Refreshdir { device, drive } => {
+                validate_device(Some(*device), DeviceValidation::Required).map_err(|e| {
+                    Error::Rs1541 {
+                        message: "Device validation failed".into(),
+                        error: e,
+                    }
+                })?;
+
+                // Units have at most two drives
+                if drive.is_some_and(|drive| drive > 1) {
+                    return Err(Error::Fs1541 {
+                        message: "Drive validation failed".into(),
+                        error: Fs1541Error::Validation(format!(
+                            "Invalid drive number {} - must be 0 or 1",
+                            drive.unwrap_or_default()
+                        )),
+                    });
+                }
+            }
+            ClientOperation::Loglevel { level: Some(level) } => {
+                let new_level = LevelFilter::from_str(level).map_err(|_| Error::Fs1541 {
+                    message: "Log level validation failed".into(),
+                    error: Fs1541Error::Validation(format!("Invalid log level: {}", level)),
+                })?;
+                *level = new_level.to_string();
+            }
+            ClientOperation::Read {
+                device, filename, ..
+            } => {
+                validate_device(Some(*device), DeviceValidation::Required).map_err(|e| {
+                    Error::Rs1541 {
+                        message: "Device validation failed".into(),
+                        error: e,
+                    }
+                })?;
+
+                if filename.is_empty() {
+                    return Err(Error::Fs1541 {
+                        message: "Read validation failed".into(),
+                        error: Fs1541Error::Validation("Filename must not be empty".into()),
+                    });
+                }
+            }
+            ClientOperation::Memread { device, addr, len } => {
                 validate_device(Some(*device), DeviceValidation::Required).map_err(|e| {
                     Error::Rs1541 {
                         message: "Device validation failed".into(),
                         error: e,
                     }
                 })?;
+                validate_memory_read(*addr, *len)?;
             }
-            ClientOperation::Resetbus | ClientOperation::Kill => {}
+            ClientOperation::Memwrite { device, addr, data } => {
+                validate_device(Some(*device), DeviceValidation::Required).map_err(|e| {
+                    Error::Rs1541 {
+                        message: "Device validation failed".into(),
+                        error: e,
+                    }
+                })?;
+                validate_memory_write(*addr, data.len())?;
+            }
+            ClientOperation::Memexec { device, .. } => {
+                validate_device(Some(*device), DeviceValidation::Required).map_err(|e| {
+                    Error::Rs1541 {
+                        message: "Device validation failed".into(),
+                        error: e,
+                    }
+                })?;
+            }
+            ClientOperation::Loglevel { level: None }
+            | ClientOperation::Resetbus
+            | ClientOperation::Scanbus
+            | ClientOperation::Health
+            | ClientOperation::Metrics { .. }
+            | ClientOperation::Mounts
+            | ClientOperation::Kill => {}
         }
         Ok(self)
     }
@@ -201,7 +591,7 @@ impl Args {
 
 #[cfg(test)]
 mod tests {
-    use crate::args::{Args, ClientOperation};
+    use crate::args::{parse_hex_addr, parse_hex_byte, Args, ClientOperation};
     use fs1541::error::Error;
     use rs1541::{DEFAULT_DEVICE_NUM, DEVICE_MAX_NUM, DEVICE_MIN_NUM};
     use tempfile::TempDir;
@@ -255,7 +645,16 @@ mod tests {
                     dummy_formats: false,
                     mountpoint: mount_path,
                     path: None,
+                    auto_refresh_secs: None,
+                    read_write: false,
+                    safe_replace: false,
+                    verify_writes: false,
+                    interleave: None,
+                    mkdir: false,
+                    image: None,
                 },
+                quiet: false,
+                upgrade_daemon: false,
             };
 
             let validated = validate_for_test(args).unwrap();
@@ -279,7 +678,16 @@ mod tests {
                         dummy_formats: false,
                         mountpoint: mount_path.clone(),
                         path: None,
+                        auto_refresh_secs: None,
+                        read_write: false,
+                        safe_replace: false,
+                        verify_writes: false,
+                        interleave: None,
+                        mkdir: false,
+                        image: None,
                     },
+                    quiet: false,
+                    upgrade_daemon: false,
                 };
 
                 let validated = validate_for_test(args).unwrap();
@@ -307,7 +715,16 @@ mod tests {
                     dummy_formats: false,
                     mountpoint: mount_path.clone(),
                     path: None,
+                    auto_refresh_secs: None,
+                    read_write: false,
+                    safe_replace: false,
+                    verify_writes: false,
+                    interleave: None,
+                    mkdir: false,
+                    image: None,
                 },
+                quiet: false,
+                upgrade_daemon: false,
             };
             assert!(validate_for_test(args).is_err());
 
@@ -318,7 +735,16 @@ mod tests {
                     dummy_formats: false,
                     mountpoint: mount_path,
                     path: None,
+                    auto_refresh_secs: None,
+                    read_write: false,
+                    safe_replace: false,
+                    verify_writes: false,
+                    interleave: None,
+                    mkdir: false,
+                    image: None,
                 },
+                quiet: false,
+                upgrade_daemon: false,
             };
             assert!(validate_for_test(args).is_err());
         }
@@ -330,6 +756,8 @@ mod tests {
                 operation: ClientOperation::Identify {
                     device: DEFAULT_DEVICE_NUM,
                 },
+                quiet: false,
+                upgrade_daemon: false,
             };
             assert!(validate_for_test(args).is_ok());
 
@@ -338,15 +766,38 @@ mod tests {
                 operation: ClientOperation::Identify {
                     device: DEVICE_MAX_NUM + 1,
                 },
+                quiet: false,
+                upgrade_daemon: false,
             };
             assert!(validate_for_test(args).is_err());
         }
+
+        #[test]
+        fn test_refreshdir_validation() {
+            for (drive, valid) in [
+                (None, true),
+                (Some(0), true),
+                (Some(1), true),
+                (Some(2), false),
+            ] {
+                let args = Args {
+                    operation: ClientOperation::Refreshdir {
+                        device: DEFAULT_DEVICE_NUM,
+                        drive,
+                    },
+                    quiet: false,
+                    upgrade_daemon: false,
+                };
+                assert_eq!(validate_for_test(args).is_ok(), valid);
+            }
+        }
     }
 
     mod mount_operations {
         use super::*;
         use std::fs::{self, Permissions};
         use std::os::unix::fs::PermissionsExt;
+        use std::path::PathBuf;
 
         #[test]
         fn test_mount_permissions() {
@@ -363,7 +814,16 @@ mod tests {
                     dummy_formats: false,
                     mountpoint: mount_path,
                     path: None,
+                    auto_refresh_secs: None,
+                    read_write: false,
+                    safe_replace: false,
+                    verify_writes: false,
+                    interleave: None,
+                    mkdir: false,
+                    image: None,
                 },
+                quiet: false,
+                upgrade_daemon: false,
             };
 
             let result = validate_for_test(args);
@@ -390,7 +850,16 @@ mod tests {
                     dummy_formats: false,
                     mountpoint: mount_path,
                     path: None,
+                    auto_refresh_secs: None,
+                    read_write: false,
+                    safe_replace: false,
+                    verify_writes: false,
+                    interleave: None,
+                    mkdir: false,
+                    image: None,
                 },
+                quiet: false,
+                upgrade_daemon: false,
             };
             assert!(validate_for_test(args).is_ok());
 
@@ -401,9 +870,80 @@ mod tests {
                     dummy_formats: false,
                     mountpoint: "/this/path/does/not/exist".to_string(),
                     path: None,
+                    auto_refresh_secs: None,
+                    read_write: false,
+                    safe_replace: false,
+                    verify_writes: false,
+                    interleave: None,
+                    mkdir: false,
+                    image: None,
                 },
+                quiet: false,
+                upgrade_daemon: false,
             };
             assert!(validate_for_test(args).is_err());
+
+            // Unless the daemon is going to create it
+            let args = Args {
+                operation: ClientOperation::Mount {
+                    device: DEFAULT_DEVICE_NUM,
+                    dummy_formats: false,
+                    mountpoint: "does/not/exist".to_string(),
+                    path: None,
+                    auto_refresh_secs: None,
+                    read_write: false,
+                    safe_replace: false,
+                    verify_writes: false,
+                    interleave: None,
+                    mkdir: true,
+                    image: None,
+                },
+                quiet: false,
+                upgrade_daemon: false,
+            };
+            match validate_for_test(args).unwrap().operation {
+                ClientOperation::Mount { path, .. } => assert!(path.unwrap().is_absolute()),
+                _ => panic!("Expected Mount operation"),
+            }
+        }
+
+        #[test]
+        fn test_mount_image() {
+            let temp_dir = setup_test_dir();
+            let image_dir = setup_test_dir();
+            let image = image_dir.path().join("disk.d64");
+            fs::write(&image, b"").expect("Failed to create image");
+            let mount = |image: PathBuf| Args {
+                operation: ClientOperation::Mount {
+                    device: DEFAULT_DEVICE_NUM,
+                    dummy_formats: false,
+                    mountpoint: temp_dir.path().to_str().unwrap().to_string(),
+                    path: None,
+                    auto_refresh_secs: None,
+                    read_write: false,
+                    safe_replace: false,
+                    verify_writes: false,
+                    interleave: None,
+                    mkdir: false,
+                    image: Some(image),
+                },
+                quiet: false,
+                upgrade_daemon: false,
+            };
+
+            // The image is passed to the daemon as an absolute path
+            match validate_for_test(mount(image.clone())).unwrap().operation {
+                ClientOperation::Mount {
+                    image: Some(path), ..
+                } => {
+                    assert!(path.is_absolute());
+                    assert!(path.ends_with("disk.d64"));
+                }
+                _ => panic!("Expected Mount operation with an image"),
+            }
+
+            // And must exist
+            assert!(validate_for_test(mount(image_dir.path().join("missing.d64"))).is_err());
         }
     }
 
@@ -422,6 +962,8 @@ mod tests {
                     mountpoint: Some(mount_path.clone()),
                     path: None,
                 },
+                quiet: false,
+                upgrade_daemon: false,
             };
             let result = validate_for_test(args);
             assert!(result.is_err());
@@ -437,6 +979,8 @@ mod tests {
                     mountpoint: None,
                     path: None,
                 },
+                quiet: false,
+                upgrade_daemon: false,
             };
             assert!(validate_for_test(args).is_ok());
 
@@ -447,6 +991,8 @@ mod tests {
                     mountpoint: Some(mount_path.clone()),
                     path: None,
                 },
+                quiet: false,
+                upgrade_daemon: false,
             };
             assert!(validate_for_test(args).is_ok());
 
@@ -457,6 +1003,8 @@ mod tests {
                     mountpoint: None,
                     path: None,
                 },
+                quiet: false,
+                upgrade_daemon: false,
             };
             assert!(validate_for_test(args).is_err());
 
@@ -467,6 +1015,8 @@ mod tests {
                     mountpoint: None,
                     path: None,
                 },
+                quiet: false,
+                upgrade_daemon: false,
             };
             let result = validate_for_test(args);
             assert!(result.is_err());
@@ -482,6 +1032,8 @@ mod tests {
                     mountpoint: Some("/this/path/does/not/exist".to_string()),
                     path: None,
                 },
+                quiet: false,
+                upgrade_daemon: false,
             };
             assert!(validate_for_test(args).is_err());
         }
@@ -495,14 +1047,134 @@ mod tests {
             // Test resetbus (should always succeed)
             let args = Args {
                 operation: ClientOperation::Resetbus,
+                quiet: false,
+                upgrade_daemon: false,
             };
             assert!(validate_for_test(args).is_ok());
 
             // Test kill (should always succeed)
             let args = Args {
                 operation: ClientOperation::Kill,
+                quiet: false,
+                upgrade_daemon: false,
+            };
+            assert!(validate_for_test(args).is_ok());
+        }
+
+        #[test]
+        fn test_loglevel_validation() {
+            let args = Args {
+                operation: ClientOperation::Loglevel {
+                    level: Some("Trace".into()),
+                },
+                quiet: false,
+                upgrade_daemon: false,
+            };
+            match validate_for_test(args).unwrap().operation {
+                ClientOperation::Loglevel { level } => assert_eq!(level.as_deref(), Some("TRACE")),
+                op => panic!("Expected Loglevel operation, got {:?}", op),
+            }
+
+            let args = Args {
+                operation: ClientOperation::Loglevel { level: None },
+                quiet: false,
+                upgrade_daemon: false,
+            };
+            assert!(validate_for_test(args).is_ok());
+
+            let args = Args {
+                operation: ClientOperation::Loglevel {
+                    level: Some("verbose".into()),
+                },
+                quiet: false,
+                upgrade_daemon: false,
+            };
+            assert!(validate_for_test(args).is_err());
+        }
+
+        #[test]
+        fn test_read_validation() {
+            let args = Args {
+                operation: ClientOperation::Read {
+                    device: DEFAULT_DEVICE_NUM,
+                    strip_load_addr: false,
+                    filename: "game".into(),
+                },
+                quiet: false,
+                upgrade_daemon: false,
             };
             assert!(validate_for_test(args).is_ok());
+
+            let args = Args {
+                operation: ClientOperation::Read {
+                    device: DEFAULT_DEVICE_NUM,
+                    strip_load_addr: true,
+                    filename: "".into(),
+                },
+                quiet: false,
+                upgrade_daemon: false,
+            };
+            assert!(validate_for_test(args).is_err());
+
+            let args = Args {
+                operation: ClientOperation::Read {
+                    device: DEVICE_MAX_NUM + 1,
+                    strip_load_addr: false,
+                    filename: "game".into(),
+                },
+                quiet: false,
+                upgrade_daemon: false,
+            };
+            assert!(validate_for_test(args).is_err());
+        }
+    }
+
+    mod memory_operations {
+        use super::*;
+
+        fn validate_op(operation: ClientOperation) -> Result<Args, TestError> {
+            validate_for_test(Args {
+                operation,
+                quiet: false,
+                upgrade_daemon: false,
+            })
+        }
+
+        #[test]
+        fn test_memory_validation() {
+            let memread = |addr, len| ClientOperation::Memread {
+                device: DEFAULT_DEVICE_NUM,
+                addr,
+                len,
+            };
+            assert!(validate_op(memread(0x0300, 255)).is_ok());
+            assert!(validate_op(memread(0x0300, 256)).is_err());
+            assert!(validate_op(memread(0xfff0, 32)).is_err());
+
+            let memwrite = |addr, data: &[u8]| ClientOperation::Memwrite {
+                device: DEFAULT_DEVICE_NUM,
+                addr,
+                data: data.to_vec(),
+            };
+            assert!(validate_op(memwrite(0x0500, &[0xa9, 0x00, 0x60])).is_ok());
+            assert!(validate_op(memwrite(0xffff, &[0xea, 0xea])).is_err());
+
+            let memexec = |device| ClientOperation::Memexec {
+                device,
+                addr: 0x0500,
+            };
+            assert!(validate_op(memexec(DEFAULT_DEVICE_NUM)).is_ok());
+            assert!(validate_op(memexec(DEVICE_MAX_NUM + 1)).is_err());
+        }
+
+        #[test]
+        fn test_hex_parsing() {
+            assert_eq!(parse_hex_addr("0300"), Ok(0x0300));
+            assert_eq!(parse_hex_addr("$1c00"), Ok(0x1c00));
+            assert_eq!(parse_hex_addr("0x0500"), Ok(0x0500));
+            assert!(parse_hex_addr("10000").is_err());
+            assert_eq!(parse_hex_byte("a9"), Ok(0xa9));
+            assert!(parse_hex_byte("100").is_err());
         }
     }
 
@@ -517,11 +1189,19 @@ mod tests {
             // Test logging for each operation type
             let operations = vec![
                 ClientOperation::Resetbus,
+                ClientOperation::Scanbus,
                 ClientOperation::Mount {
                     device: DEFAULT_DEVICE_NUM,
                     dummy_formats: false,
                     mountpoint: mount_path.clone(),
                     path: None,
+                    auto_refresh_secs: None,
+                    read_write: false,
+                    safe_replace: false,
+                    verify_writes: false,
+                    interleave: None,
+                    mkdir: false,
+                    image: None,
                 },
                 ClientOperation::Unmount {
                     device: Some(DEFAULT_DEVICE_NUM),
@@ -531,6 +1211,40 @@ mod tests {
                 ClientOperation::Identify {
                     device: DEFAULT_DEVICE_NUM,
                 },
+                ClientOperation::Health,
+                ClientOperation::Metrics { reset: true },
+                ClientOperation::Mounts,
+                ClientOperation::Watch {
+                    device: DEFAULT_DEVICE_NUM,
+                },
+                ClientOperation::Setdevice {
+                    device: DEFAULT_DEVICE_NUM,
+                    new_device: 9,
+                },
+                ClientOperation::Refreshdir {
+                    device: DEFAULT_DEVICE_NUM,
+                    drive: Some(1),
+                },
+                ClientOperation::Loglevel { level: None },
+                ClientOperation::Read {
+                    device: DEFAULT_DEVICE_NUM,
+                    strip_load_addr: true,
+                    filename: "game".into(),
+                },
+                ClientOperation::Memread {
+                    device: DEFAULT_DEVICE_NUM,
+                    addr: 0x0300,
+                    len: 16,
+                },
+                ClientOperation::Memwrite {
+                    device: DEFAULT_DEVICE_NUM,
+                    addr: 0x0500,
+                    data: vec![0xa9, 0x00, 0x60],
+                },
+                ClientOperation::Memexec {
+                    device: DEFAULT_DEVICE_NUM,
+                    addr: 0x0500,
+                },
                 ClientOperation::Kill,
             ];
 
@@ -1,26 +1,45 @@
 mod args;
 
 use args::{Args, ClientOperation};
-use fs1541::error::{Error, Fs1541Error};
+use fs1541::error::{Error, Fs1541Error, ERROR_CODE_INTERNAL, ERROR_CODE_VALIDATION};
 
 #[cfg(not(test))]
 use fs1541::ipc::SOCKET_PATH;
-use fs1541::ipc::{Request, Response};
-use fs1541::ipc::{DAEMON_PID_FILENAME, DAEMON_PNAME};
+use fs1541::ipc::{encode_frame, frame_len, Request, Response, FRAME_LEN_SIZE};
+use fs1541::ipc::{DAEMON_PID_FILENAME, DAEMON_PNAME, PROTOCOL_VERSION};
 use fs1541::logging::init_logging;
 
 use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Local};
 use clap::Parser;
+use log::LevelFilter;
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
 use std::io::{Read, Write};
 use std::os::unix::net::UnixStream;
 use std::path::Path;
 use std::process::{Command, Stdio};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
 const CONNECT_RETRY_DELAY: Duration = Duration::from_millis(1000);
-const MAX_RESPONSE_SIZE: usize = 1024 * 1024; // 1MB limit
+
+/// Exit code when asked to unmount something which isn't mounted.  This is
+/// distinct from the exit code for errors, so scripts can tell the two apart.
+const NOT_MOUNTED_EXIT_CODE: i32 = 2;
+
+/// Errors returned by the daemon exit with this plus the error's code, so
+/// scripts can tell the categories of error apart
+const ERROR_EXIT_CODE_BASE: i32 = 10;
+
+/// Returns the exit code for an error returned by the daemon.  Errors
+/// without a known category exit with 1, as other errors do.
+fn error_exit_code(code: u32) -> i32 {
+    if (ERROR_CODE_VALIDATION..=ERROR_CODE_INTERNAL).contains(&code) {
+        ERROR_EXIT_CODE_BASE + code as i32
+    } else {
+        1
+    }
+}
 
 #[cfg(not(test))]
 const STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
@@ -42,6 +61,54 @@ fn check_daemon_health() -> Result<(), Error> {
     }
 }
 
+/// Checks the protocol version reported by the daemon in response to a
+/// Version request matches our own
+fn check_protocol_version(response: Response) -> Result<(), Error> {
+    match response {
+        Response::Version { protocol, .. } if protocol == PROTOCOL_VERSION => Ok(()),
+        Response::Version { version, protocol } => Err(Error::Fs1541 {
+            message: "Daemon version check failed".into(),
+            error: Fs1541Error::Validation(format!(
+                "Daemon {} uses protocol version {}, but this client requires version {}",
+                version, protocol, PROTOCOL_VERSION
+            )),
+        }),
+        _ => Err(Error::Fs1541 {
+            message: "Daemon version check failed".into(),
+            error: Fs1541Error::Validation("Received invalid version response from server".into()),
+        }),
+    }
+}
+
+fn check_daemon_version() -> Result<(), Error> {
+    // Older daemons don't understand the Version request and will drop the
+    // connection, so any error here is treated as an incompatible version
+    send_request(Request::Version).and_then(check_protocol_version)
+}
+
+/// Tells the running daemon to exit and waits for it to stop responding
+fn kill_daemon() -> Result<(), Error> {
+    // The daemon may not be able to parse any of our responses, so ignore
+    // the result of sending Die
+    let _ = send_request(Request::Die);
+
+    let start_time = Instant::now();
+    while start_time.elapsed() < STARTUP_TIMEOUT {
+        if check_daemon_health().is_err() {
+            return Ok(());
+        }
+        std::thread::sleep(CONNECT_RETRY_DELAY);
+    }
+
+    Err(Error::Fs1541 {
+        message: "Failed to stop daemon".into(),
+        error: Fs1541Error::Timeout(
+            "Timed out waiting for daemon to exit".into(),
+            STARTUP_TIMEOUT,
+        ),
+    })
+}
+
 fn verify_daemon_process(pid_file: &Path) -> Result<(), Error> {
     let pid_str = std::fs::read_to_string(pid_file).map_err(|e| Error::Fs1541 {
         message: "Failed to read PID file".into(),
@@ -86,14 +153,32 @@ impl CommandExt for Command {
     }
 }
 
-fn ensure_daemon_running() -> Result<(), Error> {
-    let start_time = Instant::now();
-
+fn ensure_daemon_running(upgrade_daemon: bool) -> Result<(), Error> {
     if check_daemon_health().is_ok() {
-        info!("Daemon running and healthy");
-        return Ok(());
+        match check_daemon_version() {
+            Ok(()) => {
+                info!("Daemon running and healthy");
+                return Ok(());
+            }
+            Err(e) if upgrade_daemon => {
+                warn!("Restarting incompatible daemon: {}", e);
+                kill_daemon()?;
+            }
+            Err(e) => {
+                error!("{}", e);
+                return Err(Error::Fs1541 {
+                    message: "Incompatible daemon running".into(),
+                    error: Fs1541Error::Validation(format!(
+                        "{} - restart the daemon, or run with --upgrade-daemon",
+                        e
+                    )),
+                });
+            }
+        }
     }
 
+    let start_time = Instant::now();
+
     let daemon_path = Path::new(&std::env::var("DAEMON_PATH").unwrap_or_default())
         .join(DAEMON_PNAME)
         .to_string_lossy()
@@ -152,13 +237,46 @@ fn ensure_daemon_running() -> Result<(), Error> {
 }
 
 fn send_request(request: Request) -> Result<Response, Error> {
-    let mut stream = UnixStream::connect(get_socket_path()).map_err(|e| Error::Io {
+    let mut stream = connect_daemon(Some(OPERATION_TIMEOUT))?;
+    write_request(&mut stream, &request)?;
+    read_response(&mut stream, request.max_response_size())
+}
+
+/// Subscribes to the device's events and prints each one as it arrives.
+/// Only returns on error, including the daemon exiting.
+fn watch(device: u8) -> Result<(), Error> {
+    // Events may be a long time coming, so there's no read timeout
+    let request = Request::Subscribe { device };
+    let mut stream = connect_daemon(None)?;
+    write_request(&mut stream, &request)?;
+
+    loop {
+        match read_response(&mut stream, request.max_response_size())? {
+            Response::Subscribed => info!("Watching device {}", device),
+            Response::Event(event) => {
+                info!("{}", event);
+                println!("{} {}", Local::now().format("%H:%M:%S"), event);
+            }
+            Response::Error { message, .. } => {
+                return Err(Error::Fs1541 {
+                    message: "Failed to watch device".into(),
+                    error: Fs1541Error::Operation(message),
+                })
+            }
+            rsp => warn!("Unexpected response while watching: {}", rsp),
+        }
+    }
+}
+
+/// Connects to the daemon, with the given read timeout
+fn connect_daemon(read_timeout: Option<Duration>) -> Result<UnixStream, Error> {
+    let stream = UnixStream::connect(get_socket_path()).map_err(|e| Error::Io {
         message: "Failed to connect to daemon".into(),
         error: e.to_string(),
     })?;
 
     stream
-        .set_read_timeout(Some(OPERATION_TIMEOUT))
+        .set_read_timeout(read_timeout)
         .map_err(|e| Error::Io {
             message: "Failed to set read timeout".into(),
             error: e.to_string(),
@@ -171,58 +289,50 @@ fn send_request(request: Request) -> Result<Response, Error> {
             error: e.to_string(),
         })?;
 
-    serde_json::to_writer(&mut stream, &request).map_err(|e| Error::Serde {
+    Ok(stream)
+}
+
+/// Sends a length prefixed request to the daemon
+fn write_request(stream: &mut UnixStream, request: &Request) -> Result<(), Error> {
+    let frame = encode_frame(request).map_err(|e| Error::Serde {
         message: "Failed to serialize request".into(),
         error: e.to_string(),
     })?;
 
-    writeln!(&mut stream).map_err(|e| Error::Io {
-        message: "Failed to write newline".into(),
+    stream.write_all(&frame).map_err(|e| Error::Io {
+        message: "Failed to write request".into(),
         error: e.to_string(),
     })?;
 
     stream.flush().map_err(|e| Error::Io {
         message: "Failed to flush request".into(),
         error: e.to_string(),
-    })?;
-
-    let mut response_data = String::new();
-    let mut buf = [0u8; 4096];
+    })
+}
 
-    loop {
-        match stream.read(&mut buf) {
-            Ok(0) => break,
-            Ok(n) => {
-                if response_data.len() + n > MAX_RESPONSE_SIZE {
-                    return Err(Error::Fs1541 {
-                        message: "Response size exceeded limit".into(),
-                        error: Fs1541Error::Validation(format!(
-                            "Response exceeded maximum size of {} bytes",
-                            MAX_RESPONSE_SIZE
-                        )),
-                    });
-                }
-                response_data.push_str(&String::from_utf8_lossy(&buf[..n]));
-            }
-            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                return Err(Error::Fs1541 {
-                    message: "Operation timed out".into(),
-                    error: Fs1541Error::Timeout(
-                        "Response read timed out".into(),
-                        OPERATION_TIMEOUT,
-                    ),
-                })
-            }
-            Err(e) => {
-                return Err(Error::Io {
-                    message: "Failed to read response".into(),
-                    error: e.to_string(),
-                })
-            }
-        }
+/// Reads and parses a length prefixed response from the daemon, rejecting it
+/// if it exceeds max_size bytes.  The response is only decoded once it has
+/// all been read, so multibyte characters split across reads aren't
+/// corrupted.
+fn read_response<R: Read>(reader: &mut R, max_size: usize) -> Result<Response, Error> {
+    let mut prefix = [0u8; FRAME_LEN_SIZE];
+    read_response_bytes(reader, &mut prefix)?;
+
+    let len = frame_len(prefix);
+    if len > max_size {
+        return Err(Error::Fs1541 {
+            message: "Response size exceeded limit".into(),
+            error: Fs1541Error::Validation(format!(
+                "Response of {} bytes exceeded maximum size of {} bytes",
+                len, max_size
+            )),
+        });
     }
 
-    serde_json::from_str(&response_data).map_err(|e| Error::Serde {
+    let mut response_data = vec![0u8; len];
+    read_response_bytes(reader, &mut response_data)?;
+
+    serde_json::from_slice(&response_data).map_err(|e| Error::Serde {
         message: "Failed to parse response".into(),
         error: e.to_string(),
     })
@@ -234,12 +344,26 @@ fn create_request(operation: ClientOperation) -> Request {
             device,
             mountpoint,
             dummy_formats,
+            auto_refresh_secs,
+            read_write,
+            safe_replace,
+            verify_writes,
+            interleave,
+            mkdir,
+            image,
             ..
         } => Request::Mount {
             mountpoint,
             device,
             dummy_formats,
             bus_reset: false,
+            auto_refresh_secs,
+            read_write,
+            safe_replace,
+            verify_writes,
+            interleave,
+            mkdir,
+            image,
         },
         ClientOperation::Unmount {
             device, mountpoint, ..
@@ -247,15 +371,76 @@ fn create_request(operation: ClientOperation) -> Request {
         ClientOperation::Identify { device } => Request::Identify { device },
         ClientOperation::Getstatus { device } => Request::GetStatus { device },
         ClientOperation::Resetbus => Request::BusReset,
+        ClientOperation::Scanbus => Request::ScanBus,
+        ClientOperation::Health => Request::Health,
+        ClientOperation::Metrics { reset } => Request::Metrics { reset },
+        ClientOperation::Mounts => Request::ListMounts,
+        ClientOperation::Watch { device } => Request::Subscribe { device },
+        ClientOperation::Setdevice { device, new_device } => {
+            Request::SetDeviceNumber { device, new_device }
+        }
+        ClientOperation::Refreshdir { device, drive } => Request::RefreshDir { device, drive },
+        ClientOperation::Loglevel { level: Some(level) } => Request::SetLogLevel { level },
+        ClientOperation::Loglevel { level: None } => Request::GetLogLevel,
+        ClientOperation::Read {
+            device, filename, ..
+        } => Request::ReadFile { device, filename },
+        ClientOperation::Memread { device, addr, len } => Request::MemoryRead { device, addr, len },
+        ClientOperation::Memwrite { device, addr, data } => {
+            Request::MemoryWrite { device, addr, data }
+        }
+        ClientOperation::Memexec { device, addr } => Request::MemoryExecute { device, addr },
         ClientOperation::Kill => Request::Die,
     }
 }
 
-fn main() -> Result<()> {
-    init_logging(false, env!("CARGO_BIN_NAME").into());
-    info!("Logging initialized");
+/// Returns the portion of a file's contents to output, optionally stripping
+/// the 2 byte load address from the start of a PRG file
+fn file_output(contents: &[u8], strip_load_addr: bool) -> Result<&[u8], Error> {
+    if !strip_load_addr {
+        Ok(contents)
+    } else if contents.len() < 2 {
+        Err(Error::Fs1541 {
+            message: "Failed to strip load address".into(),
+            error: Fs1541Error::Validation(format!(
+                "File is only {} bytes long, so has no load address",
+                contents.len()
+            )),
+        })
+    } else {
+        Ok(&contents[2..])
+    }
+}
+
+/// Formats drive memory as lines of hex, 16 bytes to a line, each prefixed
+/// with its address
+fn memory_dump(addr: u16, data: &[u8]) -> Vec<String> {
+    data.chunks(16)
+        .enumerate()
+        .map(|(ii, chunk)| {
+            let bytes: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+            format!(
+                "${:04X}: {}",
+                addr.wrapping_add((ii * 16) as u16),
+                bytes.join(" ")
+            )
+        })
+        .collect()
+}
 
+fn main() -> Result<()> {
+    // Parse args before initializing logging, as --quiet changes the level
     let args = Args::parse();
+
+    // Logging goes to stdout, so turn it off if the file being read is to be
+    // written there.  Errors are still reported on stderr when we exit
+    let level = if matches!(args.operation, ClientOperation::Read { .. }) {
+        Some(LevelFilter::Off)
+    } else {
+        args.quiet.then_some(LevelFilter::Warn)
+    };
+    init_logging(false, env!("CARGO_BIN_NAME").into(), level, false);
+    info!("Logging initialized");
     let validated_args = args.validate().map_err(|e| {
         error!("{}", e);
         anyhow!("Argument validation failed: {}", e)
@@ -263,11 +448,33 @@ fn main() -> Result<()> {
 
     let operation = validated_args.operation;
     operation.log();
+    let strip_load_addr = matches!(
+        operation,
+        ClientOperation::Read {
+            strip_load_addr: true,
+            ..
+        }
+    );
 
-    ensure_daemon_running().context("Failed to ensure daemon is running")?;
+    ensure_daemon_running(validated_args.upgrade_daemon)
+        .context("Failed to ensure daemon is running")?;
+
+    // Watching keeps the connection open for as long as events are wanted
+    if let ClientOperation::Watch { device } = operation {
+        return Ok(watch(device)?);
+    }
 
     match send_request(create_request(operation))? {
-        Response::Error(err) => Err(anyhow!(err)),
+        Response::Error { code, message } => {
+            error!("Operation failed with error code {}: {}", code, message);
+            eprintln!("Error: {}", message);
+            std::process::exit(error_exit_code(code));
+        }
+        Response::NotMounted(what) => {
+            warn!("Nothing to unmount");
+            eprintln!("Not mounted: no mount found for {}", what);
+            std::process::exit(NOT_MOUNTED_EXIT_CODE);
+        }
         Response::Identified {
             device_type,
             description,
@@ -277,15 +484,177 @@ fn main() -> Result<()> {
             println!("{}", output);
             Ok(())
         }
+        #[allow(deprecated)]
         Response::GotStatus(status) => {
             info!("Status {}", status);
             println!("Status {}", status);
             Ok(())
         }
+        Response::GotStatusStructured {
+            error_number,
+            track,
+            sector,
+            message,
+        } => {
+            info!("Status {error_number:02},{message},{track:02},{sector:02}");
+            println!("Error number: {error_number:02}");
+            println!("Message:      {message}");
+            println!("Track:        {track:02}");
+            println!("Sector:       {sector:02}");
+            Ok(())
+        }
+        Response::BusScan(devices) => {
+            for (device, identity) in devices {
+                match identity {
+                    Some(identity) => println!(
+                        "Device {:2}: Model {} Description \"{}\"",
+                        device, identity.device_type, identity.description
+                    ),
+                    None => println!("Device {:2}: No response", device),
+                }
+            }
+            Ok(())
+        }
+        Response::Health {
+            uptime_secs,
+            active_mounts,
+            last_bus_reset_secs,
+            xum1541_open,
+        } => {
+            info!("Daemon up {uptime_secs}s with {active_mounts} active mounts");
+            println!("Uptime:         {uptime_secs}s");
+            println!("Active mounts:  {active_mounts}");
+            match last_bus_reset_secs {
+                Some(secs) => println!("Last bus reset: {secs}s ago"),
+                None => println!("Last bus reset: never"),
+            }
+            match xum1541_open {
+                Some(true) => println!("XUM1541:        open"),
+                Some(false) => println!("XUM1541:        closed"),
+                None => println!("XUM1541:        unknown"),
+            }
+            Ok(())
+        }
+        Response::Metrics(metrics) => {
+            println!(
+                "{:<18} {:>7} {:>6} {:>8} {:>8} {:>8} {:>9} {:>10}",
+                "Operation", "Count", "Errors", "Min ms", "Avg ms", "Max ms", "Queued ms", "Bytes"
+            );
+            for m in metrics {
+                println!(
+                    "{:<18} {:>7} {:>6} {:>8} {:>8} {:>8} {:>9} {:>10}",
+                    m.operation,
+                    m.count,
+                    m.errors,
+                    m.min_ms,
+                    m.avg_ms,
+                    m.max_ms,
+                    m.avg_queued_ms,
+                    m.bytes
+                );
+            }
+            Ok(())
+        }
+        Response::Mounts(mounts) => {
+            println!(
+                "{:<6} {:<10} {:>6}  {:<24} {}",
+                "Device", "Model", "Drives", "Mounted", "Mountpoint"
+            );
+            for m in mounts {
+                let mounted = m
+                    .mount_time_secs
+                    .map(|secs| {
+                        let time: DateTime<Local> = (UNIX_EPOCH + Duration::from_secs(secs)).into();
+                        time.format("%Y-%m-%d %H:%M:%S").to_string()
+                    })
+                    .unwrap_or_else(|| "-".to_string());
+                println!(
+                    "{:<6} {:<10} {:>6}  {:<24} {}",
+                    m.device, m.model, m.num_drives, mounted, m.mountpoint
+                );
+            }
+            Ok(())
+        }
+        Response::LogLevel(level) => {
+            info!("Log level {}", level);
+            println!("Log level {}", level);
+            Ok(())
+        }
+        Response::BusResetSuccess {
+            reinitialized_mounts,
+        } => {
+            let output = format!("Bus reset, {} mounts re-initialized", reinitialized_mounts);
+            info!("{}", output);
+            println!("{}", output);
+            Ok(())
+        }
+        Response::DeviceNumberSet { device, new_device } => {
+            let output = format!("Device {} is now device {}", device, new_device);
+            info!("{}", output);
+            println!("{}", output);
+            Ok(())
+        }
+        Response::DirRefreshed { device, drive } => {
+            let output = format!("Device {} drive {} directory refreshed", device, drive);
+            info!("{}", output);
+            println!("{}", output);
+            Ok(())
+        }
+        Response::MemoryContents { device, addr, data } => {
+            info!(
+                "Read {} bytes of device {} memory from ${:04X}",
+                data.len(),
+                device,
+                addr
+            );
+            for line in memory_dump(addr, &data) {
+                println!("{}", line);
+            }
+            Ok(())
+        }
+        Response::MemoryWritten { device, addr, len } => {
+            let output = format!(
+                "Wrote {} bytes to device {} memory at ${:04X}",
+                len, device, addr
+            );
+            info!("{}", output);
+            println!("{}", output);
+            Ok(())
+        }
+        Response::MemoryExecuted { device, addr } => {
+            let output = format!("Device {} ran the code at ${:04X}", device, addr);
+            info!("{}", output);
+            println!("{}", output);
+            Ok(())
+        }
+        Response::FileContents(contents) => {
+            info!("Read {} bytes", contents.len());
+            let output = file_output(&contents, strip_load_addr)?;
+            let mut stdout = std::io::stdout().lock();
+            stdout
+                .write_all(output)
+                .and_then(|_| stdout.flush())
+                .context("Failed to write file to stdout")
+        }
         _ => Ok(()),
     }
 }
 
+/// Fills buf from the daemon's response.  Reads interrupted by a signal are
+/// retried.
+fn read_response_bytes<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<(), Error> {
+    reader.read_exact(buf).map_err(|e| match e.kind() {
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => Error::Fs1541 {
+            message: "Operation timed out".into(),
+            error: Fs1541Error::Timeout("Response read timed out".into(), OPERATION_TIMEOUT),
+        },
+        _ => Error::Io {
+            message: "Failed to read response".into(),
+            error: e.to_string(),
+        },
+    })
+}
+
 // Platform-specific implementations
 #[cfg(not(test))]
 fn get_socket_path() -> &'static str {
@@ -328,7 +697,8 @@ mod test {
     use super::*;
     use crate::args::ClientOperation;
     use anyhow::Result;
-    use fs1541::ipc::{Request, Response};
+    use fs1541::error::{ERROR_CODE_DRIVE, ERROR_CODE_GENERAL};
+    use fs1541::ipc::{encode_frame, Request, Response, MAX_RESPONSE_SIZE};
     use std::io::{Read, Write};
     use std::process::Command;
 
@@ -379,6 +749,35 @@ mod test {
             }
         }
 
+        #[test]
+        fn test_check_protocol_version_match() {
+            let response = Response::Version {
+                version: "0.0.0".into(),
+                protocol: PROTOCOL_VERSION,
+            };
+            assert!(check_protocol_version(response).is_ok());
+        }
+
+        #[test]
+        fn test_check_protocol_version_mismatch() {
+            let response = Response::Version {
+                version: "0.0.0".into(),
+                protocol: PROTOCOL_VERSION + 1,
+            };
+            match check_protocol_version(response) {
+                Err(Error::Fs1541 {
+                    error: Fs1541Error::Validation(msg),
+                    ..
+                }) => assert!(msg.contains("protocol version")),
+                other => panic!("Expected Fs1541Error::Validation, got {:?}", other),
+            }
+        }
+
+        #[test]
+        fn test_check_protocol_version_unexpected_response() {
+            assert!(check_protocol_version(Response::Pong).is_err());
+        }
+
         #[test]
         fn test_command_env_if_exists() {
             std::env::set_var("TEST_VAR", "test_value");
@@ -403,6 +802,13 @@ mod test {
                 dummy_formats: true,
                 mountpoint: "/test/mount".to_string(),
                 path: None,
+                auto_refresh_secs: None,
+                read_write: true,
+                safe_replace: true,
+                verify_writes: true,
+                interleave: Some(6),
+                mkdir: true,
+                image: Some("/test/disk.d64".into()),
             };
 
             let request = create_request(operation);
@@ -412,11 +818,25 @@ mod test {
                     device,
                     dummy_formats,
                     bus_reset,
+                    auto_refresh_secs,
+                    read_write,
+                    safe_replace,
+                    verify_writes,
+                    interleave,
+                    mkdir,
+                    image,
                 } => {
                     assert_eq!(mountpoint, "/test/mount");
                     assert_eq!(device, 8);
                     assert!(dummy_formats);
                     assert!(!bus_reset);
+                    assert!(auto_refresh_secs.is_none());
+                    assert!(read_write);
+                    assert!(safe_replace);
+                    assert!(verify_writes);
+                    assert_eq!(interleave, Some(6));
+                    assert!(mkdir);
+                    assert_eq!(image, Some("/test/disk.d64".into()));
                 }
                 _ => panic!("Expected Mount request"),
             }
@@ -471,6 +891,21 @@ mod test {
             }
         }
 
+        #[test]
+        fn test_create_request_refreshdir() {
+            let operation = ClientOperation::Refreshdir {
+                device: 9,
+                drive: None,
+            };
+            match create_request(operation) {
+                Request::RefreshDir { device, drive } => {
+                    assert_eq!(device, 9);
+                    assert_eq!(drive, None);
+                }
+                _ => panic!("Expected RefreshDir request"),
+            }
+        }
+
         #[test]
         fn test_create_request_resetbus() {
             let operation = ClientOperation::Resetbus;
@@ -492,10 +927,29 @@ mod test {
         #[test]
         fn test_response_handling() {
             let test_cases = vec![
-                (Response::Error("test error".into()), true),
+                (
+                    Response::Error {
+                        code: ERROR_CODE_GENERAL,
+                        message: "test error".into(),
+                    },
+                    true,
+                ),
                 (Response::MountSuccess, false),
                 (Response::UnmountSuccess, false),
-                (Response::BusResetSuccess, false),
+                (Response::NotMounted("device 9".into()), false),
+                (
+                    Response::BusResetSuccess {
+                        reinitialized_mounts: 1,
+                    },
+                    false,
+                ),
+                (
+                    Response::DirRefreshed {
+                        device: 8,
+                        drive: 0,
+                    },
+                    false,
+                ),
                 (Response::Pong, false),
                 (Response::Dying, false),
                 (
@@ -514,18 +968,156 @@ mod test {
             }
         }
 
+        #[test]
+        fn test_read_large_response() {
+            // A file of over 1MB, which will be several times that once
+            // encoded as JSON
+            let contents: Vec<u8> = (0..(MAX_RESPONSE_SIZE + 1))
+                .map(|ii| (ii % 256) as u8)
+                .collect();
+            let data = encode_frame(&Response::FileContents(contents.clone())).unwrap();
+            assert!(data.len() > MAX_RESPONSE_SIZE);
+
+            let request = Request::ReadFile {
+                device: 8,
+                filename: "bigfile".into(),
+            };
+            let mut reader = std::io::Cursor::new(data.clone());
+            match read_response(&mut reader, request.max_response_size()) {
+                Ok(Response::FileContents(read)) => assert_eq!(read, contents),
+                other => panic!("Expected FileContents, got {:?}", other),
+            }
+
+            // The same response must be rejected for a non-bulk request
+            let mut reader = std::io::Cursor::new(data);
+            match read_response(&mut reader, Request::Ping.max_response_size()) {
+                Err(Error::Fs1541 {
+                    error: Fs1541Error::Validation(_),
+                    ..
+                }) => {}
+                other => panic!("Expected Fs1541Error::Validation, got {:?}", other),
+            }
+        }
+
+        /// Returns data in chunks of the given sizes, interrupted before each
+        struct ChunkedReader {
+            data: Vec<u8>,
+            chunks: Vec<usize>,
+            interrupt: bool,
+        }
+
+        impl Read for ChunkedReader {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                self.interrupt = !self.interrupt;
+                if self.interrupt {
+                    return Err(std::io::ErrorKind::Interrupted.into());
+                }
+                let len = match self.chunks.is_empty() {
+                    true => self.data.len(),
+                    false => self.chunks.remove(0),
+                };
+                let len = len.min(self.data.len()).min(buf.len());
+                buf[..len].copy_from_slice(&self.data[..len]);
+                self.data.drain(..len);
+                Ok(len)
+            }
+        }
+
+        #[test]
+        fn test_read_chunked_response() {
+            let message =
+                "Drive said \u{2192} \u{00e4}\u{00f6}\u{00fc}\n73,CBM DOS V2.6 1541,00,00";
+            let data = encode_frame(&Response::Error {
+                code: ERROR_CODE_GENERAL,
+                message: message.into(),
+            })
+            .unwrap();
+
+            // Split part way through the multibyte characters
+            let arrow = data
+                .windows(3)
+                .position(|w| w == "\u{2192}".as_bytes())
+                .unwrap();
+            let mut reader = ChunkedReader {
+                data,
+                chunks: vec![arrow + 1, 1, 3, 7],
+                interrupt: false,
+            };
+            match read_response(&mut reader, MAX_RESPONSE_SIZE) {
+                Ok(Response::Error { message: read, .. }) => assert_eq!(read, message),
+                other => panic!("Expected Error, got {:?}", other),
+            }
+        }
+
+        #[test]
+        fn test_error_exit_code() {
+            // Each category of error has its own exit code
+            assert_eq!(error_exit_code(ERROR_CODE_GENERAL), 1);
+            assert_eq!(error_exit_code(ERROR_CODE_VALIDATION), 12);
+            assert_eq!(error_exit_code(ERROR_CODE_DRIVE), 16);
+            assert_eq!(error_exit_code(ERROR_CODE_INTERNAL), 22);
+
+            // Unknown codes, including from daemons which don't send them
+            assert_eq!(error_exit_code(0), 1);
+            assert_eq!(error_exit_code(ERROR_CODE_INTERNAL + 1), 1);
+        }
+
+        #[test]
+        fn test_file_output() {
+            let contents = [0x01, 0x08, 0xaa, 0xbb];
+            assert_eq!(file_output(&contents, false).unwrap(), &contents[..]);
+            assert_eq!(file_output(&contents, true).unwrap(), &[0xaa, 0xbb][..]);
+
+            // Files too short to have a load address can't have it stripped
+            assert_eq!(file_output(&contents[..1], false).unwrap(), &[0x01][..]);
+            assert!(file_output(&contents[..1], true).is_err());
+            assert!(file_output(&contents[..2], true).unwrap().is_empty());
+        }
+
+        #[test]
+        fn test_memory_dump() {
+            let data: Vec<u8> = (0..20).collect();
+            assert_eq!(
+                memory_dump(0x0300, &data),
+                vec![
+                    "$0300: 00 01 02 03 04 05 06 07 08 09 0a 0b 0c 0d 0e 0f",
+                    "$0310: 10 11 12 13",
+                ]
+            );
+            assert!(memory_dump(0x0300, &[]).is_empty());
+        }
+
         // Helper function for testing response handling
         // TODO: Consider extracting response handling from main() into a shared function
         fn handle_response(response: &Response, _request: &Request) -> Result<()> {
             match response {
-                Response::Error(err) => Err(anyhow::anyhow!("Operation failed: {}", err)),
+                Response::Error { message, .. } => {
+                    Err(anyhow::anyhow!("Operation failed: {}", message))
+                }
                 Response::MountSuccess => Ok(()),
                 Response::UnmountSuccess => Ok(()),
-                Response::BusResetSuccess => Ok(()),
+                Response::NotMounted(_) => Ok(()),
+                Response::BusResetSuccess { .. } => Ok(()),
+                Response::BusScan(_) => Ok(()),
                 Response::Pong => Ok(()),
+                Response::Health { .. } => Ok(()),
+                Response::Metrics(_) => Ok(()),
+                Response::Mounts(_) => Ok(()),
+                Response::Subscribed => Ok(()),
+                Response::Event(_) => Ok(()),
                 Response::Dying => Ok(()),
                 Response::Identified { .. } => Ok(()),
+                #[allow(deprecated)]
                 Response::GotStatus(_) => Ok(()),
+                Response::GotStatusStructured { .. } => Ok(()),
+                Response::Version { .. } => Ok(()),
+                Response::LogLevel(_) => Ok(()),
+                Response::FileContents(_) => Ok(()),
+                Response::DeviceNumberSet { .. } => Ok(()),
+                Response::DirRefreshed { .. } => Ok(()),
+                Response::MemoryContents { .. } => Ok(()),
+                Response::MemoryWritten { .. } => Ok(()),
+                Response::MemoryExecuted { .. } => Ok(()),
             }
         }
     }
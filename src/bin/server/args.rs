@@ -119,6 +119,18 @@ pub struct Args {
     )]
     pub std_logging: bool,
 
+    #[arg(
+        short = 'j',
+        long = "json-logging",
+        action = ArgAction::SetTrue,
+        env = "FS1541_JSON_LOGGING",
+        value_parser = clap::value_parser!(bool),
+        next_line_help = true,
+        help = "Log JSON records when logging to stdout",
+        long_help = "Log each record as a JSON object, one per line, when logging\nto stdout.  Each record includes the PID, log level and module, and\nthe device and operation being handled, if any.  This is useful when\ncollecting 1541fsd's logs with journald or a log collector.  Has\nno effect when logging to syslog."
+    )]
+    pub json_logging: bool,
+
     #[arg(
         short = 'n',
         long = "network",
@@ -199,6 +211,17 @@ pub struct Args {
     )]
     pub file_reread_timeout_secs: u64,
 
+    #[arg(
+        long,
+        env = "FS1541_IMAGE_READ_TIMEOUT_SECS",
+        default_value = "600",
+        help_heading = "Timer Values",
+        next_line_help = true,
+        help = "How long 1541fs will wait for a disk image to be read",
+        long_help = "How long the filesystem will wait for a disk image to be read\nfrom the drive, when the image dump control file is read, before\ngiving up.  Reading an image reads every block on the disk, so takes\nmuch longer than reading a file."
+    )]
+    pub image_read_timeout_secs: u64,
+
     #[arg(
         long,
         env = "FS1541_DIR_READ_SLEEP_MS",
@@ -232,6 +255,39 @@ pub struct Args {
     )]
     pub bg_age_check_secs: u64,
 
+    #[arg(
+        long,
+        env = "FS1541_BUS_RESET_SETTLE_MS",
+        default_value = "500",
+        help_heading = "Timer Values",
+        next_line_help = true,
+        help = "How long to wait after a bus reset before using the bus",
+        long_help = "Drives need a brief period after a bus reset before they respond\nreliably - the first operation immediately after a reset may\nspuriously fail.  This is applied both at startup (after the xum1541\nhas been initialized, which resets the bus) and after any requested\nbus reset.  Higher values make the first operation after a reset\nmore reliable, at the cost of slower startup and bus resets."
+    )]
+    pub bus_reset_settle_ms: u64,
+
+    #[arg(
+        long,
+        env = "FS1541_DISK_CHANGE_POLL_SECS",
+        default_value = "0",
+        help_heading = "Timer Values",
+        next_line_help = true,
+        help = "How often to check idle drives for a changed disk (0 disables)",
+        long_help = "How often the filesystem checks whether the disk in an idle mounted\ndrive has been swapped, by reading the disk's header and comparing it\nwith the cached directory's.  If the disk has changed, the directory\nand file caches for the drive are discarded.  Only 1541s are checked.\n0 disables the check."
+    )]
+    pub disk_change_poll_secs: u64,
+
+    #[arg(
+        long,
+        env = "FS1541_IPC_IDLE_TIMEOUT_SECS",
+        default_value = "60",
+        help_heading = "Timer Values",
+        next_line_help = true,
+        help = "How long an idle client connection is kept open",
+        long_help = "Clients may send several requests over one connection.  If no\nrequest is received on a connection for this long it is closed."
+    )]
+    pub ipc_idle_timeout_secs: u64,
+
     #[arg(
         long,
         env = "FS1541_DIR_ATTR_TTL_MS",
@@ -276,6 +332,140 @@ pub struct Args {
     )]
     pub file_lookup_ttl_ms: u64,
 
+    #[arg(
+        long,
+        env = "FS1541_FUSE_NOTIFY",
+        action = ArgAction::SetTrue,
+        help_heading = "TTL Values",
+        next_line_help = true,
+        help = "Tell the kernel to drop cached entries when a directory changes",
+        long_help = "When a re-read directory differs from the cached one, notify the\nkernel to drop its cached entries and attributes for the changed\nfiles, rather than waiting for the TTLs above to expire.  Requires\nFUSE protocol 7.12 or later, so is disabled by default in case the\nkernel doesn't support it."
+    )]
+    pub fuse_notify: bool,
+
+    #[arg(
+        long,
+        env = "FS1541_MAX_MOUNTS",
+        default_value = "8",
+        help_heading = "Limits",
+        next_line_help = true,
+        help = "The maximum number of concurrent mounts",
+        long_help = "The maximum number of drives which may be mounted at once.  Each\nmount runs its own FUSE session thread and background response\nthread, in addition to 1541fsd's fixed pool of worker threads, and\nholds its directory listing and file caches in memory.  Further mounts\nare rejected once this limit is reached."
+    )]
+    pub max_mounts: usize,
+
+    #[arg(
+        long,
+        env = "FS1541_MAX_SUBSCRIBERS",
+        default_value = "4",
+        help_heading = "Limits",
+        next_line_help = true,
+        help = "The maximum number of clients subscribed to events",
+        long_help = "The maximum number of clients which may be subscribed to device\nevents (status changes, disk changes, mounts and unmounts) at once.\nEach holds a connection open to the daemon.  Further subscriptions\nare rejected once this limit is reached."
+    )]
+    pub max_subscribers: usize,
+
+    #[arg(
+        long,
+        env = "FS1541_MAX_WRITE_BUFFER_BYTES",
+        default_value = "174848",
+        help_heading = "Limits",
+        next_line_help = true,
+        help = "The maximum size of a file being written, in bytes",
+        long_help = "The maximum number of bytes 1541fsd buffers for a file being written,\nbefore writing it to the drive when the file is closed.  Writes beyond\nthis return ENOSPC.  The default is the capacity of a 1541 disk."
+    )]
+    pub max_write_buffer_bytes: usize,
+
+    #[arg(
+        long,
+        env = "FS1541_MAX_FILE_CACHE_BYTES",
+        default_value = "16777216",
+        help_heading = "Limits",
+        next_line_help = true,
+        help = "The maximum size of each mount's file caches, in bytes",
+        long_help = "The maximum number of bytes of file contents each mount caches.  Once\nthis is exceeded, the caches of the least recently read files are\ndropped, so those files are read from the drive again next time.\nFiles still being read are never dropped."
+    )]
+    pub max_file_cache_bytes: usize,
+
+    #[arg(
+        long,
+        env = "FS1541_PREFETCH",
+        action = ArgAction::SetTrue,
+        help_heading = "Cache Values",
+        next_line_help = true,
+        help = "Read every file into the cache after reading a directory",
+        long_help = "After reading a disk's directory, queue low priority reads of each of\nits files into the file cache, so they can be opened without waiting\nfor the drive.  Files larger than PREFETCH_MAX_FILE_BYTES, and those\nwhich would exceed MAX_FILE_CACHE_BYTES, are skipped."
+    )]
+    pub prefetch: bool,
+
+    #[arg(
+        long,
+        env = "FS1541_PREFETCH_MAX_FILE_BYTES",
+        default_value = "65536",
+        help_heading = "Cache Values",
+        next_line_help = true,
+        help = "The largest file to prefetch, in bytes",
+        long_help = "Files larger than this are not prefetched, even with --prefetch, as\nthey take a long time to read and hold up other reads of the drive."
+    )]
+    pub prefetch_max_file_bytes: usize,
+
+    #[arg(
+        long = "no-identify-at-startup",
+        action = ArgAction::SetFalse,
+        env = "FS1541_IDENTIFY_AT_STARTUP",
+        value_parser = clap::value_parser!(bool),
+        help_heading = "Reliability",
+        next_line_help = true,
+        help = "Disable identifying all drives on the bus at startup",
+        long_help = "By default, 1541fsd identifies all drives on the bus when it starts\nand caches their types, so later mounts of those drives skip the\nidentify round-trip.  A cached type is discarded if a mount of that\ndrive fails, in case the drive has been swapped.  Set this option to\nskip the startup scan, which speeds up startup when few drives are\nattached."
+    )]
+    pub identify_at_startup: bool,
+
+    #[arg(
+        long = "no-read-size-check",
+        action = ArgAction::SetFalse,
+        env = "FS1541_READ_SIZE_CHECK",
+        value_parser = clap::value_parser!(bool),
+        help_heading = "Reliability",
+        next_line_help = true,
+        help = "Disable checking file sizes read against directory block counts",
+        long_help = "By default, after reading a file 1541fs checks the number of bytes\nread is consistent with the number of blocks the directory listing\nsays the file uses.  If not, the data is still returned, but is not\ncached, so the file will be re-read next time.  This catches silent\ntruncation caused by flaky hardware.  Set this option to disable\nthe check."
+    )]
+    pub read_size_check: bool,
+
+    #[arg(
+        long,
+        env = "FS1541_READ_SIZE_TOLERANCE_BLOCKS",
+        default_value = "1",
+        help_heading = "Reliability",
+        next_line_help = true,
+        help = "How many blocks a file read may differ by from its directory entry",
+        long_help = "The number of blocks (of 254 bytes) the size of a file read may\ndiffer from the block count in its directory entry before the read\nis considered suspect."
+    )]
+    pub read_size_tolerance_blocks: u16,
+
+    #[arg(
+        long,
+        env = "FS1541_RETRIES",
+        default_value = "2",
+        help_heading = "Reliability",
+        next_line_help = true,
+        help = "How many times to retry reads which fail with transient drive errors",
+        long_help = "Directory and file reads which fail with a drive error which may not\nrecur, such as a read error (20-24, 27, 29) or drive not ready (74),\nare retried this many times.  Other errors, such as write protect or\nsyntax errors, are never retried.  0 disables retries."
+    )]
+    pub retries: u32,
+
+    #[arg(
+        long,
+        env = "FS1541_RETRY_DELAY_MS",
+        default_value = "500",
+        help_heading = "Reliability",
+        next_line_help = true,
+        help = "How long to wait before the first retry of a failed read",
+        long_help = "How long to wait before retrying a read which failed with a transient\ndrive error.  The delay doubles for each subsequent retry.  Retries\nare abandoned if they would take the operation past its timeout."
+    )]
+    pub retry_delay_ms: u64,
+
     /// Disable fuser auto-unmount option (mounts may remain on exit)
     #[arg(
         short = 'd',
@@ -286,6 +476,20 @@ pub struct Args {
         next_line_help = true,
         long_help = "By default, 1541fs will automatically unmount the filesystem\nwhen it exits.  However, if it crashes and is unable to clean-\nup, fuser will cleanup and unmount the filesystem.  If you wish\nto disable this behaviour, set this option.")]
     pub autounmount: bool,
+
+    #[arg(
+        long = "info-files",
+        action = ArgAction::SetTrue,
+        env = "FS1541_INFO_FILES",
+        value_parser = clap::value_parser!(bool),
+        next_line_help = true,
+        help = "Add .device_info and .drive_status files to each disk's directory",
+        long_help = "Add read-only .device_info and .drive_status files to each disk's
+directory, containing the drive's model and description, and its
+current status.  These mirror the user.device xattrs, so can be read
+with cat, rather than needing xattr tools."
+    )]
+    pub info_files: bool,
 }
 
 // Automatically sets us ARGS when Args::parse() is called
@@ -301,6 +505,12 @@ pub fn get_args() -> &'static Args {
     ARGS.get().unwrap()
 }
 
+/// Sets ARGS to the defaults, for tests of code which uses get_args()
+#[cfg(test)]
+pub fn init_test_args() -> &'static Args {
+    ARGS.get_or_init(|| Args::parse_from(["1541fsd"]))
+}
+
 fn get_effective_level(module: &str) -> Level {
     // Test each level from most verbose to least
     let levels = [
@@ -325,13 +535,15 @@ pub fn log_args(level: log::Level) {
     log!(level, "--------- 1541fsd Arguments ----------");
     log!(level, "Standard args.........................");
     if args.serial.is_some() {
-        log!(level, "  serial:      {}", args.serial.unwrap());
+        log!(level, "  serial:       {}", args.serial.unwrap());
     } else {
-        log!(level, "  serial:      <None>");
+        log!(level, "  serial:       <None>");
     }
-    log!(level, "  foreground:  {}", args.foreground);
-    log!(level, "  std_logging: {}", args.std_logging);
-    log!(level, "  autounmount: {}", args.autounmount);
+    log!(level, "  foreground:   {}", args.foreground);
+    log!(level, "  std_logging:  {}", args.std_logging);
+    log!(level, "  json_logging: {}", args.json_logging);
+    log!(level, "  autounmount:  {}", args.autounmount);
+    log!(level, "  info_files:   {}", args.info_files);
     log!(level, "Network xum1541 values ...............");
     log!(level, "  network:      {}", args.network);
     log!(
@@ -355,6 +567,12 @@ pub fn log_args(level: log::Level) {
         "  file_cache_expiry_secs:  {}s",
         args.file_cache_expiry_secs
     );
+    log!(level, "  prefetch:                {}", args.prefetch);
+    log!(
+        level,
+        "  prefetch_max_file_bytes: {}",
+        args.prefetch_max_file_bytes
+    );
     log!(level, "Timer values..........................");
     log!(
         level,
@@ -366,6 +584,11 @@ pub fn log_args(level: log::Level) {
         "  file_reread_timeout_secs:  {}s",
         args.file_reread_timeout_secs
     );
+    log!(
+        level,
+        "  image_read_timeout_secs:   {}s",
+        args.image_read_timeout_secs
+    );
     log!(
         level,
         "  dir_read_sleep_ms:         {}ms",
@@ -381,6 +604,56 @@ pub fn log_args(level: log::Level) {
         "  bg_age_check_secs:         {}s",
         args.bg_age_check_secs
     );
+    log!(
+        level,
+        "  bus_reset_settle_ms:       {}ms",
+        args.bus_reset_settle_ms
+    );
+    log!(
+        level,
+        "  disk_change_poll_secs:     {}s",
+        args.disk_change_poll_secs
+    );
+    log!(
+        level,
+        "  ipc_idle_timeout_secs:     {}s",
+        args.ipc_idle_timeout_secs
+    );
+    log!(level, "Limits................................");
+    log!(level, "  max_mounts:              {}", args.max_mounts);
+    log!(level, "  max_subscribers:         {}", args.max_subscribers);
+    log!(
+        level,
+        "  max_write_buffer_bytes:  {}",
+        args.max_write_buffer_bytes
+    );
+    log!(
+        level,
+        "  max_file_cache_bytes:    {}",
+        args.max_file_cache_bytes
+    );
+    log!(level, "Reliability values....................");
+    log!(
+        level,
+        "  identify_at_startup:         {}",
+        args.identify_at_startup
+    );
+    log!(
+        level,
+        "  read_size_check:             {}",
+        args.read_size_check
+    );
+    log!(
+        level,
+        "  read_size_tolerance_blocks:  {}",
+        args.read_size_tolerance_blocks
+    );
+    log!(level, "  retries:                     {}", args.retries);
+    log!(
+        level,
+        "  retry_delay_ms:              {}ms",
+        args.retry_delay_ms
+    );
     log!(level, "TTL values............................");
     log!(level, "  dir_attr_ttl_ms:     {}ms", args.dir_attr_ttl_ms);
     log!(level, "  file_attr_ttl_ms:    {}ms", args.file_attr_ttl_ms);
@@ -390,6 +663,7 @@ pub fn log_args(level: log::Level) {
         "  file_lookup_ttl_ms:  {}ms",
         args.file_lookup_ttl_ms
     );
+    log!(level, "  fuse_notify:         {}", args.fuse_notify);
     log!(level, "Logging settings......................");
     log!(
         level,
@@ -0,0 +1,943 @@
+use crate::file::{
+    ascii_to_petscii, cbm_append_open_name, cbm_block_read_command, cbm_block_write_command,
+    cbm_buffer_pointer_command, cbm_memory_execute_command, cbm_memory_read_command,
+    cbm_memory_write_commands, cbm_write_open_name, D64Geometry, D64_BLOCK_SIZE,
+};
+use crate::image::CbmImageBackend;
+
+use fs1541::error::{Error, Fs1541Error};
+use rs1541::{
+    Cbm, CbmDeviceInfo, CbmDeviceType, CbmDirListing, CbmDiskHeader, CbmDriveUnit, CbmErrorNumber,
+    CbmErrorNumberOk, CbmFileType, CbmStatus, CbmString,
+};
+
+use log::{debug, trace, warn};
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Data channel used to read and write files, and to access a drive buffer
+/// for block reads and writes.  Every bus operation is performed with the Cbm
+/// lock held, so nothing else has a file open on it.
+const DATA_CHANNEL: u8 = 2;
+
+/// The drive's command channel, over which it returns the memory read by
+/// M-R in place of its status
+const COMMAND_CHANNEL: u8 = 15;
+
+/// Command which resets the drive's DOS, as if it had been power cycled.
+/// Supported by all CBM DOS versions.
+const SOFT_RESET_COMMAND: &str = "UJ";
+
+/// How much of a file is read from the drive at a time.  Cancellation is
+/// checked between chunks.
+const READ_CHUNK_SIZE: usize = 256;
+
+/// The device operations the daemon needs, so they can be served either by
+/// the hardware or by a disk image.  Each operation is addressed to a device
+/// (or drive unit), which a disk image backend ignores.
+pub trait CbmBackend: Debug + Send {
+    /// Identifies the device's type
+    fn identify(&mut self, device: u8) -> Result<CbmDeviceInfo, Error>;
+
+    /// Reads the device's status channel
+    fn get_status(&mut self, device: u8) -> Result<CbmStatus, Error>;
+
+    /// Sends a raw command over the device's command channel
+    fn send_command(&mut self, device: u8, command: &[u8]) -> Result<(), Error>;
+
+    /// Sends an ASCII command over the device's command channel, converting
+    /// it to PETSCII first
+    fn send_string_command(&mut self, device: u8, command: &str) -> Result<(), Error>;
+
+    /// Formats the disk in the device's drive 0
+    fn format_disk(&mut self, device: u8, name: &str, id: &str) -> Result<(), Error>;
+
+    /// Resets the bus, and so every device on it
+    fn reset(&mut self) -> Result<(), Error>;
+
+    /// Resets the device's DOS with UJ, without resetting the bus.  The drive
+    /// forgets its open channels, so they are closed first, leaving none
+    /// allocated to the device afterwards.
+    fn soft_reset(&mut self, device: u8) -> Result<(), Error>;
+
+    /// Resets the USB device driving the bus and reopens it, for when it has
+    /// stopped responding.  This also resets the bus.
+    fn usb_reset(&mut self) -> Result<(), Error>;
+
+    /// Initializes each of the drive unit's drives, returning the result
+    /// for each
+    fn init(
+        &mut self,
+        drive_unit: &mut CbmDriveUnit,
+        ignore: &[CbmErrorNumber],
+    ) -> Vec<Result<CbmStatus, Error>>;
+
+    /// Reads the directory of each of the drive unit's drives
+    fn dir(&mut self, drive_unit: &CbmDriveUnit) -> Result<(Vec<CbmDirListing>, CbmStatus), Error>;
+
+    /// Reads a file from the drive unit, stopping with Fs1541Error::Cancelled
+    /// if cancelled is set part way through
+    fn read_file(
+        &mut self,
+        drive_unit: &CbmDriveUnit,
+        filename: &str,
+        cancelled: &AtomicBool,
+    ) -> Result<(Vec<u8>, CbmStatus), Error>;
+
+    /// Writes a new file to the disk
+    fn write_file(&mut self, device: u8, filename: &str, data: &[u8]) -> Result<(), Error>;
+
+    /// Writes a new SEQ file to the disk, opening it with ,s,w.  The
+    /// filename may have a drive number or @ prefix.  Unlike a PRG file, the
+    /// data has no load address, so is written exactly as given.
+    fn write_seq(&mut self, device: u8, filename: &str, data: &[u8]) -> Result<(), Error>;
+
+    /// Appends to an existing SEQ file on the disk, opening it with ,s,a.
+    /// The filename may have a drive number prefix.
+    fn append_to_seq(&mut self, device: u8, filename: &str, data: &[u8]) -> Result<(), Error>;
+
+    /// Reads a block from the disk, which must be within the geometry
+    fn read_block(
+        &mut self,
+        device: u8,
+        geometry: D64Geometry,
+        track: u8,
+        sector: u8,
+    ) -> Result<Vec<u8>, Error>;
+
+    /// Writes a block to the disk, which must be within the geometry
+    fn write_block(
+        &mut self,
+        device: u8,
+        geometry: D64Geometry,
+        track: u8,
+        sector: u8,
+        block: &[u8; D64_BLOCK_SIZE],
+    ) -> Result<(), Error>;
+
+    /// Reads len bytes of the drive's memory from addr, using M-R
+    fn memory_read(&mut self, device: u8, addr: u16, len: u8) -> Result<Vec<u8>, Error>;
+
+    /// Writes the data to the drive's memory at addr, using as many M-W
+    /// commands as it takes
+    fn memory_write(&mut self, device: u8, addr: u16, data: &[u8]) -> Result<(), Error>;
+
+    /// Has the drive run the code in its memory at addr, using M-E.  This
+    /// returns once the command has been sent, not when the code returns.
+    ///
+    /// Nothing checks what is at the address.  Code which doesn't return,
+    /// or which leaves interrupts disabled, stops the drive answering the
+    /// bus until it is reset or power cycled, and any bus operation started
+    /// meanwhile blocks, holding the Cbm lock.  Code which drives the disk
+    /// controller can damage the disk.  Callers should check the drive
+    /// answers afterwards, from a thread they can give up on.
+    fn memory_execute(&mut self, device: u8, addr: u16) -> Result<(), Error>;
+}
+
+impl CbmBackend for Cbm {
+    fn identify(&mut self, device: u8) -> Result<CbmDeviceInfo, Error> {
+        Cbm::identify(self, device).map_err(|e| Error::Rs1541 {
+            message: format!("Failed to identify drive {}", device),
+            error: e,
+        })
+    }
+
+    fn get_status(&mut self, device: u8) -> Result<CbmStatus, Error> {
+        Cbm::get_status(self, device).map_err(|e| Error::Rs1541 {
+            message: format!("Failed to get status for device {}", device),
+            error: e,
+        })
+    }
+
+    fn send_command(&mut self, device: u8, command: &[u8]) -> Result<(), Error> {
+        Cbm::send_command(self, device, command).map_err(|e| Error::Rs1541 {
+            message: format!("Failed to send command to device {}", device),
+            error: e,
+        })
+    }
+
+    fn send_string_command(&mut self, device: u8, command: &str) -> Result<(), Error> {
+        self.send_string_command_ascii(device, command)
+            .map_err(|e| Error::Rs1541 {
+                message: format!("Failed to send command {} to device {}", command, device),
+                error: e,
+            })
+    }
+
+    fn format_disk(&mut self, device: u8, name: &str, id: &str) -> Result<(), Error> {
+        Cbm::format_disk(self, device, name, id).map_err(|e| Error::Rs1541 {
+            message: format!("Failed to format disk in device {}", device),
+            error: e,
+        })
+    }
+
+    fn reset(&mut self) -> Result<(), Error> {
+        self.reset_bus().map_err(|e| Error::Rs1541 {
+            message: "Failed to reset bus".to_string(),
+            error: e,
+        })
+    }
+
+    fn soft_reset(&mut self, device: u8) -> Result<(), Error> {
+        cbm_close_channels(self, device);
+        CbmBackend::send_string_command(self, device, SOFT_RESET_COMMAND)
+    }
+
+    /// The new handle is swapped in inside this Cbm, so every clone of the
+    /// shared Cbm uses it from now on
+    fn usb_reset(&mut self) -> Result<(), Error> {
+        self.blocking_usb_reset_will_lock()
+            .map_err(|e| Error::Rs1541 {
+                message: "Failed to reset USB device".to_string(),
+                error: e,
+            })
+    }
+
+    fn init(
+        &mut self,
+        drive_unit: &mut CbmDriveUnit,
+        ignore: &[CbmErrorNumber],
+    ) -> Vec<Result<CbmStatus, Error>> {
+        let device = drive_unit.device_number;
+        drive_unit
+            .send_init(self, &ignore.to_vec())
+            .into_iter()
+            .map(|r| {
+                r.map_err(|e| Error::Rs1541 {
+                    message: format!("Failed to initialize drive {}", device),
+                    error: e,
+                })
+            })
+            .collect()
+    }
+
+    fn dir(&mut self, drive_unit: &CbmDriveUnit) -> Result<(Vec<CbmDirListing>, CbmStatus), Error> {
+        drive_unit.dir(self).map_err(|e| Error::Rs1541 {
+            message: format!(
+                "Failed to read directory for device {}",
+                drive_unit.device_number
+            ),
+            error: e,
+        })
+    }
+
+    fn read_file(
+        &mut self,
+        drive_unit: &CbmDriveUnit,
+        filename: &str,
+        cancelled: &AtomicBool,
+    ) -> Result<(Vec<u8>, CbmStatus), Error> {
+        cbm_read_file(self, drive_unit.device_number, filename, cancelled)
+    }
+
+    fn write_file(&mut self, device: u8, filename: &str, data: &[u8]) -> Result<(), Error> {
+        let cbm_filename = CbmString::from_ascii_bytes(filename.as_bytes());
+        Cbm::write_file(self, device, &cbm_filename, data).map_err(|e| Error::Rs1541 {
+            message: format!("Failed to write file {} for device {}", filename, device),
+            error: e,
+        })
+    }
+
+    fn write_seq(&mut self, device: u8, filename: &str, data: &[u8]) -> Result<(), Error> {
+        let name = cbm_write_open_name(0, filename, &CbmFileType::SEQ, false);
+        cbm_write_channel(self, device, &name, data)
+    }
+
+    fn append_to_seq(&mut self, device: u8, filename: &str, data: &[u8]) -> Result<(), Error> {
+        // SEQ files can always be appended to
+        let name = cbm_append_open_name(0, filename, &CbmFileType::SEQ).unwrap_or_default();
+        cbm_write_channel(self, device, &name, data)
+    }
+
+    fn read_block(
+        &mut self,
+        device: u8,
+        geometry: D64Geometry,
+        track: u8,
+        sector: u8,
+    ) -> Result<Vec<u8>, Error> {
+        cbm_read_block(self, device, geometry, track, sector).map(|block| block.to_vec())
+    }
+
+    fn write_block(
+        &mut self,
+        device: u8,
+        geometry: D64Geometry,
+        track: u8,
+        sector: u8,
+        block: &[u8; D64_BLOCK_SIZE],
+    ) -> Result<(), Error> {
+        cbm_write_block(self, device, geometry, track, sector, block)
+    }
+
+    fn memory_read(&mut self, device: u8, addr: u16, len: u8) -> Result<Vec<u8>, Error> {
+        cbm_memory_read(self, device, addr, len)
+    }
+
+    fn memory_write(&mut self, device: u8, addr: u16, data: &[u8]) -> Result<(), Error> {
+        trace!(
+            "Writing {} bytes to device {device} memory at ${addr:04X}",
+            data.len()
+        );
+        cbm_memory_write_commands(addr, data)
+            .iter()
+            .try_for_each(|command| CbmBackend::send_command(self, device, command))
+    }
+
+    fn memory_execute(&mut self, device: u8, addr: u16) -> Result<(), Error> {
+        trace!("Executing device {device} code at ${addr:04X}");
+        CbmBackend::send_command(self, device, &cbm_memory_execute_command(addr))
+    }
+}
+
+/// Reads a file from the device, READ_CHUNK_SIZE bytes at a time.  If the
+/// drive can't open the file, its status is returned with no contents.  If
+/// cancelled is set between chunks, the channel is untalked and closed, and
+/// Fs1541Error::Cancelled returned.
+fn cbm_read_file(
+    cbm: &mut Cbm,
+    device: u8,
+    filename: &str,
+    cancelled: &AtomicBool,
+) -> Result<(Vec<u8>, CbmStatus), Error> {
+    trace!("Reading file {filename} from device {device}");
+    let read_error = |e| Error::Rs1541 {
+        message: format!("Failed to read file {} for device {}", filename, device),
+        error: e,
+    };
+    with_channel(cbm, device, &ascii_to_petscii(filename), |cbm| {
+        let status = CbmBackend::get_status(cbm, device)?;
+        if status.is_ok() != CbmErrorNumberOk::Ok {
+            return Ok((Vec::new(), status));
+        }
+
+        cbm.talk(device, DATA_CHANNEL).map_err(read_error)?;
+        let mut contents = Vec::new();
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        let result = loop {
+            if cancelled.load(Ordering::Relaxed) {
+                debug!("Read of {filename} from device {device} cancelled");
+                break Err(Error::Fs1541 {
+                    message: "Operation cancelled".to_string(),
+                    error: Fs1541Error::Cancelled(format!("Read of {} cancelled", filename)),
+                });
+            }
+            match cbm.raw_read(&mut chunk) {
+                Ok(read) => {
+                    contents.extend_from_slice(&chunk[..read]);
+                    if read < READ_CHUNK_SIZE {
+                        break Ok((contents, status));
+                    }
+                }
+                Err(e) => break Err(read_error(e)),
+            }
+        };
+        if let Err(e) = cbm.untalk() {
+            warn!("Failed to untalk device {device}: {e}");
+        }
+        result
+    })
+}
+
+/// Opens the file with the ASCII name, including its type and mode suffix,
+/// and writes the data to it over DATA_CHANNEL as is.  Any problem opening
+/// the file is reported on the drive's status channel.
+fn cbm_write_channel(cbm: &mut Cbm, device: u8, name: &str, data: &[u8]) -> Result<(), Error> {
+    trace!("Writing {} bytes to {name} on device {device}", data.len());
+    with_channel(cbm, device, &ascii_to_petscii(name), |cbm| {
+        cbm.listen(device, DATA_CHANNEL)
+            .and_then(|_| {
+                let written = cbm.raw_write(data);
+                cbm.unlisten().and(written)
+            })
+            .map_err(|e| Error::Rs1541 {
+                message: format!("Failed to write {} to device {}", name, device),
+                error: e,
+            })
+            .and_then(|written| {
+                if written == data.len() {
+                    Ok(())
+                } else {
+                    Err(Error::Fs1541 {
+                        message: format!("Failed to write {} to device {}", name, device),
+                        error: Fs1541Error::Operation(format!(
+                            "Wrote {} of {} bytes",
+                            written,
+                            data.len()
+                        )),
+                    })
+                }
+            })
+    })
+}
+
+/// Reads a block from the disk in the device's drive 0.  A buffer is opened
+/// in the drive on DATA_CHANNEL, the block read into it with U1, and the
+/// buffer then read over the channel from its start.
+fn cbm_read_block(
+    cbm: &mut Cbm,
+    device: u8,
+    geometry: D64Geometry,
+    track: u8,
+    sector: u8,
+) -> Result<[u8; D64_BLOCK_SIZE], Error> {
+    geometry.validate_block(track, sector)?;
+    trace!("Reading track {track} sector {sector} from device {device}");
+    with_channel(cbm, device, b"#", |cbm| {
+        block_command(
+            cbm,
+            device,
+            &cbm_block_read_command(DATA_CHANNEL, track, sector),
+        )?;
+        block_command(cbm, device, &cbm_buffer_pointer_command(DATA_CHANNEL, 0))?;
+
+        let mut block = [0; D64_BLOCK_SIZE];
+        cbm.talk(device, DATA_CHANNEL)
+            .and_then(|_| {
+                let read = cbm.raw_read(&mut block);
+                cbm.untalk().and(read)
+            })
+            .map_err(|e| Error::Rs1541 {
+                message: format!(
+                    "Failed to read track {} sector {} from device {}",
+                    track, sector, device
+                ),
+                error: e,
+            })
+            .and_then(|read| match read {
+                D64_BLOCK_SIZE => Ok(block),
+                _ => Err(Error::Fs1541 {
+                    message: format!(
+                        "Failed to read track {} sector {} from device {}",
+                        track, sector, device
+                    ),
+                    error: Fs1541Error::Operation(format!(
+                        "Read {} of {} bytes from the drive's buffer",
+                        read, D64_BLOCK_SIZE
+                    )),
+                }),
+            })
+    })
+}
+
+/// Writes a block to the disk in the device's drive 0.  A buffer is opened
+/// in the drive on DATA_CHANNEL, filled over the channel from its start,
+/// and then written to the disk with U2.
+fn cbm_write_block(
+    cbm: &mut Cbm,
+    device: u8,
+    geometry: D64Geometry,
+    track: u8,
+    sector: u8,
+    block: &[u8; D64_BLOCK_SIZE],
+) -> Result<(), Error> {
+    geometry.validate_block(track, sector)?;
+    trace!("Writing track {track} sector {sector} to device {device}");
+    with_channel(cbm, device, b"#", |cbm| {
+        block_command(cbm, device, &cbm_buffer_pointer_command(DATA_CHANNEL, 0))?;
+
+        cbm.listen(device, DATA_CHANNEL)
+            .and_then(|_| {
+                let written = cbm.raw_write(block);
+                cbm.unlisten().and(written)
+            })
+            .map_err(|e| Error::Rs1541 {
+                message: format!(
+                    "Failed to write track {} sector {} to device {}",
+                    track, sector, device
+                ),
+                error: e,
+            })?;
+
+        block_command(
+            cbm,
+            device,
+            &cbm_block_write_command(DATA_CHANNEL, track, sector),
+        )
+    })
+}
+
+/// Reads len bytes of the drive's memory from addr.  The drive returns them
+/// over the command channel in response to M-R.
+fn cbm_memory_read(cbm: &mut Cbm, device: u8, addr: u16, len: u8) -> Result<Vec<u8>, Error> {
+    trace!("Reading {len} bytes of device {device} memory from ${addr:04X}");
+    CbmBackend::send_command(cbm, device, &cbm_memory_read_command(addr, len))?;
+
+    let mut data = vec![0; len as usize];
+    cbm.talk(device, COMMAND_CHANNEL)
+        .and_then(|_| {
+            let read = cbm.raw_read(&mut data);
+            cbm.untalk().and(read)
+        })
+        .map_err(|e| Error::Rs1541 {
+            message: format!("Failed to read memory of device {}", device),
+            error: e,
+        })
+        .and_then(|read| {
+            if read == data.len() {
+                Ok(data)
+            } else {
+                Err(Error::Fs1541 {
+                    message: format!("Failed to read memory of device {}", device),
+                    error: Fs1541Error::Operation(format!(
+                        "Read {} of {} bytes from ${:04X}",
+                        read, len, addr
+                    )),
+                })
+            }
+        })
+}
+
+/// Closes each of the device's data channels, so none is left allocated to
+/// it.  Closing a channel which isn't open does nothing, so failures are
+/// only logged.
+fn cbm_close_channels(cbm: &mut Cbm, device: u8) {
+    for channel in 0..COMMAND_CHANNEL {
+        if let Err(e) = cbm.close(device, channel) {
+            debug!("Failed to close channel {channel} on device {device}: {e}");
+        }
+    }
+}
+
+/// Opens the PETSCII name (a file, or "#" for a drive buffer) on
+/// DATA_CHANNEL, runs f, and closes the channel again whether or not f
+/// succeeded
+fn with_channel<T, F>(cbm: &mut Cbm, device: u8, name: &[u8], f: F) -> Result<T, Error>
+where
+    F: FnOnce(&mut Cbm) -> Result<T, Error>,
+{
+    cbm.open(device, DATA_CHANNEL, name)
+        .map_err(|e| Error::Rs1541 {
+            message: format!("Failed to open a channel on device {}", device),
+            error: e,
+        })?;
+    let result = f(cbm);
+    if let Err(e) = cbm.close(device, DATA_CHANNEL) {
+        warn!("Failed to close channel on device {device}: {e}");
+    }
+    result
+}
+
+/// Sends a block command (U1, U2, B-P) over the command channel, failing if
+/// the drive reports an error
+fn block_command(cbm: &mut Cbm, device: u8, command: &str) -> Result<(), Error> {
+    let status = cbm
+        .send_string_command_ascii(device, command)
+        .and_then(|_| cbm.get_status(device))
+        .map_err(|e| Error::Rs1541 {
+            message: format!("Failed to send {} to device {}", command, device),
+            error: e,
+        })?;
+    if status.is_ok() == CbmErrorNumberOk::Ok {
+        Ok(())
+    } else {
+        Err(Error::Fs1541 {
+            message: format!("Device {} rejected {}", device, command),
+            error: Fs1541Error::from(&status),
+        })
+    }
+}
+
+/// Disk images are served read-only, and have no drive to report errors, so
+/// always report the default (OK) status
+impl CbmBackend for CbmImageBackend {
+    /// A .d64 image is what a 1541 would have in its drive
+    fn identify(&mut self, _device: u8) -> Result<CbmDeviceInfo, Error> {
+        Ok(CbmDeviceInfo {
+            device_type: CbmDeviceType::Cbm1541,
+            description: format!("Disk image {}", self.path().display()),
+        })
+    }
+
+    fn get_status(&mut self, _device: u8) -> Result<CbmStatus, Error> {
+        Ok(CbmStatus::default())
+    }
+
+    fn send_command(&mut self, _device: u8, _command: &[u8]) -> Result<(), Error> {
+        Err(self.read_only_error("send commands to"))
+    }
+
+    fn send_string_command(&mut self, _device: u8, command: &str) -> Result<(), Error> {
+        Err(self.read_only_error(&format!("send {} to", command)))
+    }
+
+    fn format_disk(&mut self, _device: u8, _name: &str, _id: &str) -> Result<(), Error> {
+        Err(self.read_only_error("format"))
+    }
+
+    fn reset(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn soft_reset(&mut self, _device: u8) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn usb_reset(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn init(
+        &mut self,
+        _drive_unit: &mut CbmDriveUnit,
+        _ignore: &[CbmErrorNumber],
+    ) -> Vec<Result<CbmStatus, Error>> {
+        vec![Ok(CbmStatus::default())]
+    }
+
+    fn dir(
+        &mut self,
+        _drive_unit: &CbmDriveUnit,
+    ) -> Result<(Vec<CbmDirListing>, CbmStatus), Error> {
+        let dir = CbmImageBackend::dir(self)?;
+        let listing = CbmDirListing {
+            header: CbmDiskHeader {
+                drive_number: 0,
+                name: dir.name,
+                id: dir.id,
+            },
+            files: dir.files,
+            blocks_free: dir.blocks_free,
+        };
+        Ok((vec![listing], CbmStatus::default()))
+    }
+
+    fn read_file(
+        &mut self,
+        _drive_unit: &CbmDriveUnit,
+        filename: &str,
+        _cancelled: &AtomicBool,
+    ) -> Result<(Vec<u8>, CbmStatus), Error> {
+        CbmImageBackend::read_file(self, filename).map(|contents| (contents, CbmStatus::default()))
+    }
+
+    fn write_file(&mut self, _device: u8, _filename: &str, _data: &[u8]) -> Result<(), Error> {
+        Err(self.read_only_error("write to"))
+    }
+
+    fn write_seq(&mut self, _device: u8, _filename: &str, _data: &[u8]) -> Result<(), Error> {
+        Err(self.read_only_error("write to"))
+    }
+
+    fn append_to_seq(&mut self, _device: u8, _filename: &str, _data: &[u8]) -> Result<(), Error> {
+        Err(self.read_only_error("write to"))
+    }
+
+    /// Blocks are validated against the image's own geometry
+    fn read_block(
+        &mut self,
+        _device: u8,
+        _geometry: D64Geometry,
+        track: u8,
+        sector: u8,
+    ) -> Result<Vec<u8>, Error> {
+        CbmImageBackend::read_block(self, track, sector).map(|block| block.to_vec())
+    }
+
+    fn write_block(
+        &mut self,
+        _device: u8,
+        _geometry: D64Geometry,
+        _track: u8,
+        _sector: u8,
+        _block: &[u8; D64_BLOCK_SIZE],
+    ) -> Result<(), Error> {
+        Err(self.read_only_error("write to"))
+    }
+
+    fn memory_read(&mut self, _device: u8, _addr: u16, _len: u8) -> Result<Vec<u8>, Error> {
+        Err(self.no_drive_error("read the drive memory of"))
+    }
+
+    fn memory_write(&mut self, _device: u8, _addr: u16, _data: &[u8]) -> Result<(), Error> {
+        Err(self.no_drive_error("write the drive memory of"))
+    }
+
+    fn memory_execute(&mut self, _device: u8, _addr: u16) -> Result<(), Error> {
+        Err(self.no_drive_error("execute code in"))
+    }
+}
+
+impl CbmImageBackend {
+    fn read_only_error(&self, what: &str) -> Error {
+        Error::Fs1541 {
+            message: format!("Can't {} disk image {}", what, self.path().display()),
+            error: Fs1541Error::ReadOnly(self.path().display().to_string()),
+        }
+    }
+
+    /// For operations on the drive itself, rather than the disk
+    fn no_drive_error(&self, what: &str) -> Error {
+        Error::Fs1541 {
+            message: format!("Can't {} disk image {}", what, self.path().display()),
+            error: Fs1541Error::Validation("A disk image has no drive".to_string()),
+        }
+    }
+}
+
+/// A backend for tests, which serves files from memory.  It can be
+/// disconnected, like an xum1541 which has stopped answering, after which
+/// every operation fails until it is reset over USB.
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+    use crate::file::{cbm_block_write_command, cbm_filename_from_open_name, cbm_format_command};
+    use rs1541::CbmFileEntry;
+    use std::collections::HashMap;
+
+    #[derive(Debug, Default)]
+    pub struct MockBackend {
+        /// File contents, by filename
+        pub files: HashMap<String, Vec<u8>>,
+        /// Entries returned in the directory listing
+        pub dir_files: Vec<CbmFileEntry>,
+        /// The names files were opened with to read them
+        pub reads: Vec<String>,
+        /// The commands sent to the drive, including formats and block writes
+        pub commands: Vec<String>,
+        /// The drive's memory, by address.  Unwritten addresses read as 0.
+        pub memory: HashMap<u16, u8>,
+        /// How long executed drive code runs before returning
+        pub execute_time: std::time::Duration,
+        /// The devices whose drives have been initialized, in order
+        pub inits: Vec<u8>,
+        pub disconnected: bool,
+        /// Makes USB resets fail, like an xum1541 which has been unplugged
+        pub unplugged: bool,
+        /// Corrupts the data written to files, so they don't verify
+        pub corrupt_writes: bool,
+        pub usb_resets: usize,
+    }
+
+    impl MockBackend {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        fn check_connected(&self) -> Result<(), Error> {
+            if self.disconnected {
+                Err(Error::Fs1541 {
+                    message: "Mock device disconnected".into(),
+                    error: Fs1541Error::Operation("USB device not responding".into()),
+                })
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    impl CbmBackend for MockBackend {
+        fn identify(&mut self, device: u8) -> Result<CbmDeviceInfo, Error> {
+            self.check_connected()?;
+            Ok(CbmDeviceInfo {
+                device_type: CbmDeviceType::Cbm1541,
+                description: format!("Mock drive {}", device),
+            })
+        }
+
+        fn get_status(&mut self, _device: u8) -> Result<CbmStatus, Error> {
+            self.check_connected()?;
+            Ok(CbmStatus::default())
+        }
+
+        fn send_command(&mut self, _device: u8, command: &[u8]) -> Result<(), Error> {
+            self.check_connected()?;
+            self.commands
+                .push(String::from_utf8_lossy(command).into_owned());
+            Ok(())
+        }
+
+        fn send_string_command(&mut self, _device: u8, command: &str) -> Result<(), Error> {
+            self.check_connected()?;
+            self.commands.push(command.to_string());
+            Ok(())
+        }
+
+        fn format_disk(&mut self, _device: u8, name: &str, id: &str) -> Result<(), Error> {
+            self.check_connected()?;
+            self.commands.push(cbm_format_command(0, name, id));
+            self.files.clear();
+            Ok(())
+        }
+
+        fn reset(&mut self) -> Result<(), Error> {
+            self.check_connected()
+        }
+
+        fn soft_reset(&mut self, device: u8) -> Result<(), Error> {
+            self.send_string_command(device, SOFT_RESET_COMMAND)
+        }
+
+        fn usb_reset(&mut self) -> Result<(), Error> {
+            self.usb_resets += 1;
+            if self.unplugged {
+                return Err(Error::Fs1541 {
+                    message: "Mock device unplugged".into(),
+                    error: Fs1541Error::Operation("USB device not found".into()),
+                });
+            }
+            self.disconnected = false;
+            Ok(())
+        }
+
+        fn init(
+            &mut self,
+            drive_unit: &mut CbmDriveUnit,
+            _ignore: &[CbmErrorNumber],
+        ) -> Vec<Result<CbmStatus, Error>> {
+            self.inits.push(drive_unit.device_number);
+            vec![self.check_connected().map(|_| CbmStatus::default())]
+        }
+
+        /// The directory lists dir_files, which needn't match files
+        fn dir(
+            &mut self,
+            _drive_unit: &CbmDriveUnit,
+        ) -> Result<(Vec<CbmDirListing>, CbmStatus), Error> {
+            self.check_connected()?;
+            let listing = CbmDirListing {
+                header: CbmDiskHeader {
+                    drive_number: 0,
+                    name: "MOCK".to_string(),
+                    id: "01".to_string(),
+                },
+                files: self.dir_files.clone(),
+                blocks_free: 664,
+            };
+            Ok((vec![listing], CbmStatus::default()))
+        }
+
+        fn read_file(
+            &mut self,
+            _drive_unit: &CbmDriveUnit,
+            filename: &str,
+            _cancelled: &AtomicBool,
+        ) -> Result<(Vec<u8>, CbmStatus), Error> {
+            self.check_connected()?;
+            self.reads.push(filename.to_string());
+            match self.files.get(cbm_filename_from_open_name(filename)) {
+                Some(data) => Ok((data.clone(), CbmStatus::default())),
+                None => Err(Error::Fs1541 {
+                    message: format!("Failed to read {}", filename),
+                    error: Fs1541Error::NoEntry(filename.to_string()),
+                }),
+            }
+        }
+
+        fn write_file(&mut self, _device: u8, filename: &str, data: &[u8]) -> Result<(), Error> {
+            self.check_connected()?;
+            let mut data = data.to_vec();
+            if self.corrupt_writes {
+                data.iter_mut().for_each(|byte| *byte ^= 0xff);
+            }
+            self.files.insert(filename.to_string(), data);
+            Ok(())
+        }
+
+        fn write_seq(&mut self, device: u8, filename: &str, data: &[u8]) -> Result<(), Error> {
+            self.write_file(device, filename, data)
+        }
+
+        fn append_to_seq(&mut self, _device: u8, filename: &str, data: &[u8]) -> Result<(), Error> {
+            self.check_connected()?;
+            match self.files.get_mut(filename) {
+                Some(file) => {
+                    file.extend_from_slice(data);
+                    Ok(())
+                }
+                None => Err(Error::Fs1541 {
+                    message: format!("Failed to append to {}", filename),
+                    error: Fs1541Error::NoEntry(filename.to_string()),
+                }),
+            }
+        }
+
+        fn read_block(
+            &mut self,
+            _device: u8,
+            _geometry: D64Geometry,
+            _track: u8,
+            _sector: u8,
+        ) -> Result<Vec<u8>, Error> {
+            self.check_connected()?;
+            Ok(vec![0; D64_BLOCK_SIZE])
+        }
+
+        fn write_block(
+            &mut self,
+            _device: u8,
+            _geometry: D64Geometry,
+            track: u8,
+            sector: u8,
+            _block: &[u8; D64_BLOCK_SIZE],
+        ) -> Result<(), Error> {
+            self.check_connected()?;
+            self.commands
+                .push(cbm_block_write_command(2, track, sector));
+            Ok(())
+        }
+
+        fn memory_read(&mut self, _device: u8, addr: u16, len: u8) -> Result<Vec<u8>, Error> {
+            self.check_connected()?;
+            Ok((0..len as u16)
+                .map(|offset| {
+                    let addr = addr.wrapping_add(offset);
+                    self.memory.get(&addr).copied().unwrap_or_default()
+                })
+                .collect())
+        }
+
+        fn memory_write(&mut self, device: u8, addr: u16, data: &[u8]) -> Result<(), Error> {
+            for command in cbm_memory_write_commands(addr, data) {
+                self.send_command(device, &command)?;
+            }
+            for (offset, byte) in data.iter().enumerate() {
+                self.memory.insert(addr.wrapping_add(offset as u16), *byte);
+            }
+            Ok(())
+        }
+
+        fn memory_execute(&mut self, device: u8, addr: u16) -> Result<(), Error> {
+            self.send_command(device, &cbm_memory_execute_command(addr))?;
+            std::thread::sleep(self.execute_time);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_backend() {
+        let mut image = vec![0; D64Geometry::Standard.image_size()];
+        image[D64_BLOCK_SIZE] = 0xaa;
+        let mut backend: Box<dyn CbmBackend> =
+            Box::new(CbmImageBackend::new("test.d64", image).unwrap());
+
+        // Blocks are read from the image, regardless of device
+        let geometry = D64Geometry::Standard;
+        let block = backend.read_block(8, geometry, 1, 1).unwrap();
+        assert_eq!(block.len(), D64_BLOCK_SIZE);
+        assert_eq!(block[0], 0xaa);
+        assert!(backend.read_block(8, geometry, 36, 0).is_err());
+
+        // The image holds a single drive's directory, which is empty
+        let drive_unit = CbmDriveUnit::new(8, CbmDeviceType::Cbm1541);
+        let (listings, _) = backend.dir(&drive_unit).unwrap();
+        assert_eq!(listings.len(), 1);
+        assert_eq!(listings[0].header.drive_number, 0);
+        assert!(listings[0].files.is_empty());
+
+        assert!(backend.reset().is_ok());
+        assert!(backend.format_disk(8, "name", "id").is_err());
+        assert!(matches!(
+            backend.write_file(8, "hello", b"data"),
+            Err(Error::Fs1541 {
+                error: Fs1541Error::ReadOnly(_),
+                ..
+            })
+        ));
+    }
+}
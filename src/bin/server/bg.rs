@@ -1,27 +1,54 @@
 use crate::args::get_args;
+use crate::backend::CbmBackend;
 use crate::drivemgr::DriveManager;
+use crate::file::{
+    cbm_read_open_name, cbm_rename_command, cbm_scratch_command, d64_offset, D64Geometry,
+    D64_BLOCK_SIZE,
+};
 use crate::locking_section;
-use crate::mount::Mount;
+use crate::mount::{refresh_dir, remove_created_mountpoint, Mount};
 use crate::mountsvc::MountService;
-use fs1541::error::{Error, Fs1541Error};
+use fs1541::error::{dos_error_transient, Error, Fs1541Error};
+use fs1541::ipc::{MountInfo, Response};
+use fs1541::logging::{with_log_context, LogContext};
 /// Background processing - provides a single worker thread which handles IPC
 /// and background tasks on behalf of Mounts
-use rs1541::{Cbm, CbmDeviceInfo, CbmDirListing, CbmStatus, CbmString};
+use rs1541::{
+    CbmDeviceInfo, CbmDirListing, CbmDriveUnit, CbmErrorNumberOk, CbmFileEntry, CbmStatus,
+};
 
 use flume::{Receiver, Sender};
 use log::{debug, error, info, trace, warn};
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::future::Future;
 use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 use std::time::Instant;
-use tokio::net::unix::OwnedWriteHalf;
 use tokio::sync::{Mutex, RwLock};
 
 // Max number of BackgroundProcess channels which willbe opened
 pub const MAX_BG_CHANNELS: usize = 16;
 
+/// How long drive code run with M-E has to return, after which the drive is
+/// assumed to have hung
+pub const MEMORY_EXECUTE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How a WriteFile operation opens the file on the drive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteMode {
+    /// The path is the complete name to open the file with, including any
+    /// type and mode suffix
+    Open,
+
+    /// The path is a SEQ file to write, which is opened with ,s,w
+    Seq,
+
+    /// The path is a SEQ file to append to, which is opened with ,s,a
+    SeqAppend,
+}
+
 /// Background operation types for Commodore disk operations
 #[derive(Debug, Clone)]
 #[allow(dead_code)]
@@ -29,12 +56,30 @@ pub enum OpType {
     /// Reset the entire Commodore bus
     BusReset,
 
+    /// Identify every device on the bus
+    ScanBus,
+
+    /// Report the operation metrics, optionally resetting them
+    Metrics {
+        reset: bool,
+    },
+
+    /// List the current mounts
+    ListMounts,
+
     /// Operations for mounting/unmounting drives
     Mount {
         device: u8,
         mountpoint: PathBuf,
         dummy_formats: bool,
         bus_reset: bool,
+        read_write: bool,
+        safe_replace: bool,
+        verify_writes: bool,
+        interleave: Option<u8>,
+        created_mountpoint: bool,
+        auto_refresh: Option<Duration>,
+        image: Option<PathBuf>,
     },
     Unmount {
         device: Option<u8>,
@@ -52,8 +97,19 @@ pub enum OpType {
     },
     WriteFile {
         device: u8,
+        // The drive the file is on, whose write protect state the status
+        // updates
+        drive_num: u8,
         path: String,
+        mode: WriteMode,
         data: Vec<u8>,
+        // Scratch command to send first, so an existing file is replaced
+        // without using @:
+        scratch: Option<String>,
+        // Name to read the file back with after writing it, to verify it
+        verify: Option<String>,
+        // Command to set the drive's sector interleave before writing
+        interleave: Option<Vec<u8>>,
     },
 
     /// Drive-specific operations
@@ -67,6 +123,113 @@ pub enum OpType {
     GetStatus {
         device: u8,
     },
+    DriveCommand {
+        device: u8,
+        command: String,
+    },
+    /// Reset the drive's DOS, and the channels open to it, without resetting
+    /// the bus
+    SoftReset {
+        device: u8,
+    },
+    FormatDisk {
+        device: u8,
+        name: String,
+        id: String,
+        // Command to set the drive's sector interleave after formatting
+        interleave: Option<Vec<u8>>,
+    },
+
+    /// Send a sequence of raw (PETSCII) partition commands to a 1581
+    Partition {
+        device: u8,
+        commands: Vec<Vec<u8>>,
+    },
+
+    /// Switch a 1571 between double-sided (1571) and single-sided (1541)
+    /// mode
+    Set1571Mode {
+        device: u8,
+        double_sided: bool,
+    },
+
+    /// Change a drive's device number
+    SetDeviceNumber {
+        device: u8,
+        new_device: u8,
+    },
+
+    /// Read the drive's memory with M-R.  The length has already been
+    /// validated.
+    MemoryRead {
+        device: u8,
+        addr: u16,
+        len: u8,
+    },
+
+    /// Write to the drive's memory with M-W
+    MemoryWrite {
+        device: u8,
+        addr: u16,
+        data: Vec<u8>,
+    },
+
+    /// Run the code in the drive's memory with M-E, failing if the drive
+    /// doesn't answer again within the timeout
+    MemoryExecute {
+        device: u8,
+        addr: u16,
+        timeout: Duration,
+    },
+
+    /// Re-read the directory of the mount for the device, replying once the
+    /// new listing has been read, or the re-read has timed out
+    RefreshDir {
+        device: u8,
+        drive_num: u8,
+    },
+
+    /// Scratch a file from the disk in one of the unit's drives
+    DeleteFile {
+        device: u8,
+        drive_num: u8,
+        filename: String,
+    },
+
+    /// Rename a file on the disk in one of the unit's drives
+    RenameFile {
+        device: u8,
+        drive_num: u8,
+        old: String,
+        new: String,
+    },
+
+    /// Read every block of the disk, to create a .d64 image
+    ReadImage {
+        device: u8,
+        inode: u64,
+        geometry: D64Geometry,
+    },
+
+    /// Read a single block from the disk, such as the BAM, to check whether
+    /// the disk has been changed.  Blocks outside the geometry are rejected.
+    ReadBlock {
+        device: u8,
+        track: u8,
+        sector: u8,
+        geometry: D64Geometry,
+    },
+
+    /// Format the disk and write every block of a .d64 image to it
+    WriteImage {
+        device: u8,
+        inode: u64,
+        name: String,
+        id: String,
+        geometry: D64Geometry,
+        data: Vec<u8>,
+        interleave: Option<Vec<u8>>,
+    },
 
     /// Read a file for caching purposes (will be given lower priority)
     ReadFileCache {
@@ -85,6 +248,9 @@ impl std::fmt::Display for OpType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             OpType::BusReset => write!(f, "BusReset"),
+            OpType::ScanBus => write!(f, "ScanBus"),
+            OpType::Metrics { .. } => write!(f, "Metrics"),
+            OpType::ListMounts => write!(f, "ListMounts"),
             OpType::Mount { .. } => write!(f, "Mount"),
             OpType::Unmount { .. } => write!(f, "Unmount"),
             OpType::ReadDirectory { .. } => write!(f, "ReadDirectory"),
@@ -93,6 +259,21 @@ impl std::fmt::Display for OpType {
             OpType::InitDrive { .. } => write!(f, "InitDrive"),
             OpType::Identify { .. } => write!(f, "Identify"),
             OpType::GetStatus { .. } => write!(f, "GetStatus"),
+            OpType::DriveCommand { .. } => write!(f, "DriveCommand"),
+            OpType::SoftReset { .. } => write!(f, "SoftReset"),
+            OpType::FormatDisk { .. } => write!(f, "FormatDisk"),
+            OpType::RenameFile { .. } => write!(f, "RenameFile"),
+            OpType::DeleteFile { .. } => write!(f, "DeleteFile"),
+            OpType::Partition { .. } => write!(f, "Partition"),
+            OpType::Set1571Mode { .. } => write!(f, "Set1571Mode"),
+            OpType::SetDeviceNumber { .. } => write!(f, "SetDeviceNumber"),
+            OpType::MemoryRead { .. } => write!(f, "MemoryRead"),
+            OpType::MemoryWrite { .. } => write!(f, "MemoryWrite"),
+            OpType::MemoryExecute { .. } => write!(f, "MemoryExecute"),
+            OpType::RefreshDir { .. } => write!(f, "RefreshDir"),
+            OpType::ReadImage { .. } => write!(f, "ReadImage"),
+            OpType::WriteImage { .. } => write!(f, "WriteImage"),
+            OpType::ReadBlock { .. } => write!(f, "ReadBlock"),
             OpType::ReadFileCache { .. } => write!(f, "ReadFileCache"),
             OpType::CancelDeviceCache { .. } => write!(f, "CancelDeviceCache"),
         }
@@ -106,6 +287,14 @@ impl OpType {
             // Critical operations
             Self::BusReset => Priority::Critical,
 
+            // Metrics are critical, as they're most useful when the drives
+            // are busy
+            Self::Metrics { .. } => Priority::Critical,
+
+            // Listing mounts doesn't touch the drives, so is answered
+            // straight away
+            Self::ListMounts => Priority::Critical,
+
             // Mounting and unmounting are high priority
             Self::Mount { .. } | Self::Unmount { .. } => Priority::High,
 
@@ -113,17 +302,36 @@ impl OpType {
             Self::ReadFile { .. } | Self::WriteFile { .. } => Priority::Normal,
 
             // Directory operations are normal priority
-            Self::ReadDirectory { .. } => Priority::Normal,
+            Self::ReadDirectory { .. } | Self::RefreshDir { .. } => Priority::Normal,
 
             // Drive operations are normal priority
-            Self::InitDrive { .. } => Priority::Normal,
+            Self::InitDrive { .. }
+            | Self::DriveCommand { .. }
+            | Self::SoftReset { .. }
+            | Self::FormatDisk { .. }
+            | Self::RenameFile { .. }
+            | Self::DeleteFile { .. }
+            | Self::Partition { .. }
+            | Self::Set1571Mode { .. }
+            | Self::SetDeviceNumber { .. }
+            | Self::MemoryRead { .. }
+            | Self::MemoryWrite { .. }
+            | Self::MemoryExecute { .. } => Priority::Normal,
 
             // Status operations are normal priority
-            Self::Identify { .. } | Self::GetStatus { .. } => Priority::Normal,
+            Self::Identify { .. } | Self::GetStatus { .. } | Self::ScanBus => Priority::Normal,
 
             // Cache operations are low priority
             Self::ReadFileCache { .. } => Priority::Low,
 
+            // Imaging a disk takes a long time, so shouldn't hold up other
+            // operations
+            Self::ReadImage { .. } | Self::WriteImage { .. } => Priority::Low,
+
+            // Reading a block is only used to check for disk changes, which
+            // can wait for other operations
+            Self::ReadBlock { .. } => Priority::Low,
+
             // Cancelling cache operations is a critical priority (as it will
             // clear space for other operations)
             Self::CancelDeviceCache { .. } => Priority::Critical,
@@ -137,19 +345,65 @@ impl OpType {
 
     /// Whether this operation affects the entire bus or just a single drive
     pub fn affects_bus(&self) -> bool {
-        matches!(self, Self::BusReset)
+        matches!(self, Self::BusReset | Self::ScanBus)
+    }
+
+    /// The device this operation is for, if it's for a single device
+    pub fn device(&self) -> Option<u8> {
+        match self {
+            Self::BusReset | Self::ScanBus | Self::Metrics { .. } | Self::ListMounts => None,
+            Self::Unmount { device, .. } => *device,
+            Self::Mount { device, .. }
+            | Self::ReadDirectory { device }
+            | Self::ReadFile { device, .. }
+            | Self::WriteFile { device, .. }
+            | Self::InitDrive { device, .. }
+            | Self::Identify { device }
+            | Self::GetStatus { device }
+            | Self::DriveCommand { device, .. }
+            | Self::SoftReset { device }
+            | Self::FormatDisk { device, .. }
+            | Self::Partition { device, .. }
+            | Self::Set1571Mode { device, .. }
+            | Self::SetDeviceNumber { device, .. }
+            | Self::MemoryRead { device, .. }
+            | Self::MemoryWrite { device, .. }
+            | Self::MemoryExecute { device, .. }
+            | Self::RefreshDir { device, .. }
+            | Self::DeleteFile { device, .. }
+            | Self::RenameFile { device, .. }
+            | Self::ReadImage { device, .. }
+            | Self::ReadBlock { device, .. }
+            | Self::WriteImage { device, .. }
+            | Self::ReadFileCache { device, .. }
+            | Self::CancelDeviceCache { device } => Some(*device),
+        }
     }
 
     /// Whether this operation requires exclusive access to the drive
     pub fn requires_drive_access(&self) -> bool {
-        !matches!(self, Self::CancelDeviceCache { .. })
+        // A directory refresh queues its own ReadDirectory to access the
+        // drive
+        !matches!(
+            self,
+            Self::Metrics { .. }
+                | Self::ListMounts
+                | Self::CancelDeviceCache { .. }
+                | Self::RefreshDir { .. }
+        )
     }
 }
 
+/// The outcome of an operation, as reported to whoever is waiting for it:
+/// the drive's status at the end of the operation, or the error it failed
+/// with
+pub type OpOutcome = Result<CbmStatus, Error>;
+
 #[derive(Debug)]
 pub struct OpResponse {
     pub rsp: Result<OpResponseType, Error>,
-    stream: Option<OwnedWriteHalf>,
+    reply: Option<Sender<Response>>,
+    waiter: Option<Sender<OpOutcome>>,
 }
 
 impl std::fmt::Display for OpResponse {
@@ -157,11 +411,27 @@ impl std::fmt::Display for OpResponse {
         match &self.rsp {
             Ok(response_type) => {
                 match response_type {
-                    OpResponseType::BusReset() => write!(f, "Bus Reset"),
+                    OpResponseType::BusReset { reinitialized } => {
+                        write!(f, "Bus Reset - {} mounts re-initialized", reinitialized)
+                    }
+
+                    OpResponseType::ScanBus { devices } => write!(
+                        f,
+                        "Scan Bus - {} devices responded",
+                        devices.iter().filter(|(_, info)| info.is_some()).count()
+                    ),
+
+                    OpResponseType::Metrics { metrics } => {
+                        write!(f, "Metrics - {} operation types", metrics.len())
+                    }
+
+                    OpResponseType::ListMounts { mounts } => {
+                        write!(f, "List Mounts - {} mounts", mounts.len())
+                    }
 
                     OpResponseType::Mount() => write!(f, "Mount"),
 
-                    OpResponseType::Unmount() => write!(f, "Unmount"),
+                    OpResponseType::Unmount { .. } => write!(f, "Unmount"),
 
                     OpResponseType::ReadDirectory {
                         status: _,
@@ -188,10 +458,12 @@ impl std::fmt::Display for OpResponse {
                         path,
                         status,
                         bytes_written,
+                        verified,
+                        ..
                     } => write!(
                         f,
-                        "Write File {} {} - {} bytes written, status: {}",
-                        device, path, bytes_written, status
+                        "Write File {} {} - {} bytes written, verified: {:?}, status: {}",
+                        device, path, bytes_written, verified, status
                     ),
 
                     OpResponseType::InitDrive { status } => {
@@ -204,6 +476,118 @@ impl std::fmt::Display for OpResponse {
 
                     OpResponseType::GetStatus { status } => write!(f, "Get Status - {}", status),
 
+                    OpResponseType::DriveCommand { status } => {
+                        write!(f, "Drive Command - status: {}", status)
+                    }
+
+                    OpResponseType::SoftReset { status } => {
+                        write!(f, "Soft Reset - status: {}", status)
+                    }
+
+                    OpResponseType::FormatDisk { status } => {
+                        write!(f, "Format Disk - status: {}", status)
+                    }
+
+                    OpResponseType::RenameFile { drive_num, status } => {
+                        write!(f, "Rename File drive {} - status: {}", drive_num, status)
+                    }
+
+                    OpResponseType::DeleteFile { drive_num, status } => {
+                        write!(f, "Delete File drive {} - status: {}", drive_num, status)
+                    }
+
+                    OpResponseType::Partition { status } => {
+                        write!(f, "Partition - status: {}", status)
+                    }
+
+                    OpResponseType::Set1571Mode {
+                        double_sided,
+                        status,
+                    } => write!(
+                        f,
+                        "Set 1571 Mode double-sided {} - status: {}",
+                        double_sided, status
+                    ),
+
+                    OpResponseType::SetDeviceNumber {
+                        device,
+                        new_device,
+                        status,
+                    } => write!(
+                        f,
+                        "Set Device Number {} to {} - status: {}",
+                        device, new_device, status
+                    ),
+
+                    OpResponseType::RefreshDir { device, drive_num } => {
+                        write!(f, "Refresh Directory {} drive {}", device, drive_num)
+                    }
+
+                    OpResponseType::MemoryRead { device, addr, data } => write!(
+                        f,
+                        "Memory Read {} ${:04X} - {} bytes read",
+                        device,
+                        addr,
+                        data.len()
+                    ),
+
+                    OpResponseType::MemoryWrite {
+                        device,
+                        addr,
+                        len,
+                        status,
+                    } => write!(
+                        f,
+                        "Memory Write {} ${:04X} - {} bytes written, status: {}",
+                        device, addr, len, status
+                    ),
+
+                    OpResponseType::MemoryExecute {
+                        device,
+                        addr,
+                        status,
+                    } => write!(
+                        f,
+                        "Memory Execute {} ${:04X} - status: {}",
+                        device, addr, status
+                    ),
+
+                    OpResponseType::ReadImage {
+                        contents,
+                        bad_sectors,
+                        ..
+                    } => write!(
+                        f,
+                        "Read Image - {} bytes read, {} bad sectors",
+                        contents.len(),
+                        bad_sectors.len()
+                    ),
+
+                    OpResponseType::WriteImage {
+                        status,
+                        bad_sectors,
+                        ..
+                    } => write!(
+                        f,
+                        "Write Image - {} bad sectors, status: {}",
+                        bad_sectors.len(),
+                        status
+                    ),
+
+                    OpResponseType::ReadBlock {
+                        device,
+                        track,
+                        sector,
+                        contents,
+                    } => write!(
+                        f,
+                        "Read Block {} track {} sector {} - {} bytes read",
+                        device,
+                        track,
+                        sector,
+                        contents.len()
+                    ),
+
                     OpResponseType::ReadFileCache {
                         contents, status, ..
                     } => write!(
@@ -218,16 +602,16 @@ impl std::fmt::Display for OpResponse {
                     }
                 }?;
 
-                // Add stream status if relevant
-                if self.stream.is_some() {
-                    write!(f, " (with stream)")?;
+                // Add reply status if relevant
+                if self.reply.is_some() {
+                    write!(f, " (with reply)")?;
                 }
                 Ok(())
             }
             Err(error) => {
                 write!(f, "Error: {}", error)?;
-                if self.stream.is_some() {
-                    write!(f, " (with stream)")?;
+                if self.reply.is_some() {
+                    write!(f, " (with reply)")?;
                 }
                 Ok(())
             }
@@ -238,9 +622,23 @@ impl std::fmt::Display for OpResponse {
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub enum OpResponseType {
-    BusReset(),
+    BusReset {
+        reinitialized: usize,
+    },
+    ScanBus {
+        devices: Vec<(u8, Option<CbmDeviceInfo>)>,
+    },
+    Metrics {
+        metrics: Vec<(String, OpMetrics)>,
+    },
+    ListMounts {
+        mounts: Vec<MountInfo>,
+    },
     Mount(),
-    Unmount(),
+    Unmount {
+        // What was looked up, if there turned out to be nothing mounted
+        not_mounted: Option<String>,
+    },
     ReadDirectory {
         status: CbmStatus,
         listings: Vec<CbmDirListing>,
@@ -254,9 +652,12 @@ pub enum OpResponseType {
     },
     WriteFile {
         device: u8,
+        drive_num: u8,
         path: String,
         status: CbmStatus,
         bytes_written: u64,
+        // None if the file wasn't read back to verify it
+        verified: Option<bool>,
     },
     InitDrive {
         status: CbmStatus,
@@ -267,6 +668,73 @@ pub enum OpResponseType {
     GetStatus {
         status: CbmStatus,
     },
+    DriveCommand {
+        status: CbmStatus,
+    },
+    SoftReset {
+        status: CbmStatus,
+    },
+    FormatDisk {
+        status: CbmStatus,
+    },
+    RenameFile {
+        drive_num: u8,
+        status: CbmStatus,
+    },
+    DeleteFile {
+        drive_num: u8,
+        status: CbmStatus,
+    },
+    Partition {
+        status: CbmStatus,
+    },
+    Set1571Mode {
+        double_sided: bool,
+        status: CbmStatus,
+    },
+    SetDeviceNumber {
+        device: u8,
+        new_device: u8,
+        status: CbmStatus,
+    },
+    MemoryRead {
+        device: u8,
+        addr: u16,
+        data: Vec<u8>,
+    },
+    MemoryWrite {
+        device: u8,
+        addr: u16,
+        len: usize,
+        status: CbmStatus,
+    },
+    MemoryExecute {
+        device: u8,
+        addr: u16,
+        status: CbmStatus,
+    },
+    RefreshDir {
+        device: u8,
+        drive_num: u8,
+    },
+    ReadImage {
+        device: u8,
+        inode: u64,
+        contents: Vec<u8>,
+        bad_sectors: Vec<(u8, u8)>,
+    },
+    WriteImage {
+        device: u8,
+        inode: u64,
+        status: CbmStatus,
+        bad_sectors: Vec<(u8, u8)>,
+    },
+    ReadBlock {
+        device: u8,
+        track: u8,
+        sector: u8,
+        contents: Vec<u8>,
+    },
     ReadFileCache {
         device: u8,
         path: String,
@@ -282,11 +750,21 @@ pub enum OpResponseType {
 impl From<OpType> for OpResponseType {
     fn from(op: OpType) -> Self {
         match op {
-            OpType::BusReset => OpResponseType::BusReset(),
+            OpType::BusReset => OpResponseType::BusReset { reinitialized: 0 },
+
+            OpType::ScanBus => OpResponseType::ScanBus {
+                devices: Vec::new(),
+            },
+
+            OpType::Metrics { .. } => OpResponseType::Metrics {
+                metrics: Vec::new(),
+            },
+
+            OpType::ListMounts => OpResponseType::ListMounts { mounts: Vec::new() },
 
             OpType::Mount { .. } => OpResponseType::Mount(),
 
-            OpType::Unmount { .. } => OpResponseType::Unmount(),
+            OpType::Unmount { .. } => OpResponseType::Unmount { not_mounted: None },
 
             OpType::ReadDirectory { .. } => OpResponseType::ReadDirectory {
                 status: CbmStatus::default(),
@@ -306,11 +784,18 @@ impl From<OpType> for OpResponseType {
                 contents: Vec::new(),
             },
 
-            OpType::WriteFile { device, path, .. } => OpResponseType::WriteFile {
+            OpType::WriteFile {
+                device,
+                drive_num,
+                path,
+                ..
+            } => OpResponseType::WriteFile {
                 device,
+                drive_num,
                 path,
                 status: CbmStatus::default(),
                 bytes_written: 0,
+                verified: None,
             },
 
             OpType::InitDrive { .. } => OpResponseType::InitDrive {
@@ -325,6 +810,92 @@ impl From<OpType> for OpResponseType {
                 status: CbmStatus::default(),
             },
 
+            OpType::DriveCommand { .. } => OpResponseType::DriveCommand {
+                status: CbmStatus::default(),
+            },
+
+            OpType::SoftReset { .. } => OpResponseType::SoftReset {
+                status: CbmStatus::default(),
+            },
+
+            OpType::RenameFile { drive_num, .. } => OpResponseType::RenameFile {
+                drive_num,
+                status: CbmStatus::default(),
+            },
+
+            OpType::DeleteFile { drive_num, .. } => OpResponseType::DeleteFile {
+                drive_num,
+                status: CbmStatus::default(),
+            },
+
+            OpType::Partition { .. } => OpResponseType::Partition {
+                status: CbmStatus::default(),
+            },
+
+            OpType::Set1571Mode { double_sided, .. } => OpResponseType::Set1571Mode {
+                double_sided,
+                status: CbmStatus::default(),
+            },
+
+            OpType::SetDeviceNumber { device, new_device } => OpResponseType::SetDeviceNumber {
+                device,
+                new_device,
+                status: CbmStatus::default(),
+            },
+
+            OpType::RefreshDir { device, drive_num } => {
+                OpResponseType::RefreshDir { device, drive_num }
+            }
+
+            OpType::MemoryRead { device, addr, .. } => OpResponseType::MemoryRead {
+                device,
+                addr,
+                data: Vec::new(),
+            },
+
+            OpType::MemoryWrite { device, addr, data } => OpResponseType::MemoryWrite {
+                device,
+                addr,
+                len: data.len(),
+                status: CbmStatus::default(),
+            },
+
+            OpType::MemoryExecute { device, addr, .. } => OpResponseType::MemoryExecute {
+                device,
+                addr,
+                status: CbmStatus::default(),
+            },
+
+            OpType::FormatDisk { .. } => OpResponseType::FormatDisk {
+                status: CbmStatus::default(),
+            },
+
+            OpType::ReadImage { device, inode, .. } => OpResponseType::ReadImage {
+                device,
+                inode,
+                contents: Vec::new(),
+                bad_sectors: Vec::new(),
+            },
+
+            OpType::WriteImage { device, inode, .. } => OpResponseType::WriteImage {
+                device,
+                inode,
+                status: CbmStatus::default(),
+                bad_sectors: Vec::new(),
+            },
+
+            OpType::ReadBlock {
+                device,
+                track,
+                sector,
+                ..
+            } => OpResponseType::ReadBlock {
+                device,
+                track,
+                sector,
+                contents: Vec::new(),
+            },
+
             OpType::ReadFileCache {
                 device,
                 path,
@@ -343,26 +914,110 @@ impl From<OpType> for OpResponseType {
     }
 }
 
-/// Priority levels for background operations
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[allow(dead_code)]
-pub enum Priority {
-    Critical, // Bus reset
-    High,     // Mount/Unmount
-    Normal,   // FUSE operations
-    Low,      // Other IPC and background tasks
-}
-
-impl Priority {
-    pub fn timeout(&self) -> Duration {
+impl OpResponseType {
+    /// The number of bytes of file or disk data read or written by the
+    /// operation
+    pub fn bytes_transferred(&self) -> u64 {
         match self {
-            Priority::Critical => Duration::from_secs(30),
-            Priority::High => Duration::from_secs(60),
-            Priority::Normal => Duration::from_secs(120),
-            Priority::Low => Duration::from_secs(300),
+            OpResponseType::ReadFile { contents, .. }
+            | OpResponseType::ReadFileCache { contents, .. }
+            | OpResponseType::ReadImage { contents, .. }
+            | OpResponseType::ReadBlock { contents, .. } => contents.len() as u64,
+            OpResponseType::WriteFile { bytes_written, .. } => *bytes_written,
+            _ => 0,
         }
     }
-}
+
+    /// The drive's status at the end of the operation, if the operation
+    /// reads it
+    pub fn status(&self) -> Option<&CbmStatus> {
+        match self {
+            OpResponseType::ReadDirectory { status, .. }
+            | OpResponseType::ReadFile { status, .. }
+            | OpResponseType::ReadFileCache { status, .. }
+            | OpResponseType::WriteFile { status, .. }
+            | OpResponseType::InitDrive { status }
+            | OpResponseType::GetStatus { status }
+            | OpResponseType::DriveCommand { status }
+            | OpResponseType::SoftReset { status }
+            | OpResponseType::FormatDisk { status }
+            | OpResponseType::RenameFile { status, .. }
+            | OpResponseType::DeleteFile { status, .. }
+            | OpResponseType::Partition { status }
+            | OpResponseType::Set1571Mode { status, .. }
+            | OpResponseType::SetDeviceNumber { status, .. }
+            | OpResponseType::MemoryWrite { status, .. }
+            | OpResponseType::MemoryExecute { status, .. }
+            | OpResponseType::WriteImage { status, .. } => Some(status),
+            _ => None,
+        }
+    }
+}
+
+/// Accumulated timings for one type of operation.  Durations are the time
+/// spent executing operations - the time they spent queued beforehand is
+/// tracked separately.
+#[derive(Debug, Clone, Default)]
+pub struct OpMetrics {
+    pub count: u64,
+    pub errors: u64,
+    pub min: Duration,
+    pub max: Duration,
+    pub total: Duration,
+    pub queued: Duration,
+    pub bytes: u64,
+}
+
+impl OpMetrics {
+    fn record(&mut self, duration: Duration, queued: Duration, bytes: u64, ok: bool) {
+        self.min = if self.count == 0 {
+            duration
+        } else {
+            self.min.min(duration)
+        };
+        self.max = self.max.max(duration);
+        self.count += 1;
+        self.total += duration;
+        self.queued += queued;
+        self.bytes += bytes;
+        if !ok {
+            self.errors += 1;
+        }
+    }
+
+    pub fn avg(&self) -> Duration {
+        self.total
+            .checked_div(self.count as u32)
+            .unwrap_or_default()
+    }
+
+    pub fn avg_queued(&self) -> Duration {
+        self.queued
+            .checked_div(self.count as u32)
+            .unwrap_or_default()
+    }
+}
+
+/// Priority levels for background operations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum Priority {
+    Critical, // Bus reset
+    High,     // Mount/Unmount
+    Normal,   // FUSE operations
+    Low,      // Other IPC and background tasks
+}
+
+impl Priority {
+    pub fn timeout(&self) -> Duration {
+        match self {
+            Priority::Critical => Duration::from_secs(30),
+            Priority::High => Duration::from_secs(60),
+            Priority::Normal => Duration::from_secs(120),
+            Priority::Low => Duration::from_secs(300),
+        }
+    }
+}
 
 impl std::fmt::Display for Priority {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -376,16 +1031,18 @@ impl std::fmt::Display for Priority {
 }
 
 /// A background operation to be processed sender is the flumempsc:Sender to
-/// use to send the OpResponse/Error back to the originator stream is a
-/// UnixStream OwnedWriteHalf to pass back to the originator (provided) so the
-/// can send the data out of the socket
+/// use to send the OpResponse/Error back to the originator reply is a
+/// Sender to pass back to the originator (provided) so the IPC server can
+/// send the client's Response on to the connection which is waiting for it
 #[derive(Debug)]
 pub struct Operation {
     priority: Priority,
     op_type: OpType,
     created_at: Instant,
     sender: Arc<Sender<OpResponse>>,
-    stream: Option<OwnedWriteHalf>,
+    reply: Option<Sender<Response>>,
+    waiter: Option<Sender<OpOutcome>>,
+    cancelled: Arc<AtomicBool>,
 }
 
 // Note that the Sender only needs to be an Arc, because Sender implements
@@ -395,29 +1052,52 @@ impl Operation {
     pub fn new(
         op_type: OpType,
         sender: Arc<Sender<OpResponse>>,
-        stream: Option<OwnedWriteHalf>,
+        reply: Option<Sender<Response>>,
     ) -> Self {
         Self {
             priority: op_type.priority(),
             op_type,
             created_at: Instant::now(),
             sender,
-            stream,
+            reply,
+            waiter: None,
+            cancelled: Arc::new(AtomicBool::new(false)),
         }
     }
 
+    /// Returns a channel which receives the operation's outcome, once the
+    /// response has been processed by whoever submitted the operation.  This
+    /// allows the submitter to wait for this particular operation, rather
+    /// than for any change in the state it updates.
+    pub fn waiter(&mut self) -> Receiver<OpOutcome> {
+        let (tx, rx) = flume::bounded(1);
+        self.waiter = Some(tx);
+        rx
+    }
+
+    /// Returns a token which can be set to cancel this operation.  A
+    /// cancelled operation fails with Fs1541Error::Cancelled if it hasn't
+    /// started yet, and stops as soon as it can if it has.
+    pub fn cancel_token(&self) -> Arc<AtomicBool> {
+        self.cancelled.clone()
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
     pub fn priority_timeout(&self) -> Duration {
         self.priority.timeout()
     }
 
-    pub fn set_stream(&mut self, stream: OwnedWriteHalf) -> Result<(), Error> {
-        if self.stream.is_some() {
+    pub fn set_reply(&mut self, reply: Sender<Response>) -> Result<(), Error> {
+        if self.reply.is_some() {
             return Err(Error::Fs1541 {
                 message: "Couldn't handle request from client".into(),
-                error: Fs1541Error::Internal("Operation stream already set".into()),
+                error: Fs1541Error::Internal("Operation reply already set".into()),
             });
         }
-        self.stream = Some(stream);
+        self.reply = Some(reply);
         Ok(())
     }
 }
@@ -429,35 +1109,48 @@ impl From<Operation> for OpResponse {
 
         OpResponse {
             rsp: Ok(rsp_type), // Wrap in Ok since we're creating a default/empty response
-            stream: op.stream, // Pass through the stream
+            reply: op.reply,   // Pass through the reply
+            waiter: op.waiter,
         }
     }
 }
 
 #[allow(dead_code)]
 impl OpResponse {
-    pub fn with_error(error: Error, stream: Option<OwnedWriteHalf>) -> Self {
+    pub fn with_error(error: Error, reply: Option<Sender<Response>>) -> Self {
         OpResponse {
             rsp: Err(error),
-            stream,
+            reply,
+            waiter: None,
         }
     }
 
-    pub fn take_stream(&mut self) -> Result<OwnedWriteHalf, Error> {
-        self.stream.take().ok_or_else(|| Error::Fs1541 {
+    pub fn take_reply(&mut self) -> Result<Sender<Response>, Error> {
+        self.reply.take().ok_or_else(|| Error::Fs1541 {
             message: "Couldn't resond to request from client".into(),
-            error: Fs1541Error::Internal("No stream on OpResponse".into()),
+            error: Fs1541Error::Internal("No reply on OpResponse".into()),
         })
     }
+
+    /// Splits the response into its result and the channel, if any, to send
+    /// the operation's outcome to once the result has been processed
+    pub fn into_parts(self) -> (Result<OpResponseType, Error>, Option<Sender<OpOutcome>>) {
+        (self.rsp, self.waiter)
+    }
 }
 
-/// Manages separate queues for different priority levels
+/// Manages separate queues for different priority levels.  Critical and
+/// High operations are processed strictly in order.  Normal and Low
+/// operations are shared round-robin between devices, so one busy device
+/// can't starve the others.
 #[derive(Debug)]
 struct OperationQueues {
     critical: VecDeque<Operation>,
     high: VecDeque<Operation>,
     normal: VecDeque<Operation>,
     low: VecDeque<Operation>,
+    // The device of the last Normal or Low operation processed
+    last_device: Option<u8>,
 }
 
 impl OperationQueues {
@@ -467,6 +1160,7 @@ impl OperationQueues {
             high: VecDeque::new(),
             normal: VecDeque::new(),
             low: VecDeque::new(),
+            last_device: None,
         }
     }
 
@@ -480,11 +1174,39 @@ impl OperationQueues {
     }
 
     fn pop_next(&mut self) -> Option<Operation> {
-        self.critical
-            .pop_front()
-            .or_else(|| self.high.pop_front())
-            .or_else(|| self.normal.pop_front())
-            .or_else(|| self.low.pop_front())
+        if let Some(op) = self.critical.pop_front().or_else(|| self.high.pop_front()) {
+            return Some(op);
+        }
+
+        let op = Self::pop_round_robin(&mut self.normal, self.last_device)
+            .or_else(|| Self::pop_round_robin(&mut self.low, self.last_device))?;
+        if let Some(device) = op.op_type.device() {
+            self.last_device = Some(device);
+        }
+        Some(op)
+    }
+
+    // Pops the oldest operation for the next device number after
+    // last_device, wrapping around.  Operations which aren't for a single
+    // device are taken first, as they can't be attributed to a busy device.
+    fn pop_round_robin(
+        queue: &mut VecDeque<Operation>,
+        last_device: Option<u8>,
+    ) -> Option<Operation> {
+        let Some(last_device) = last_device else {
+            return queue.pop_front();
+        };
+        let index = queue
+            .iter()
+            .enumerate()
+            .min_by_key(|(ii, op)| {
+                let distance = op.op_type.device().map_or(0, |device| {
+                    device.wrapping_sub(last_device).wrapping_sub(1) as u16 + 1
+                });
+                (distance, *ii)
+            })
+            .map(|(ii, _)| ii)?;
+        queue.remove(index)
     }
 
     async fn cleanup_on_age(&mut self) {
@@ -557,7 +1279,8 @@ impl OperationQueues {
                     message: error.to_string(),
                     error,
                 }),
-                stream: op.stream.take(),
+                reply: op.reply.take(),
+                waiter: op.waiter.take(),
             };
 
             let _ = op
@@ -569,6 +1292,119 @@ impl OperationQueues {
     }
 }
 
+/// How many times, and how quickly, drive operations which fail with
+/// transient errors are retried
+#[derive(Debug, Clone, Copy)]
+struct RetryPolicy {
+    retries: u32,
+    delay: Duration,
+}
+
+impl RetryPolicy {
+    /// The delay before a retry (numbered from 0), which doubles each time
+    fn backoff(&self, retry: u32) -> Duration {
+        self.delay.saturating_mul(1 << retry.min(16))
+    }
+}
+
+/// Runs a drive operation, retrying it with exponential backoff while
+/// should_retry says its result is a transient failure.  Retries stop once
+/// the policy's limit is reached, or if waiting for the next one would take
+/// the operation past its timeout, and the last result is returned.
+///
+/// Each attempt should take the Cbm lock itself, so the bus is free for
+/// other operations while waiting to retry.
+async fn with_retries<T, E, F, Fut, R>(
+    what: &str,
+    policy: RetryPolicy,
+    timeout: Duration,
+    mut f: F,
+    should_retry: R,
+) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    R: Fn(&T) -> bool,
+{
+    let deadline = Instant::now() + timeout;
+    let mut retry = 0;
+    loop {
+        let result = f().await;
+        if retry >= policy.retries || !result.as_ref().is_ok_and(&should_retry) {
+            return result;
+        }
+
+        let delay = policy.backoff(retry);
+        if Instant::now() + delay >= deadline {
+            debug!("Not retrying {what}, as it would exceed the operation timeout");
+            return result;
+        }
+        retry += 1;
+        debug!(
+            "{what} hit a transient error, retry {retry} of {} in {delay:?}",
+            policy.retries
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// The error returned for an operation which was cancelled
+fn cancelled_error(op_type: &OpType) -> Error {
+    Error::Fs1541 {
+        message: "Operation cancelled".to_string(),
+        error: Fs1541Error::Cancelled(format!("{} cancelled", op_type)),
+    }
+}
+
+/// Configures the drive's sector interleave, if a command to do so was
+/// given.  Failing to set it isn't fatal - the drive's default is used.
+fn set_interleave(cbm: &mut dyn CbmBackend, device: u8, command: Option<&[u8]>) {
+    let Some(command) = command else {
+        return;
+    };
+    debug!("Setting interleave on device {device}");
+    match cbm
+        .send_command(device, command)
+        .and_then(|_| cbm.get_status(device))
+    {
+        Ok(status) if status.is_ok() == CbmErrorNumberOk::Ok => (),
+        Ok(status) => warn!("Failed to set interleave on device {device}: {status}"),
+        Err(e) => warn!("Failed to set interleave on device {device}: {e}"),
+    }
+}
+
+/// Returns the name to open a file read over IPC with.  The file's type
+/// isn't given, so it is looked up in the directory, and SEQ and USR files
+/// are opened with their type suffix.  A file which isn't in the directory
+/// is opened by the name given, so the drive reports the error.
+fn ipc_read_open_name(
+    cbm: &mut dyn CbmBackend,
+    drive_unit: &CbmDriveUnit,
+    filename: &str,
+) -> Result<String, Error> {
+    let (drive_num, name) = match filename.split_once(':') {
+        Some((drive, name)) => (drive.parse().unwrap_or(0), name),
+        None => (0, filename),
+    };
+    let (listings, _) = cbm.dir(drive_unit)?;
+    let file_type = listings
+        .iter()
+        .filter(|listing| listing.header.drive_number == drive_num)
+        .flat_map(|listing| listing.files.iter())
+        .find_map(|file| match file {
+            CbmFileEntry::ValidFile {
+                filename,
+                file_type,
+                ..
+            } if filename == name => Some(file_type),
+            _ => None,
+        });
+    Ok(match file_type {
+        Some(file_type) => cbm_read_open_name(filename, file_type),
+        None => filename.to_string(),
+    })
+}
+
 /// Processes background operations in priority order
 #[derive(Debug)]
 #[allow(dead_code)]
@@ -577,11 +1413,13 @@ pub struct Proc {
     operation_receiver: Receiver<Operation>,
     operation_sender: Arc<Sender<Operation>>,
     last_cleanup: Instant,
+    metrics: BTreeMap<String, OpMetrics>,
     shutdown: Arc<AtomicBool>,
-    cbm: Arc<Mutex<Cbm>>,
+    cbm: Arc<Mutex<dyn CbmBackend>>,
     drive_mgr: Arc<Mutex<DriveManager>>,
     mount_svc: MountService,
     age_check_period: Duration,
+    retry_policy: RetryPolicy,
 }
 
 impl Proc {
@@ -589,7 +1427,7 @@ impl Proc {
         operation_receiver: Receiver<Operation>,
         operation_sender: Arc<Sender<Operation>>,
         shutdown: Arc<AtomicBool>,
-        cbm: Arc<Mutex<Cbm>>,
+        cbm: Arc<Mutex<dyn CbmBackend>>,
         drive_mgr: Arc<Mutex<DriveManager>>,
         mountpoints: Arc<RwLock<HashMap<PathBuf, Arc<parking_lot::RwLock<Mount>>>>>,
     ) -> Self {
@@ -599,14 +1437,27 @@ impl Proc {
             operation_receiver,
             operation_sender,
             last_cleanup: Instant::now(),
+            metrics: BTreeMap::new(),
             shutdown,
             cbm,
             drive_mgr,
             mount_svc,
             age_check_period: Duration::from_secs(get_args().bg_age_check_secs),
+            retry_policy: RetryPolicy {
+                retries: get_args().retries,
+                delay: Duration::from_millis(get_args().retry_delay_ms),
+            },
         }
     }
 
+    /// The backend serving the device - its disk image if it's mounted from
+    /// one, and otherwise the bus
+    async fn backend(&self, device: u8) -> Arc<Mutex<dyn CbmBackend>> {
+        locking_section!("Lock", "Drive Manager", {
+            self.drive_mgr.lock().await.backend(device).await
+        })
+    }
+
     pub async fn send_resp(
         &self,
         sender: Arc<Sender<OpResponse>>,
@@ -624,10 +1475,65 @@ impl Proc {
         })
     }
 
+    /// Starts a re-read of the directory of the mount for the device, and
+    /// spawns a task to reply once the new listing has been read.  The
+    /// response isn't included in the metrics, as most of its time is spent
+    /// waiting for the ReadDirectory it queues, which is.
+    async fn process_refresh_dir(
+        &self,
+        device: u8,
+        drive_num: u8,
+        sender: Arc<Sender<OpResponse>>,
+        reply: Option<Sender<Response>>,
+    ) -> Result<(), Error> {
+        let mount = match self.mount_svc.find_by_device(device).await {
+            Ok(mount) => mount,
+            Err(e) => {
+                return self
+                    .send_resp(sender, OpResponse::with_error(e, reply))
+                    .await
+            }
+        };
+
+        let args = get_args();
+        let timeout = Duration::from_secs(args.dir_reread_timeout_secs);
+        let interval = Duration::from_millis(args.dir_read_sleep_ms);
+        tokio::spawn(async move {
+            let rsp = refresh_dir(mount, drive_num, timeout, interval)
+                .await
+                .map(|_| OpResponseType::RefreshDir { device, drive_num });
+            let rsp = OpResponse {
+                rsp,
+                reply,
+                waiter: None,
+            };
+            if let Err(e) = sender.send_async(rsp).await {
+                error!("Failed to send directory refresh response: {}", e);
+            }
+        });
+        Ok(())
+    }
+
     async fn process_operation(&mut self, op: Operation) -> Result<(), Error> {
         let timeout = op.priority_timeout();
 
         let sender = op.sender.clone();
+
+        // Cancel any queued cache reads for a device being unmounted before
+        // the Mount is dropped, so no bus time is wasted reading files for a
+        // filesystem that's going away.  This is done here rather than in
+        // MountService::unmount, as it needs access to the queues.
+        if let OpType::Unmount { device, mountpoint } = &op.op_type {
+            if let Ok(device) = self
+                .mount_svc
+                .find_device_number(*device, mountpoint.as_ref())
+                .await
+            {
+                debug!("Cancelling cache reads for device {device} before unmounting");
+                let _ = self.process_cancel_device_cache(device).await;
+            }
+        }
+
         let resp = match op.op_type {
             OpType::CancelDeviceCache { device } => {
                 // We have to process a cancel device cache request here
@@ -636,9 +1542,35 @@ impl Proc {
                 // may need to remove operations from queues.
                 self.process_cancel_device_cache(device).await
             }
+            // Also requires mutable access, to reset the metrics
+            OpType::Metrics { reset } => Ok(self.process_metrics(reset)),
+            // Waits for a ReadDirectory, which this processor has to be free
+            // to run, so replies from its own task
+            OpType::RefreshDir { device, drive_num } => {
+                return self
+                    .process_refresh_dir(device, drive_num, sender, op.reply)
+                    .await;
+            }
+            // No point tying up the bus with an operation nobody is waiting
+            // for any more
+            _ if op.is_cancelled() => {
+                debug!("Not running cancelled operation {}", op.op_type);
+                Err(cancelled_error(&op.op_type))
+            }
             _ => {
-                match tokio::time::timeout(timeout, self.execute_operation(op.op_type.clone()))
-                    .await
+                let started = Instant::now();
+                let context = LogContext {
+                    device: op.op_type.device(),
+                    operation: op.op_type.to_string(),
+                };
+                let resp = match with_log_context(
+                    context,
+                    tokio::time::timeout(
+                        timeout,
+                        self.execute_operation(op.op_type.clone(), &op.cancelled),
+                    ),
+                )
+                .await
                 {
                     Ok(resp) => {
                         trace!("Handled Operation with response {:?}", resp);
@@ -654,13 +1586,25 @@ impl Proc {
                             ),
                         })
                     }
-                }
+                };
+
+                self.metrics
+                    .entry(op.op_type.to_string())
+                    .or_default()
+                    .record(
+                        started.elapsed(),
+                        started.duration_since(op.created_at),
+                        resp.as_ref().map_or(0, |rsp| rsp.bytes_transferred()),
+                        resp.is_ok(),
+                    );
+                resp
             }
         };
 
         let op_response = OpResponse {
             rsp: resp,
-            stream: op.stream,
+            reply: op.reply,
+            waiter: op.waiter,
         };
         self.send_resp(sender, op_response).await
     }
@@ -707,7 +1651,11 @@ impl Proc {
         self.mount_svc.cleanup().await;
     }
 
-    async fn execute_operation(&self, op_type: OpType) -> Result<OpResponseType, Error> {
+    async fn execute_operation(
+        &self,
+        op_type: OpType,
+        cancelled: &AtomicBool,
+    ) -> Result<OpResponseType, Error> {
         if self.shutdown.load(Ordering::Relaxed) {
             return Err(Error::Fs1541 {
                 message: "Operation cancelled".to_string(),
@@ -715,28 +1663,65 @@ impl Proc {
             });
         }
 
+        // Cache reads are performed in the same way as other reads, but
+        // responded to differently
+        let cache_read = matches!(op_type, OpType::ReadFileCache { .. });
+        let timeout = op_type.timeout();
+
         match op_type {
             OpType::Mount {
                 device,
                 mountpoint,
                 dummy_formats,
                 bus_reset: _,
+                read_write,
+                safe_replace,
+                verify_writes,
+                interleave,
+                created_mountpoint,
+                auto_refresh,
+                image,
             } => self
                 .mount_svc
                 .mount(
                     device,
-                    mountpoint,
+                    &mountpoint,
                     dummy_formats,
+                    read_write,
+                    safe_replace,
+                    verify_writes,
+                    interleave,
+                    created_mountpoint,
+                    auto_refresh,
+                    image,
                     self.operation_sender.clone(),
                 )
                 .await
+                .inspect_err(|_| {
+                    // Don't leave behind a mountpoint we created
+                    if created_mountpoint {
+                        remove_created_mountpoint(&mountpoint);
+                    }
+                })
                 .map(|_| OpResponseType::Mount()),
 
-            OpType::Unmount { device, mountpoint } => self
-                .mount_svc
-                .unmount(device, mountpoint, false)
-                .await
-                .map(|_| OpResponseType::Mount()),
+            OpType::Unmount { device, mountpoint } => {
+                match self.mount_svc.unmount(device, mountpoint, false).await {
+                    Ok(()) => Ok(OpResponseType::Unmount { not_mounted: None }),
+                    // Unmounting something which has already gone is treated
+                    // as done, so unmount can safely be repeated
+                    Err(Error::Fs1541 {
+                        error: Fs1541Error::NotMounted(what),
+                        ..
+                    }) => {
+                        warn!("Nothing to unmount - no mount found for {}", what);
+                        Ok(OpResponseType::Unmount {
+                            not_mounted: Some(what),
+                        })
+                    }
+                    Err(e) => Err(e),
+                }
+            }
 
             OpType::Identify { device } => {
                 locking_section!("Lock", "Drive Manager", {
@@ -758,40 +1743,279 @@ impl Proc {
                 })
             }
 
-            OpType::BusReset => {
+            OpType::DriveCommand { device, command } => {
+                debug!("Drive command {device} {command}");
+                let backend = self.backend(device).await;
+                locking_section!("Lock", "Cbm", {
+                    let mut cbm = backend.lock().await;
+
+                    // The command is ASCII, and is converted to PETSCII by
+                    // rs1541 before being sent
+                    cbm.send_string_command(device, &command)
+                        .and_then(|_| cbm.get_status(device))
+                        .map(|status| OpResponseType::DriveCommand { status })
+                })
+            }
+
+            OpType::SoftReset { device } => {
+                debug!("Soft resetting device {device}");
+                let backend = self.backend(device).await;
+                locking_section!("Lock", "Cbm", {
+                    let mut cbm = backend.lock().await;
+                    cbm.soft_reset(device)
+                        .and_then(|_| cbm.get_status(device))
+                        .map(|status| OpResponseType::SoftReset { status })
+                })
+            }
+
+            OpType::FormatDisk {
+                device,
+                name,
+                id,
+                interleave,
+            } => {
+                info!("Formatting disk in device {device} name {name} id {id}");
+                let backend = self.backend(device).await;
+                locking_section!("Lock", "Cbm", {
+                    let mut cbm = backend.lock().await;
+                    cbm.format_disk(device, &name, &id)
+                        .and_then(|_| cbm.get_status(device))
+                        .inspect(|status| {
+                            if status.is_ok() == CbmErrorNumberOk::Ok {
+                                set_interleave(&mut cbm, device, interleave.as_deref())
+                            }
+                        })
+                        .map(|status| OpResponseType::FormatDisk { status })
+                })
+            }
+
+            OpType::RenameFile {
+                device,
+                drive_num,
+                old,
+                new,
+            } => {
+                let command = cbm_rename_command(drive_num, &old, &new);
+                debug!("Renaming file on device {device}: {command}");
+                let backend = self.backend(device).await;
+                locking_section!("Lock", "Cbm", {
+                    let mut cbm = backend.lock().await;
+                    cbm.send_string_command(device, &command)
+                        .and_then(|_| cbm.get_status(device))
+                        .map(|status| OpResponseType::RenameFile { drive_num, status })
+                })
+            }
+
+            OpType::DeleteFile {
+                device,
+                drive_num,
+                filename,
+            } => {
+                // The scratch command is sent directly, rather than via
+                // Cbm::delete_file, so the file is removed from the right
+                // drive of multi-drive units
+                let command = cbm_scratch_command(drive_num, &filename);
+                debug!("Deleting file on device {device}: {command}");
+                let backend = self.backend(device).await;
+                locking_section!("Lock", "Cbm", {
+                    let mut cbm = backend.lock().await;
+                    cbm.send_string_command(device, &command)
+                        .and_then(|_| cbm.get_status(device))
+                        .map(|status| OpResponseType::DeleteFile { drive_num, status })
+                })
+            }
+
+            OpType::Partition { device, commands } => {
+                debug!(
+                    "Sending {} partition commands to device {device}",
+                    commands.len()
+                );
+                let backend = self.backend(device).await;
+                locking_section!("Lock", "Cbm", {
+                    let mut cbm = backend.lock().await;
+
+                    // Stop at the first command the drive rejects, so a
+                    // failed create doesn't go on to select the partition
+                    let mut result = Ok(CbmStatus::default());
+                    for command in commands.iter() {
+                        result = cbm
+                            .send_command(device, command)
+                            .and_then(|_| cbm.get_status(device));
+                        match &result {
+                            Ok(status) if status.is_ok() == CbmErrorNumberOk::Ok => (),
+                            _ => break,
+                        }
+                    }
+                    result.map(|status| OpResponseType::Partition { status })
+                })
+            }
+
+            OpType::Set1571Mode {
+                device,
+                double_sided,
+            } => {
+                // U0>M1 selects 1571 mode and U0>M0 1541 mode
+                let command: &[u8] = if double_sided { b"U0>M1" } else { b"U0>M0" };
+                debug!("Setting device {device} double-sided mode {double_sided}");
+                let backend = self.backend(device).await;
+                locking_section!("Lock", "Cbm", {
+                    let mut cbm = backend.lock().await;
+                    cbm.send_command(device, command)
+                        .and_then(|_| cbm.get_status(device))
+                        .map(|status| OpResponseType::Set1571Mode {
+                            double_sided,
+                            status,
+                        })
+                })
+            }
+
+            OpType::SetDeviceNumber { device, new_device } => {
                 locking_section!("Lock", "Drive Manager", {
                     let drive_mgr = self.drive_mgr.lock().await;
                     drive_mgr
-                        .reset_bus()
+                        .set_device_number(device, new_device)
+                        .await
+                        .map(|status| OpResponseType::SetDeviceNumber {
+                            device,
+                            new_device,
+                            status,
+                        })
+                })
+            }
+
+            OpType::MemoryRead { device, addr, len } => {
+                debug!("Reading {len} bytes of device {device} memory from ${addr:04X}");
+                let backend = self.backend(device).await;
+                locking_section!("Lock", "Cbm", {
+                    backend
+                        .lock()
                         .await
-                        .map(|_| OpResponseType::BusReset())
+                        .memory_read(device, addr, len)
+                        .map(|data| OpResponseType::MemoryRead { device, addr, data })
+                })
+            }
+
+            OpType::MemoryWrite { device, addr, data } => {
+                debug!(
+                    "Writing {} bytes to device {device} memory at ${addr:04X}",
+                    data.len()
+                );
+                let backend = self.backend(device).await;
+                locking_section!("Lock", "Cbm", {
+                    let mut cbm = backend.lock().await;
+                    cbm.memory_write(device, addr, &data)
+                        .and_then(|_| cbm.get_status(device))
+                        .map(|status| OpResponseType::MemoryWrite {
+                            device,
+                            addr,
+                            len: data.len(),
+                            status,
+                        })
                 })
             }
 
+            OpType::MemoryExecute {
+                device,
+                addr,
+                timeout,
+            } => {
+                debug!("Executing device {device} code at ${addr:04X}");
+                // The drive only answers the status read once the code has
+                // returned.  If it never does, the bus call blocks, so it's
+                // made from a blocking thread which is given up on after the
+                // timeout, although it holds the Cbm lock until the drive is
+                // reset.
+                let backend = self.backend(device).await;
+                let execute = tokio::task::spawn_blocking(move || {
+                    locking_section!("Lock", "Cbm", {
+                        let mut cbm = backend.blocking_lock();
+                        cbm.memory_execute(device, addr)
+                            .and_then(|_| cbm.get_status(device))
+                    })
+                });
+                match tokio::time::timeout(timeout, execute).await {
+                    Ok(Ok(result)) => result.map(|status| OpResponseType::MemoryExecute {
+                        device,
+                        addr,
+                        status,
+                    }),
+                    Ok(Err(e)) => Err(Error::Fs1541 {
+                        message: format!("Failed to execute device {} code", device),
+                        error: Fs1541Error::Internal(format!("Execution failed to run: {}", e)),
+                    }),
+                    Err(_) => {
+                        warn!(
+                            "Device {} code at ${:04X} didn't return within {:?}",
+                            device, addr, timeout
+                        );
+                        Err(Error::Fs1541 {
+                            message: format!(
+                                "Device {} code at ${:04X} didn't return",
+                                device, addr
+                            ),
+                            error: Fs1541Error::Timeout("Memory execute".to_string(), timeout),
+                        })
+                    }
+                }
+            }
+
+            OpType::ScanBus => {
+                locking_section!("Lock", "Drive Manager", {
+                    let drive_mgr = self.drive_mgr.lock().await;
+                    Ok(OpResponseType::ScanBus {
+                        devices: drive_mgr.scan_bus().await,
+                    })
+                })
+            }
+
+            OpType::ListMounts => Ok(OpResponseType::ListMounts {
+                mounts: self.mount_svc.mount_infos().await,
+            }),
+
+            OpType::BusReset => {
+                let reset = locking_section!("Lock", "Drive Manager", {
+                    self.drive_mgr.lock().await.reset_bus().await
+                });
+                if let Err(e) = reset {
+                    // The xum1541 has most likely stopped responding, so
+                    // reset it over USB, which also resets the bus
+                    warn!("Bus reset failed, resetting USB device: {}", e);
+                    locking_section!("Lock", "Drive Manager", {
+                        self.drive_mgr.lock().await.usb_reset().await
+                    })?;
+                }
+
+                // Mounted drives lose their state in the reset
+                let reinitialized = self.mount_svc.reinit_all().await;
+                info!("Re-initialized {} mounts after bus reset", reinitialized);
+                Ok(OpResponseType::BusReset { reinitialized })
+            }
+
             OpType::ReadDirectory { device } => {
                 let drive_unit = locking_section!("Lock", "Drive Manager", {
                     self.drive_mgr.lock().await.get_drive(device).await?
                 });
 
-                locking_section!("Lock", "Cbm", {
-                    let mut cbm = self.cbm.lock().await;
-
-                    locking_section!("Read", "Drive Unit", {
-                        let drive_unit = drive_unit.read().await;
-                        drive_unit
-                            .dir(&mut cbm)
-                            .map(|(l, s)| OpResponseType::ReadDirectory {
-                                status: s,
-                                listings: l,
+                let backend = self.backend(device).await;
+                let (cbm, drive_unit) = (&backend, &drive_unit);
+                with_retries(
+                    "Directory read",
+                    self.retry_policy,
+                    timeout,
+                    || async move {
+                        locking_section!("Lock", "Cbm", {
+                            let mut cbm = cbm.lock().await;
+                            locking_section!("Read", "Drive Unit", {
+                                cbm.dir(&*drive_unit.read().await)
                             })
-                            .map_err(|e| Error::Rs1541 {
-                                message: format!(
-                                    "Failed to read directory for device {}",
-                                    drive_unit.device_number
-                                ),
-                                error: e,
-                            })
-                    })
+                        })
+                    },
+                    |(_, status)| dos_error_transient(status.number),
+                )
+                .await
+                .map(|(l, s)| OpResponseType::ReadDirectory {
+                    status: s,
+                    listings: l,
                 })
             }
 
@@ -799,41 +2023,336 @@ impl Proc {
                 device,
                 path,
                 inode,
+            }
+            | OpType::ReadFileCache {
+                device,
+                path,
+                inode,
             } => {
                 debug!("Read file {device} {path}");
-                let filename = CbmString::from_ascii_bytes(path.as_bytes());
 
+                // Files may be read over IPC from drives which aren't mounted
                 let drive_unit = locking_section!("Lock", "Drive Manager", {
-                    self.drive_mgr.lock().await.get_drive(device).await?
+                    self.drive_mgr
+                        .lock()
+                        .await
+                        .get_or_identify_drive(device)
+                        .await?
                 });
 
-                locking_section!("Lock", "Cbm", {
-                    let mut cbm = self.cbm.lock().await;
-                    locking_section!("Read", "Drive Unit", {
-                        let drive_unit = drive_unit.read().await;
+                let backend = self.backend(device).await;
 
-                        drive_unit
-                            .read_file(&mut cbm, &filename)
-                            .map(|(c, s)| OpResponseType::ReadFile {
-                                device,
-                                path: path.clone(),
-                                inode,
-                                status: s,
-                                contents: c,
-                            })
-                            .map_err(|e| Error::Rs1541 {
-                                message: format!(
-                                    "Failed to read file {} for device {}",
-                                    path, drive_unit.device_number
-                                ),
-                                error: e,
+                // Files read over IPC, rather than via a mount, have no inode
+                // and are named without their type, so it is looked up
+                let open_name = match inode {
+                    0 => locking_section!("Lock", "Cbm", {
+                        let mut cbm = backend.lock().await;
+                        locking_section!("Read", "Drive Unit", {
+                            ipc_read_open_name(&mut *cbm, &*drive_unit.read().await, &path)
+                        })
+                    })?,
+                    _ => path.clone(),
+                };
+
+                let (cbm, drive_unit, filename) = (&backend, &drive_unit, &open_name);
+                with_retries(
+                    "File read",
+                    self.retry_policy,
+                    timeout,
+                    || async move {
+                        locking_section!("Lock", "Cbm", {
+                            let mut cbm = cbm.lock().await;
+                            locking_section!("Read", "Drive Unit", {
+                                cbm.read_file(&*drive_unit.read().await, filename, cancelled)
                             })
-                    })
+                        })
+                    },
+                    // A cancelled read isn't worth retrying
+                    |(_, status)| {
+                        !cancelled.load(Ordering::Relaxed) && dos_error_transient(status.number)
+                    },
+                )
+                .await
+                .map(|(c, s)| {
+                    if cache_read {
+                        OpResponseType::ReadFileCache {
+                            device,
+                            path: path.clone(),
+                            inode,
+                            status: s,
+                            contents: c,
+                        }
+                    } else {
+                        OpResponseType::ReadFile {
+                            device,
+                            path: path.clone(),
+                            inode,
+                            status: s,
+                            contents: c,
+                        }
+                    }
+                })
+            }
+
+            OpType::WriteFile {
+                device,
+                drive_num,
+                path,
+                mode,
+                data,
+                scratch,
+                verify,
+                interleave,
+            } => {
+                debug!("Write file {device} {path} {} bytes", data.len());
+
+                // Empty files have nothing to verify.  Others are read back
+                // the same way as ReadFile, which needs the drive unit.
+                let verify = verify.filter(|_| !data.is_empty());
+                let drive_unit = match verify {
+                    Some(_) => Some(locking_section!("Lock", "Drive Manager", {
+                        self.drive_mgr
+                            .lock()
+                            .await
+                            .get_or_identify_drive(device)
+                            .await?
+                    })),
+                    None => None,
+                };
+
+                let backend = self.backend(device).await;
+                let (status, read_back) = locking_section!("Lock", "Cbm", {
+                    let mut cbm = backend.lock().await;
+                    set_interleave(&mut cbm, device, interleave.as_deref());
+
+                    if let Some(command) = scratch {
+                        debug!("Scratching file on device {device} before writing: {command}");
+                        let status = cbm
+                            .send_string_command(device, &command)
+                            .and_then(|_| cbm.get_status(device))?;
+
+                        // 62 (file not found) just means there was nothing
+                        // to replace
+                        if status.is_ok() != CbmErrorNumberOk::Ok && status.number != 62 {
+                            return Ok(OpResponseType::WriteFile {
+                                device,
+                                drive_num,
+                                path,
+                                status,
+                                bytes_written: 0,
+                                verified: None,
+                            });
+                        }
+                    }
+
+                    // The backend allocates the channel to write the file on
+                    let write = match mode {
+                        WriteMode::Open => cbm.write_file(device, &path, &data),
+                        WriteMode::Seq => cbm.write_seq(device, &path, &data),
+                        WriteMode::SeqAppend => cbm.append_to_seq(device, &path, &data),
+                    };
+                    let status = write.and_then(|_| cbm.get_status(device))?;
+                    let written = status.is_ok() == CbmErrorNumberOk::Ok;
+
+                    let read_back = match (verify, drive_unit) {
+                        (Some(read_path), Some(drive_unit)) if written => {
+                            debug!("Verifying file {device} {read_path}");
+                            let (contents, _) = locking_section!("Read", "Drive Unit", {
+                                cbm.read_file(&*drive_unit.read().await, &read_path, cancelled)
+                            })?;
+                            Some(contents)
+                        }
+                        _ => None,
+                    };
+                    (status, read_back)
+                });
+
+                // Checking what was read back doesn't need the bus, so is
+                // done once the Cbm lock has been released.  A file which
+                // doesn't match what was written fails the write.
+                let verified = match read_back {
+                    Some(contents) if contents != data => {
+                        warn!(
+                            "Verify of {} failed - wrote {} bytes, read back {}",
+                            path,
+                            data.len(),
+                            contents.len()
+                        );
+                        return Err(Error::Fs1541 {
+                            message: format!("Failed to verify {} on device {}", path, device),
+                            error: Fs1541Error::Operation(format!(
+                                "Wrote {} bytes, but read back {} which didn't match",
+                                data.len(),
+                                contents.len()
+                            )),
+                        });
+                    }
+                    Some(_) => Some(true),
+                    None => None,
+                };
+                let written = status.is_ok() == CbmErrorNumberOk::Ok;
+                Ok(OpResponseType::WriteFile {
+                    device,
+                    drive_num,
+                    path,
+                    bytes_written: if written { data.len() as u64 } else { 0 },
+                    status,
+                    verified,
+                })
+            }
+
+            OpType::ReadImage {
+                device,
+                inode,
+                geometry,
+            } => {
+                info!("Reading {geometry} disk image from device {device}");
+                let mut contents = Vec::with_capacity(geometry.image_size());
+                let mut bad_sectors = Vec::new();
+                let backend = self.backend(device).await;
+
+                // Read the blocks in .d64 order.  Unreadable blocks are
+                // filled with zeros, so the rest of the image is usable.
+                for track in 1..=geometry.tracks() {
+                    for sector in 0..geometry.sectors_per_track(track).unwrap_or_default() {
+                        if cancelled.load(Ordering::Relaxed) {
+                            info!("Disk image read from device {device} cancelled");
+                            return Err(cancelled_error(&op_type));
+                        }
+                        let block = locking_section!("Lock", "Cbm", {
+                            let mut cbm = backend.lock().await;
+                            cbm.read_block(device, geometry, track, sector)
+                        });
+                        match block {
+                            Ok(block) => contents.extend_from_slice(&block),
+                            Err(e) => {
+                                debug!("Failed to read track {track} sector {sector}: {e}");
+                                bad_sectors.push((track, sector));
+                                contents.extend_from_slice(&[0; D64_BLOCK_SIZE]);
+                            }
+                        }
+                    }
+                }
+
+                if !bad_sectors.is_empty() {
+                    warn!(
+                        "Failed to read {} sectors imaging device {}",
+                        bad_sectors.len(),
+                        device
+                    );
+                }
+                Ok(OpResponseType::ReadImage {
+                    device,
+                    inode,
+                    contents,
+                    bad_sectors,
+                })
+            }
+
+            OpType::ReadBlock {
+                device,
+                track,
+                sector,
+                geometry,
+            } => {
+                trace!("Read block {device} track {track} sector {sector}");
+                let backend = self.backend(device).await;
+                let contents = locking_section!("Lock", "Cbm", {
+                    let mut cbm = backend.lock().await;
+                    cbm.read_block(device, geometry, track, sector)
+                })?;
+                Ok(OpResponseType::ReadBlock {
+                    device,
+                    track,
+                    sector,
+                    contents,
+                })
+            }
+
+            OpType::WriteImage {
+                device,
+                inode,
+                name,
+                id,
+                geometry,
+                data,
+                interleave,
+            } => {
+                // Reject images which don't fill the geometry before
+                // formatting, rather than writing part of one
+                if data.len() < geometry.image_size() {
+                    return Err(Error::Fs1541 {
+                        message: "Invalid disk image".into(),
+                        error: Fs1541Error::Validation(format!(
+                            "Disk image of {} bytes is too short for a {} disk",
+                            data.len(),
+                            geometry
+                        )),
+                    });
+                }
+                info!("Restoring disk image to device {device} name {name} id {id}");
+                let backend = self.backend(device).await;
+                let status = locking_section!("Lock", "Cbm", {
+                    let mut cbm = backend.lock().await;
+                    cbm.format_disk(device, &name, &id)
+                        .and_then(|_| cbm.get_status(device))
+                        .inspect(|status| {
+                            if status.is_ok() == CbmErrorNumberOk::Ok {
+                                set_interleave(&mut cbm, device, interleave.as_deref())
+                            }
+                        })
+                })?;
+                if status.is_ok() != CbmErrorNumberOk::Ok {
+                    warn!("Failed to format disk for image restore: {status}");
+                    return Ok(OpResponseType::WriteImage {
+                        device,
+                        inode,
+                        status,
+                        bad_sectors: Vec::new(),
+                    });
+                }
+
+                // Write the blocks in .d64 order, carrying on past failures
+                // so as much of the image as possible is restored
+                let mut bad_sectors = Vec::new();
+                for track in 1..=geometry.tracks() {
+                    for sector in 0..geometry.sectors_per_track(track).unwrap_or_default() {
+                        let offset = d64_offset(track, sector);
+                        let block: &[u8; D64_BLOCK_SIZE] = data[offset..offset + D64_BLOCK_SIZE]
+                            .try_into()
+                            .expect("Image length validated above");
+                        let result = locking_section!("Lock", "Cbm", {
+                            let mut cbm = backend.lock().await;
+                            cbm.write_block(device, geometry, track, sector, block)
+                        });
+                        if let Err(e) = result {
+                            debug!("Failed to write track {track} sector {sector}: {e}");
+                            bad_sectors.push((track, sector));
+                        }
+                    }
+                }
+
+                if !bad_sectors.is_empty() {
+                    warn!(
+                        "Failed to write {} sectors restoring image to device {}",
+                        bad_sectors.len(),
+                        device
+                    );
+                }
+                locking_section!("Lock", "Cbm", {
+                    let mut cbm = backend.lock().await;
+                    cbm.get_status(device)
+                        .map(|status| OpResponseType::WriteImage {
+                            device,
+                            inode,
+                            status,
+                            bad_sectors,
+                        })
                 })
             }
 
             // Handled in process_operation
-            OpType::CancelDeviceCache { .. } => unreachable!(),
+            OpType::CancelDeviceCache { .. } | OpType::Metrics { .. } => unreachable!(),
 
             _ => Err(Error::Fs1541 {
                 message: format!("Operation not yet supported {}", op_type),
@@ -842,8 +2361,414 @@ impl Proc {
         }
     }
 
+    fn process_metrics(&mut self, reset: bool) -> OpResponseType {
+        let metrics = if reset {
+            debug!("Resetting operation metrics");
+            std::mem::take(&mut self.metrics)
+        } else {
+            self.metrics.clone()
+        };
+        OpResponseType::Metrics {
+            metrics: metrics.into_iter().collect(),
+        }
+    }
+
     async fn process_cancel_device_cache(&mut self, device: u8) -> Result<OpResponseType, Error> {
         self.queues.remove_cache_for_device(device).await;
         Ok(OpResponseType::CancelDeviceCache { device })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::init_test_args;
+    use crate::backend::mock::MockBackend;
+    use crate::file::{FileEntryType, RestoreStatus};
+
+    fn cache_read(device: u8, inode: u64, sender: &Arc<Sender<OpResponse>>) -> Operation {
+        Operation::new(
+            OpType::ReadFileCache {
+                device,
+                path: format!("file{inode}"),
+                inode,
+            },
+            sender.clone(),
+            None,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_remove_cache_for_device() {
+        let (tx, rx) = flume::unbounded();
+        let tx = Arc::new(tx);
+        let mut queues = OperationQueues::new();
+
+        // Queue cache reads for two devices, and a regular read for the
+        // device being unmounted
+        queues.push(cache_read(8, 1, &tx));
+        queues.push(cache_read(9, 2, &tx));
+        queues.push(cache_read(8, 3, &tx));
+        queues.push(Operation::new(
+            OpType::ReadFile {
+                device: 8,
+                path: "file4".into(),
+                inode: 4,
+            },
+            tx.clone(),
+            None,
+        ));
+
+        queues.remove_cache_for_device(8).await;
+
+        // Both of device 8's cache reads were cancelled
+        let cancelled: Vec<_> = rx.drain().collect();
+        assert_eq!(cancelled.len(), 2);
+        for rsp in cancelled {
+            assert!(matches!(
+                rsp.rsp,
+                Err(Error::Fs1541 {
+                    error: Fs1541Error::Cancelled(_),
+                    ..
+                })
+            ));
+        }
+
+        // The other operations are still queued
+        assert!(matches!(
+            queues.pop_next().map(|op| op.op_type),
+            Some(OpType::ReadFile { device: 8, .. })
+        ));
+        assert!(matches!(
+            queues.pop_next().map(|op| op.op_type),
+            Some(OpType::ReadFileCache { device: 9, .. })
+        ));
+        assert!(queues.pop_next().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_op_waiter() {
+        let (tx, rx) = flume::unbounded();
+        let tx = Arc::new(tx);
+        let mut queues = OperationQueues::new();
+
+        // The waiter is passed through to the response, even when the
+        // operation never runs
+        let mut op = cache_read(8, 1, &tx);
+        let waiter = op.waiter();
+        queues.push(op);
+        queues.remove_cache_for_device(8).await;
+
+        let (rsp, rsp_waiter) = rx.recv().unwrap().into_parts();
+        assert!(rsp.is_err());
+        assert!(waiter.try_recv().is_err());
+        rsp_waiter.unwrap().send(Ok(CbmStatus::default())).unwrap();
+        assert!(waiter.recv().is_ok());
+
+        // Operations without a waiter don't get one
+        let (_, rsp_waiter) = OpResponse::from(cache_read(8, 2, &tx)).into_parts();
+        assert!(rsp_waiter.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_write_verify_mismatch() {
+        init_test_args();
+        let mut mock = MockBackend::new();
+        mock.corrupt_writes = true;
+        let cbm: Arc<Mutex<dyn CbmBackend>> = Arc::new(Mutex::new(mock));
+        let (tx, rx) = flume::unbounded();
+        let proc = Proc::new(
+            rx,
+            Arc::new(tx),
+            Arc::new(AtomicBool::new(false)),
+            cbm.clone(),
+            Arc::new(Mutex::new(DriveManager::new(cbm))),
+            Arc::new(RwLock::new(HashMap::new())),
+        );
+        let write = |verify: Option<&str>| OpType::WriteFile {
+            device: 8,
+            drive_num: 0,
+            path: "FILE".into(),
+            mode: WriteMode::Open,
+            data: b"data".to_vec(),
+            scratch: None,
+            verify: verify.map(String::from),
+            interleave: None,
+        };
+        let cancelled = AtomicBool::new(false);
+
+        // The corruption goes unnoticed unless the file is verified
+        assert!(proc
+            .execute_operation(write(None), &cancelled)
+            .await
+            .is_ok());
+
+        // When it is, the write fails, and is reported to FUSE as EIO
+        let error = proc
+            .execute_operation(write(Some("FILE")), &cancelled)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            Error::Fs1541 {
+                error: Fs1541Error::Operation(_),
+                ..
+            }
+        ));
+        assert_eq!(error.to_fuse_reply_error(), libc::EIO);
+    }
+
+    #[tokio::test]
+    async fn test_soft_reset() {
+        init_test_args();
+        let mock = Arc::new(Mutex::new(MockBackend::new()));
+        let cbm: Arc<Mutex<dyn CbmBackend>> = mock.clone();
+        let (tx, rx) = flume::unbounded();
+        let proc = Proc::new(
+            rx,
+            Arc::new(tx),
+            Arc::new(AtomicBool::new(false)),
+            cbm.clone(),
+            Arc::new(Mutex::new(DriveManager::new(cbm))),
+            Arc::new(RwLock::new(HashMap::new())),
+        );
+
+        // The reset goes to the backend, which resets the drive's channels
+        // along with its DOS
+        let rsp = proc
+            .execute_operation(OpType::SoftReset { device: 8 }, &AtomicBool::new(false))
+            .await
+            .unwrap();
+        assert!(matches!(rsp, OpResponseType::SoftReset { .. }));
+        assert_eq!(mock.lock().await.commands, vec!["UJ"]);
+    }
+
+    #[tokio::test]
+    async fn test_memory_execute_timeout() {
+        init_test_args();
+        let mock = Arc::new(Mutex::new(MockBackend::new()));
+        let cbm: Arc<Mutex<dyn CbmBackend>> = mock.clone();
+        let (tx, rx) = flume::unbounded();
+        let proc = Proc::new(
+            rx,
+            Arc::new(tx),
+            Arc::new(AtomicBool::new(false)),
+            cbm.clone(),
+            Arc::new(Mutex::new(DriveManager::new(cbm))),
+            Arc::new(RwLock::new(HashMap::new())),
+        );
+        let execute = || OpType::MemoryExecute {
+            device: 8,
+            addr: 0x0500,
+            timeout: Duration::from_millis(100),
+        };
+        let cancelled = AtomicBool::new(false);
+
+        // Code which returns is reported with the drive's status
+        let rsp = proc.execute_operation(execute(), &cancelled).await.unwrap();
+        assert!(matches!(
+            rsp,
+            OpResponseType::MemoryExecute {
+                device: 8,
+                addr: 0x0500,
+                ..
+            }
+        ));
+
+        // Code which doesn't return in time fails with a timeout, rather
+        // than blocking the operation
+        mock.lock().await.execute_time = Duration::from_millis(500);
+        let start = Instant::now();
+        let error = proc
+            .execute_operation(execute(), &cancelled)
+            .await
+            .unwrap_err();
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert!(matches!(
+            error,
+            Error::Fs1541 {
+                error: Fs1541Error::Timeout(..),
+                ..
+            }
+        ));
+        assert_eq!(error.to_fuse_reply_error(), libc::ETIMEDOUT);
+
+        // The lock is held until the code eventually returns
+        assert_eq!(mock.lock().await.commands, vec!["M-E\0\u{5}"; 2]);
+    }
+
+    #[tokio::test]
+    async fn test_dummy_formats() {
+        init_test_args();
+
+        // A device no other test subscribes to
+        let device = 28;
+        let mock = Arc::new(Mutex::new(MockBackend::new()));
+        let cbm: Arc<Mutex<dyn CbmBackend>> = mock.clone();
+        let drive_mgr = Arc::new(Mutex::new(DriveManager::new(cbm.clone())));
+        let drive_unit = drive_mgr.lock().await.add_drive(device).await.unwrap();
+        let (tx, rx) = flume::unbounded();
+        let tx = Arc::new(tx);
+        let proc = Proc::new(
+            rx.clone(),
+            tx.clone(),
+            Arc::new(AtomicBool::new(false)),
+            cbm.clone(),
+            drive_mgr.clone(),
+            Arc::new(RwLock::new(HashMap::new())),
+        );
+        let mut mount = Mount::new(
+            device,
+            "/tmp/dummy_formats",
+            true,
+            true,
+            false,
+            false,
+            None,
+            false,
+            None,
+            cbm,
+            drive_mgr,
+            drive_unit,
+            tx,
+        )
+        .unwrap();
+        mount.mount().await.unwrap();
+
+        // Format the disk, and restore an image to it
+        mount.format_disk_sync("DISK".into(), "01".into()).unwrap();
+        let inode = mount.disk_info()[0]
+            .control_files
+            .iter()
+            .find(|file| {
+                matches!(&file.native, FileEntryType::ControlFile(ctrl) if ctrl.is_image_restore())
+            })
+            .unwrap()
+            .inode();
+        let file = mount.file_by_inode_mut(inode).unwrap();
+        let image = vec![0; D64Geometry::Standard.image_size()];
+        assert_eq!(file.write(0, &image).unwrap(), image.len());
+        assert!(mount.write_file_sync(inode).unwrap().is_none());
+        assert!(matches!(
+            mount.file_by_inode(inode).unwrap().restore,
+            Some((RestoreStatus::Complete, _))
+        ));
+
+        // Run whatever reached the background processor - only the mount's
+        // directory read - and the drive saw no commands
+        let cancelled = AtomicBool::new(false);
+        while let Ok(op) = rx.try_recv() {
+            assert!(matches!(op.op_type, OpType::ReadDirectory { .. }));
+            proc.execute_operation(op.op_type, &cancelled)
+                .await
+                .unwrap();
+        }
+        assert!(mock.lock().await.commands.is_empty());
+
+        // Whereas a real format is sent to the drive
+        let format = OpType::FormatDisk {
+            device,
+            name: "DISK".into(),
+            id: "01".into(),
+            interleave: None,
+        };
+        proc.execute_operation(format, &cancelled).await.unwrap();
+        assert_eq!(mock.lock().await.commands, vec!["N0:DISK,01"]);
+    }
+
+    #[test]
+    fn test_response_status() {
+        let status = CbmStatus::default();
+        let rsp = OpResponseType::WriteFile {
+            device: 8,
+            drive_num: 0,
+            path: "FILE".into(),
+            status: status.clone(),
+            bytes_written: 0,
+            verified: None,
+        };
+        assert_eq!(
+            rsp.status().map(|s| s.to_string()),
+            Some(status.to_string())
+        );
+        assert!(OpResponseType::CancelDeviceCache { device: 8 }
+            .status()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_with_retries() {
+        let policy = RetryPolicy {
+            retries: 2,
+            delay: Duration::from_millis(1),
+        };
+        assert_eq!(policy.backoff(0), Duration::from_millis(1));
+        assert_eq!(policy.backoff(2), Duration::from_millis(4));
+
+        // Transient failures (odd numbers here) are retried until they
+        // succeed, or the retries run out
+        let run = |results: Vec<u8>, policy, timeout| {
+            let mut results = results.into_iter();
+            let mut attempts = 0;
+            async move {
+                let result: Result<u8, ()> = with_retries(
+                    "Test",
+                    policy,
+                    timeout,
+                    || {
+                        attempts += 1;
+                        std::future::ready(Ok(results.next().unwrap()))
+                    },
+                    |n| n % 2 == 1,
+                )
+                .await;
+                (result, attempts)
+            }
+        };
+        let timeout = Duration::from_secs(10);
+        assert_eq!(run(vec![1, 3, 2], policy, timeout).await, (Ok(2), 3));
+        assert_eq!(run(vec![1, 3, 5, 7], policy, timeout).await, (Ok(5), 3));
+        assert_eq!(run(vec![4], policy, timeout).await, (Ok(4), 1));
+
+        // Retries which would exceed the timeout aren't attempted
+        assert_eq!(run(vec![1, 2], policy, Duration::ZERO).await, (Ok(1), 1));
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let (tx, _rx) = flume::unbounded();
+        let tx = Arc::new(tx);
+        let mut queues = OperationQueues::new();
+        let read = |device, inode| {
+            Operation::new(
+                OpType::ReadFile {
+                    device,
+                    path: format!("file{inode}"),
+                    inode,
+                },
+                tx.clone(),
+                None,
+            )
+        };
+
+        // Device 8 has a backlog of reads queued before device 9's
+        for (device, inode) in [(8, 1), (8, 2), (8, 3), (9, 4), (9, 5)] {
+            queues.push(read(device, inode));
+        }
+        queues.push(Operation::new(OpType::BusReset, tx.clone(), None));
+
+        // Critical operations still go first, then the devices take turns,
+        // each in the order their operations were queued
+        assert!(matches!(
+            queues.pop_next().map(|op| op.op_type),
+            Some(OpType::BusReset)
+        ));
+        let order: Vec<_> = std::iter::from_fn(|| queues.pop_next())
+            .map(|op| match op.op_type {
+                OpType::ReadFile { device, inode, .. } => (device, inode),
+                op_type => panic!("Unexpected operation {}", op_type),
+            })
+            .collect();
+        assert_eq!(order, vec![(8, 1), (9, 4), (8, 2), (9, 5), (8, 3)]);
+    }
+}
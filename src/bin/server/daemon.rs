@@ -1,11 +1,12 @@
+use crate::args::get_args;
+use crate::backend::CbmBackend;
 use crate::bg::{OpResponse, Operation, Proc, MAX_BG_CHANNELS};
 use crate::drivemgr::DriveManager;
-use crate::ipc::{IpcServer, MAX_BG_RSP_CHANNELS};
+use crate::ipc::{HealthState, IpcServer, MAX_BG_RSP_CHANNELS};
 use crate::locking_section;
 use crate::mount::Mount;
 
 use fs1541::error::{Error, Fs1541Error};
-use rs1541::Cbm;
 
 use flume::{Receiver, Sender};
 use log::{debug, error, info, trace, warn};
@@ -40,11 +41,15 @@ pub struct Daemon {
     pid: Pid,
 
     // Muted cbm object - which will be shared widely between threads
-    cbm: Arc<Mutex<Cbm>>,
+    cbm: Arc<Mutex<dyn CbmBackend>>,
 
     // DriveManager object to handle drives
     drive_mgr: Arc<Mutex<DriveManager>>,
 
+    // What the IPC server reports for Health requests, shared with the
+    // DriveManager and mountpoints so it doesn't need their locks
+    health: HealthState,
+
     // Mountpoints HashMap.  This is needed by DriveManager (and hence
     // MountService as DriveManager's creator) to check for the existence of
     // an existing mount at a mountpoint
@@ -85,7 +90,7 @@ pub struct Daemon {
 }
 
 impl Daemon {
-    pub fn new(pid: Pid, cbm: Arc<Mutex<Cbm>>) -> Result<Self, Error> {
+    pub fn new(pid: Pid, cbm: Arc<Mutex<dyn CbmBackend>>) -> Result<Self, Error> {
         // Create channels - to send to the BackgroundProcess and for IpcServer
         // to receive back from it
         let (bg_proc_tx, bg_proc_rx) = flume::bounded(MAX_BG_CHANNELS);
@@ -97,12 +102,14 @@ impl Daemon {
 
         // Create DriveManager
         let drive_mgr = DriveManager::new(cbm.clone());
+        let health = HealthState::new(mountpoints.clone(), drive_mgr.bus_state());
         let drive_mgr = Arc::new(Mutex::new(drive_mgr));
 
         let daemon = Self {
             pid,
             cbm,
             drive_mgr,
+            health,
             mountpoints,
             runtime: None,
             bg_proc_tx: Some(Arc::new(bg_proc_tx)),
@@ -145,7 +152,9 @@ impl Daemon {
         // Take the BG rsp RX half to give to IPC
         let bg_rsp_rx = self.bg_rsp_rx.take().unwrap();
 
-        let ipc_server = IpcServer::new(self.pid, bg_proc_tx, bg_rsp_tx);
+        let ipc_server = IpcServer::new(self.pid, bg_proc_tx, bg_rsp_tx, self.health.clone())
+            .with_idle_timeout(Duration::from_secs(get_args().ipc_idle_timeout_secs))
+            .with_max_subscribers(get_args().max_subscribers);
         self.ipc_server = Some(Arc::new(Mutex::new(ipc_server)));
         Ok(bg_rsp_rx)
     }
@@ -252,6 +261,15 @@ impl Daemon {
         }
     }
 
+    /// Identify all drives on the bus, so their types are cached ready for
+    /// mounting
+    pub async fn identify_drives(&self) {
+        let identified = locking_section!("Lock", "Drive Manager", {
+            self.drive_mgr.lock().await.identify_all().await
+        });
+        info!("Identified {} drive(s) on the bus", identified.len());
+    }
+
     pub async fn cleanup_drive_mgr(&self) -> () {
         trace!("Entered cleanup_drive_mgr");
         self.drive_mgr.lock().await.cleanup_drives().await;
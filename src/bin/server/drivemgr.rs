@@ -1,13 +1,137 @@
+use crate::args::get_args;
+use crate::backend::CbmBackend;
+use crate::events;
 use crate::locking_section;
 use fs1541::error::{Error, Fs1541Error};
-use rs1541::{Cbm, CbmDeviceInfo, CbmDriveUnit, CbmErrorNumber, CbmStatus};
+use fs1541::ipc::Event;
+use fs1541::validate::validate_new_device_number;
+use rs1541::{
+    CbmDeviceInfo, CbmDeviceType, CbmDriveUnit, CbmErrorNumber, CbmErrorNumberOk, CbmStatus,
+};
 use rs1541::{DEVICE_MAX_NUM, DEVICE_MIN_NUM};
 
 use log::{debug, error, info, trace, warn};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::{Mutex, RwLock};
 
+/// How long to wait for a drive to answer a status probe before treating it
+/// as not responding
+const DRIVE_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Waits for the configured period after a bus reset, to give the drives time
+/// to become responsive
+pub async fn bus_reset_settle() {
+    let settle = Duration::from_millis(get_args().bus_reset_settle_ms);
+    if !settle.is_zero() {
+        debug!(
+            "Waiting {}ms for bus to settle after reset",
+            settle.as_millis()
+        );
+        tokio::time::sleep(settle).await;
+    }
+}
+
+/// Cache of the results of identifying drives, so mounting an already
+/// identified drive doesn't need another identify round-trip on the bus.
+///
+/// An entry is only used for a single mount.  Once a drive has been mounted
+/// it may be swapped for another type before it is remounted, so a remount
+/// always re-identifies the drive.
+#[derive(Debug, Default)]
+struct IdentityCache {
+    entries: HashMap<u8, CbmDeviceInfo>,
+    mounted: HashSet<u8>,
+}
+
+impl IdentityCache {
+    fn get(&self, device_number: u8) -> Option<CbmDeviceInfo> {
+        self.entries.get(&device_number).cloned()
+    }
+
+    /// Stores a freshly identified drive, returning the previous entry if
+    /// there was one
+    fn insert(&mut self, device_number: u8, info: CbmDeviceInfo) -> Option<CbmDeviceInfo> {
+        self.mounted.remove(&device_number);
+        self.entries.insert(device_number, info)
+    }
+
+    /// Returns the cached entry for a mount, if there is one which hasn't
+    /// already been used by a previous mount
+    fn get_unmounted(&self, device_number: u8) -> Option<CbmDeviceInfo> {
+        if self.mounted.contains(&device_number) {
+            None
+        } else {
+            self.get(device_number)
+        }
+    }
+
+    fn set_mounted(&mut self, device_number: u8) {
+        self.mounted.insert(device_number);
+    }
+
+    /// Returns whether there was an entry to invalidate
+    fn invalidate(&mut self, device_number: u8) -> bool {
+        self.mounted.remove(&device_number);
+        self.entries.remove(&device_number).is_some()
+    }
+}
+
+/// Whether a drive now reports a different type to when it was last
+/// identified
+fn drive_type_changed(previous: &CbmDeviceInfo, current: &CbmDeviceInfo) -> bool {
+    previous.device_type.as_str() != current.device_type.as_str()
+}
+
+/// Whether the drive's DOS supports changing its device number with the U0>
+/// command.  Other drives can only be renumbered using their jumpers or DIP
+/// switches.
+fn device_number_settable(device_type: &CbmDeviceType) -> bool {
+    matches!(
+        device_type,
+        CbmDeviceType::Cbm1541 | CbmDeviceType::Cbm1571 | CbmDeviceType::Cbm1581
+    )
+}
+
+/// The state of the bus and the xum1541 driving it.  This is shared outside
+/// the DriveManager, so it can be reported without taking the Drive Manager
+/// lock, which is held for as long as a bus operation takes.
+#[derive(Debug)]
+pub struct BusState {
+    last_reset: RwLock<Option<Instant>>,
+    xum1541_open: AtomicBool,
+}
+
+impl BusState {
+    pub fn new() -> Self {
+        Self {
+            // Initializing the xum1541, which happens just before we're
+            // created, resets the bus
+            last_reset: RwLock::new(Some(Instant::now())),
+            xum1541_open: AtomicBool::new(true),
+        }
+    }
+
+    /// When the bus was last reset, if ever
+    pub async fn last_reset(&self) -> Option<Instant> {
+        locking_section!("Read", "Last Bus Reset", { *self.last_reset.read().await })
+    }
+
+    async fn set_reset(&self) {
+        locking_section!("Write", "Last Bus Reset", {
+            *self.last_reset.write().await = Some(Instant::now());
+        });
+    }
+
+    /// Whether the xum1541's handle is open.  It is closed while the xum1541
+    /// is reset over USB, and stays closed if it couldn't be reopened.
+    pub fn xum1541_open(&self) -> bool {
+        self.xum1541_open.load(Ordering::Relaxed)
+    }
+}
+
 /// DriveManager is used by bg::Proc to access the disk drives.
 ///
 /// Drives (CbmDriveUnit) are Hashed using device number, as this is
@@ -15,19 +139,45 @@ use tokio::sync::{Mutex, RwLock};
 /// there may be reads to identify the drive, or whether its busy.
 #[derive(Debug)]
 pub struct DriveManager {
-    cbm: Arc<Mutex<Cbm>>,
+    cbm: Arc<Mutex<dyn CbmBackend>>,
     drives: RwLock<HashMap<u8, Arc<RwLock<CbmDriveUnit>>>>,
+    identities: RwLock<IdentityCache>,
+    bus_state: Arc<BusState>,
+    /// Backends serving drives from disk images, in place of the bus, by
+    /// the device number they were added as
+    images: RwLock<HashMap<u8, Arc<Mutex<dyn CbmBackend>>>>,
 }
 
 impl DriveManager {
-    pub fn new(cbm: Arc<Mutex<Cbm>>) -> Self {
+    pub fn new(cbm: Arc<Mutex<dyn CbmBackend>>) -> Self {
         debug!("Initializing new DriveManager");
         Self {
             cbm,
             drives: RwLock::new(HashMap::new()),
+            identities: RwLock::new(IdentityCache::default()),
+            bus_state: Arc::new(BusState::new()),
+            images: RwLock::new(HashMap::new()),
         }
     }
 
+    /// The state of the bus, to share with those reporting it
+    pub fn bus_state(&self) -> Arc<BusState> {
+        self.bus_state.clone()
+    }
+
+    /// The backend serving the device - its disk image, if it was added
+    /// from one, and otherwise the bus
+    pub async fn backend(&self, device_number: u8) -> Arc<Mutex<dyn CbmBackend>> {
+        locking_section!("Read", "Images", {
+            self.images
+                .read()
+                .await
+                .get(&device_number)
+                .cloned()
+                .unwrap_or_else(|| self.cbm.clone())
+        })
+    }
+
     /// Add a new drive to the manager
     pub async fn add_drive(&self, device_number: u8) -> Result<Arc<RwLock<CbmDriveUnit>>, Error> {
         info!("Adding drive with device number {}", device_number);
@@ -50,15 +200,75 @@ impl DriveManager {
         // Create the drive unit.  We do this now even though it might already
         // exist to simplify processing - if it does exist we'll drop this
         // instance when it goes out of scope
-        let drive_unit = locking_section!("Lock", "Cbm", {
-            let cbm = self.cbm.lock().await;
-            CbmDriveUnit::try_from_bus(&cbm, device_number).map_err(|e| Error::Rs1541 {
-                message: format!("Failed to create drive {}", device_number),
-                error: e,
-            })?
+        // If we've identified this drive since it was last mounted, use the
+        // cached type rather than identifying it again
+        let (cached, previous) = locking_section!("Read", "Identities", {
+            let identities = self.identities.read().await;
+            (
+                identities.get_unmounted(device_number),
+                identities.get(device_number),
+            )
         });
+        let info = match cached {
+            Some(info) => {
+                debug!(
+                    "Using cached type {} for drive {}",
+                    info.device_type.as_str(),
+                    device_number
+                );
+                info
+            }
+            None => {
+                let info = self.identify_drive(device_number).await?;
+                if let Some(previous) = previous {
+                    if drive_type_changed(&previous, &info) {
+                        warn!(
+                            "Drive {} type has changed from {} to {}",
+                            device_number,
+                            previous.device_type.as_str(),
+                            info.device_type.as_str()
+                        );
+                    }
+                }
+                info
+            }
+        };
+        let drive_unit = CbmDriveUnit::new(device_number, info.device_type);
+        locking_section!("Write", "Identities", {
+            self.identities.write().await.set_mounted(device_number);
+        });
+
+        self.insert_drive(device_number, drive_unit).await
+    }
+
+    /// Add a new drive served from a disk image rather than the bus.  Until
+    /// the drive is removed, operations on the device go to the image.
+    pub async fn add_image_drive(
+        &self,
+        device_number: u8,
+        image: Arc<Mutex<dyn CbmBackend>>,
+    ) -> Result<Arc<RwLock<CbmDriveUnit>>, Error> {
+        info!("Adding drive with device number {device_number} from a disk image");
 
-        // Insert the drive unit into the hashmap
+        // The image isn't a drive on the bus, so isn't cached with them
+        let info = locking_section!("Lock", "Image", {
+            image.lock().await.identify(device_number)?
+        });
+        let drive_unit = CbmDriveUnit::new(device_number, info.device_type);
+        let drive_unit = self.insert_drive(device_number, drive_unit).await?;
+        locking_section!("Write", "Images", {
+            self.images.write().await.insert(device_number, image);
+        });
+        Ok(drive_unit)
+    }
+
+    /// Insert a drive unit into the hashmap, failing if there's already one
+    /// for the device
+    async fn insert_drive(
+        &self,
+        device_number: u8,
+        drive_unit: CbmDriveUnit,
+    ) -> Result<Arc<RwLock<CbmDriveUnit>>, Error> {
         locking_section!("Write", "Drives", {
             let mut drives = self.drives.write().await;
             let shared_drive_unit = Arc::new(RwLock::new(drive_unit));
@@ -111,6 +321,9 @@ impl DriveManager {
         locking_section!("Write", "Drives", {
             self.drives.write().await.remove(&device_number)
         });
+        locking_section!("Write", "Images", {
+            self.images.write().await.remove(&device_number)
+        });
         info!("Successfully removed drive {}", device_number);
         Ok(())
     }
@@ -136,37 +349,172 @@ impl DriveManager {
         })
     }
 
+    /// Get a reference to a drive, or if it isn't managed (because it isn't
+    /// mounted) a standalone drive unit for one-off operations on it, which
+    /// isn't added to the manager
+    pub async fn get_or_identify_drive(
+        &self,
+        device_number: u8,
+    ) -> Result<Arc<RwLock<CbmDriveUnit>>, Error> {
+        if let Ok(drive) = self.get_drive(device_number).await {
+            return Ok(drive);
+        }
+
+        let cached = locking_section!("Read", "Identities", {
+            self.identities.read().await.get(device_number)
+        });
+        let info = match cached {
+            Some(info) => info,
+            None => self.identify_drive(device_number).await?,
+        };
+        debug!(
+            "Using unmanaged drive unit for drive {} type {}",
+            device_number,
+            info.device_type.as_str()
+        );
+        Ok(Arc::new(RwLock::new(CbmDriveUnit::new(
+            device_number,
+            info.device_type,
+        ))))
+    }
+
+    /// Identify a drive, caching the result for use by subsequent mounts
     pub async fn identify_drive(&self, device_number: u8) -> Result<CbmDeviceInfo, Error> {
-        locking_section!("Lock", "Cbm", {
-            let guard = self.cbm.lock().await;
-            guard
-                .identify(device_number)
-                .inspect(|info| {
-                    debug!(
-                        "Identify completed successfully {} {}",
-                        info.device_type.as_str(),
-                        info.description
-                    )
-                })
-                .map_err(|e| Error::Rs1541 {
-                    message: format!("Failed to identify drive {}", device_number),
-                    error: e,
-                })
-        })
+        let backend = self.backend(device_number).await;
+        let info = locking_section!("Lock", "Cbm", {
+            let mut guard = backend.lock().await;
+            guard.identify(device_number).inspect(|info| {
+                debug!(
+                    "Identify completed successfully {} {}",
+                    info.device_type.as_str(),
+                    info.description
+                )
+            })?
+        });
+
+        locking_section!("Write", "Identities", {
+            self.identities
+                .write()
+                .await
+                .insert(device_number, info.clone());
+        });
+
+        Ok(info)
+    }
+
+    /// Identify all drives on the bus, returning (and caching) the info for
+    /// those which responded
+    pub async fn identify_all(&self) -> HashMap<u8, CbmDeviceInfo> {
+        self.scan_bus()
+            .await
+            .into_iter()
+            .filter_map(|(device_number, info)| info.map(|info| (device_number, info)))
+            .collect()
+    }
+
+    /// Try to identify every device number on the bus, returning the info
+    /// for those which responded and None for the rest.  Absent devices are
+    /// expected to time out, so a failure doesn't stop the scan.
+    pub async fn scan_bus(&self) -> Vec<(u8, Option<CbmDeviceInfo>)> {
+        let mut devices = Vec::new();
+        for device_number in DEVICE_MIN_NUM..=DEVICE_MAX_NUM {
+            let info = match self.identify_drive(device_number).await {
+                Ok(info) => {
+                    info!(
+                        "Found drive {} type {}",
+                        device_number,
+                        info.device_type.as_str()
+                    );
+                    Some(info)
+                }
+                Err(e) => {
+                    trace!("No drive identified at {}: {}", device_number, e);
+                    None
+                }
+            };
+            devices.push((device_number, info));
+        }
+        devices
+    }
+
+    /// Forget the cached type for a drive, for example because a mount of it
+    /// failed, which may mean the drive has been swapped for another type
+    pub async fn invalidate_identity(&self, device_number: u8) {
+        locking_section!("Write", "Identities", {
+            if self.identities.write().await.invalidate(device_number) {
+                debug!("Invalidated cached type for drive {}", device_number);
+            }
+        });
+    }
+
+    /// Change a drive's device number using the U0> command, returning the
+    /// status the drive reports at its new number.  The drive only keeps the
+    /// new number until it is reset or powered off - to change it
+    /// permanently set the drive's jumpers or DIP switches instead.  Neither
+    /// number may be in use by a mount, and the drive's cached identity is
+    /// moved to the new number.
+    pub async fn set_device_number(
+        &self,
+        device_number: u8,
+        new_device_number: u8,
+    ) -> Result<CbmStatus, Error> {
+        validate_new_device_number(device_number, new_device_number)?;
+        for device in [device_number, new_device_number] {
+            if self.drive_exists(device).await {
+                return Err(Error::Fs1541 {
+                    message: "Device is mounted".into(),
+                    error: Fs1541Error::Validation(format!(
+                        "Device {} is mounted - unmount it first",
+                        device
+                    )),
+                });
+            }
+        }
+
+        let cached = locking_section!("Read", "Identities", {
+            self.identities.read().await.get(device_number)
+        });
+        let info = match cached {
+            Some(info) => info,
+            None => self.identify_drive(device_number).await?,
+        };
+        if !device_number_settable(&info.device_type) {
+            return Err(Error::Fs1541 {
+                message: "Changing device number not supported".into(),
+                error: Fs1541Error::Validation(format!(
+                    "Device {} is a {}, which can only be renumbered using its jumpers or switches",
+                    device_number,
+                    info.device_type.as_str()
+                )),
+            });
+        }
+
+        let backend = self.backend(device_number).await;
+        let status = locking_section!("Lock", "Cbm", {
+            let mut cbm = backend.lock().await;
+            cbm.send_command(device_number, &[b'U', b'0', b'>', new_device_number])
+                .and_then(|_| cbm.get_status(new_device_number))?
+        });
+
+        if status.is_ok() == CbmErrorNumberOk::Ok {
+            info!("Device {device_number} is now device {new_device_number}");
+            locking_section!("Write", "Identities", {
+                let mut identities = self.identities.write().await;
+                identities.invalidate(device_number);
+                identities.insert(new_device_number, info);
+            });
+        }
+
+        Ok(status)
     }
 
     pub async fn get_drive_status(&self, device_number: u8) -> Result<CbmStatus, Error> {
+        let backend = self.backend(device_number).await;
         locking_section!("Lock", "Cbm", {
-            let guard = self.cbm.lock().await;
-            guard
-                .get_status(device_number)
-                .inspect(|status| {
-                    debug!("Status retrieved for device {} {}", device_number, status)
-                })
-                .map_err(|e| Error::Rs1541 {
-                    message: format!("Failed to get status for drive {}", device_number),
-                    error: e,
-                })
+            let mut guard = backend.lock().await;
+            guard.get_status(device_number).inspect(|status| {
+                debug!("Status retrieved for device {} {}", device_number, status)
+            })
         })
     }
 
@@ -175,78 +523,146 @@ impl DriveManager {
         device_number: u8,
         ignore: &Vec<CbmErrorNumber>,
     ) -> Result<Vec<Result<CbmStatus, Error>>, Error> {
+        let backend = self.backend(device_number).await;
         locking_section!("Lock", "Cbm and Drive Manager", {
-            let mut cbm = self.cbm.lock().await.clone();
+            // Use the shared backend under its lock, rather than a clone, so
+            // nothing else uses the bus during the init
+            let mut cbm = backend.lock().await;
             let drive = self.get_drive(device_number).await?;
             locking_section!("Write", "Drive", {
                 let mut drive = drive.write().await;
-                let results = drive.send_init(&mut cbm, &ignore);
-
-                Ok(results
-                    .into_iter()
-                    .map(|r| {
-                        r.map_err(|e| Error::Rs1541 {
-                            message: format!("Failed to initialize drive {}", device_number),
-                            error: e,
-                        })
-                    })
-                    .collect())
+                Ok(cbm.init(&mut drive, ignore))
             })
         })
     }
 
-    /// Reset the entire bus
+    /// Reset the entire bus, then wait for the drives to settle before
+    /// allowing any other bus operations
     pub async fn reset_bus(&self) -> Result<(), Error> {
         info!("Initiating bus reset");
         locking_section!("Lock", "Cbm", {
-            let cbm = self.cbm.lock().await.clone();
-            cbm.reset_bus().map_err(|e| Error::Rs1541 {
-                message: "Failed to reset bus".to_string(),
-                error: e,
-            })?;
+            self.cbm.lock().await.reset()?;
+
+            // Hold the Cbm lock while settling, so nothing else uses the bus
+            bus_reset_settle().await;
         });
 
+        self.bus_state.set_reset().await;
+
         info!("Bus reset completed successfully");
         Ok(())
     }
 
+    /// Resets the xum1541 over USB, for when it has stopped responding.  The
+    /// Cbm handle is reopened inside the shared Cbm, so mounts holding it
+    /// carry on working, although their drives have been reset along with
+    /// the bus.  Subscribers to each drive on the bus are told it has
+    /// reconnected.
+    pub async fn usb_reset(&self) -> Result<(), Error> {
+        info!("Initiating USB reset");
+        locking_section!("Lock", "Cbm", {
+            let reset = self.cbm.lock().await.usb_reset();
+            self.bus_state
+                .xum1541_open
+                .store(reset.is_ok(), Ordering::Relaxed);
+            reset?;
+
+            // Reopening the xum1541 resets the bus
+            bus_reset_settle().await;
+        });
+
+        self.bus_state.set_reset().await;
+
+        let images = locking_section!("Read", "Images", {
+            self.images
+                .read()
+                .await
+                .keys()
+                .copied()
+                .collect::<HashSet<_>>()
+        });
+        let devices = locking_section!("Read", "Drives", {
+            self.drives
+                .read()
+                .await
+                .keys()
+                .copied()
+                .filter(|device| !images.contains(device))
+                .collect::<Vec<_>>()
+        });
+        for device in devices {
+            events::publish(Event::Reconnected { device });
+        }
+
+        info!("USB reset completed successfully");
+        Ok(())
+    }
+
+    /// Probes the drive by reading its status, returning whether it answered
+    /// within DRIVE_PROBE_TIMEOUT.  This touches the bus, so is for explicit
+    /// checks such as before mounting, and not for hot paths.
+    ///
+    /// The probe runs on a blocking thread so it can be timed out.  If it
+    /// does time out, the Cbm lock is held until the probe gives up.
+    pub async fn is_responding(&self, device_number: u8) -> bool {
+        let cbm = self.backend(device_number).await;
+        let probe = tokio::task::spawn_blocking(move || {
+            locking_section!("Lock", "Cbm", {
+                cbm.blocking_lock().get_status(device_number)
+            })
+        });
+
+        match tokio::time::timeout(DRIVE_PROBE_TIMEOUT, probe).await {
+            Ok(Ok(Ok(status))) => {
+                debug!("Drive {} responded to probe: {}", device_number, status);
+                true
+            }
+            Ok(Ok(Err(e))) => {
+                debug!("Drive {} failed probe: {}", device_number, e);
+                false
+            }
+            Ok(Err(e)) => {
+                warn!("Probe of drive {} failed to run: {}", device_number, e);
+                false
+            }
+            Err(_) => {
+                debug!(
+                    "Drive {} didn't respond to probe within {:?}",
+                    device_number, DRIVE_PROBE_TIMEOUT
+                );
+                false
+            }
+        }
+    }
+
     /// Check if a drive exists and is responding
-    #[allow(dead_code)]
     pub async fn validate_drive(&self, device_number: u8) -> Result<(), Error> {
         debug!("Validating drive {}", device_number);
 
-        let drive = locking_section!("Read", "Drives", {
-            let drives = self.drives.read().await;
-            match drives.get(&device_number) {
-                Some(drive) => drive,
-                None => {
-                    debug!("Drive {} not found during validation", device_number);
-                    return Err(Error::Fs1541 {
-                        message: format!("Drive {} not found", device_number),
-                        error: Fs1541Error::Validation("Drive does not exist".to_string()),
-                    });
-                }
-            }
-            .clone()
-        });
+        if !self.drive_exists(device_number).await {
+            debug!("Drive {} not found during validation", device_number);
+            return Err(Error::Fs1541 {
+                message: format!("Drive {} not found", device_number),
+                error: Fs1541Error::Validation("Drive does not exist".to_string()),
+            });
+        }
 
-        locking_section!("Read", "Drive", {
-            let drive = drive.read().await;
-            if !drive.is_responding() {
-                warn!("Drive {} is not responding", device_number);
-                return Err(Error::Fs1541 {
-                    message: format!("Drive {} is not responding", device_number),
-                    error: Fs1541Error::Operation("Drive not responding".to_string()),
-                });
-            }
-        });
+        if !self.is_responding(device_number).await {
+            warn!("Drive {} is not responding", device_number);
+            return Err(Error::Fs1541 {
+                message: format!("Drive {} is not responding", device_number),
+                error: Fs1541Error::Operation(format!(
+                    "No response from device {} - check it is switched on and connected to the bus",
+                    device_number
+                )),
+            });
+        }
 
         debug!("Drive {} validated successfully", device_number);
         Ok(())
     }
 
     // Rest of the implementation remains unchanged as it doesn't involve error handling
-    #[allow(dead_code)]
     pub async fn drive_exists(&self, device_number: u8) -> bool {
         trace!("Checking existence of drive {}", device_number);
         let exists = locking_section!("Read", "Drives", {
@@ -311,3 +727,140 @@ impl DriveManager {
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::init_test_args;
+    use crate::backend::mock::MockBackend;
+    use std::sync::atomic::AtomicBool;
+
+    #[test]
+    fn test_device_number_settable() {
+        assert!(device_number_settable(&CbmDeviceType::Cbm1541));
+        assert!(device_number_settable(&CbmDeviceType::Cbm1581));
+        assert!(!device_number_settable(&CbmDeviceType::Cbm8250));
+    }
+
+    #[test]
+    fn test_identity_cache() {
+        let mut cache = IdentityCache::default();
+        assert!(cache.get(8).is_none());
+
+        let info = CbmDeviceInfo::default();
+        cache.insert(8, info.clone());
+        assert_eq!(cache.get(8).unwrap().description, info.description);
+        assert!(cache.get(9).is_none());
+
+        // Invalidating removes only that device's entry
+        cache.insert(9, info);
+        assert!(cache.invalidate(8));
+        assert!(cache.get(8).is_none());
+        assert!(cache.get(9).is_some());
+
+        // Invalidating again is harmless
+        assert!(!cache.invalidate(8));
+    }
+
+    #[test]
+    fn test_identity_cache_remount() {
+        let mut cache = IdentityCache::default();
+        let mut info = CbmDeviceInfo::default();
+        info.device_type = CbmDeviceType::Cbm1541;
+        assert!(cache.insert(8, info.clone()).is_none());
+
+        // First mount uses the cached entry, a remount doesn't
+        assert!(cache.get_unmounted(8).is_some());
+        cache.set_mounted(8);
+        assert!(cache.get_unmounted(8).is_none());
+
+        // Re-identifying finds a different type of drive
+        let mut new_info = CbmDeviceInfo::default();
+        new_info.device_type = CbmDeviceType::Cbm8250;
+        let previous = cache.insert(8, new_info.clone()).unwrap();
+        assert!(drive_type_changed(&previous, &new_info));
+        assert!(!drive_type_changed(&info, &info));
+
+        // And the new type, with its geometry, is what gets used
+        let cached = cache.get_unmounted(8).unwrap();
+        assert_eq!(cached.device_type.as_str(), new_info.device_type.as_str());
+        assert_eq!(
+            cached.device_type.num_disk_drives(),
+            CbmDeviceType::Cbm8250.num_disk_drives()
+        );
+        assert_ne!(
+            cached.device_type.num_disk_drives(),
+            info.device_type.num_disk_drives()
+        );
+    }
+
+    async fn read_file(
+        cbm: &Arc<Mutex<dyn CbmBackend>>,
+        drive_unit: &Arc<RwLock<CbmDriveUnit>>,
+        filename: &str,
+    ) -> Result<Vec<u8>, Error> {
+        let drive_unit = drive_unit.read().await;
+        cbm.lock()
+            .await
+            .read_file(&drive_unit, filename, &AtomicBool::new(false))
+            .map(|(data, _status)| data)
+    }
+
+    #[tokio::test]
+    async fn test_usb_reset_mid_session() {
+        init_test_args();
+
+        // A device no other test subscribes to
+        let device = 29;
+        let mut mock = MockBackend::new();
+        mock.files.insert("hello".into(), b"world".to_vec());
+        let mock = Arc::new(Mutex::new(mock));
+        let drive_mgr = DriveManager::new(mock.clone());
+        let drive_unit = drive_mgr.add_drive(device).await.unwrap();
+        let events = events::subscribe(device, usize::MAX).unwrap();
+
+        // A mount holds its own clone of the shared backend
+        let mount_cbm = drive_mgr.backend(device).await;
+        assert_eq!(
+            read_file(&mount_cbm, &drive_unit, "hello").await.unwrap(),
+            b"world"
+        );
+
+        // The xum1541 stops responding mid-session
+        mock.lock().await.disconnected = true;
+        assert!(read_file(&mount_cbm, &drive_unit, "hello").await.is_err());
+
+        // After the reset the mount's clone reads successfully again
+        drive_mgr.usb_reset().await.unwrap();
+        assert_eq!(mock.lock().await.usb_resets, 1);
+        assert_eq!(
+            read_file(&mount_cbm, &drive_unit, "hello").await.unwrap(),
+            b"world"
+        );
+        assert!(drive_mgr.bus_state().last_reset().await.is_some());
+        assert!(drive_mgr.bus_state().xum1541_open());
+
+        // And subscribers are told about it
+        match events.try_recv() {
+            Ok(Event::Reconnected {
+                device: event_device,
+            }) => assert_eq!(event_device, device),
+            event => panic!("Expected Reconnected event, got {:?}", event),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_usb_reset_failed() {
+        init_test_args();
+        let mut mock = MockBackend::new();
+        mock.unplugged = true;
+        let drive_mgr = DriveManager::new(Arc::new(Mutex::new(mock)));
+        let bus_state = drive_mgr.bus_state();
+        assert!(bus_state.xum1541_open());
+
+        // The handle can't be reopened, which is visible without the Drive
+        // Manager
+        assert!(drive_mgr.usb_reset().await.is_err());
+        assert!(!bus_state.xum1541_open());
+    }
+}
@@ -0,0 +1,129 @@
+use fs1541::error::{Error, Fs1541Error};
+use fs1541::ipc::Event;
+
+use flume::{Receiver, Sender};
+use log::{debug, trace};
+
+/// Number of events queued for a subscriber before further events for it are
+/// dropped
+const SUBSCRIBER_QUEUE_LEN: usize = 32;
+
+/// The clients subscribed to events from the mounts.  Mounts publish events
+/// from both fuser threads and the tokio runtime, so this is a global rather
+/// than being passed to each of them.
+static SUBSCRIBERS: parking_lot::Mutex<Subscribers> = parking_lot::const_mutex(Subscribers::new());
+
+/// Subscribes to events for the device, returning the Receiver they will be
+/// sent to.  Dropping the Receiver unsubscribes.
+pub fn subscribe(device: u8, max_subscribers: usize) -> Result<Receiver<Event>, Error> {
+    SUBSCRIBERS.lock().subscribe(device, max_subscribers)
+}
+
+/// Sends the event to every client subscribed to its device
+pub fn publish(event: Event) {
+    SUBSCRIBERS.lock().publish(event)
+}
+
+/// Removes any subscribers which have gone away
+pub fn prune() {
+    SUBSCRIBERS.lock().prune()
+}
+
+#[derive(Debug)]
+struct Subscriber {
+    device: u8,
+    tx: Sender<Event>,
+}
+
+#[derive(Debug)]
+struct Subscribers {
+    subscribers: Vec<Subscriber>,
+}
+
+impl Subscribers {
+    const fn new() -> Self {
+        Self {
+            subscribers: Vec::new(),
+        }
+    }
+
+    fn subscribe(&mut self, device: u8, max_subscribers: usize) -> Result<Receiver<Event>, Error> {
+        self.prune();
+        if self.subscribers.len() >= max_subscribers {
+            return Err(Error::Fs1541 {
+                message: "Too many subscribers".into(),
+                error: Fs1541Error::Operation(format!(
+                    "Maximum of {} subscribers already connected",
+                    max_subscribers
+                )),
+            });
+        }
+
+        let (tx, rx) = flume::bounded(SUBSCRIBER_QUEUE_LEN);
+        self.subscribers.push(Subscriber { device, tx });
+        debug!(
+            "Added subscriber for device {}, now {} subscribers",
+            device,
+            self.subscribers.len()
+        );
+        Ok(rx)
+    }
+
+    fn publish(&mut self, event: Event) {
+        self.prune();
+        trace!("Publishing event: {}", event);
+        for subscriber in self
+            .subscribers
+            .iter()
+            .filter(|subscriber| subscriber.device == event.device())
+        {
+            // A subscriber which isn't keeping up misses events, rather
+            // than holding up the mount
+            if subscriber.tx.try_send(event.clone()).is_err() {
+                debug!("Dropped event for subscriber: {}", event);
+            }
+        }
+    }
+
+    fn prune(&mut self) {
+        self.subscribers
+            .retain(|subscriber| !subscriber.tx.is_disconnected());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subscribers() {
+        let mut subscribers = Subscribers::new();
+        let rx8 = subscribers.subscribe(8, 2).unwrap();
+        let rx9 = subscribers.subscribe(9, 2).unwrap();
+        assert!(subscribers.subscribe(10, 2).is_err());
+
+        // Events only go to the device's subscribers
+        let event = Event::Mounted {
+            device: 8,
+            mountpoint: "/mnt/8".into(),
+        };
+        subscribers.publish(event.clone());
+        assert_eq!(rx8.try_recv().unwrap(), event);
+        assert!(rx9.try_recv().is_err());
+
+        // Dropping the receiver unsubscribes, making room for another
+        drop(rx9);
+        let rx10 = subscribers.subscribe(10, 2).unwrap();
+        assert_eq!(subscribers.subscribers.len(), 2);
+
+        // Events for a slow subscriber are dropped once its queue is full
+        for _ in 0..(SUBSCRIBER_QUEUE_LEN + 1) {
+            subscribers.publish(Event::DiskChanged {
+                device: 10,
+                name: "disk".into(),
+                id: "ab".into(),
+            });
+        }
+        assert_eq!(rx10.len(), SUBSCRIBER_QUEUE_LEN);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::mount::Mount;
 use crate::{Error, Fs1541Error};
 use rs1541::{CbmDirListing, CbmDiskHeader, CbmFileEntry, CbmFileType, CbmStatus, DosVersion};
 
@@ -5,11 +6,195 @@ use chrono::{DateTime, Local};
 use fuser::{FileAttr, FileType, FUSE_ROOT_ID};
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
+use serde::Serialize;
 use std::fmt::{self, Display};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 use strum::IntoEnumIterator;
 use strum_macros::EnumIter;
 
+/// Number of bytes of file data stored in each block of a Commodore file (the
+/// other two bytes of the 256 byte sector link to the next block)
+pub const CBM_BLOCK_DATA_BYTES: usize = 254;
+
+/// Size of each block (sector) on a disk
+pub const D64_BLOCK_SIZE: usize = 256;
+
+/// Number of tracks on a standard (35 track) 1541 disk
+pub const D64_TRACKS: u8 = 35;
+
+/// Number of tracks on an extended (40 track) 1541 disk.  The 1541 and
+/// 1541-II can step to these, but DOS doesn't format or use them.
+pub const D64_TRACKS_EXTENDED: u8 = 40;
+
+/// Returns the number of sectors on a 1541 track (numbered from 1).  Outer
+/// tracks are longer, so have more sectors.
+pub fn d64_sectors_per_track(track: u8) -> u8 {
+    match track {
+        1..=17 => 21,
+        18..=24 => 19,
+        25..=30 => 18,
+        _ => 17,
+    }
+}
+
+/// Returns the offset of a block within a .d64 image
+pub fn d64_offset(track: u8, sector: u8) -> usize {
+    let preceding: usize = (1..track).map(|t| d64_sectors_per_track(t) as usize).sum();
+    (preceding + sector as usize) * D64_BLOCK_SIZE
+}
+
+/// Track layout of a 1541 disk, used to bound block operations and size
+/// .d64 images
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum D64Geometry {
+    /// The 35 tracks formatted by DOS
+    Standard,
+    /// 40 tracks, as used by some copy protected and extended DOS disks
+    Extended,
+}
+
+impl D64Geometry {
+    pub fn tracks(&self) -> u8 {
+        match self {
+            D64Geometry::Standard => D64_TRACKS,
+            D64Geometry::Extended => D64_TRACKS_EXTENDED,
+        }
+    }
+
+    /// Returns the number of sectors on the track, or None if this geometry
+    /// doesn't have the track
+    pub fn sectors_per_track(&self, track: u8) -> Option<u8> {
+        (1..=self.tracks())
+            .contains(&track)
+            .then(|| d64_sectors_per_track(track))
+    }
+
+    /// Total number of blocks on the disk
+    pub fn blocks(&self) -> usize {
+        (1..=self.tracks())
+            .map(|track| d64_sectors_per_track(track) as usize)
+            .sum()
+    }
+
+    /// Size of a .d64 image of the disk, without error information
+    pub fn image_size(&self) -> usize {
+        self.blocks() * D64_BLOCK_SIZE
+    }
+
+    /// Checks the block exists in this geometry, so nothing is read from or
+    /// written to a track the disk doesn't have
+    pub fn validate_block(&self, track: u8, sector: u8) -> Result<(), Error> {
+        match self.sectors_per_track(track) {
+            Some(sectors) if sector < sectors => Ok(()),
+            _ => Err(Error::Fs1541 {
+                message: "Invalid block".into(),
+                error: Fs1541Error::Validation(format!(
+                    "Track {} sector {} isn't on a {} track disk",
+                    track,
+                    sector,
+                    self.tracks()
+                )),
+            }),
+        }
+    }
+}
+
+impl std::fmt::Display for D64Geometry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} track", self.tracks())
+    }
+}
+
+/// Returns the geometry of a .d64 image of the given size.  Images may have
+/// an error byte appended per block.
+pub fn d64_image_geometry(len: usize) -> Result<D64Geometry, Error> {
+    [D64Geometry::Standard, D64Geometry::Extended]
+        .into_iter()
+        .find(|geometry| {
+            len == geometry.image_size() || len == geometry.image_size() + geometry.blocks()
+        })
+        .ok_or_else(|| Error::Fs1541 {
+            message: "Invalid disk image".into(),
+            error: Fs1541Error::Validation(format!(
+                "Disk image size {} isn't a valid .d64 size",
+                len
+            )),
+        })
+}
+
+/// Track and sector of a 1541 disk's BAM, which holds the disk's header name
+/// and ID
+pub const D64_BAM_TRACK: u8 = 18;
+pub const D64_BAM_SECTOR: u8 = 0;
+
+/// Returns the header name and ID from a .d64 image's BAM, as ASCII, for use
+/// when formatting a disk to restore the image to
+pub fn d64_header(image: &[u8]) -> (String, String) {
+    let bam = d64_offset(D64_BAM_TRACK, D64_BAM_SECTOR);
+    bam_header(&image[bam..bam + D64_BLOCK_SIZE])
+}
+
+/// Returns the header name and ID from a 1541 disk's BAM block, as ASCII
+pub fn bam_header(bam: &[u8]) -> (String, String) {
+    (
+        padded_petscii_to_ascii(&bam[0x90..0xa0]),
+        padded_petscii_to_ascii(&bam[0xa2..0xa4]),
+    )
+}
+
+/// Converts a name stored on disk, padded with shifted spaces (0xa0), to
+/// ASCII.  Characters which can't be converted become '?'.
+pub fn padded_petscii_to_ascii(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .take_while(|b| **b != 0xa0)
+        .map(|b| match b {
+            0x20..=0x5f => (*b as char).to_ascii_lowercase(),
+            _ => '?',
+        })
+        .collect()
+}
+
+/// Converts an ASCII filename to the PETSCII the drive expects, reversing
+/// [`padded_petscii_to_ascii`] - lowercase letters become PETSCII's unshifted
+/// letters, and everything else is sent as is
+pub fn ascii_to_petscii(name: &str) -> Vec<u8> {
+    name.bytes().map(|b| b.to_ascii_uppercase()).collect()
+}
+
+/// Checks whether a header name and ID read from a disk's BAM match those
+/// from a directory listing.  The comparison ignores case and trailing
+/// spaces, and characters [`bam_header`] couldn't convert match anything.
+pub fn disk_header_matches(cached_name: &str, cached_id: &str, name: &str, id: &str) -> bool {
+    let matches = |cached: &str, read: &str| {
+        let (cached, read) = (cached.trim_end(), read.trim_end());
+        cached.chars().count() == read.chars().count()
+            && cached
+                .chars()
+                .zip(read.chars())
+                .all(|(c, r)| r == '?' || c.eq_ignore_ascii_case(&r))
+    };
+    matches(cached_name, name) && matches(cached_id, id)
+}
+
+/// Maximum length of a disk's header name
+const MAX_HEADER_NAME_LEN: usize = 16;
+
+/// Length of a disk's ID
+const DISK_ID_LEN: usize = 2;
+
+/// Checks whether the number of bytes read for a file is consistent with the
+/// number of blocks its directory entry says it uses, allowing for
+/// `tolerance_blocks` blocks of slack either way.  A file using n blocks
+/// should contain more than (n-1)*254 and at most n*254 bytes.
+pub fn read_size_plausible(blocks: u16, bytes: usize, tolerance_blocks: u16) -> bool {
+    let min_blocks = (blocks as usize).saturating_sub(1 + tolerance_blocks as usize);
+    let max_blocks = blocks as usize + tolerance_blocks as usize;
+    bytes >= min_blocks * CBM_BLOCK_DATA_BYTES && bytes <= max_blocks * CBM_BLOCK_DATA_BYTES
+}
+
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub enum BufferType {
@@ -22,6 +207,7 @@ pub struct Buffer {
     buffer_type: BufferType,
     data: Vec<u8>,
     complete: bool,
+    max_len: Option<usize>,
 }
 
 #[allow(dead_code)]
@@ -31,6 +217,7 @@ impl Buffer {
             buffer_type: BufferType::Read,
             data: Vec::new(),
             complete: false,
+            max_len: None,
         }
     }
 
@@ -39,6 +226,15 @@ impl Buffer {
             buffer_type: BufferType::Write,
             data: Vec::new(),
             complete: false,
+            max_len: None,
+        }
+    }
+
+    /// Creates a write buffer which refuses to grow beyond max_len bytes
+    pub fn new_write_limited(max_len: usize) -> Self {
+        Buffer {
+            max_len: Some(max_len),
+            ..Self::new_write()
         }
     }
 
@@ -50,6 +246,17 @@ impl Buffer {
                         message: "Cannot write to completed buffer".into(),
                         error: Fs1541Error::FileAccess("".into()),
                     })
+                } else if self
+                    .max_len
+                    .is_some_and(|max| self.data.len() + data.len() > max)
+                {
+                    Err(Error::Fs1541 {
+                        message: format!(
+                            "Write buffer limit of {} bytes reached",
+                            self.max_len.unwrap_or_default()
+                        ),
+                        error: Fs1541Error::NoSpace("".into()),
+                    })
                 } else {
                     self.data.extend_from_slice(data);
                     Ok(data.len())
@@ -82,6 +289,31 @@ impl Buffer {
         self.complete = true;
     }
 
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Removes and returns each complete (newline terminated) line from the
+    /// buffer, leaving any trailing partial line in place.  Empty lines are
+    /// dropped.
+    pub fn take_lines(&mut self) -> Vec<String> {
+        let Some(last_newline) = self.data.iter().rposition(|&b| b == b'\n') else {
+            return Vec::new();
+        };
+        let remainder = self.data.split_off(last_newline + 1);
+        let complete = std::mem::replace(&mut self.data, remainder);
+        String::from_utf8_lossy(&complete)
+            .lines()
+            .map(|line| line.trim_end_matches('\r').to_string())
+            .filter(|line| !line.is_empty())
+            .collect()
+    }
+
+    /// Removes and returns all data in the buffer
+    pub fn take_data(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.data)
+    }
+
     pub fn is_complete(&self) -> bool {
         self.complete
     }
@@ -103,6 +335,10 @@ pub enum DriveXattr {
     LastErrorTime(SystemTime),
     DosVersion(DosVersion),
     Fs1541Version(String),
+    BgStatus(String),
+    FileCacheBytes(usize),
+    LastDiskChange(SystemTime),
+    Mode1571(bool),
 }
 
 #[derive(Debug, Clone)]
@@ -113,12 +349,16 @@ pub enum DiskXattr {
     BlocksUsed(u16),
     TotalBlocks(u16),
     LastDirRead(SystemTime),
+    DirAgeSecs(Option<u64>),
+    WriteProtected(bool),
 }
 
 #[derive(Debug, Clone)]
 pub enum FileXattr {
     Inode(u64),
     Blocks(u16),
+    CbmFileType(CbmFileType),
+    LoadAddress(u16),
     ControlFileRwType(RwType),
     ReadBufferSize(usize),
     WriteBufferSize(usize),
@@ -128,6 +368,8 @@ pub enum FileXattr {
     CacheCompleteTime(Option<SystemTime>),
     LastDeviceRead(SystemTime),
     CacheEnabled(bool),
+    BadSectors(Vec<(u8, u8)>),
+    RestoreStatus(RestoreStatus),
 }
 
 pub trait Xattr {
@@ -200,6 +442,10 @@ impl Xattr for DriveXattr {
             DriveXattr::LastErrorTime(_) => "user.device.last_error.tim",
             DriveXattr::DosVersion(_) => "user.device.dos_version",
             DriveXattr::Fs1541Version(_) => "user.1541fs.version",
+            DriveXattr::BgStatus(_) => "user.1541fs.bg_status",
+            DriveXattr::FileCacheBytes(_) => "user.1541fs.file_cache.bytes",
+            DriveXattr::LastDiskChange(_) => "user.device.last_disk_change.time",
+            DriveXattr::Mode1571(_) => "user.device.1571_mode",
         }
     }
 
@@ -214,13 +460,17 @@ impl Xattr for DriveXattr {
             DriveXattr::Mountpoint(mountpoint) => mountpoint.to_string(),
             DriveXattr::MountTime(time)
             | DriveXattr::LastStatusTime(time)
-            | DriveXattr::LastErrorTime(time) => {
+            | DriveXattr::LastErrorTime(time)
+            | DriveXattr::LastDiskChange(time) => {
                 let local_time: DateTime<Local> = (*time).into();
                 local_time.format("%a %b %d %H:%M:%S %Z %Y").to_string()
             }
             DriveXattr::LastStatus(status) | DriveXattr::LastError(status) => status.to_string(),
             DriveXattr::DosVersion(version) => version.to_string(),
             DriveXattr::Fs1541Version(version) => version.to_string(),
+            DriveXattr::BgStatus(status) => status.to_string(),
+            DriveXattr::FileCacheBytes(bytes) => bytes.to_string(),
+            DriveXattr::Mode1571(double_sided) => mode_1571_name(*double_sided).to_string(),
         }
     }
 }
@@ -234,6 +484,8 @@ impl Xattr for DiskXattr {
             DiskXattr::BlocksUsed(_) => "user.disk.cbm_blocks.used",
             DiskXattr::TotalBlocks(_) => "user.disk.cbm_blocks.total",
             DiskXattr::LastDirRead(_) => "user.disk.last_dir_read",
+            DiskXattr::DirAgeSecs(_) => "user.disk.cache.dir_age_secs",
+            DiskXattr::WriteProtected(_) => "user.disk.write_protected",
         }
     }
 
@@ -248,6 +500,11 @@ impl Xattr for DiskXattr {
                 let local_time: DateTime<Local> = (*time).into();
                 local_time.format("%a %b %d %H:%M:%S %Z %Y").to_string()
             }
+            DiskXattr::DirAgeSecs(age) => match age {
+                Some(secs) => secs.to_string(),
+                None => "invalid".to_string(),
+            },
+            DiskXattr::WriteProtected(write_protected) => write_protected.to_string(),
         }
     }
 }
@@ -260,12 +517,16 @@ impl Xattr for FileXattr {
             FileXattr::ReadBufferSize(_) => "user.file.read_buffer.size",
             FileXattr::WriteBufferSize(_) => "user.file.write_buffer.size",
             FileXattr::Blocks(_) => "user.file.cbm_blocks.used",
+            FileXattr::CbmFileType(_) => "user.file.cbm_type",
+            FileXattr::LoadAddress(_) => "user.file.cbm.load_address",
             FileXattr::CacheStatus(_) => "user.file.cache.status",
             FileXattr::CacheSize(_) => "user.file.cache.size",
             FileXattr::CacheStartTime(_) => "user.file.cache.start_time",
             FileXattr::CacheCompleteTime(_) => "user.file.cache.complete_time",
             FileXattr::LastDeviceRead(_) => "user.file.cache.last_device_read",
             FileXattr::CacheEnabled(_) => "user.file.cache.enabled",
+            FileXattr::BadSectors(_) => "user.file.image.bad_sectors",
+            FileXattr::RestoreStatus(_) => "user.file.image.restore_status",
         }
     }
 
@@ -276,6 +537,8 @@ impl Xattr for FileXattr {
             FileXattr::ReadBufferSize(size) => size.to_string(),
             FileXattr::WriteBufferSize(size) => size.to_string(),
             FileXattr::Blocks(blocks) => blocks.to_string(),
+            FileXattr::CbmFileType(file_type) => cbm_file_type_name(file_type).to_string(),
+            FileXattr::LoadAddress(address) => format!("${:04X}", address),
             FileXattr::CacheStatus(status) => status.to_string(),
             FileXattr::CacheSize(size) => size.to_string(),
             FileXattr::CacheStartTime(time) | FileXattr::LastDeviceRead(time) => {
@@ -290,6 +553,18 @@ impl Xattr for FileXattr {
                 None => "incomplete".to_string(),
             },
             FileXattr::CacheEnabled(enabled) => enabled.to_string(),
+            FileXattr::RestoreStatus(status) => status.to_string(),
+            FileXattr::BadSectors(sectors) => {
+                if sectors.is_empty() {
+                    "none".to_string()
+                } else {
+                    sectors
+                        .iter()
+                        .map(|(track, sector)| format!("{}/{}", track, sector))
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                }
+            }
         }
     }
 }
@@ -316,6 +591,7 @@ impl DriveXattr {
             DriveXattr::DosVersion(dos_version),
             DriveXattr::FsType("1541fs".to_string()),
             DriveXattr::Fs1541Version(env!("CARGO_PKG_VERSION").to_string()),
+            DriveXattr::BgStatus("ok".to_string()),
         ]
     }
 }
@@ -366,14 +642,24 @@ impl FileXattr {
             xattrs.push(FileXattr::ControlFileRwType(ctrl.rw_type()));
         }
 
+        if let Some((status, bad_sectors)) = &file_entry.restore {
+            xattrs.push(FileXattr::RestoreStatus(status.clone()));
+            xattrs.push(FileXattr::BadSectors(bad_sectors.clone()));
+        }
+
         if let FileEntryType::CbmFile(cbm) = &file_entry.native {
-            if let CbmFileEntry::ValidFile { blocks, .. } = cbm {
+            if let CbmFileEntry::ValidFile {
+                blocks, file_type, ..
+            } = cbm
+            {
                 xattrs.push(FileXattr::Blocks(*blocks));
+                xattrs.push(FileXattr::CbmFileType(file_type.clone()));
             }
         }
 
         let cache_enabled = match &file_entry.native {
             FileEntryType::CbmFile(_) => true,
+            FileEntryType::ControlFile(ctrl) => ctrl.is_image_dump(),
             _ => false,
         };
 
@@ -381,7 +667,9 @@ impl FileXattr {
 
         match &file_entry.cache {
             Some(cache) => {
-                xattrs.push(FileXattr::CacheStatus(if cache.is_complete {
+                xattrs.push(FileXattr::CacheStatus(if cache.suspect {
+                    CacheStatus::Suspect
+                } else if cache.is_complete {
                     CacheStatus::Complete
                 } else {
                     CacheStatus::InProgress
@@ -392,6 +680,20 @@ impl FileXattr {
                     xattrs.push(FileXattr::CacheCompleteTime(Some(complete_time)));
                 }
                 xattrs.push(FileXattr::LastDeviceRead(cache.last_device_read));
+                if let FileEntryType::CbmFile(CbmFileEntry::ValidFile {
+                    file_type: CbmFileType::PRG,
+                    ..
+                }) = &file_entry.native
+                {
+                    if let Some(address) = prg_load_address(&cache.data) {
+                        xattrs.push(FileXattr::LoadAddress(address));
+                    }
+                }
+                if cache.is_complete && cache_enabled {
+                    if let FileEntryType::ControlFile(_) = &file_entry.native {
+                        xattrs.push(FileXattr::BadSectors(cache.bad_sectors.clone()));
+                    }
+                }
             }
             None => {
                 if cache_enabled {
@@ -417,6 +719,10 @@ pub struct DiskInfo {
     /// read a disk
     pub blocks_free: Option<u16>,
 
+    /// The total number of blocks on this disk - will be None until we have
+    /// read a disk
+    pub total_blocks: Option<u16>,
+
     /// The file entry for this disk's directory (0 or 1).  None if there is
     /// only one drive unit in this drive
     pub disk_dir: Option<FileEntry>,
@@ -431,20 +737,27 @@ pub struct DiskInfo {
     pub xattrs: Vec<DiskXattr>,
 
     pub disk_read_time: Option<SystemTime>,
+
+    /// Whether the disk is write protected, as last reported by the drive -
+    /// None until a write has been attempted, or once the disk is changed
+    pub write_protected: Option<bool>,
 }
 
 impl DiskInfo {
-    /// Used to create DiskInfo before we have a directory listing
-    pub fn new(drive_num: u8) -> Self {
+    /// Used to create DiskInfo before we have a directory listing.  The
+    /// info files are only included if info_files is set.
+    pub fn new(drive_num: u8, info_files: bool) -> Self {
         DiskInfo {
             drive_num,
             header: None,
             blocks_free: None,
+            total_blocks: None,
             disk_dir: None,
-            control_files: Self::control_files(),
+            control_files: Self::control_files(info_files),
             cbm_files: Vec::new(),
             xattrs: Vec::new(),
             disk_read_time: None,
+            write_protected: None,
         }
     }
 
@@ -457,9 +770,69 @@ impl DiskInfo {
         assert!(self.control_files.len() > 0);
         self.header = Some(listing.header.clone());
         self.blocks_free = Some(listing.blocks_free);
-        self.cbm_files = Self::cbm_files_from_dir_listing(listing);
+        self.total_blocks = Some(listing.total_blocks());
+        let mut cbm_files = Self::cbm_files_from_dir_listing(listing);
+
+        // Files being written keep their buffered data.  This includes those
+        // created in the mount, which aren't on the disk until written.
+        for file in self
+            .cbm_files
+            .drain(..)
+            .filter(|file| file.write_buffer.is_some())
+        {
+            match cbm_files.iter_mut().find(|f| f.fuse.name == file.fuse.name) {
+                Some(listed) => *listed = file,
+                None => cbm_files.push(file),
+            }
+        }
+        self.cbm_files = cbm_files;
         self.xattrs = DiskXattr::from_dir_listing(listing);
         self.disk_read_time = Some(SystemTime::now());
+        self.update_dir_json_size();
+    }
+
+    /// Returns the disk's directory listing as JSON, or None if it hasn't
+    /// been read yet
+    pub fn dir_json(&self) -> Option<Vec<u8>> {
+        let header = self.header.as_ref()?;
+        let listing = DirListingJson {
+            header: DirHeaderJson {
+                name: header.name.clone(),
+                id: header.id.clone(),
+            },
+            files: self
+                .cbm_files
+                .iter()
+                .filter_map(|file| match &file.native {
+                    FileEntryType::CbmFile(CbmFileEntry::ValidFile {
+                        blocks,
+                        filename,
+                        file_type,
+                        ..
+                    }) => Some(DirFileJson {
+                        blocks: *blocks,
+                        name: filename.clone(),
+                        file_type: cbm_file_type_name(file_type).to_string(),
+                    }),
+                    _ => None,
+                })
+                .collect(),
+            blocks_free: self.blocks_free.unwrap_or(0),
+        };
+        listing.to_json().ok()
+    }
+
+    /// The JSON control file's size must match its contents, or the kernel
+    /// won't read it
+    fn update_dir_json_size(&mut self) {
+        let size = self.dir_json().map_or(0, |json| json.len() as u64);
+        for file in self.control_files.iter_mut() {
+            if let FileEntryType::ControlFile(control_file) = &file.native {
+                if matches!(control_file.purpose(), ControlFilePurpose::GetDirJson) {
+                    file.fuse.size = size;
+                }
+            }
+        }
     }
 
     fn cbm_files_from_dir_listing(listing: &CbmDirListing) -> Vec<FileEntry> {
@@ -474,9 +847,9 @@ impl DiskInfo {
         cbm_files
     }
 
-    fn control_files() -> Vec<FileEntry> {
+    fn control_files(info_files: bool) -> Vec<FileEntry> {
         let mut files = Vec::new();
-        for purpose in ControlFilePurpose::iter() {
+        for purpose in ControlFilePurpose::iter().filter(|p| info_files || !p.is_info_file()) {
             let file_entry = FileEntry::from_control_file_purpose(purpose, 0);
             files.push(file_entry);
         }
@@ -506,6 +879,44 @@ pub enum ControlFilePurpose {
     ExecDriveCommand,
     ExecDirRefresh,
     ExecFormatDrive,
+    ExecImageDump,
+    ExecImageDump40,
+    ExecImageRestore,
+    ExecPartition,
+    GetDirJson,
+    Exec1571Mode,
+    ExecSoftReset,
+    GetDeviceInfo,
+    GetDriveStatus,
+}
+
+impl ControlFilePurpose {
+    /// Whether this is one of the optional info files, which mirror the
+    /// drive's xattrs
+    pub fn is_info_file(&self) -> bool {
+        matches!(
+            self,
+            ControlFilePurpose::GetDeviceInfo | ControlFilePurpose::GetDriveStatus
+        )
+    }
+}
+
+/// Progress of restoring an image to a disk, reported via xattrs
+#[derive(Debug, Clone)]
+pub enum RestoreStatus {
+    InProgress,
+    Complete,
+    Failed(String),
+}
+
+impl std::fmt::Display for RestoreStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RestoreStatus::InProgress => write!(f, "In progress"),
+            RestoreStatus::Complete => write!(f, "Complete"),
+            RestoreStatus::Failed(reason) => write!(f, "Failed: {}", reason),
+        }
+    }
 }
 
 impl std::fmt::Display for ControlFilePurpose {
@@ -517,6 +928,15 @@ impl std::fmt::Display for ControlFilePurpose {
             ControlFilePurpose::ExecDriveCommand => write!(f, "ExecDriveCommand"),
             ControlFilePurpose::ExecDirRefresh => write!(f, "ExecDirRefresh"),
             ControlFilePurpose::ExecFormatDrive => write!(f, "ExecFormatDrive"),
+            ControlFilePurpose::ExecImageDump => write!(f, "ExecImageDump"),
+            ControlFilePurpose::ExecImageDump40 => write!(f, "ExecImageDump40"),
+            ControlFilePurpose::ExecImageRestore => write!(f, "ExecImageRestore"),
+            ControlFilePurpose::ExecPartition => write!(f, "ExecPartition"),
+            ControlFilePurpose::GetDirJson => write!(f, "GetDirJson"),
+            ControlFilePurpose::Exec1571Mode => write!(f, "Exec1571Mode"),
+            ControlFilePurpose::ExecSoftReset => write!(f, "ExecSoftReset"),
+            ControlFilePurpose::GetDeviceInfo => write!(f, "GetDeviceInfo"),
+            ControlFilePurpose::GetDriveStatus => write!(f, "GetDriveStatus"),
         }
     }
 }
@@ -557,6 +977,15 @@ impl ControlFile {
             ControlFilePurpose::GetLastErrorStatus => None,
             ControlFilePurpose::ExecDriveCommand => Some(format!("To run a drive command echo the command (as lower case ASCII) into this file.\nFor example:\n  echo \"i\" > {}\n", self.filename()).into()),
             ControlFilePurpose::ExecDirRefresh => Some(format!("To refresh the directory listing echo \"1\" into this file.\nFor example: \n  echo \"1\" > {}\n", self.filename()).into()),
+            ControlFilePurpose::ExecImageDump => None,
+            ControlFilePurpose::ExecImageDump40 => None,
+            ControlFilePurpose::ExecImageRestore => None,
+            ControlFilePurpose::ExecPartition => Some(format!("To create a partition on a 1581 disk, echo its name, start track and size in blocks (a multiple of 40), separated by commas, into this file.  The new partition is then selected.\nTo select an existing partition echo its name, and to return to the root echo \"/\".\nFor example:\n  echo \"games,41,120\" > {}\n", self.filename()).into()),
+            ControlFilePurpose::GetDirJson => None,
+            ControlFilePurpose::GetDeviceInfo => None,
+            ControlFilePurpose::GetDriveStatus => None,
+            ControlFilePurpose::Exec1571Mode => Some(format!("To switch a 1571 to double-sided (native) mode echo \"1571\" into this file, and to switch it to single-sided (1541) mode echo \"1541\".\nFor example:\n  echo \"1571\" > {}\n", self.filename()).into()),
+            ControlFilePurpose::ExecSoftReset => Some(format!("To reset the drive's DOS, without resetting the bus, echo \"1\" into this file.\nFor example:\n  echo \"1\" > {}\n", self.filename()).into()),
             ControlFilePurpose::ExecFormatDrive => Some(format!("To format the disk in the drive, echo the new header name followed by the disk ID, separated by commands, into this file.\nThe header name may be maximum of 16 characters, and may include whitespace.  The ID must be precisely 2 characters.\nFor example:\n  echo \"my new disk,aa\" > {}\n", self.filename()).into()),
         }
     }
@@ -567,7 +996,9 @@ impl ControlFile {
     /// information - for example for GetLastDriveStatus, the size can
     /// reresent the last drive status - 0 meaning 00, 73 meaning 73, etc.
     pub fn size(&self) -> u64 {
-        if let Some(text) = self.read_static() {
+        if let Some(geometry) = self.image_geometry() {
+            geometry.image_size() as u64
+        } else if let Some(text) = self.read_static() {
             text.len() as u64
         } else {
             0
@@ -592,18 +1023,60 @@ impl ControlFile {
             ControlFilePurpose::ExecDriveCommand => RwType::ReadWrite,
             ControlFilePurpose::ExecDirRefresh => RwType::ReadWrite,
             ControlFilePurpose::ExecFormatDrive => RwType::ReadWrite,
+            ControlFilePurpose::ExecImageDump => RwType::Read,
+            ControlFilePurpose::ExecImageDump40 => RwType::Read,
+            ControlFilePurpose::ExecImageRestore => RwType::Write,
+            ControlFilePurpose::ExecPartition => RwType::ReadWrite,
+            ControlFilePurpose::GetDirJson => RwType::Read,
+            ControlFilePurpose::Exec1571Mode => RwType::ReadWrite,
+            ControlFilePurpose::ExecSoftReset => RwType::ReadWrite,
+            ControlFilePurpose::GetDeviceInfo => RwType::Read,
+            ControlFilePurpose::GetDriveStatus => RwType::Read,
+        }
+    }
+
+    /// Whether a .d64 image written to this control file is restored to the
+    /// disk.  Its contents are binary, so aren't split into commands.
+    pub fn is_image_restore(&self) -> bool {
+        matches!(self.purpose, ControlFilePurpose::ExecImageRestore)
+    }
+
+    /// Whether this control file returns an image of the disk when read.
+    /// Its contents come from the drive, so are read (and cached) like a
+    /// CBM file's.
+    pub fn is_image_dump(&self) -> bool {
+        self.image_geometry().is_some()
+    }
+
+    /// Returns the geometry of the disk image this control file returns when
+    /// read, if it's an image dump file
+    pub fn image_geometry(&self) -> Option<D64Geometry> {
+        match self.purpose {
+            ControlFilePurpose::ExecImageDump => Some(D64Geometry::Standard),
+            ControlFilePurpose::ExecImageDump40 => Some(D64Geometry::Extended),
+            _ => None,
         }
     }
 
-    /// Returns the filename for this control file
+    /// Returns the filename for this control file.  The info files are named
+    /// for what they contain, rather than with the usual suffix.
     pub fn filename(&self) -> String {
         let name = match self.purpose {
+            ControlFilePurpose::GetDeviceInfo => return ".device_info".to_string(),
+            ControlFilePurpose::GetDriveStatus => return ".drive_status".to_string(),
             ControlFilePurpose::GetCurDriveStatus => "get_current_status",
             ControlFilePurpose::GetLastDriveStatus => "get_last_status",
             ControlFilePurpose::GetLastErrorStatus => "get_last_error_status",
             ControlFilePurpose::ExecDriveCommand => "exec_command",
             ControlFilePurpose::ExecDirRefresh => "exec_dir_refresh",
             ControlFilePurpose::ExecFormatDrive => "exec_format_drive",
+            ControlFilePurpose::ExecImageDump => "exec_image_dump",
+            ControlFilePurpose::ExecImageDump40 => "exec_image_dump_40",
+            ControlFilePurpose::ExecImageRestore => "exec_image_restore",
+            ControlFilePurpose::ExecPartition => "exec_partition",
+            ControlFilePurpose::GetDirJson => "get_dir_json",
+            ControlFilePurpose::Exec1571Mode => "exec_1571_mode",
+            ControlFilePurpose::ExecSoftReset => "exec_soft_reset",
         };
         let suffix = match self.rw_type() {
             RwType::Read => "r",
@@ -618,16 +1091,10 @@ impl ControlFile {
         &self.purpose
     }
 
-    /*
-    pub fn write(
-        &self,
-        mount: &mut Mount,
-        _offset: i64,
-        data: &[u8],
-        _write_flags: u32,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-    ) -> Result<u32, Error> {
+    /// Executes a complete command written to this control file.  Commands
+    /// are buffered by FileEntry::buffer_control_write until complete, so
+    /// this is never called with a partial command.
+    pub fn execute(&self, mount: &mut Mount, command: &str) -> Result<(), Error> {
         if self.rw_type() == RwType::Read {
             return Err(Error::Fs1541 {
                 message: "Cannot write to read-only control file".to_string(),
@@ -635,32 +1102,35 @@ impl ControlFile {
             });
         }
 
+        debug!(
+            "Executing control file {} command: {}",
+            self.purpose, command
+        );
         match self.purpose {
             // TODO - fix drive_num in ExecDirRefresh
-            ControlFilePurpose::ExecDirRefresh => {
-                mount.do_dir_sync(0, false).map(|_| data.len() as u32)
+            ControlFilePurpose::ExecDirRefresh => mount.do_dir_sync(0, false),
+            ControlFilePurpose::ExecDriveCommand => {
+                mount.drive_command_sync(drive_command(command)?)
             }
-            ControlFilePurpose::ExecDriveCommand => Err(Error::Fs1541 {
-                message: "Not implemented".to_string(),
-                error: Fs1541Error::Internal("ExecDriveCommand not implemented".to_string()),
-            }),
-            ControlFilePurpose::ExecFormatDrive => Err(Error::Fs1541 {
-                message: "Not implemented".to_string(),
-                error: Fs1541Error::Internal("ExecFormatDrive not implemented".to_string()),
-            }),
-            _ => {
-                return Err(Error::Fs1541 {
-                    message: "Unknown control file".to_string(),
-                    error: Fs1541Error::Internal(format!(
-                        "File {} control file purpose {}",
-                        self.filename(),
-                        self.purpose
-                    )),
-                });
+            ControlFilePurpose::ExecFormatDrive => {
+                let (name, id) = format_command(command)?;
+                mount.format_disk_sync(name, id)
+            }
+            ControlFilePurpose::ExecPartition => mount.partition_sync(partition_command(command)?),
+            ControlFilePurpose::Exec1571Mode => {
+                mount.set_1571_mode_sync(mode_1571_command(command)?)
             }
+            ControlFilePurpose::ExecSoftReset => mount.soft_reset_sync(),
+            _ => Err(Error::Fs1541 {
+                message: "Unknown control file".to_string(),
+                error: Fs1541Error::Internal(format!(
+                    "File {} control file purpose {}",
+                    self.filename(),
+                    self.purpose
+                )),
+            }),
         }
     }
-    */
 }
 
 #[derive(Debug, Clone)]
@@ -681,16 +1151,533 @@ pub struct FuseFile {
     pub created_time: SystemTime,
 }
 
+/// Validates a command written to the drive command control file, stripping
+/// any trailing newline
+pub fn drive_command(command: &str) -> Result<String, Error> {
+    let command = command.trim_end_matches(['\r', '\n']);
+    if command.trim().is_empty() {
+        Err(Error::Fs1541 {
+            message: "Invalid drive command".into(),
+            error: Fs1541Error::Validation("Drive command must not be empty".into()),
+        })
+    } else {
+        Ok(command.to_string())
+    }
+}
+
+/// Blocks per track on a 1581 disk.  Partitions usable as sub-directories
+/// must be made up of whole tracks.
+const BLOCKS_PER_1581_TRACK: u16 = 40;
+
+/// Tracks on a 1581 disk.  Track 40 holds the root directory, so can't be
+/// partitioned.
+const TRACKS_1581: u8 = 80;
+const DIR_TRACK_1581: u8 = 40;
+
+/// A command written to the 1581 partition control file
+#[derive(Debug, Clone, PartialEq)]
+pub enum PartitionCommand {
+    /// Create a partition, then select it
+    Create {
+        name: String,
+        start_track: u8,
+        blocks: u16,
+    },
+    /// Select an existing partition
+    Select(String),
+    /// Return to the root of the disk
+    Root,
+}
+
+impl PartitionCommand {
+    /// Returns the DOS commands to send to the drive, in order.  They are
+    /// PETSCII, as partition creation includes binary parameters.
+    pub fn dos_commands(&self) -> Vec<Vec<u8>> {
+        let select = |name: &str| format!("/0:{}", name).to_ascii_uppercase().into_bytes();
+        match self {
+            PartitionCommand::Create {
+                name,
+                start_track,
+                blocks,
+            } => {
+                let mut create = format!("/0:{},", name).to_ascii_uppercase().into_bytes();
+                create.extend_from_slice(&[*start_track, 0]);
+                create.extend_from_slice(&blocks.to_le_bytes());
+                create.extend_from_slice(b",C");
+                vec![create, select(name)]
+            }
+            PartitionCommand::Select(name) => vec![select(name)],
+            PartitionCommand::Root => vec![b"/".to_vec()],
+        }
+    }
+}
+
+/// Parses a command written to the partition control file - "/" to return to
+/// the root, a partition name to select it, or the name, start track and
+/// number of blocks, separated by commas, to create one
+pub fn partition_command(command: &str) -> Result<PartitionCommand, Error> {
+    let command = command.trim_end_matches(['\r', '\n']);
+    let invalid = |detail: String| Error::Fs1541 {
+        message: "Invalid partition command".into(),
+        error: Fs1541Error::Validation(detail),
+    };
+
+    let fields: Vec<&str> = command.split(',').collect();
+    match fields.as_slice() {
+        ["/"] => Ok(PartitionCommand::Root),
+        [name] => {
+            validate_cbm_filename(name)?;
+            Ok(PartitionCommand::Select(name.to_string()))
+        }
+        [name, start_track, blocks] => {
+            validate_cbm_filename(name)?;
+            let start_track = start_track
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|track| (1..=TRACKS_1581).contains(track) && *track != DIR_TRACK_1581)
+                .ok_or_else(|| invalid(format!("Invalid start track: {}", start_track)))?;
+            let blocks = blocks
+                .trim()
+                .parse::<u16>()
+                .ok()
+                .filter(|blocks| *blocks > 0 && blocks % BLOCKS_PER_1581_TRACK == 0)
+                .ok_or_else(|| {
+                    invalid(format!(
+                        "Size must be a multiple of {} blocks: {}",
+                        BLOCKS_PER_1581_TRACK, blocks
+                    ))
+                })?;
+
+            // The partition can't run off the end of the disk, or include
+            // the directory track
+            let tracks = (blocks / BLOCKS_PER_1581_TRACK) as u8;
+            let end_track = start_track as u16 + tracks as u16 - 1;
+            if end_track > TRACKS_1581 as u16
+                || (start_track..=end_track as u8).contains(&DIR_TRACK_1581)
+            {
+                return Err(invalid(format!(
+                    "Partition of {} blocks from track {} doesn't fit",
+                    blocks, start_track
+                )));
+            }
+
+            Ok(PartitionCommand::Create {
+                name: name.to_string(),
+                start_track,
+                blocks,
+            })
+        }
+        _ => Err(invalid(format!(
+            "Partition command must be \"/\", a name, or name,start track,blocks: {}",
+            command
+        ))),
+    }
+}
+
+/// Parses a command written to the 1571 mode control file - "1571" for
+/// double-sided mode or "1541" for single-sided mode - returning whether
+/// double-sided mode was requested
+pub fn mode_1571_command(command: &str) -> Result<bool, Error> {
+    match command.trim() {
+        "1571" => Ok(true),
+        "1541" => Ok(false),
+        other => Err(Error::Fs1541 {
+            message: "Invalid 1571 mode command".into(),
+            error: Fs1541Error::Validation(format!(
+                "1571 mode must be \"1571\" or \"1541\": {}",
+                other
+            )),
+        }),
+    }
+}
+
+/// Describes the mode of a 1571, as reported by its xattr
+pub fn mode_1571_name(double_sided: bool) -> &'static str {
+    if double_sided {
+        "1571 (double-sided)"
+    } else {
+        "1541 (single-sided)"
+    }
+}
+
+/// Parses a command written to the format control file, which is the new
+/// header name, followed by a comma, followed by the 2 character disk ID,
+/// returning the name and ID
+pub fn format_command(command: &str) -> Result<(String, String), Error> {
+    let command = command.trim_end_matches(['\r', '\n']);
+    let invalid = |detail: String| Error::Fs1541 {
+        message: "Invalid format command".into(),
+        error: Fs1541Error::Validation(detail),
+    };
+
+    let Some((name, id)) = command.rsplit_once(',') else {
+        return Err(invalid(format!(
+            "Format command must be header name and ID separated by a comma: {}",
+            command
+        )));
+    };
+    if name.is_empty() || name.chars().count() > MAX_HEADER_NAME_LEN {
+        return Err(invalid(format!(
+            "Header name must be 1-{} characters: {}",
+            MAX_HEADER_NAME_LEN, name
+        )));
+    }
+    if id.chars().count() != DISK_ID_LEN {
+        return Err(invalid(format!(
+            "Disk ID must be exactly {} characters: {}",
+            DISK_ID_LEN, id
+        )));
+    }
+
+    Ok((name.to_string(), id.to_string()))
+}
+
+/// Maximum length of a Commodore filename
+const MAX_CBM_FILENAME_LEN: usize = 16;
+
+/// Characters which have special meaning to the drive's DOS, so can't be used
+/// in filenames
+const CBM_RESERVED_CHARS: [char; 6] = [':', '=', ',', '*', '?', '"'];
+
+/// Checks the filename can be used for a file on a Commodore disk
+pub fn validate_cbm_filename(name: &str) -> Result<(), Error> {
+    let invalid = |reason: String| Error::Fs1541 {
+        message: "Invalid filename".into(),
+        error: Fs1541Error::Validation(reason),
+    };
+
+    if name.is_empty() || name.chars().count() > MAX_CBM_FILENAME_LEN {
+        return Err(invalid(format!(
+            "Filename must be 1-{} characters: {}",
+            MAX_CBM_FILENAME_LEN, name
+        )));
+    }
+    if let Some(c) = name.chars().find(|c| CBM_RESERVED_CHARS.contains(c)) {
+        return Err(invalid(format!(
+            "Filename contains reserved character '{}': {}",
+            c, name
+        )));
+    }
+
+    Ok(())
+}
+
+/// Escapes a Commodore filename for use as a FUSE filename.  Characters
+/// outside printable ASCII, such as PETSCII shifted spaces (0xA0) and reverse
+/// video on/off (0x12/0x92), are ambiguous or unusable in a Linux filename,
+/// so are percent-escaped, as are '%' and '/'.  unescape_cbm_name reverses
+/// this.
+pub fn escape_cbm_name(name: &str) -> String {
+    let mut escaped = String::with_capacity(name.len());
+    for c in name.chars() {
+        match c as u32 {
+            0x25 | 0x2f => escaped.push_str(&format!("%{:02X}", c as u32)),
+            0x20..=0x7e | 0x100.. => escaped.push(c),
+            byte => escaped.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    escaped
+}
+
+/// Recovers a Commodore filename from one escaped by escape_cbm_name.  A '%'
+/// which isn't followed by two hex digits is left as it is, so names typed
+/// by the user containing '%' are still accepted.
+pub fn unescape_cbm_name(name: &str) -> String {
+    let mut unescaped = String::with_capacity(name.len());
+    let mut rest = name;
+    while let Some(pos) = rest.find('%') {
+        unescaped.push_str(&rest[..pos]);
+        let byte = rest
+            .get(pos + 1..pos + 3)
+            .filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()))
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match byte {
+            Some(byte) => {
+                unescaped.push(byte as char);
+                rest = &rest[pos + 3..];
+            }
+            _ => {
+                unescaped.push('%');
+                rest = &rest[pos + 1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+/// Returns the Commodore filename for a FUSE filename, by removing the suffix
+/// added for the file's type, if present, and unescaping it
+pub fn cbm_name_from_fuse(fuse_name: &str, file_type: &CbmFileType) -> String {
+    unescape_cbm_name(
+        fuse_name
+            .strip_suffix(FuseFile::fuse_suffix(file_type))
+            .unwrap_or(fuse_name),
+    )
+}
+
+/// Returns the load address of a PRG file, from its first two bytes (low
+/// byte first), or None if fewer than two bytes have been read
+pub fn prg_load_address(data: &[u8]) -> Option<u16> {
+    match data {
+        [low, high, ..] => Some(u16::from_le_bytes([*low, *high])),
+        _ => None,
+    }
+}
+
+/// Returns the DOS command to rename a file on the given drive
+pub fn cbm_rename_command(drive_num: u8, old: &str, new: &str) -> String {
+    format!("R{}:{}={}", drive_num, new, old)
+}
+
+/// Returns the DOS command to scratch (delete) a file on the given drive
+pub fn cbm_scratch_command(drive_num: u8, filename: &str) -> String {
+    format!("S{}:{}", drive_num, filename)
+}
+
+/// Returns the DOS command to format (new) the disk in the given drive
+pub fn cbm_format_command(drive_num: u8, name: &str, id: &str) -> String {
+    format!("N{}:{},{}", drive_num, name, id)
+}
+
+/// Returns the U1 (block read) command, which reads a block from the disk in
+/// drive 0 into the buffer open on the given channel
+pub fn cbm_block_read_command(channel: u8, track: u8, sector: u8) -> String {
+    format!("U1 {} 0 {} {}", channel, track, sector)
+}
+
+/// Returns the U2 (block write) command, which writes the buffer open on the
+/// given channel to a block of the disk in drive 0
+pub fn cbm_block_write_command(channel: u8, track: u8, sector: u8) -> String {
+    format!("U2 {} 0 {} {}", channel, track, sector)
+}
+
+/// Returns the B-P (buffer pointer) command, which moves the pointer of the
+/// buffer open on the given channel to the given byte
+pub fn cbm_buffer_pointer_command(channel: u8, position: u8) -> String {
+    format!("B-P {} {}", channel, position)
+}
+
+/// Returns the M-R (memory read) command, which has the drive return len
+/// bytes of its memory from addr over the command channel
+pub fn cbm_memory_read_command(addr: u16, len: u8) -> Vec<u8> {
+    let [lo, hi] = addr.to_le_bytes();
+    vec![b'M', b'-', b'R', lo, hi, len]
+}
+
+/// Returns the M-E (memory execute) command, which has the drive run the
+/// code in its memory at addr
+pub fn cbm_memory_execute_command(addr: u16) -> Vec<u8> {
+    let [lo, hi] = addr.to_le_bytes();
+    vec![b'M', b'-', b'E', lo, hi]
+}
+
+/// The most data sent with each M-W command, so the command fits in the
+/// drive's command buffer
+const MEMORY_WRITE_CHUNK_SIZE: usize = 32;
+
+/// Returns the M-W (memory write) commands which write the data to the
+/// drive's memory at addr, MEMORY_WRITE_CHUNK_SIZE bytes at a time
+pub fn cbm_memory_write_commands(addr: u16, data: &[u8]) -> Vec<Vec<u8>> {
+    data.chunks(MEMORY_WRITE_CHUNK_SIZE)
+        .enumerate()
+        .map(|(ii, chunk)| {
+            let offset = (ii * MEMORY_WRITE_CHUNK_SIZE) as u16;
+            let [lo, hi] = addr.wrapping_add(offset).to_le_bytes();
+            let mut command = vec![b'M', b'-', b'W', lo, hi, chunk.len() as u8];
+            command.extend_from_slice(chunk);
+            command
+        })
+        .collect()
+}
+
+/// Returns the name to open a file on the given drive with, before any type
+/// and mode suffix.  Drive 0 is the default, so isn't given.  If overwrite is
+/// set, the name is given the @ prefix, so the drive replaces any existing
+/// file of that name.
+pub fn cbm_open_name(drive_num: u8, filename: &str, overwrite: bool) -> String {
+    match (overwrite, drive_num) {
+        (false, 0) => filename.to_string(),
+        (true, 0) => format!("@:{}", filename),
+        (false, drive_num) => format!("{}:{}", drive_num, filename),
+        (true, drive_num) => format!("@{}:{}", drive_num, filename),
+    }
+}
+
+/// Returns the name to use to open a Commodore file on the given drive for
+/// writing.  As for reading, SEQ and USR files are given an explicit type
+/// and mode suffix.
+pub fn cbm_write_open_name(
+    drive_num: u8,
+    filename: &str,
+    file_type: &CbmFileType,
+    overwrite: bool,
+) -> String {
+    let name = cbm_open_name(drive_num, filename, overwrite);
+    match file_type {
+        CbmFileType::SEQ => format!("{},s,w", name),
+        CbmFileType::USR => format!("{},u,w", name),
+        CbmFileType::PRG | CbmFileType::REL | CbmFileType::Unknown => name,
+    }
+}
+
+/// Returns the name to use to open a Commodore file on the given drive to
+/// append to it, or None if the drive can't append to files of this type.
+/// Unlike other writes, the type must always be given.
+pub fn cbm_append_open_name(
+    drive_num: u8,
+    filename: &str,
+    file_type: &CbmFileType,
+) -> Option<String> {
+    let name = cbm_open_name(drive_num, filename, false);
+    match file_type {
+        CbmFileType::SEQ => Some(format!("{},s,a", name)),
+        CbmFileType::USR => Some(format!("{},u,a", name)),
+        CbmFileType::PRG => Some(format!("{},p,a", name)),
+        CbmFileType::REL | CbmFileType::Unknown => None,
+    }
+}
+
+/// Returns the name to use to open a Commodore file for reading.  SEQ, USR
+/// and DEL files are opened with an explicit type and mode suffix, so the
+/// drive returns the file's data with the correct semantics, rather than
+/// assuming PRG.  PRG and REL files are opened using the plain filename,
+/// preserving the existing PRG handling.
+pub fn cbm_read_open_name(filename: &str, file_type: &CbmFileType) -> String {
+    match file_type {
+        CbmFileType::SEQ => format!("{},s,r", filename),
+        CbmFileType::USR => format!("{},u,r", filename),
+        CbmFileType::Unknown => format!("{},d,r", filename),
+        CbmFileType::PRG | CbmFileType::REL => filename.to_string(),
+    }
+}
+
+/// Returns the filename from a name used to open a Commodore file, without
+/// any @ or drive prefix or type and mode suffix, as the drive would look it up
+/// in the directory.  Filenames can't contain : or , so these are unambiguous.
+pub fn cbm_filename_from_open_name(open_name: &str) -> &str {
+    let name = open_name.strip_prefix('@').unwrap_or(open_name);
+    let name = match name.split_once(':') {
+        Some((drive, name)) if drive.chars().all(|c| c.is_ascii_digit()) => name,
+        _ => name,
+    };
+    name.split(',').next().unwrap_or(name)
+}
+
+/// A disk's directory listing, as returned by the GetDirJson control file
+#[derive(Debug, Clone, Serialize)]
+pub struct DirListingJson {
+    pub header: DirHeaderJson,
+    pub files: Vec<DirFileJson>,
+    pub blocks_free: u16,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DirHeaderJson {
+    pub name: String,
+    pub id: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DirFileJson {
+    pub blocks: u16,
+    pub name: String,
+    pub file_type: String,
+}
+
+impl DirListingJson {
+    pub fn to_json(&self) -> Result<Vec<u8>, serde_json::Error> {
+        let mut json = serde_json::to_vec_pretty(self)?;
+        json.push(b'\n');
+        Ok(json)
+    }
+}
+
+/// Returns the name of a Commodore file type, as shown in a directory
+/// listing.  rs1541 has no DEL type, and parses DEL entries as Unknown.
+/// They're the only type a 1541 lists which it doesn't recognize, so Unknown
+/// is treated as DEL throughout.
+pub fn cbm_file_type_name(file_type: &CbmFileType) -> &'static str {
+    match file_type {
+        CbmFileType::PRG => "PRG",
+        CbmFileType::SEQ => "SEQ",
+        CbmFileType::USR => "USR",
+        CbmFileType::REL => "REL",
+        CbmFileType::Unknown => "DEL",
+    }
+}
+
 impl FuseFile {
+    /// Returns the suffix added to a Commodore filename for its type.  DEL
+    /// files, which are usually separators in the listing, are still given
+    /// a suffix, so they can't collide with a file of another type, and
+    /// their names can be mapped back to the Commodore filename.
     pub fn fuse_suffix(file_type: &CbmFileType) -> &'static str {
         match file_type {
             CbmFileType::PRG => ".prg",
             CbmFileType::SEQ => ".seq",
             CbmFileType::USR => ".usr",
             CbmFileType::REL => ".rel",
-            CbmFileType::Unknown => "",
+            CbmFileType::Unknown => ".del",
         }
     }
+
+    /// Returns the type of a new file from its FUSE name's suffix, for the
+    /// types which can be created by writing them.  REL files need a record
+    /// length, so can't be.
+    pub fn creatable_file_type(fuse_name: &str) -> Option<CbmFileType> {
+        [CbmFileType::PRG, CbmFileType::SEQ, CbmFileType::USR]
+            .into_iter()
+            .find(|file_type| fuse_name.ends_with(Self::fuse_suffix(file_type)))
+    }
+}
+
+/// Returns the next value of a process-wide counter, used to order file cache
+/// reads by recency
+fn next_cache_read_tick() -> u64 {
+    static TICK: AtomicU64 = AtomicU64::new(0);
+    TICK.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Drops the least recently read completed file caches until the total
+/// cached across files is no more than max_bytes.  The cache of the file
+/// with inode keep, which has just been read for a waiting reader, and caches
+/// still being read are never dropped.  Returns the number of bytes dropped.
+pub fn evict_file_caches(files: &mut [&mut FileEntry], max_bytes: usize, keep: u64) -> usize {
+    let mut total: usize = files
+        .iter()
+        .filter_map(|file| file.cache.as_ref())
+        .map(|cache| cache.len())
+        .sum();
+    let mut evicted = 0;
+
+    while total > max_bytes {
+        let Some(file) = files
+            .iter_mut()
+            .filter(|file| file.inode() != keep)
+            .filter(|file| {
+                file.cache
+                    .as_ref()
+                    .is_some_and(|cache| cache.get_data_read_complete().is_some())
+            })
+            .min_by_key(|file| file.cache.as_ref().map(|cache| cache.last_read()))
+        else {
+            break;
+        };
+
+        let len = file.cache.take().map(|cache| cache.len()).unwrap_or(0);
+        debug!(
+            "Evicted {} byte file cache for {} {}",
+            len,
+            file.inode(),
+            file.fuse.name
+        );
+        total -= len;
+        evicted += len;
+    }
+
+    evicted
 }
 
 /// Represents a cache for progressively loading a file into memory.
@@ -709,6 +1696,22 @@ pub struct FileCache {
     cache_complete: Option<SystemTime>,
     /// Last time we accessed the file on the disk to update this cache
     last_device_read: SystemTime,
+    /// Whether the data read looks wrong (for example it is the wrong size
+    /// for the file's directory entry).  Suspect data is not served from the
+    /// cache, so the file gets re-read.
+    suspect: bool,
+    /// The status reported by the drive if the read failed
+    failed: Option<CbmStatus>,
+    /// For disk images, the blocks (track, sector) which couldn't be read,
+    /// and were filled with zeros
+    bad_sectors: Vec<(u8, u8)>,
+    /// When the cached data was completed or last served, as a tick from
+    /// next_cache_read_tick(), so the least recently read caches can be
+    /// evicted first.  Atomic so it can be updated while the Mount is only
+    /// read locked.
+    last_read: Arc<AtomicU64>,
+    /// Token to cancel the background operation populating this cache
+    cancel_token: Option<Arc<AtomicBool>>,
 }
 
 impl FileCache {
@@ -726,16 +1729,92 @@ impl FileCache {
             cache_start: now,
             cache_complete: None,
             last_device_read: now,
+            suspect: false,
+            failed: None,
+            bad_sectors: Vec::new(),
+            last_read: Arc::new(AtomicU64::new(next_cache_read_tick())),
+            cancel_token: None,
+        }
+    }
+
+    /// Creates a new empty file cache, populated by a background operation
+    /// which can be cancelled with the given token
+    pub fn with_cancel_token(cancel_token: Arc<AtomicBool>) -> Self {
+        FileCache {
+            cancel_token: Some(cancel_token),
+            ..Self::new()
         }
     }
 
+    /// Cancels the read populating this cache, if it hasn't completed
+    pub fn cancel_read(&self) {
+        if let Some(token) = self.cancel_token.as_ref() {
+            if !self.is_complete && self.failed.is_none() {
+                token.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Whether the read populating this cache was cancelled before it
+    /// completed
+    pub fn is_cancelled(&self) -> bool {
+        !self.is_complete
+            && self
+                .cancel_token
+                .as_ref()
+                .is_some_and(|token| token.load(Ordering::Relaxed))
+    }
+
+    /// When the cached data was last read, relative to other caches
+    pub fn last_read(&self) -> u64 {
+        self.last_read.load(Ordering::Relaxed)
+    }
+
+    fn touch(&self) {
+        self.last_read
+            .store(next_cache_read_tick(), Ordering::Relaxed);
+    }
+
     pub fn cache_complete_time(&self) -> &Option<SystemTime> {
         &self.cache_complete
     }
 
-    /// Checks if we've reached the end of the file and cached all data.
+    /// Checks if we've reached the end of the file and cached all data, and
+    /// that data isn't suspect.
     pub fn is_fully_cached(&self) -> bool {
-        self.is_complete
+        self.is_complete && !self.suspect
+    }
+
+    /// Marks the cached data as suspect, so it won't be served from the cache
+    pub fn mark_suspect(&mut self) {
+        self.suspect = true;
+    }
+
+    pub fn is_suspect(&self) -> bool {
+        self.suspect
+    }
+
+    /// Records that the read to populate this cache failed, with the status
+    /// reported by the drive, so the reader can be given an appropriate error
+    pub fn mark_failed(&mut self, status: CbmStatus) {
+        self.failed = Some(status);
+    }
+
+    /// Records the blocks which couldn't be read when imaging a disk
+    pub fn set_bad_sectors(&mut self, bad_sectors: Vec<(u8, u8)>) {
+        self.bad_sectors = bad_sectors;
+    }
+
+    /// Returns the drive's status if the read to populate this cache failed
+    pub fn failed_status(&self) -> Option<&CbmStatus> {
+        self.failed.as_ref()
+    }
+
+    /// Returns the data if the read which populated this cache has completed,
+    /// even if the data is suspect.  Used to return the data to the reader
+    /// which triggered the read - subsequent reads will re-read the file.
+    pub fn get_data_read_complete(&self) -> Option<&Vec<u8>> {
+        self.is_complete.then_some(&self.data)
     }
 
     /// Adds a chunk of file data to the cache.
@@ -757,6 +1836,7 @@ impl FileCache {
         if is_final_chunk {
             // Use the same precise time as last_device_read for consistency
             self.cache_complete = Some(self.last_device_read);
+            self.touch();
         }
     }
 
@@ -777,11 +1857,14 @@ impl FileCache {
 
     pub fn set_data_complete(&mut self, data: &[u8]) {
         self.data = Vec::new();
+        self.suspect = false;
+        self.failed = None;
         self.add_chunk(data, true);
     }
 
     /// Allows caller to pass in a Duration and will check if the cache is
-    /// complete and at least that fresh
+    /// complete and at least that fresh.  If so, the cache is marked as
+    /// recently read.
     pub fn get_data_complete_and_fresh(&self, cache_duration: Duration) -> Option<&Vec<u8>> {
         if self.is_fully_cached() {
             if let Some(cache_time) = self.cache_complete {
@@ -789,6 +1872,7 @@ impl FileCache {
                 if let Ok(duration) = SystemTime::now().duration_since(cache_time) {
                     if duration < cache_duration {
                         trace!("File cache is still valid");
+                        self.touch();
                         Some(self.get_data())
                     } else {
                         trace!("File cache is stale");
@@ -814,6 +1898,7 @@ pub enum CacheStatus {
     Uncached,
     InProgress,
     Complete,
+    Suspect,
 }
 
 impl std::fmt::Display for CacheStatus {
@@ -822,6 +1907,7 @@ impl std::fmt::Display for CacheStatus {
             CacheStatus::Uncached => write!(f, "Uncached"),
             CacheStatus::InProgress => write!(f, "In progress"),
             CacheStatus::Complete => write!(f, "Complete"),
+            CacheStatus::Suspect => write!(f, "Suspect"),
         }
     }
 }
@@ -840,6 +1926,22 @@ pub struct FileEntry {
     read_buffer: Option<Buffer>,
     write_buffer: Option<Buffer>,
 
+    /// Whether the file was opened with O_TRUNC, so any existing file on the
+    /// disk is to be replaced when the written data is saved
+    overwrite: bool,
+
+    /// Whether the file was opened with O_APPEND, so the written data is to
+    /// be appended to the existing file on the disk
+    append: bool,
+
+    /// The offset of the first byte in the write buffer.  Only non-zero for
+    /// appends, which start at the end of the existing file.
+    write_offset: u64,
+
+    /// For the image restore control file, the status of the last restore
+    /// and the blocks which couldn't be written
+    pub restore: Option<(RestoreStatus, Vec<(u8, u8)>)>,
+
     /// File cache
     pub cache: Option<FileCache>,
 }
@@ -878,6 +1980,10 @@ impl FileEntry {
             native: FileEntryType::Directory(drive_num),
             read_buffer: None,
             write_buffer: None,
+            overwrite: false,
+            append: false,
+            write_offset: 0,
+            restore: None,
             cache: None,
         }
     }
@@ -900,6 +2006,10 @@ impl FileEntry {
             native: FileEntryType::ControlFile(control_file),
             read_buffer: None,
             write_buffer: None,
+            overwrite: false,
+            append: false,
+            write_offset: 0,
+            restore: None,
             cache: None,
         }
     }
@@ -911,13 +2021,17 @@ impl FileEntry {
                 filename,
                 ..
             } => (
-                format!("{}{}", filename, FuseFile::fuse_suffix(&file_type)),
+                format!(
+                    "{}{}",
+                    escape_cbm_name(filename),
+                    FuseFile::fuse_suffix(&file_type)
+                ),
                 file.max_size().unwrap_or(0),
             ),
             CbmFileEntry::InvalidFile { .. } => return None,
         };
 
-        let permissions = 0o444;
+        let permissions = 0o644;
         let time_now = SystemTime::now();
         let fuse_file = FuseFile {
             name,
@@ -933,11 +2047,17 @@ impl FileEntry {
             native: FileEntryType::CbmFile(file.clone()),
             read_buffer: None,
             write_buffer: None,
+            overwrite: false,
+            append: false,
+            write_offset: 0,
+            restore: None,
             cache: None,
         })
     }
 
-    pub fn open(&mut self, flags: i32) -> Result<(), Error> {
+    /// Opens the file.  If opened for writing, a Commodore file's data is
+    /// buffered up to max_write_len bytes.
+    pub fn open(&mut self, flags: i32, max_write_len: usize) -> Result<(), Error> {
         if libc::O_RDWR & flags != 0 {
             return Err(Error::Fs1541 {
                 message: "Only read OR write supported".into(),
@@ -950,14 +2070,52 @@ impl FileEntry {
                 message: format!("Cannot open directory {}", drive_num),
                 error: Fs1541Error::IsDir(self.fuse.name.clone()),
             }),
-            FileEntryType::CbmFile(_file) => {
-                if libc::O_WRONLY & flags != 0 {
-                    Err(Error::Fs1541 {
-                        message: "CBM files are currently read-only".into(),
+            FileEntryType::CbmFile(file) => {
+                // DEL files are listed, but hold nothing to read or write
+                if let CbmFileEntry::ValidFile {
+                    file_type: CbmFileType::Unknown,
+                    ..
+                } = file
+                {
+                    return Err(Error::Fs1541 {
+                        message: "Cannot open a DEL file".into(),
+                        error: Fs1541Error::FileAccess(self.fuse.name.clone()),
+                    });
+                }
+                if libc::O_WRONLY & flags == 0 {
+                    return Ok(());
+                }
+                match file {
+                    CbmFileEntry::ValidFile {
+                        filename,
+                        file_type,
+                        ..
+                    } => {
+                        // Truncating the file leaves nothing to append to
+                        let overwrite = libc::O_TRUNC & flags != 0;
+                        let append = libc::O_APPEND & flags != 0 && !overwrite;
+                        if append && cbm_append_open_name(0, &filename, &file_type).is_none() {
+                            return Err(Error::Fs1541 {
+                                message: format!(
+                                    "Cannot append to a {} file",
+                                    cbm_file_type_name(&file_type)
+                                ),
+                                error: Fs1541Error::FileAccess(self.fuse.name.clone()),
+                            });
+                        }
+
+                        // The written data is buffered until the file is
+                        // flushed or released, and then written in one go
+                        self.write_buffer = Some(Buffer::new_write_limited(max_write_len));
+                        self.overwrite = overwrite;
+                        self.append = append;
+                        self.write_offset = 0;
+                        Ok(())
+                    }
+                    CbmFileEntry::InvalidFile { .. } => Err(Error::Fs1541 {
+                        message: "Cannot write to an improperly read file".into(),
                         error: Fs1541Error::ReadOnly(self.fuse.name.clone()),
-                    })
-                } else {
-                    Ok(())
+                    }),
                 }
             }
             FileEntryType::ControlFile(ctrl) => match ctrl.rw_type() {
@@ -995,6 +2153,59 @@ impl FileEntry {
         }
     }
 
+    /// Returns any complete (newline terminated) commands buffered by write()
+    /// to a control file, removing them from the write buffer.  Commands may
+    /// arrive across multiple write() calls, so any trailing partial command
+    /// is left buffered until more data arrives, or until it is retrieved by
+    /// take_pending_control_command() when the file is flushed or released.
+    pub fn take_control_commands(&mut self) -> Vec<String> {
+        match (&self.native, self.write_buffer.as_mut()) {
+            (FileEntryType::ControlFile(ctrl), Some(buffer)) if !ctrl.is_image_restore() => {
+                buffer.take_lines()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Returns any buffered partial control file command (i.e. not newline
+    /// terminated), and resets the write buffer
+    pub fn take_pending_control_command(&mut self) -> Option<String> {
+        match &self.native {
+            FileEntryType::ControlFile(ctrl) if !ctrl.is_image_restore() => (),
+            _ => return None,
+        }
+        let data = self.write_buffer.take()?.take_data();
+        let command = String::from_utf8_lossy(&data).trim_end().to_string();
+        if command.is_empty() {
+            None
+        } else {
+            Some(command)
+        }
+    }
+
+    /// Returns the data written to a CBM file (or the image restore control
+    /// file) since it was opened, and whether it is to overwrite the existing
+    /// file, resetting the write buffer.  Returns None if nothing has been
+    /// written.
+    pub fn take_write_data(&mut self) -> Option<(Vec<u8>, bool)> {
+        match &self.native {
+            FileEntryType::CbmFile(_) => (),
+            FileEntryType::ControlFile(ctrl) if ctrl.is_image_restore() => (),
+            _ => return None,
+        }
+        let data = self.write_buffer.take()?.take_data();
+        if data.is_empty() {
+            None
+        } else {
+            Some((data, self.overwrite))
+        }
+    }
+
+    /// Whether the data written is to be appended to the existing file
+    pub fn is_append(&self) -> bool {
+        self.append
+    }
+
     pub fn inode(&self) -> u64 {
         self.fuse.ino
     }
@@ -1021,6 +2232,10 @@ impl FileEntry {
             native: FileEntryType::Directory(255),
             read_buffer: None,
             write_buffer: None,
+            overwrite: false,
+            append: false,
+            write_offset: 0,
+            restore: None,
             cache: None,
         }
     }
@@ -1061,7 +2276,7 @@ impl FileEntry {
     /// offset: Position in file to write (from FUSE)
     /// data: Bytes to write (from FUSE)
     /// Returns: Number of bytes written or error
-    pub fn write(&mut self, _offset: u64, data: &[u8]) -> Result<usize, Error> {
+    pub fn write(&mut self, offset: u64, data: &[u8]) -> Result<usize, Error> {
         match &mut self.native {
             FileEntryType::Directory(drive_num) => Err(Error::Fs1541 {
                 message: format!("Cannot write to directory {}", drive_num),
@@ -1070,34 +2285,78 @@ impl FileEntry {
             FileEntryType::CbmFile(file) => {
                 match file {
                     CbmFileEntry::ValidFile { .. } => {
-                        // This is a real Commodore file being written
-                        // Buffer the data until we have the complete file
-                        // TODO: Add logic to detect when we have complete file
-                        self.write_buffer.as_mut().unwrap().write(data)?;
-
-                        // When buffer is complete:
-                        // TODO: Convert buffered data to CBM file format
-                        // TODO: Write to actual Commodore drive
-                        // TODO: Clear buffer after successful write
+                        // This is a real Commodore file being written.
+                        // Buffer the data until the file is flushed or
+                        // released, when it is written to the drive.
+                        let Some(buffer) = self.write_buffer.as_mut() else {
+                            return Err(Error::Fs1541 {
+                                message: "File not open for writing".into(),
+                                error: Fs1541Error::FileAccess(self.fuse.name.clone()),
+                            });
+                        };
+
+                        // Appended data starts wherever the existing file
+                        // ends
+                        if self.append && buffer.len() == 0 {
+                            self.write_offset = offset;
+                        }
+
+                        // Commodore files can only be written sequentially
+                        if offset != self.write_offset + buffer.len() as u64 {
+                            return Err(Error::Fs1541 {
+                                message: format!(
+                                    "Non-sequential write at offset {} to {} byte file",
+                                    offset,
+                                    self.write_offset + buffer.len() as u64
+                                ),
+                                error: Fs1541Error::Validation(self.fuse.name.clone()),
+                            });
+                        }
+                        buffer.write(data)?;
 
                         Ok(data.len())
                     }
                     CbmFileEntry::InvalidFile { .. } => Err(Error::Fs1541 {
                         message: "Cannot write to an improperly read file".into(),
-                        error: Fs1541Error::FileAccess(self.fuse.name.clone()),
+                        error: Fs1541Error::ReadOnly(self.fuse.name.clone()),
                     }),
                 }
             }
             FileEntryType::ControlFile(ctrl) => match ctrl.purpose {
+                ControlFilePurpose::ExecImageRestore => {
+                    // The image is binary, and written sequentially
+                    let buffer = self.write_buffer.get_or_insert_with(Buffer::new_write);
+                    let max_size = D64_IMAGE_SIZES.iter().map(|(size, _)| *size).max();
+                    if Some(buffer.len() + data.len()) > max_size {
+                        return Err(Error::Fs1541 {
+                            message: "Disk image too large".into(),
+                            error: Fs1541Error::Validation(self.fuse.name.clone()),
+                        });
+                    }
+                    buffer.write(data)?;
+                    Ok(data.len())
+                }
                 ControlFilePurpose::ExecFormatDrive
                 | ControlFilePurpose::ExecDriveCommand
-                | ControlFilePurpose::ExecDirRefresh => {
-                    self.write_buffer.as_mut().unwrap().write(data)?;
+                | ControlFilePurpose::ExecDirRefresh
+                | ControlFilePurpose::ExecPartition
+                | ControlFilePurpose::Exec1571Mode
+                | ControlFilePurpose::ExecSoftReset => {
+                    // Commands are written sequentially, so the offset is
+                    // ignored and data is always appended
+                    self.write_buffer
+                        .get_or_insert_with(Buffer::new_write)
+                        .write(data)?;
                     Ok(data.len())
                 }
                 ControlFilePurpose::GetCurDriveStatus
                 | ControlFilePurpose::GetLastDriveStatus
-                | ControlFilePurpose::GetLastErrorStatus => Err(Error::Fs1541 {
+                | ControlFilePurpose::GetLastErrorStatus
+                | ControlFilePurpose::GetDirJson
+                | ControlFilePurpose::GetDeviceInfo
+                | ControlFilePurpose::GetDriveStatus
+                | ControlFilePurpose::ExecImageDump
+                | ControlFilePurpose::ExecImageDump40 => Err(Error::Fs1541 {
                     message: "Attempt to write to readonly file".into(),
                     error: Fs1541Error::ReadOnly(self.fuse.name.clone()),
                 }),
@@ -1143,3 +2402,669 @@ match &self.native {
 
 
 */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Size of a standard 35 track .d64 image, without error information
+    const D64_SIZE: usize = 174848;
+
+    #[test]
+    fn test_cbm_read_open_name() {
+        assert_eq!(cbm_read_open_name("DATA", &CbmFileType::SEQ), "DATA,s,r");
+        assert_eq!(cbm_read_open_name("DATA", &CbmFileType::USR), "DATA,u,r");
+        assert_eq!(cbm_read_open_name("GAME", &CbmFileType::PRG), "GAME");
+        assert_eq!(cbm_read_open_name("RECS", &CbmFileType::REL), "RECS");
+        assert_eq!(
+            cbm_read_open_name("----", &CbmFileType::Unknown),
+            "----,d,r"
+        );
+
+        // And back again, as the drive finds the file
+        assert_eq!(cbm_filename_from_open_name("DATA,s,r"), "DATA");
+        assert_eq!(cbm_filename_from_open_name("1:DATA,u,r"), "DATA");
+        assert_eq!(cbm_filename_from_open_name("@0:GAME"), "GAME");
+        assert_eq!(cbm_filename_from_open_name("@:LOG,s,w"), "LOG");
+        assert_eq!(cbm_filename_from_open_name("GAME"), "GAME");
+    }
+
+    #[test]
+    fn test_drive_command() {
+        assert_eq!(drive_command("i0\n").unwrap(), "i0");
+        assert_eq!(drive_command("i0\r\n").unwrap(), "i0");
+        assert_eq!(drive_command("n:my disk,aa").unwrap(), "n:my disk,aa");
+        assert!(drive_command("").is_err());
+        assert!(drive_command("\n").is_err());
+        assert!(drive_command("  \n").is_err());
+    }
+
+    #[test]
+    fn test_format_command() {
+        assert_eq!(
+            format_command("my new disk,aa\n").unwrap(),
+            ("my new disk".to_string(), "aa".to_string())
+        );
+        assert_eq!(
+            format_command("sixteen chars!!!,01").unwrap(),
+            ("sixteen chars!!!".to_string(), "01".to_string())
+        );
+
+        // The last comma separates the ID
+        assert_eq!(
+            format_command("a,b,cd").unwrap(),
+            ("a,b".to_string(), "cd".to_string())
+        );
+
+        assert!(format_command("my disk").is_err());
+        assert!(format_command("my disk,a").is_err());
+        assert!(format_command("my disk,abc").is_err());
+        assert!(format_command(",aa").is_err());
+        assert!(format_command("seventeen chars!!,aa").is_err());
+    }
+
+    #[test]
+    fn test_block_commands() {
+        assert_eq!(cbm_block_read_command(2, 18, 0), "U1 2 0 18 0");
+        assert_eq!(cbm_block_write_command(2, 1, 20), "U2 2 0 1 20");
+        assert_eq!(cbm_buffer_pointer_command(2, 0), "B-P 2 0");
+    }
+
+    #[test]
+    fn test_memory_commands() {
+        // Addresses are sent low byte first
+        assert_eq!(cbm_memory_read_command(0x0300, 16), b"M-R\x00\x03\x10");
+        assert_eq!(cbm_memory_execute_command(0x0500), b"M-E\x00\x05");
+        assert_eq!(
+            cbm_memory_write_commands(0x1c00, &[0xa9, 0x00]),
+            vec![b"M-W\x00\x1c\x02\xa9\x00".to_vec()]
+        );
+
+        // Longer writes are split across several commands
+        let commands = cbm_memory_write_commands(0x05f0, &[0xea; 40]);
+        assert_eq!(commands.len(), 2);
+        assert_eq!(&commands[0][..6], b"M-W\xf0\x05\x20");
+        assert_eq!(commands[0].len(), 6 + MEMORY_WRITE_CHUNK_SIZE);
+        assert_eq!(commands[1], [&b"M-W\x10\x06\x08"[..], &[0xea; 8]].concat());
+    }
+
+    #[test]
+    fn test_cbm_open_name() {
+        assert_eq!(cbm_open_name(0, "GAME", false), "GAME");
+        assert_eq!(cbm_open_name(0, "GAME", true), "@:GAME");
+        assert_eq!(cbm_open_name(1, "GAME", false), "1:GAME");
+        assert_eq!(cbm_open_name(1, "GAME", true), "@1:GAME");
+    }
+
+    #[test]
+    fn test_cbm_write_open_name() {
+        assert_eq!(
+            cbm_write_open_name(0, "DATA", &CbmFileType::SEQ, false),
+            "DATA,s,w"
+        );
+        assert_eq!(
+            cbm_write_open_name(0, "DATA", &CbmFileType::USR, true),
+            "@:DATA,u,w"
+        );
+        assert_eq!(
+            cbm_write_open_name(0, "GAME", &CbmFileType::PRG, false),
+            "GAME"
+        );
+        assert_eq!(
+            cbm_write_open_name(0, "GAME", &CbmFileType::PRG, true),
+            "@:GAME"
+        );
+        assert_eq!(
+            cbm_write_open_name(1, "DATA", &CbmFileType::SEQ, true),
+            "@1:DATA,s,w"
+        );
+    }
+
+    #[test]
+    fn test_cbm_append_open_name() {
+        assert_eq!(
+            cbm_append_open_name(0, "DATA", &CbmFileType::SEQ),
+            Some("DATA,s,a".to_string())
+        );
+        assert_eq!(
+            cbm_append_open_name(0, "DATA", &CbmFileType::USR),
+            Some("DATA,u,a".to_string())
+        );
+        assert_eq!(
+            cbm_append_open_name(0, "GAME", &CbmFileType::PRG),
+            Some("GAME,p,a".to_string())
+        );
+        assert_eq!(
+            cbm_append_open_name(1, "DATA", &CbmFileType::SEQ),
+            Some("1:DATA,s,a".to_string())
+        );
+        assert_eq!(cbm_append_open_name(0, "DB", &CbmFileType::REL), None);
+    }
+
+    #[test]
+    fn test_seq_file_write() {
+        let file = |file_type| {
+            FileEntry::from_cbm_file_entry(
+                &CbmFileEntry::ValidFile {
+                    blocks: 1,
+                    filename: "data".to_string(),
+                    file_type,
+                },
+                0,
+            )
+            .unwrap()
+        };
+
+        // SEQ data is written as given, without a load address
+        let mut seq = file(CbmFileType::SEQ);
+        seq.open(libc::O_WRONLY | libc::O_TRUNC, 1024).unwrap();
+        assert!(seq.write(0, b"hello").is_ok());
+        assert!(!seq.is_append());
+        assert_eq!(seq.take_write_data(), Some((b"hello".to_vec(), true)));
+
+        // Appends start at the end of the existing file, and continue
+        // sequentially from there
+        let mut seq = file(CbmFileType::SEQ);
+        seq.open(libc::O_WRONLY | libc::O_APPEND, 1024).unwrap();
+        assert!(seq.write(254, b"more").is_ok());
+        assert!(seq.write(258, b" data").is_ok());
+        assert!(seq.write(254, b"again").is_err());
+        assert!(seq.is_append());
+        assert_eq!(seq.take_write_data(), Some((b"more data".to_vec(), false)));
+
+        // REL files can't be appended to
+        let mut rel = file(CbmFileType::REL);
+        assert!(rel.open(libc::O_WRONLY | libc::O_APPEND, 1024).is_err());
+    }
+
+    #[test]
+    fn test_creatable_file_type() {
+        assert!(matches!(
+            FuseFile::creatable_file_type("game.prg"),
+            Some(CbmFileType::PRG)
+        ));
+        assert!(matches!(
+            FuseFile::creatable_file_type("data.seq"),
+            Some(CbmFileType::SEQ)
+        ));
+        assert!(matches!(
+            FuseFile::creatable_file_type("data.usr"),
+            Some(CbmFileType::USR)
+        ));
+        assert!(FuseFile::creatable_file_type("db.rel").is_none());
+        assert!(FuseFile::creatable_file_type("file.del").is_none());
+        assert!(FuseFile::creatable_file_type("game").is_none());
+    }
+
+    #[test]
+    fn test_dir_listing_keeps_pending_writes() {
+        let mut disk_info = DiskInfo::new(0, false);
+        let entry = CbmFileEntry::ValidFile {
+            blocks: 0,
+            filename: "new".to_string(),
+            file_type: CbmFileType::SEQ,
+        };
+        let mut file = FileEntry::from_cbm_file_entry(&entry, 0).unwrap();
+        file.open(libc::O_WRONLY, 1024).unwrap();
+        file.write(0, b"data").unwrap();
+        disk_info.cbm_files.push(file);
+
+        // A file created in the mount isn't on the disk until written, so
+        // re-reading the directory keeps it, with its data
+        let listing = CbmDirListing {
+            header: CbmDiskHeader {
+                drive_number: 0,
+                name: "DISK".to_string(),
+                id: "01".to_string(),
+            },
+            files: Vec::new(),
+            blocks_free: 664,
+        };
+        disk_info.update_from_dir_listing(&listing);
+        assert_eq!(disk_info.cbm_files.len(), 1);
+        assert_eq!(
+            disk_info.cbm_files[0].take_write_data(),
+            Some((b"data".to_vec(), false))
+        );
+
+        // Once written, it's only kept if the drive lists it
+        disk_info.update_from_dir_listing(&listing);
+        assert!(disk_info.cbm_files.is_empty());
+    }
+
+    #[test]
+    fn test_d64_geometry() {
+        // The image is made up of 683 blocks
+        let blocks: usize = (1..=D64_TRACKS)
+            .map(|t| d64_sectors_per_track(t) as usize)
+            .sum();
+        assert_eq!(blocks, 683);
+        assert_eq!(blocks * D64_BLOCK_SIZE, D64_SIZE);
+
+        // Track 18 (the directory track) starts at the standard offset
+        assert_eq!(d64_offset(1, 0), 0);
+        assert_eq!(d64_offset(1, 1), D64_BLOCK_SIZE);
+        assert_eq!(d64_offset(18, 0), 0x16500);
+        assert_eq!(d64_offset(35, 16), D64_SIZE - D64_BLOCK_SIZE);
+    }
+
+    #[test]
+    fn test_d64_geometry_tracks() {
+        let standard = D64Geometry::Standard;
+        let extended = D64Geometry::Extended;
+
+        // Sector counts per speed zone
+        for (track, sectors) in [(1, 21), (17, 21), (18, 19), (24, 19), (25, 18), (30, 18)] {
+            assert_eq!(standard.sectors_per_track(track), Some(sectors));
+            assert_eq!(extended.sectors_per_track(track), Some(sectors));
+        }
+        assert_eq!(standard.sectors_per_track(35), Some(17));
+        assert_eq!(standard.sectors_per_track(36), None);
+        assert_eq!(extended.sectors_per_track(36), Some(17));
+        assert_eq!(extended.sectors_per_track(40), Some(17));
+        assert_eq!(extended.sectors_per_track(41), None);
+        assert_eq!(standard.sectors_per_track(0), None);
+
+        assert_eq!(standard.blocks(), 683);
+        assert_eq!(standard.image_size(), D64_SIZE);
+        assert_eq!(extended.blocks(), 768);
+        assert_eq!(extended.image_size(), 196608);
+        assert_eq!(d64_offset(40, 16) + D64_BLOCK_SIZE, extended.image_size());
+
+        // Blocks outside the geometry are rejected
+        assert!(standard.validate_block(35, 16).is_ok());
+        assert!(standard.validate_block(35, 17).is_err());
+        assert!(standard.validate_block(36, 0).is_err());
+        assert!(extended.validate_block(36, 0).is_ok());
+        assert!(extended.validate_block(18, 19).is_err());
+        assert!(extended.validate_block(0, 0).is_err());
+    }
+
+    #[test]
+    fn test_partition_command() {
+        assert_eq!(partition_command("/\n").unwrap(), PartitionCommand::Root);
+        assert_eq!(
+            partition_command("games").unwrap(),
+            PartitionCommand::Select("games".into())
+        );
+        let create = partition_command("games,41,120\n").unwrap();
+        assert_eq!(
+            create,
+            PartitionCommand::Create {
+                name: "games".into(),
+                start_track: 41,
+                blocks: 120,
+            }
+        );
+        assert_eq!(
+            create.dos_commands(),
+            vec![
+                b"/0:GAMES,\x29\x00\x78\x00,C".to_vec(),
+                b"/0:GAMES".to_vec()
+            ]
+        );
+
+        // Partitions must be whole tracks, on the disk, avoiding track 40
+        assert!(partition_command("games,41,100").is_err());
+        assert!(partition_command("games,0,40").is_err());
+        assert!(partition_command("games,40,40").is_err());
+        assert!(partition_command("games,39,80").is_err());
+        assert!(partition_command("games,80,80").is_err());
+        assert!(partition_command("games,41").is_err());
+        assert!(partition_command("a:b").is_err());
+    }
+
+    #[test]
+    fn test_mode_1571_command() {
+        assert!(mode_1571_command("1571\n").unwrap());
+        assert!(!mode_1571_command("1541").unwrap());
+        assert!(mode_1571_command("1581").is_err());
+        assert!(mode_1571_command("").is_err());
+        assert_eq!(
+            DriveXattr::Mode1571(true).value(),
+            "1571 (double-sided)".to_string()
+        );
+    }
+
+    #[test]
+    fn test_cbm_filenames() {
+        assert!(validate_cbm_filename("game").is_ok());
+        assert!(validate_cbm_filename("sixteen chars ok").is_ok());
+        assert!(validate_cbm_filename("").is_err());
+        assert!(validate_cbm_filename("seventeen chars!!").is_err());
+        assert!(validate_cbm_filename("a:b").is_err());
+        assert!(validate_cbm_filename("a=b").is_err());
+        assert!(validate_cbm_filename("wild*").is_err());
+
+        assert_eq!(cbm_name_from_fuse("game.prg", &CbmFileType::PRG), "game");
+        assert_eq!(cbm_name_from_fuse("notes.seq", &CbmFileType::SEQ), "notes");
+        assert_eq!(
+            cbm_name_from_fuse("notes.seq", &CbmFileType::PRG),
+            "notes.seq"
+        );
+        assert_eq!(cbm_name_from_fuse("raw", &CbmFileType::Unknown), "raw");
+        assert_eq!(cbm_name_from_fuse("raw.del", &CbmFileType::Unknown), "raw");
+        assert_eq!(
+            cbm_name_from_fuse("raw.prg.del", &CbmFileType::Unknown),
+            "raw.prg"
+        );
+        assert_eq!(
+            cbm_name_from_fuse("game%A0%A0.prg", &CbmFileType::PRG),
+            "game\u{a0}\u{a0}"
+        );
+
+        assert_eq!(cbm_rename_command(0, "old", "new"), "R0:new=old");
+        assert_eq!(cbm_rename_command(1, "old", "new"), "R1:new=old");
+        assert_eq!(cbm_scratch_command(0, "game"), "S0:game");
+        assert_eq!(cbm_format_command(0, "my disk", "ab"), "N0:my disk,ab");
+    }
+
+    #[test]
+    fn test_escape_cbm_name() {
+        // Shifted spaces, reverse video on/off and a graphics character
+        for name in [
+            "game\u{a0}\u{a0}",
+            "\u{12}title\u{92}",
+            "\u{c1}\u{1c}x",
+            "50% off",
+            "a/b",
+            "%41",
+            "plain name",
+        ] {
+            let escaped = escape_cbm_name(name);
+            assert!(escaped.chars().all(|c| (' '..='~').contains(&c)));
+            assert!(!escaped.contains('/'));
+            assert_eq!(unescape_cbm_name(&escaped), name);
+        }
+        assert_eq!(escape_cbm_name("game\u{a0}"), "game%A0");
+        assert_eq!(escape_cbm_name("\u{12}rvs\u{92}"), "%12rvs%92");
+
+        // Stray '%'s, as a user might type, are left alone
+        assert_eq!(unescape_cbm_name("50% off"), "50% off");
+        assert_eq!(unescape_cbm_name("100%"), "100%");
+        assert_eq!(unescape_cbm_name("%4"), "%4");
+        assert_eq!(unescape_cbm_name("%+1a"), "%+1a");
+    }
+
+    #[test]
+    fn test_d64_image() {
+        assert_eq!(d64_image_geometry(D64_SIZE).unwrap(), D64Geometry::Standard);
+        assert_eq!(
+            d64_image_geometry(D64_SIZE + 683).unwrap(),
+            D64Geometry::Standard
+        );
+        assert_eq!(d64_image_geometry(196608).unwrap(), D64Geometry::Extended);
+        assert_eq!(
+            d64_image_geometry(196608 + 768).unwrap(),
+            D64Geometry::Extended
+        );
+        assert!(d64_image_geometry(D64_SIZE - 1).is_err());
+        assert!(d64_image_geometry(0).is_err());
+
+        // Header name and ID are padded with shifted spaces in the BAM
+        let mut image = vec![0; D64_SIZE];
+        let bam = d64_offset(18, 0);
+        image[bam + 0x90..bam + 0xa0].fill(0xa0);
+        image[bam + 0x90..bam + 0x97].copy_from_slice(b"MY DISK");
+        image[bam + 0xa2..bam + 0xa4].copy_from_slice(b"AB");
+        assert_eq!(
+            d64_header(&image),
+            ("my disk".to_string(), "ab".to_string())
+        );
+    }
+
+    #[test]
+    fn test_ascii_to_petscii() {
+        assert_eq!(ascii_to_petscii("game,s,r"), b"GAME,S,R");
+        assert_eq!(ascii_to_petscii("@:data 2"), b"@:DATA 2");
+        assert_eq!(
+            padded_petscii_to_ascii(&ascii_to_petscii("my file")),
+            "my file"
+        );
+    }
+
+    #[test]
+    fn test_disk_header_matches() {
+        assert!(disk_header_matches("my disk", "ab", "my disk", "ab"));
+        assert!(disk_header_matches("MY DISK  ", "AB", "my disk", "ab"));
+        assert!(disk_header_matches("my disk!", "ab", "my disk?", "ab"));
+        assert!(!disk_header_matches("my disk", "ab", "my disk", "ac"));
+        assert!(!disk_header_matches("my disk", "ab", "other disk", "ab"));
+        assert!(!disk_header_matches("my disk", "ab", "my dis", "ab"));
+    }
+
+    #[test]
+    fn test_image_restore_write() {
+        let mut file =
+            FileEntry::from_control_file_purpose(ControlFilePurpose::ExecImageRestore, 5);
+
+        // Binary data with newlines isn't treated as commands
+        assert!(file.write(0, b"\x01\n\x02\n").is_ok());
+        assert!(file.take_control_commands().is_empty());
+        assert!(file.take_pending_control_command().is_none());
+        let (data, _) = file.take_write_data().unwrap();
+        assert_eq!(data, b"\x01\n\x02\n");
+
+        // Images larger than the largest valid size are rejected
+        let too_big = vec![0; 196608 + 769];
+        assert!(file.write(0, &too_big).is_err());
+    }
+
+    #[test]
+    fn test_write_buffer_limit() {
+        let mut buffer = Buffer::new_write_limited(4);
+        assert_eq!(buffer.write(b"abc").unwrap(), 3);
+        assert_eq!(buffer.write(b"d").unwrap(), 1);
+        let e = buffer.write(b"e").unwrap_err();
+        assert_eq!(e.to_fuse_reply_error(), libc::ENOSPC);
+        assert_eq!(buffer.len(), 4);
+
+        // Unlimited buffers grow as needed
+        let mut buffer = Buffer::new_write();
+        assert!(buffer.write(&[0; 200000]).is_ok());
+    }
+
+    #[test]
+    fn test_evict_file_caches() {
+        let cached = |ino: u64, len: usize| {
+            let mut file =
+                FileEntry::from_control_file_purpose(ControlFilePurpose::ExecImageDump, ino);
+            let mut cache = FileCache::new();
+            cache.set_data_complete(&vec![0; len]);
+            file.cache = Some(cache);
+            file
+        };
+        let mut a = cached(10, 100);
+        let mut b = cached(11, 100);
+        let mut c = cached(12, 100);
+
+        // A read in progress is never evicted
+        let mut d = FileEntry::from_control_file_purpose(ControlFilePurpose::ExecImageDump, 13);
+        let mut cache = FileCache::new();
+        cache.add_chunk(&[0; 100], false);
+        d.cache = Some(cache);
+
+        // Serving a from its cache makes b the least recently read
+        let fresh = Duration::from_secs(60);
+        assert!(a
+            .cache
+            .as_ref()
+            .unwrap()
+            .get_data_complete_and_fresh(fresh)
+            .is_some());
+
+        let mut files = vec![&mut a, &mut b, &mut c, &mut d];
+        assert_eq!(evict_file_caches(&mut files, 400, 12), 0);
+        assert_eq!(evict_file_caches(&mut files, 300, 12), 100);
+        assert!(files[1].cache.is_none());
+
+        // c is kept, and d is still being read, so only a can go
+        assert_eq!(evict_file_caches(&mut files, 100, 12), 100);
+        assert!(files[0].cache.is_none());
+        assert!(files[2].cache.is_some());
+        assert!(files[3].cache.is_some());
+    }
+
+    #[test]
+    fn test_file_cache_cancel_read() {
+        // Cancelling a read in progress sets its operation's token
+        let token = Arc::new(AtomicBool::new(false));
+        let cache = FileCache::with_cancel_token(token.clone());
+        assert!(!cache.is_cancelled());
+        cache.cancel_read();
+        assert!(token.load(Ordering::Relaxed));
+        assert!(cache.is_cancelled());
+
+        // But not once the read has completed
+        let token = Arc::new(AtomicBool::new(false));
+        let mut cache = FileCache::with_cancel_token(token.clone());
+        cache.set_data_complete(&[0; 10]);
+        cache.cancel_read();
+        assert!(!token.load(Ordering::Relaxed));
+        assert!(!cache.is_cancelled());
+    }
+
+    #[test]
+    fn test_read_size_plausible() {
+        // A 2 block file holds between 255 and 508 bytes
+        assert!(read_size_plausible(2, 255, 0));
+        assert!(read_size_plausible(2, 508, 0));
+        assert!(!read_size_plausible(2, 509, 0));
+        assert!(!read_size_plausible(2, 100, 0));
+
+        // Tolerance allows for a block either way
+        assert!(read_size_plausible(2, 100, 1));
+        assert!(read_size_plausible(2, 762, 1));
+        assert!(!read_size_plausible(2, 763, 1));
+
+        // Gross truncation is caught
+        assert!(!read_size_plausible(100, 254, 1));
+    }
+
+    #[test]
+    fn test_control_file_write_split() {
+        let mut file = FileEntry::from_control_file_purpose(ControlFilePurpose::ExecFormatDrive, 5);
+
+        // A command split across two writes isn't returned until complete
+        assert_eq!(file.write(0, b"my new d").unwrap(), 8);
+        assert!(file.take_control_commands().is_empty());
+        file.write(8, b"isk,aa\n").unwrap();
+        assert_eq!(file.take_control_commands(), vec!["my new disk,aa"]);
+
+        // The buffer is reset after the command is taken
+        assert!(file.take_control_commands().is_empty());
+        assert!(file.take_pending_control_command().is_none());
+
+        // A trailing partial command is left until flush/release
+        file.write(15, b"i\nv").unwrap();
+        assert_eq!(file.take_control_commands(), vec!["i"]);
+        assert_eq!(file.take_pending_control_command().as_deref(), Some("v"));
+        assert!(file.take_pending_control_command().is_none());
+    }
+
+    #[test]
+    fn test_read_only_control_file_write() {
+        let mut file =
+            FileEntry::from_control_file_purpose(ControlFilePurpose::GetCurDriveStatus, 5);
+        assert!(file.write(0, b"i\n").is_err());
+        assert!(file.take_pending_control_command().is_none());
+    }
+
+    #[test]
+    fn test_info_files() {
+        let names = |info_files| {
+            DiskInfo::new(0, info_files)
+                .control_files
+                .iter()
+                .map(|file| file.fuse.name.clone())
+                .collect::<Vec<_>>()
+        };
+        assert!(!names(false).iter().any(|name| name == ".device_info"));
+        let names = names(true);
+        assert!(names.iter().any(|name| name == ".device_info"));
+        assert!(names.iter().any(|name| name == ".drive_status"));
+
+        let mut file = FileEntry::from_control_file_purpose(ControlFilePurpose::GetDeviceInfo, 5);
+        assert!(file.write(0, b"x\n").is_err());
+    }
+
+    #[test]
+    fn test_prg_load_address() {
+        assert_eq!(prg_load_address(&[0x01, 0x08, 0x0b, 0x08]), Some(0x0801));
+        assert_eq!(prg_load_address(&[0x00, 0xc0]), Some(0xc000));
+        assert_eq!(prg_load_address(&[0x01]), None);
+        assert_eq!(prg_load_address(&[]), None);
+        assert_eq!(FileXattr::LoadAddress(0x0801).value(), "$0801");
+    }
+
+    #[test]
+    fn test_unknown_file_type_names() {
+        let entry = |filename: &str, file_type: CbmFileType| {
+            FileEntry::from_cbm_file_entry(
+                &CbmFileEntry::ValidFile {
+                    blocks: 1,
+                    filename: filename.to_string(),
+                    file_type,
+                },
+                0,
+            )
+            .unwrap()
+        };
+
+        // A DEL file, which rs1541 parses as Unknown, is given a suffix, so
+        // it can't collide with a file of another type
+        let prg = entry("game", CbmFileType::PRG);
+        let mut del = entry("game.prg", CbmFileType::Unknown);
+        assert_eq!(prg.fuse.name, "game.prg");
+        assert_eq!(del.fuse.name, "game.prg.del");
+        assert_eq!(
+            cbm_name_from_fuse(&del.fuse.name, &CbmFileType::Unknown),
+            "game.prg"
+        );
+
+        // It can't be opened, for reading or writing
+        for flags in [libc::O_RDONLY, libc::O_WRONLY] {
+            let error = del.open(flags, 1024).unwrap_err();
+            assert_eq!(error.to_fuse_reply_error(), libc::EACCES);
+        }
+
+        // The type is reported via an xattr
+        assert!(FileXattr::from_file_entry(&del)
+            .iter()
+            .any(|xattr| xattr.name() == "user.file.cbm_type" && xattr.value() == "DEL"));
+        assert!(FileXattr::from_file_entry(&prg)
+            .iter()
+            .any(|xattr| xattr.name() == "user.file.cbm_type" && xattr.value() == "PRG"));
+    }
+
+    #[test]
+    fn test_dir_listing_json() {
+        let listing = DirListingJson {
+            header: DirHeaderJson {
+                name: "my disk".to_string(),
+                id: "ab".to_string(),
+            },
+            files: vec![DirFileJson {
+                blocks: 3,
+                name: "hello".to_string(),
+                file_type: cbm_file_type_name(&CbmFileType::PRG).to_string(),
+            }],
+            blocks_free: 661,
+        };
+        let json = listing.to_json().unwrap();
+        assert_eq!(json.last(), Some(&b'\n'));
+
+        let value: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "header": { "name": "my disk", "id": "ab" },
+                "files": [{ "blocks": 3, "name": "hello", "file_type": "PRG" }],
+                "blocks_free": 661,
+            })
+        );
+
+        // No listing until the directory has been read
+        assert!(DiskInfo::new(0, false).dir_json().is_none());
+    }
+}
@@ -1,16 +1,21 @@
 use crate::args::get_args;
-use crate::file::{FileEntry, FileEntryType, RwType, XattrOps};
+use crate::bg::OpOutcome;
+use crate::file::{ControlFilePurpose, FileEntry, FileEntryType, RwType, XattrOps, D64_BLOCK_SIZE};
 use crate::locking_section;
 use crate::mount::Mount;
 use crate::{Error, Fs1541Error};
+use fs1541::validate::validate_fuse_filename;
 
 use either::Either::{self, Right};
+use flume::{Receiver, RecvTimeoutError};
 use fuser::{
-    FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry,
-    ReplyOpen, ReplyXattr, Request, FUSE_ROOT_ID,
+    FileAttr, FileType, Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty,
+    ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request, FUSE_ROOT_ID,
 };
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
+use rs1541::{CbmErrorNumberOk, CbmStatus};
+use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::sync::Arc;
 use std::thread::sleep;
@@ -66,6 +71,9 @@ struct Counts {
 
     /// Equivalent number of times to check for file reads
     file_check: u32,
+
+    /// Equivalent number of times to check for disk image reads
+    image_check: u32,
 }
 
 impl Counts {
@@ -84,9 +92,17 @@ impl Counts {
         }
         let file_check = file_check as u32;
 
+        let image_check = timer.image_read.as_millis() / timer.file_read_sleep.as_millis();
+        trace!("FuserMount::Counts image_check = {image_check}");
+        if image_check > u32::MAX as u128 {
+            panic!("FuserMount::Counts::image_check is too large");
+        }
+        let image_check = image_check as u32;
+
         Counts {
             dir_check,
             file_check,
+            image_check,
         }
     }
 }
@@ -107,6 +123,9 @@ struct Timers {
     /// File equivalent of dir_read
     file_read: Duration,
 
+    /// How long to wait for a disk image to be read
+    image_read: Duration,
+
     /// How long to sleep between reads of the directory contents cache, to
     /// see if it's been updated.  Used in conjunction with Counts::dir_check
     dir_read_sleep: Duration,
@@ -122,6 +141,7 @@ impl Timers {
             file_cache: Duration::from_secs(get_args().file_cache_expiry_secs),
             dir_read: Duration::from_secs(get_args().dir_reread_timeout_secs),
             file_read: Duration::from_secs(get_args().file_reread_timeout_secs),
+            image_read: Duration::from_secs(get_args().image_read_timeout_secs),
             dir_read_sleep: Duration::from_millis(get_args().dir_read_sleep_ms),
             file_read_sleep: Duration::from_millis(get_args().file_read_sleep_ms),
         }
@@ -142,6 +162,11 @@ pub struct FuserMount {
 
     /// TTLs for the filesyste,
     ttls: TTLs,
+
+    /// The inodes of in flight reads, by the unique ID of the FUSE request
+    /// waiting for them.  The background operation serving each read can be
+    /// cancelled via the inode's file cache.
+    in_flight: HashMap<u64, u64>,
 }
 
 impl FuserMount {
@@ -155,6 +180,7 @@ impl FuserMount {
             timers,
             counts,
             ttls,
+            in_flight: HashMap::new(),
         }
     }
 }
@@ -170,11 +196,13 @@ impl Filesystem for FuserMount {
     fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
         trace!("FuserMount::lookup");
 
-        // Convert OsStr to String
-        let name = match name.to_str() {
-            Some(n) => n,
-            None => {
-                reply.error(libc::ENOENT);
+        // Convert OsStr to String, rejecting any names containing NUL or
+        // other control bytes
+        let name = match validate_fuse_filename(name) {
+            Ok(n) => n,
+            Err(e) => {
+                debug!("Rejecting lookup of invalid filename: {}", e);
+                reply.error(e.to_fuse_reply_error());
                 return;
             }
         };
@@ -190,7 +218,7 @@ impl Filesystem for FuserMount {
         // The first step is to find all the files for this directory
 
         // Start of locking section
-        let file = locking_section!("Read", "Mount", {
+        let (file, generation) = locking_section!("Read", "Mount", {
             let mount = self.mount.read();
 
             let file_entries = if parent == FUSE_ROOT_ID {
@@ -240,7 +268,7 @@ impl Filesystem for FuserMount {
                     "File found for fuse filename {name} inode {}",
                     file.fuse.ino
                 );
-                file.clone()
+                (file.clone(), mount.inode_generation(file.fuse.ino))
             } else {
                 trace!("File not found for fuse filename name {name}");
                 reply.error(libc::ENOENT);
@@ -257,7 +285,7 @@ impl Filesystem for FuserMount {
         };
 
         // Reply with the inode
-        reply.entry(ttl, &FileAttr::from(file), 0);
+        reply.entry(ttl, &FileAttr::from(file), generation);
 
         return;
     }
@@ -313,8 +341,8 @@ impl Filesystem for FuserMount {
         trace!("FuserMount::readdir");
 
         // Now, decide whether we want to provide a directory listing of the
-        // files on a disk, or, if we have 2 drives, the two directories (one
-        // for each drive)
+        // files on a disk, or, if we have multiple drives, a directory for
+        // each drive
 
         // Left is file_entries, right is the drive  we want to read
 
@@ -474,12 +502,12 @@ impl Filesystem for FuserMount {
                 // Root directory xattrs
 
                 // Add the drive xattrs first
-                let mut listxattr = XattrOps::listxattr_from_vec(mount.drive_xattrs());
+                let mut listxattr = XattrOps::listxattr_from_vec(&mount.drive_xattrs());
 
                 if mount.num_drives() == 1 {
                     // As we only have 1 drive, we expose the disk
                     // xattrs on the root as well
-                    listxattr.extend(XattrOps::listxattr_from_vec(mount.disk_xattrs(0).into()));
+                    listxattr.extend(XattrOps::listxattr_from_vec(&mount.disk_xattrs(0)));
                 }
 
                 listxattr
@@ -494,9 +522,8 @@ impl Filesystem for FuserMount {
                         // This is for a directory type so add the special dir
                         // xattrs (which were created when processing the
                         // CbmDirListing)
-                        listxattr.extend(XattrOps::listxattr_from_vec(
-                            mount.disk_xattrs(drive_num).into(),
-                        ));
+                        listxattr
+                            .extend(XattrOps::listxattr_from_vec(&mount.disk_xattrs(drive_num)));
                     }
 
                     listxattr
@@ -557,9 +584,9 @@ impl Filesystem for FuserMount {
             if ino == FUSE_ROOT_ID {
                 // The query is for the root directory, but it might be
                 // for the drive, or, if num_drives is 1, for the disk.
-                XattrOps::getxattr_from_vec(mount.drive_xattrs(), name_str).or_else(|| {
+                XattrOps::getxattr_from_vec(&mount.drive_xattrs(), name_str).or_else(|| {
                     if mount.num_drives() == 1 {
-                        XattrOps::getxattr_from_vec(mount.disk_xattrs(0).into(), name_str)
+                        XattrOps::getxattr_from_vec(&mount.disk_xattrs(0), name_str)
                     } else {
                         None
                     }
@@ -570,11 +597,10 @@ impl Filesystem for FuserMount {
                 // xattr valiue
                 if let Some(entry) = mount.file_by_inode(ino) {
                     match entry.native {
-                        FileEntryType::Directory(drive_num) => XattrOps::getxattr_from_vec(
-                            mount.disk_xattrs(drive_num).into(),
-                            name_str,
-                        )
-                        .or_else(|| entry.getxattr(name_str)),
+                        FileEntryType::Directory(drive_num) => {
+                            XattrOps::getxattr_from_vec(&mount.disk_xattrs(drive_num), name_str)
+                                .or_else(|| entry.getxattr(name_str))
+                        }
                         _ => entry.getxattr(name_str),
                     }
                 } else {
@@ -609,7 +635,7 @@ impl Filesystem for FuserMount {
 
     fn read(
         &mut self,
-        _req: &Request,
+        req: &Request,
         ino: u64,
         _fh: u64,
         offset: i64,
@@ -625,7 +651,7 @@ impl Filesystem for FuserMount {
         // a minty-fresh cached version
 
         // Start of locking section
-        let data = locking_section!("Read", "Mount", {
+        let (data, is_image) = locking_section!("Read", "Mount", {
             let mount = self.mount.read();
 
             // Find the file
@@ -645,15 +671,8 @@ impl Filesystem for FuserMount {
 
             trace!("Found file: {}", file.fuse.name);
 
-            // If a control file, check it supports read
-            if let FileEntryType::ControlFile(purpose) = &file.native {
-                if purpose.rw_type() != RwType::Write {
-                    purpose.read_static()
-                } else {
-                    reply.error(libc::EACCES);
-                    return;
-                }
-            } else {
+            // Get any cached copy of the file's contents
+            let cached = || {
                 file.cache
                     .as_ref()
                     .and_then(|cache| cache.get_data_complete_and_fresh(self.timers.file_cache))
@@ -662,6 +681,27 @@ impl Filesystem for FuserMount {
                         trace!("No cache");
                         None
                     })
+            };
+
+            // If a control file, check it supports read
+            if let FileEntryType::ControlFile(purpose) = &file.native {
+                if purpose.is_image_dump() {
+                    // The disk image is read from the drive
+                    (cached(), true)
+                } else if purpose.rw_type() != RwType::Write {
+                    // Control files never need reading from the drive
+                    let data = purpose
+                        .read_static()
+                        .or_else(|| mount.control_file_status(purpose.purpose()))
+                        .or_else(|| mount.control_file_dir_json(ino))
+                        .unwrap_or_default();
+                    (Some(data), false)
+                } else {
+                    reply.error(libc::EACCES);
+                    return;
+                }
+            } else {
+                (cached(), false)
             }
         });
 
@@ -682,14 +722,30 @@ impl Filesystem for FuserMount {
                         return;
                     }
                 };
+
+                // Track the read, so it can be cancelled if this request is
+                // interrupted
+                self.in_flight.insert(req.unique(), ino);
             });
             // End of locking section
 
-            // Now wait for it to complete
-            match self.wait_for_file_read(ino) {
-                Ok(data) => data,
+            // Now wait for it to complete - reading an image takes much
+            // longer than a file
+            let (max_count, timeout) = if is_image {
+                (self.counts.image_check, self.timers.image_read)
+            } else {
+                (self.counts.file_check, self.timers.file_read)
+            };
+            match self.wait_for_file_read(ino, max_count, timeout) {
+                Ok(data) => {
+                    self.in_flight.remove(&req.unique());
+                    data
+                }
                 Err(e) => {
+                    // Nobody is waiting for the read any more, so stop it
+                    // tying up the bus
                     warn!("File read as requested by FUSE failed to complete");
+                    self.interrupt(req, req.unique());
                     reply.error(e.to_fuse_reply_error());
                     return;
                 }
@@ -712,71 +768,94 @@ impl Filesystem for FuserMount {
         return;
     }
 
-    /*
-        fn write(
-            &mut self,
-            _req: &Request<'_>,
-            ino: u64,
-            _fh: u64,
-            offset: i64,
-            data: &[u8],
-            write_flags: u32,
-            flags: i32,
-            lock_owner: Option<u64>,
-            reply: ReplyWrite,
-        ) {
-            debug!("FuserMount::write");
-            if ino == FUSE_ROOT_ID {
-                reply.error(libc::EISDIR);
+    /// Writes to control files and CBM files are buffered.  For control
+    /// files any complete (newline terminated) commands are executed
+    /// immediately, and a trailing partial command is executed on
+    /// flush/release.  CBM files are written to the drive on flush/release.
+    fn write(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        debug!("FuserMount::write");
+        if ino == FUSE_ROOT_ID {
+            reply.error(libc::EISDIR);
+            return;
+        }
+
+        locking_section!("Write", "Mount", {
+            let mut mount = self.mount.write();
+
+            if !mount.read_write() {
+                reply.error(libc::EROFS);
                 return;
             }
 
-            locking_section!("Write", "Mount", {
-                let mut mount = self.mount.write();
+            // Find the matching file and buffer the data
+            let Some(file) = mount.file_by_inode_mut(ino) else {
+                reply.error(libc::ENOENT);
+                return;
+            };
+            let native = file.native.clone();
+            trace!("Buffering {} bytes at offset {}", data.len(), offset);
+            let commands = match file.write(offset as u64, data) {
+                Ok(_) => file.take_control_commands(),
+                Err(e) => {
+                    reply.error(e.to_fuse_reply_error());
+                    return;
+                }
+            };
 
-                // Find the matching file
-                let file_entry = mount.files().iter().find(|x| x.fuse.ino == ino);
-                if let Some(file_entry) = file_entry {
-                    match file_entry.native.clone() {
-                        FileEntryType::ControlFile(control_file) => {
-                            match control_file.write(
-                                &mut mount,
-                                offset,
-                                data,
-                                write_flags,
-                                flags,
-                                lock_owner,
-                            ) {
-                                Ok(size) => {
-                                    reply.written(size);
-                                }
-                                Err(_) => {
-                                    reply.error(libc::EROFS);
-                                }
-                            }
-                        }
-                        _ => {
-                            reply.error(libc::EROFS);
-                        }
-                    }
+            // CBM files are written to the drive on flush/release
+            let FileEntryType::ControlFile(control_file) = native else {
+                reply.written(data.len() as u32);
+                return;
+            };
+
+            // Execute any complete commands
+            for command in commands {
+                if let Err(e) = control_file.execute(&mut mount, &command) {
+                    warn!("Control file command failed: {}", e);
+                    reply.error(e.to_fuse_reply_error());
+                    return;
                 }
-            });
-        }
-    */
+            }
+        });
+
+        reply.written(data.len() as u32);
+    }
 
     // Very basic open implementation
-    fn open(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
         debug!("FuserMount::open");
         if ino == FUSE_ROOT_ID {
             reply.error(libc::EISDIR);
             return;
         }
 
-        locking_section!("Read", "Mount", {
-            let mount = self.mount.read();
+        let current_status = locking_section!("Write", "Mount", {
+            let mut mount = self.mount.write();
+
+            if flags & libc::O_ACCMODE != libc::O_RDONLY && !mount.read_write() {
+                debug!("Rejecting open for write on read-only mount {ino}");
+                reply.error(libc::EROFS);
+                return;
+            }
+
+            // CBM files are written to the drive they're on
+            let write_protected = mount
+                .get_drive_num_for_file(ino)
+                .is_some_and(|drive_num| mount.write_protected(drive_num));
 
             // Find the matching file
-            let Some(file) = mount.file_by_inode(ino) else {
+            let Some(file) = mount.file_by_inode_mut(ino) else {
                 debug!("Couldn't find file {ino}");
                 reply.error(libc::ENOENT);
                 return;
@@ -788,17 +867,247 @@ impl Filesystem for FuserMount {
                     reply.error(libc::ENOENT);
                     return;
                 }
-                _ => (),
+                // Sets CBM files up for writing, if opened for write
+                FileEntryType::CbmFile(_) => {
+                    if flags & libc::O_ACCMODE != libc::O_RDONLY && write_protected {
+                        warn!("Rejecting open for write of {ino} as the disk is write protected");
+                        reply.error(libc::EROFS);
+                        return;
+                    }
+                    if let Err(e) = file.open(flags, get_args().max_write_buffer_bytes) {
+                        debug!("Failed to open file {ino}: {e}");
+                        reply.error(e.to_fuse_reply_error());
+                        return;
+                    }
+                    false
+                }
+                FileEntryType::ControlFile(ref control_file) => matches!(
+                    control_file.purpose(),
+                    ControlFilePurpose::GetCurDriveStatus | ControlFilePurpose::GetDriveStatus
+                ),
+                _ => false,
             }
         });
 
+        // The current status is fetched from the drive on each open.  Its
+        // length isn't known until then, so the kernel is told to ignore the
+        // file's size when reading it.
+        let open_flags = if current_status {
+            if let Err(e) = self.refresh_drive_status() {
+                warn!("Failed to get current drive status: {}", e);
+                reply.error(e.to_fuse_reply_error());
+                return;
+            }
+            fuser::consts::FOPEN_DIRECT_IO
+        } else {
+            0
+        };
+
         // If we got here, say OK!
         trace!("opened OK {ino}");
-        reply.opened(ino, 0);
+        reply.opened(ino, open_flags);
 
         return;
     }
 
+    fn rename(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        _flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        debug!("FuserMount::rename");
+        let (name, newname) = match (
+            validate_fuse_filename(name),
+            validate_fuse_filename(newname),
+        ) {
+            (Ok(name), Ok(newname)) => (name.to_string(), newname.to_string()),
+            (Err(e), _) | (_, Err(e)) => {
+                reply.error(e.to_fuse_reply_error());
+                return;
+            }
+        };
+
+        let drive_num = locking_section!("Read", "Mount", {
+            let mount = self.mount.read();
+            if !mount.read_write() {
+                reply.error(libc::EROFS);
+                return;
+            }
+
+            // Files can't move between drives
+            let Some(drive_num) = mount.get_drive_num_for_dir(parent) else {
+                reply.error(libc::ENOENT);
+                return;
+            };
+            if mount.write_protected(drive_num) {
+                warn!("Not renaming {name} as the disk is write protected");
+                reply.error(libc::EROFS);
+                return;
+            }
+            if mount.get_drive_num_for_dir(newparent) != Some(drive_num) {
+                reply.error(libc::EXDEV);
+                return;
+            }
+            drive_num
+        });
+
+        let result = self
+            .drive_op_status(|mount| mount.rename_file_sync(drive_num, &name, &newname))
+            .and_then(|status| {
+                if status.is_ok() == CbmErrorNumberOk::Ok {
+                    Ok(())
+                } else {
+                    Err(Error::Fs1541 {
+                        message: format!("Failed to rename {}", name),
+                        error: Fs1541Error::from(&status),
+                    })
+                }
+            });
+        match result {
+            Ok(()) => {
+                info!("Renamed {name} to {newname}");
+                reply.ok();
+            }
+            Err(e) => {
+                warn!("Failed to rename {name} to {newname}: {e}");
+                reply.error(e.to_fuse_reply_error());
+            }
+        }
+    }
+
+    /// Creates a new CBM file, whose type is given by its suffix.  As for
+    /// writes to an existing file, the data is buffered and written to the
+    /// drive on flush/release.
+    fn create(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        debug!("FuserMount::create");
+        let name = match validate_fuse_filename(name) {
+            Ok(name) => name.to_string(),
+            Err(e) => {
+                reply.error(e.to_fuse_reply_error());
+                return;
+            }
+        };
+
+        let (file, generation) = locking_section!("Write", "Mount", {
+            let mut mount = self.mount.write();
+            if !mount.read_write() {
+                reply.error(libc::EROFS);
+                return;
+            }
+            let Some(drive_num) = mount.get_drive_num_for_dir(parent) else {
+                reply.error(libc::ENOENT);
+                return;
+            };
+            if mount.write_protected(drive_num) {
+                warn!("Not creating {name} as the disk is write protected");
+                reply.error(libc::EROFS);
+                return;
+            }
+            if mount
+                .get_drive_files(drive_num)
+                .iter()
+                .any(|file| file.fuse.name == name)
+            {
+                reply.error(libc::EEXIST);
+                return;
+            }
+
+            match mount.create_file(drive_num, &name, flags, get_args().max_write_buffer_bytes) {
+                Ok(file) => {
+                    let generation = mount.inode_generation(file.inode());
+                    (file, generation)
+                }
+                Err(e) => {
+                    warn!("Failed to create {name}: {e}");
+                    reply.error(e.to_fuse_reply_error());
+                    return;
+                }
+            }
+        });
+
+        info!("Created {name} as inode {}", file.inode());
+        reply.created(
+            &self.ttls.file_lookup,
+            &FileAttr::from(&file),
+            generation,
+            file.inode(),
+            0,
+        );
+    }
+
+    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        debug!("FuserMount::unlink");
+        let name = match validate_fuse_filename(name) {
+            Ok(name) => name.to_string(),
+            Err(e) => {
+                reply.error(e.to_fuse_reply_error());
+                return;
+            }
+        };
+
+        let drive_num = locking_section!("Read", "Mount", {
+            let mount = self.mount.read();
+            if !mount.read_write() {
+                reply.error(libc::EROFS);
+                return;
+            }
+            let Some(drive_num) = mount.get_drive_num_for_dir(parent) else {
+                reply.error(libc::ENOENT);
+                return;
+            };
+            if mount.write_protected(drive_num) {
+                warn!("Not deleting {name} as the disk is write protected");
+                reply.error(libc::EROFS);
+                return;
+            }
+            drive_num
+        });
+
+        // The drive reports 01,FILES SCRATCHED even if nothing matched, with
+        // the number scratched in the track field, so check that too
+        let result = self
+            .drive_op_status(|mount| mount.delete_file_sync(drive_num, &name))
+            .and_then(|status| {
+                if status.is_ok() != CbmErrorNumberOk::Ok {
+                    Err(Error::Fs1541 {
+                        message: format!("Failed to delete {}", name),
+                        error: Fs1541Error::from(&status),
+                    })
+                } else if status.number == 1 && status.track == 0 {
+                    Err(Error::Fs1541 {
+                        message: format!("Failed to delete {}", name),
+                        error: Fs1541Error::NoEntry(status.to_string()),
+                    })
+                } else {
+                    Ok(())
+                }
+            });
+        match result {
+            Ok(()) => {
+                info!("Deleted {name}");
+                reply.ok();
+            }
+            Err(e) => {
+                warn!("Failed to delete {name}: {e}");
+                reply.error(e.to_fuse_reply_error());
+            }
+        }
+    }
+
     /// Very basic release implementation
     fn release(
         &mut self,
@@ -834,6 +1143,16 @@ impl Filesystem for FuserMount {
             }
         });
 
+        // Execute any partial command written to a control file, and write
+        // any data written to a CBM file to the drive
+        if let Err(e) = self
+            .exec_pending_control_command(ino)
+            .and_then(|_| self.write_pending_file(ino))
+        {
+            reply.error(e.to_fuse_reply_error());
+            return;
+        }
+
         // If we got here, say OK!
         if fh != ino {
             warn!("File handle doesn't match inode");
@@ -843,6 +1162,30 @@ impl Filesystem for FuserMount {
         return;
     }
 
+    /// Reports the capacity of the drive's disks, from their cached
+    /// directory listings, so df and file managers can show it.  Each CBM
+    /// block is reported as a 256 byte block.
+    fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
+        trace!("FuserMount::statfs");
+
+        let (blocks, blocks_free) =
+            locking_section!("Read", "Mount", { self.mount.read().block_counts() });
+
+        // Escaped filenames can be longer than the 16 characters the drive
+        // allows, so use the usual maximum filename length
+        let block_size = D64_BLOCK_SIZE as u32;
+        reply.statfs(
+            blocks,
+            blocks_free,
+            blocks_free,
+            0,
+            0,
+            block_size,
+            255,
+            block_size,
+        );
+    }
+
     /// Very basic flush implementation
     fn flush(&mut self, _req: &Request, ino: u64, fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
         debug!("FuserMount::flush");
@@ -869,6 +1212,16 @@ impl Filesystem for FuserMount {
             }
         });
 
+        // Execute any partial command written to a control file, and write
+        // any data written to a CBM file to the drive
+        if let Err(e) = self
+            .exec_pending_control_command(ino)
+            .and_then(|_| self.write_pending_file(ino))
+        {
+            reply.error(e.to_fuse_reply_error());
+            return;
+        }
+
         // If we got here, say OK!
         if fh != ino {
             warn!("File handle doesn't match inode");
@@ -879,6 +1232,144 @@ impl Filesystem for FuserMount {
 
 // Non Filesystem FuserMount functions
 impl FuserMount {
+    /// Cancels the background operation serving the FUSE request with the
+    /// given unique ID, if it's still in flight.  fuser 0.15 answers
+    /// FUSE_INTERRUPT itself rather than passing it on to the filesystem, so
+    /// this is called when a read is abandoned, such as on timeout.
+    fn interrupt(&mut self, _req: &Request, unique: u64) {
+        let Some(inode) = self.in_flight.remove(&unique) else {
+            return;
+        };
+        debug!("Cancelling read of inode {inode} for FUSE request {unique}");
+        locking_section!("Read", "Mount", {
+            let mount = self.mount.read();
+            if let Some(cache) = mount.file_by_inode(inode).and_then(|f| f.cache.as_ref()) {
+                cache.cancel_read();
+            }
+        });
+    }
+
+    /// Executes any command left buffered in a control file's write buffer -
+    /// i.e. one which wasn't newline terminated.  Called on flush and release,
+    /// when we know no more of the command is coming.
+    fn exec_pending_control_command(&mut self, ino: u64) -> Result<(), Error> {
+        locking_section!("Write", "Mount", {
+            let mut mount = self.mount.write();
+
+            let Some(file) = mount.file_by_inode_mut(ino) else {
+                return Ok(());
+            };
+            let FileEntryType::ControlFile(control_file) = file.native.clone() else {
+                return Ok(());
+            };
+            match file.take_pending_control_command() {
+                Some(command) => control_file
+                    .execute(&mut mount, &command)
+                    .inspect_err(|e| warn!("Control file command failed: {}", e)),
+                None => Ok(()),
+            }
+        })
+    }
+
+    /// Sends any data buffered for a CBM file to the drive, and waits for it
+    /// to be written.  Called on flush and release, when the file has been
+    /// completely written, so the error can be returned to the writer.
+    fn write_pending_file(&mut self, ino: u64) -> Result<(), Error> {
+        let waiter = locking_section!("Write", "Mount", {
+            self.mount
+                .write()
+                .write_file_sync(ino)
+                .inspect_err(|e| warn!("Failed to write file: {}", e))?
+        });
+        match waiter {
+            Some(waiter) => self.wait_for_file_write(waiter).inspect_err(|e| {
+                warn!("Failed to write file: {}", e);
+
+                // Some or all of the file may have been written regardless,
+                // such as when it didn't verify, so the listing is re-read
+                locking_section!("Write", "Mount", {
+                    self.mount.write().invalidate_all_dir_caches();
+                });
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Waits for the outcome of a file write, returning the drive's error if
+    /// it reported one.  The BG processor times out writes which are queued
+    /// or run for too long, so there's no separate timeout here, but the
+    /// Mount lock isn't held while waiting.
+    fn wait_for_file_write(&mut self, waiter: Receiver<OpOutcome>) -> Result<(), Error> {
+        let status = self.wait_for_outcome(waiter, None)?;
+        if status.is_ok() == CbmErrorNumberOk::Ok {
+            Ok(())
+        } else {
+            Err(Error::Fs1541 {
+                message: "File write failed".into(),
+                error: Fs1541Error::from(&status),
+            })
+        }
+    }
+
+    /// Waits for the outcome of an operation submitted to the BG processor,
+    /// checking every Timers::file_read_sleep that the response can still
+    /// arrive.  If max_count is given, gives up after that many checks.
+    fn wait_for_outcome(
+        &mut self,
+        waiter: Receiver<OpOutcome>,
+        max_count: Option<u32>,
+    ) -> OpOutcome {
+        let mut count = 0;
+        loop {
+            if max_count.is_some_and(|max_count| count >= max_count) {
+                break Err(Error::Fs1541 {
+                    message: "Operation timed out".into(),
+                    error: Fs1541Error::Timeout("".into(), self.timers.file_read_sleep * count),
+                });
+            }
+
+            match waiter.recv_timeout(self.timers.file_read_sleep) {
+                Ok(outcome) => break outcome,
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => {
+                    break Err(Error::Fs1541 {
+                        message: "Operation failed".into(),
+                        error: Fs1541Error::Internal("No response to operation".into()),
+                    })
+                }
+            }
+
+            // No point waiting if the response will never arrive
+            let liveness =
+                locking_section!("Read", "Mount", { self.mount.read().check_bg_liveness() });
+            if let Err(e) = liveness {
+                break Err(self.bg_failed(e));
+            }
+            count += 1;
+        }
+    }
+
+    /// Reads the drive's status via the BG processor, and waits for the
+    /// Mount's last status to be updated with it
+    fn refresh_drive_status(&mut self) -> Result<(), Error> {
+        self.drive_op_status(|mount| mount.get_status_sync())
+            .map(|_| ())
+    }
+
+    /// Submits an operation to the BG processor which updates the drive's
+    /// status, and waits for that operation's response, returning the
+    /// status the drive reported at the end of it
+    fn drive_op_status<F>(&mut self, op: F) -> Result<CbmStatus, Error>
+    where
+        F: FnOnce(&mut Mount) -> Result<Receiver<OpOutcome>, Error>,
+    {
+        let waiter = locking_section!("Write", "Mount", { op(&mut *self.mount.write())? });
+
+        // Status is quick to read, so use the file read timers
+        self.wait_for_outcome(waiter, Some(self.counts.file_check))
+            .inspect_err(|e| warn!("Failed waiting for drive status: {}", e))
+    }
+
     /// Called after mount.do_dir_sync() to wait for the directory re-read
     /// to complete.
     ///
@@ -909,6 +1400,12 @@ impl FuserMount {
             let disk_is_fresh = locking_section!("Read", "Mount", {
                 let mount = self.mount.read();
 
+                // No point waiting if the response will never arrive
+                if let Err(e) = mount.check_bg_liveness() {
+                    drop(mount);
+                    return Err(self.bg_failed(e));
+                }
+
                 if let Some(read_time) = mount.disk_info()[drive_num as usize].disk_read_time {
                     match SystemTime::now().duration_since(read_time) {
                         Ok(duration) if duration < self.timers.dir_cache => true,
@@ -939,15 +1436,30 @@ impl FuserMount {
         }
     }
 
-    fn wait_for_file_read(&mut self, inode: u64) -> Result<Vec<u8>, Error> {
+    /// Records that the Mount's background processing has failed, returning
+    /// the error for the caller to return
+    fn bg_failed(&self, error: Error) -> Error {
+        warn!("{}", error);
+        locking_section!("Write", "Mount", {
+            self.mount.write().set_bg_failed(&error.to_string());
+        });
+        error
+    }
+
+    fn wait_for_file_read(
+        &mut self,
+        inode: u64,
+        max_count: u32,
+        timeout: Duration,
+    ) -> Result<Vec<u8>, Error> {
         let mut count = 0;
         loop {
             // Check count before doing anything else
-            if count >= self.counts.file_check {
-                warn!("Couldn't read file data in 10s");
+            if count >= max_count {
+                warn!("Couldn't read file data in {}s", timeout.as_secs());
                 break Err(Error::Fs1541 {
                     message: "File read timed out".into(),
-                    error: Fs1541Error::Timeout("".into(), self.timers.file_read),
+                    error: Fs1541Error::Timeout("".into(), timeout),
                 });
             }
 
@@ -955,18 +1467,35 @@ impl FuserMount {
             let file_data = locking_section!("Read", "Mount", {
                 let mount = self.mount.read();
 
-                // Try to get the file from the inode
+                // No point waiting if the response will never arrive
+                if let Err(e) = mount.check_bg_liveness() {
+                    drop(mount);
+                    return Err(self.bg_failed(e));
+                }
+
+                // Try to get the file from the inode.  We accept suspect
+                // data here, as this is the result of the read we asked for
                 mount.file_by_inode(inode).and_then(|file| {
                     file.cache
                         .as_ref()
-                        .and_then(|cache| cache.get_data_complete_and_fresh(self.timers.file_cache))
-                        .map(Vec::clone)
+                        .and_then(|cache| match cache.failed_status() {
+                            Some(status) => Some(Err(status.clone())),
+                            None => cache.get_data_read_complete().map(|d| Ok(d.clone())),
+                        })
                 })
             });
 
-            // If we got fresh data, return it
-            if let Some(data) = file_data {
-                break Ok(data);
+            // If we got fresh data, return it.  If the drive reported an
+            // error, return that, mapped to an appropriate errno
+            match file_data {
+                Some(Ok(data)) => break Ok(data),
+                Some(Err(status)) => {
+                    break Err(Error::Fs1541 {
+                        message: "File read failed".into(),
+                        error: Fs1541Error::from(&status),
+                    })
+                }
+                None => (),
             }
 
             // Increase count and sleep before trying again
@@ -0,0 +1,314 @@
+use crate::file::{
+    bam_header, cbm_filename_from_open_name, d64_image_geometry, d64_offset,
+    padded_petscii_to_ascii, D64Geometry, D64_BAM_SECTOR, D64_BAM_TRACK, D64_BLOCK_SIZE,
+    D64_TRACKS,
+};
+
+use fs1541::error::{Error, Fs1541Error};
+use rs1541::{CbmFileEntry, CbmFileType};
+
+use log::{debug, info};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Sector of the BAM track holding the first block of the directory
+const D64_DIR_SECTOR: u8 = 1;
+
+/// Size of each directory entry.  There are 8 per directory block.
+const DIR_ENTRY_SIZE: usize = 32;
+
+/// Offset of the free block count for track 1 in the BAM.  Each track's
+/// entry is 4 bytes.
+const BAM_FREE_OFFSET: usize = 4;
+
+/// A disk's directory, read from a .d64 image
+#[derive(Debug, Clone)]
+pub struct ImageDirectory {
+    pub name: String,
+    pub id: String,
+    pub files: Vec<CbmFileEntry>,
+    pub blocks_free: u16,
+}
+
+/// Serves a .d64 disk image from a local file in place of a physical drive,
+/// so mounts can be exercised without an XUM1541.  The image is read into
+/// memory when opened.
+#[derive(Debug)]
+pub struct CbmImageBackend {
+    path: PathBuf,
+    image: Vec<u8>,
+    geometry: D64Geometry,
+}
+
+#[allow(dead_code)]
+impl CbmImageBackend {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let image = fs::read(path).map_err(|e| Error::Io {
+            message: format!("Failed to read disk image {}", path.display()),
+            error: e.to_string(),
+        })?;
+        let backend = Self::new(path, image)?;
+        info!("Opened {} disk image {}", backend.geometry, path.display());
+        Ok(backend)
+    }
+
+    pub fn new<P: AsRef<Path>>(path: P, image: Vec<u8>) -> Result<Self, Error> {
+        let geometry = d64_image_geometry(image.len())?;
+        Ok(Self {
+            path: path.as_ref().to_path_buf(),
+            image,
+            geometry,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn geometry(&self) -> D64Geometry {
+        self.geometry
+    }
+
+    pub fn read_block(&self, track: u8, sector: u8) -> Result<&[u8], Error> {
+        self.geometry.validate_block(track, sector)?;
+        let offset = d64_offset(track, sector);
+        Ok(&self.image[offset..offset + D64_BLOCK_SIZE])
+    }
+
+    /// Reads the header and free block count from the BAM, and the files
+    /// from the directory on track 18.  Scratched entries are skipped.
+    pub fn dir(&self) -> Result<ImageDirectory, Error> {
+        let bam = self.read_block(D64_BAM_TRACK, D64_BAM_SECTOR)?;
+        let (name, id) = bam_header(bam);
+
+        // DOS doesn't count the directory track's free blocks, or those
+        // on any extended tracks
+        let blocks_free = (1..=D64_TRACKS)
+            .filter(|track| *track != D64_BAM_TRACK)
+            .map(|track| bam[BAM_FREE_OFFSET + 4 * (track as usize - 1)] as u16)
+            .sum();
+
+        let mut files = Vec::new();
+        for block in self.chain(D64_BAM_TRACK, D64_DIR_SECTOR)? {
+            for entry in block.chunks_exact(DIR_ENTRY_SIZE) {
+                if entry[2] == 0 {
+                    continue;
+                }
+                files.push(CbmFileEntry::ValidFile {
+                    blocks: u16::from_le_bytes([entry[30], entry[31]]),
+                    filename: padded_petscii_to_ascii(&entry[5..21]),
+                    file_type: file_type(entry[2]),
+                });
+            }
+        }
+        debug!(
+            "Read {} files from disk image {}",
+            files.len(),
+            self.path.display()
+        );
+
+        Ok(ImageDirectory {
+            name,
+            id,
+            files,
+            blocks_free,
+        })
+    }
+
+    /// Reads a file's contents by following its chain of blocks.  The name
+    /// can be one used to open the file on a drive, with a drive prefix and
+    /// type and mode suffix, which are ignored, as there's one drive and the
+    /// type is in the directory.
+    pub fn read_file(&self, open_name: &str) -> Result<Vec<u8>, Error> {
+        let filename = cbm_filename_from_open_name(open_name);
+        let entry = self
+            .chain(D64_BAM_TRACK, D64_DIR_SECTOR)?
+            .into_iter()
+            .flat_map(|block| block.chunks_exact(DIR_ENTRY_SIZE))
+            .find(|entry| entry[2] != 0 && padded_petscii_to_ascii(&entry[5..21]) == filename)
+            .ok_or_else(|| Error::Fs1541 {
+                message: "File not found".into(),
+                error: Fs1541Error::NoEntry(format!(
+                    "{} isn't in disk image {}",
+                    filename,
+                    self.path.display()
+                )),
+            })?;
+        let blocks = self.chain(entry[3], entry[4])?;
+        let mut contents = Vec::with_capacity(blocks.len() * (D64_BLOCK_SIZE - 2));
+        for block in blocks {
+            // The last block's sector link is the index of its last byte
+            let end = match block[0] {
+                0 => (block[1] as usize + 1).max(2),
+                _ => D64_BLOCK_SIZE,
+            };
+            contents.extend_from_slice(&block[2..end]);
+        }
+        Ok(contents)
+    }
+
+    /// Returns the blocks in the chain starting at the given block.  Each
+    /// block's first two bytes link to the next, with track 0 ending the
+    /// chain.
+    fn chain(&self, mut track: u8, mut sector: u8) -> Result<Vec<&[u8]>, Error> {
+        let mut blocks = Vec::new();
+        loop {
+            // A chain longer than the disk must loop back on itself
+            if blocks.len() >= self.geometry.blocks() {
+                return Err(Error::Fs1541 {
+                    message: "Invalid disk image".into(),
+                    error: Fs1541Error::Validation(format!(
+                        "Block chain from track {} sector {} loops in disk image {}",
+                        track,
+                        sector,
+                        self.path.display()
+                    )),
+                });
+            }
+
+            let block = self.read_block(track, sector)?;
+            blocks.push(block);
+            if block[0] == 0 {
+                return Ok(blocks);
+            }
+            (track, sector) = (block[0], block[1]);
+        }
+    }
+}
+
+/// Maps a directory entry's file type byte to the file type.  Only the low
+/// bits give the type - the high bits are flags.  rs1541 has no DEL type, so
+/// DEL files (type 0, but not scratched) are Unknown, as they are when a
+/// drive lists them.
+fn file_type(type_byte: u8) -> CbmFileType {
+    match type_byte & 0x07 {
+        1 => CbmFileType::SEQ,
+        2 => CbmFileType::PRG,
+        3 => CbmFileType::USR,
+        4 => CbmFileType::REL,
+        _ => CbmFileType::Unknown,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::FileEntry;
+
+    /// Builds a disk image with a header, and one PRG file spanning two
+    /// blocks on track 17
+    fn test_image() -> Vec<u8> {
+        let mut image = vec![0; D64Geometry::Standard.image_size()];
+
+        let bam = d64_offset(D64_BAM_TRACK, D64_BAM_SECTOR);
+        image[bam..bam + 2].copy_from_slice(&[D64_BAM_TRACK, D64_DIR_SECTOR]);
+        for track in 1..=D64_TRACKS as usize {
+            image[bam + BAM_FREE_OFFSET + 4 * (track - 1)] = 10;
+        }
+        image[bam + 0x90..bam + 0xa0].fill(0xa0);
+        image[bam + 0x90..bam + 0x97].copy_from_slice(b"MY DISK");
+        image[bam + 0xa2..bam + 0xa4].copy_from_slice(b"AB");
+
+        let dir = d64_offset(D64_BAM_TRACK, D64_DIR_SECTOR);
+        image[dir + 1] = 0xff;
+        let entry = &mut image[dir..dir + DIR_ENTRY_SIZE];
+        entry[2] = 0x82;
+        entry[3..5].copy_from_slice(&[17, 0]);
+        entry[5..21].fill(0xa0);
+        entry[5..10].copy_from_slice(b"HELLO");
+        entry[30] = 2;
+
+        // A scratched entry
+        image[dir + DIR_ENTRY_SIZE + 5] = b'X';
+
+        let first = d64_offset(17, 0);
+        image[first..first + 2].copy_from_slice(&[17, 1]);
+        image[first + 2..first + D64_BLOCK_SIZE].fill(1);
+        let last = d64_offset(17, 1);
+        image[last..last + 2].copy_from_slice(&[0, 11]);
+        image[last + 2..last + 12].fill(2);
+
+        image
+    }
+
+    #[test]
+    fn test_image_dir() {
+        let backend = CbmImageBackend::new("test.d64", test_image()).unwrap();
+        assert_eq!(backend.geometry(), D64Geometry::Standard);
+
+        let dir = backend.dir().unwrap();
+        assert_eq!(dir.name, "my disk");
+        assert_eq!(dir.id, "ab");
+        assert_eq!(dir.blocks_free, 340);
+        assert_eq!(dir.files.len(), 1);
+        assert!(matches!(
+            &dir.files[0],
+            CbmFileEntry::ValidFile {
+                blocks: 2,
+                filename,
+                file_type: CbmFileType::PRG,
+            } if filename == "hello"
+        ));
+    }
+
+    #[test]
+    fn test_image_dir_del() {
+        // A DEL entry, as used for a separator, after the scratched one
+        let mut image = test_image();
+        let dir = d64_offset(D64_BAM_TRACK, D64_DIR_SECTOR) + 2 * DIR_ENTRY_SIZE;
+        let entry = &mut image[dir..dir + DIR_ENTRY_SIZE];
+        entry[2] = 0x80;
+        entry[5..21].fill(0xa0);
+        entry[5..11].copy_from_slice(b"------");
+        let backend = CbmImageBackend::new("test.d64", image).unwrap();
+
+        // It's listed, as a file of Unknown type, which is shown as DEL
+        let dir = backend.dir().unwrap();
+        assert_eq!(dir.files.len(), 2);
+        assert!(matches!(
+            &dir.files[1],
+            CbmFileEntry::ValidFile {
+                blocks: 0,
+                filename,
+                file_type: CbmFileType::Unknown,
+            } if filename == "------"
+        ));
+        let file = FileEntry::from_cbm_file_entry(&dir.files[1], 0).unwrap();
+        assert_eq!(file.fuse.name, "------.del");
+    }
+
+    #[test]
+    fn test_image_read_file() {
+        let backend = CbmImageBackend::new("test.d64", test_image()).unwrap();
+
+        let contents = backend.read_file("hello").unwrap();
+        assert_eq!(contents.len(), 264);
+        assert!(contents[..254].iter().all(|b| *b == 1));
+        assert!(contents[254..].iter().all(|b| *b == 2));
+
+        // As is a file opened with a drive and type
+        assert_eq!(backend.read_file("0:hello,p,r").unwrap(), contents);
+
+        assert!(matches!(
+            backend.read_file("missing"),
+            Err(Error::Fs1541 {
+                error: Fs1541Error::NoEntry(_),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_image_invalid() {
+        // Not a .d64 size
+        assert!(CbmImageBackend::new("test.d64", vec![0; 1000]).is_err());
+
+        // A chain which loops back on itself
+        let mut image = test_image();
+        let last = d64_offset(17, 1);
+        image[last..last + 2].copy_from_slice(&[17, 0]);
+        let backend = CbmImageBackend::new("test.d64", image).unwrap();
+        assert!(backend.read_file("hello").is_err());
+    }
+}
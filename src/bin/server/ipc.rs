@@ -1,4 +1,4 @@
-use fs1541::error::{Error, Fs1541Error};
+use fs1541::error::{Error, Fs1541Error, ERROR_CODE_INTERNAL, ERROR_CODE_VALIDATION};
 /// Contains IPC server implementation to receive and response to 1541fs
 /// client requests.  Any requests which need to be handled asyncronously are
 /// sent to BackgroundProcess for handling.
@@ -12,26 +12,45 @@ use fs1541::error::{Error, Fs1541Error};
 /// We use flume for our mpsc channels, instead of tokio::sync::mpsc, as it
 /// supports sync and async contexts - we need a sync context in order to
 /// use from within fuser threads.
-use fs1541::ipc::Request::{self, BusReset, Die, GetStatus, Identify, Mount, Ping, Unmount};
-use fs1541::ipc::{Response, SOCKET_PATH};
+use fs1541::ipc::Request::{
+    self, BusReset, Die, GetLogLevel, GetStatus, Health, Identify, ListMounts, MemoryExecute,
+    MemoryRead, MemoryWrite, Metrics, Mount, Ping, ReadFile, RefreshDir, ScanBus, SetDeviceNumber,
+    SetLogLevel, Subscribe, Unmount, Version,
+};
+use fs1541::ipc::{
+    encode_frame, frame_len, DeviceIdentity, OperationMetrics, Response, FRAME_LEN_SIZE,
+    MAX_REQUEST_SIZE, PROTOCOL_VERSION, SOCKET_PATH,
+};
+use fs1541::logging::{get_log_level, set_log_level};
+use fs1541::validate::{validate_memory_read, validate_memory_write, validate_new_device_number};
+use rs1541::CbmErrorNumberOk;
 
-use crate::bg::{OpResponse, OpResponseType, OpType, Operation};
-use crate::mount::{validate_mount_request, validate_unmount_request};
+use crate::bg::{OpResponse, OpResponseType, OpType, Operation, MEMORY_EXECUTE_TIMEOUT};
+use crate::drivemgr::BusState;
+use crate::events;
+use crate::locking_section;
+use crate::mount::{
+    create_mountpoint, remove_created_mountpoint, validate_mount_request, validate_unmount_request,
+    Mount,
+};
 
 use either::{Left, Right};
 use flume::{Receiver, Sender};
+use log::LevelFilter;
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
 use nix::sys::signal::{kill, Signal};
 use nix::unistd::Pid;
-use std::path::Path;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::io::BufReader;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
-use tokio::net::unix::OwnedWriteHalf;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::RwLock;
 use tokio::task::JoinHandle;
 use tokio::time::sleep;
 
@@ -41,6 +60,53 @@ pub const BG_LIST_WAIT_TIME_MS: u64 = 100;
 const BG_LISTENER_SHUTDOWN_CHECK_DUR: Duration = Duration::from_millis(50);
 const IPC_SERVER_SHUTDOWN_CHECK_DUR: Duration = Duration::from_millis(50);
 
+/// How long a client connection may be idle before it is dropped, unless
+/// overridden
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How many clients may be subscribed to events at once, unless overridden
+const DEFAULT_MAX_SUBSCRIBERS: usize = 4;
+
+/// What Health requests report.  This is shared with the rest of the daemon,
+/// so Health is answered straight away, rather than waiting behind other
+/// operations for the background processor, or for the Drive Manager lock,
+/// which a wedged XUM1541 could hold indefinitely.
+#[derive(Debug, Clone)]
+pub struct HealthState {
+    started: Instant,
+    mountpoints: Arc<RwLock<HashMap<PathBuf, Arc<parking_lot::RwLock<Mount>>>>>,
+    bus_state: Arc<BusState>,
+}
+
+impl HealthState {
+    pub fn new(
+        mountpoints: Arc<RwLock<HashMap<PathBuf, Arc<parking_lot::RwLock<Mount>>>>>,
+        bus_state: Arc<BusState>,
+    ) -> Self {
+        Self {
+            started: Instant::now(),
+            mountpoints,
+            bus_state,
+        }
+    }
+
+    async fn response(&self) -> Response {
+        let active_mounts = locking_section!("Lock", "Mountpoints", {
+            self.mountpoints.read().await.len()
+        });
+        Response::Health {
+            uptime_secs: self.started.elapsed().as_secs(),
+            active_mounts,
+            last_bus_reset_secs: self
+                .bus_state
+                .last_reset()
+                .await
+                .map(|reset| reset.elapsed().as_secs()),
+            xum1541_open: Some(self.bus_state.xum1541_open()),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct IpcServer {
     // Whether we should be running - if we are running and this is set to
@@ -58,6 +124,20 @@ pub struct IpcServer {
     // This doesn't need to be Mutexed, as Senders implement Send, but does
     // need to be an Arc
     bg_rsp_tx: Arc<Sender<OpResponse>>,
+
+    // The path of the Unix socket to listen on.  Defaults to SOCKET_PATH, but
+    // can be overridden so tests can bind to a temporary socket
+    socket_path: PathBuf,
+
+    // How long to wait for the next request on a connection before dropping
+    // it
+    idle_timeout: Duration,
+
+    // How many clients may be subscribed to events at once
+    max_subscribers: usize,
+
+    // What to report in response to Health requests
+    health: HealthState,
 }
 
 /// IPC Server does not store the bg_rsp_rx (an mpsc:channel Receiver), because
@@ -69,6 +149,7 @@ impl IpcServer {
         pid: Pid,
         bg_proc_tx: Arc<Sender<Operation>>,
         bg_rsp_tx: Arc<Sender<OpResponse>>,
+        health: HealthState,
     ) -> Self {
         // We need a Mutex for the TX half, so we can give it to the Background
         // Processor on multiple messages (all of them!)
@@ -79,24 +160,45 @@ impl IpcServer {
             pid,
             bg_proc_tx,
             bg_rsp_tx: shared_bg_rsp_tx,
+            socket_path: PathBuf::from(SOCKET_PATH),
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            max_subscribers: DEFAULT_MAX_SUBSCRIBERS,
+            health,
         }
     }
 
+    /// Drop client connections which haven't sent a request for this long
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Limit the number of clients which may be subscribed to events at once
+    pub fn with_max_subscribers(mut self, max_subscribers: usize) -> Self {
+        self.max_subscribers = max_subscribers;
+        self
+    }
+
+    /// Listen on a different socket path to the default.  Used by the test
+    /// harness to avoid clashing with a running daemon.
+    #[cfg(test)]
+    pub fn with_socket_path(mut self, socket_path: PathBuf) -> Self {
+        self.socket_path = socket_path;
+        self
+    }
+
     async fn send_response(stream: &mut OwnedWriteHalf, response: Response) -> Result<(), Error> {
-        // Serialize to string first since we can't use to_writer directly with async Write
-        let response_str = serde_json::to_string(&response).map_err(|e| Error::Fs1541 {
+        // Serialize first since we can't use to_writer directly with async Write
+        let frame = encode_frame(&response).map_err(|e| Error::Fs1541 {
             message: "Failed to serialize response".to_string(),
             error: Fs1541Error::Internal(e.to_string()),
         })?;
 
-        // Write the response and newline in one operation
-        stream
-            .write_all(format!("{}\n", response_str).as_bytes())
-            .await
-            .map_err(|e| Error::Io {
-                message: "Failed to write response".to_string(),
-                error: e.to_string(),
-            })?;
+        // Write the length prefix and response in one operation
+        stream.write_all(&frame).await.map_err(|e| Error::Io {
+            message: "Failed to write response".to_string(),
+            error: e.to_string(),
+        })?;
 
         // Flush the stream
         stream.flush().await.map_err(|e| Error::Io {
@@ -107,19 +209,118 @@ impl IpcServer {
         Ok(())
     }
 
+    /// Handles requests from a client connection until the client closes it,
+    /// it is idle for longer than the idle timeout, or the server is stopped.
+    /// Each request's response is sent before the next request is read.
+    async fn handle_connection(&self, stream: UnixStream) {
+        let (mut reader, mut writer) = stream.into_split();
+
+        loop {
+            let request =
+                match tokio::time::timeout(self.idle_timeout, Self::receive_request(&mut reader))
+                    .await
+                {
+                    Ok(Ok(Some(request))) => request,
+                    Ok(Ok(None)) => {
+                        trace!("Client closed connection");
+                        break;
+                    }
+                    Ok(Err(e)) => {
+                        debug!("Hit error receiving request from client {}", e);
+                        break;
+                    }
+                    Err(_) => {
+                        debug!("Dropping idle client connection");
+                        break;
+                    }
+                };
+
+            // A subscription takes over the connection until the client
+            // closes it
+            if let Subscribe { device } = request {
+                self.handle_subscription(device, &mut reader, &mut writer)
+                    .await;
+                break;
+            }
+
+            // Errors are returned to the client, with their code, rather than
+            // the connection being dropped without a response
+            let response = match self.handle_client_request(request).await {
+                Ok(response) => response,
+                Err(e) => {
+                    warn!("Error handling client request: {}", e);
+                    Response::from(e)
+                }
+            };
+
+            debug!("Sending response: {}", response);
+            if let Err(e) = Self::send_response(&mut writer, response).await {
+                warn!("Failed to send response back to client {}", e);
+                break;
+            }
+
+            if !self.ipc_server_run.load(Ordering::SeqCst) {
+                break;
+            }
+        }
+    }
+
+    /// Sends events for the device to the client as they happen, until the
+    /// client closes the connection (or sends anything else) or the server is
+    /// stopped.
+    async fn handle_subscription(
+        &self,
+        device: u8,
+        reader: &mut OwnedReadHalf,
+        writer: &mut OwnedWriteHalf,
+    ) {
+        let rx = match events::subscribe(device, self.max_subscribers) {
+            Ok(rx) => rx,
+            Err(e) => {
+                debug!("Rejected subscription to device {}: {}", device, e);
+                let _ = Self::send_response(writer, Response::from(e)).await;
+                return;
+            }
+        };
+        if let Err(e) = Self::send_response(writer, Response::Subscribed).await {
+            warn!("Failed to send response back to client {}", e);
+            return;
+        }
+        debug!("Client subscribed to device {} events", device);
+
+        let mut buf = [0u8; 1];
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(IPC_SERVER_SHUTDOWN_CHECK_DUR) => {
+                    if !self.ipc_server_run.load(Ordering::SeqCst) {
+                        break;
+                    }
+                }
+                event = rx.recv_async() => {
+                    let Ok(event) = event else {
+                        break;
+                    };
+                    if let Err(e) = Self::send_response(writer, Response::Event(event)).await {
+                        debug!("Failed to send event to client {}", e);
+                        break;
+                    }
+                }
+                _ = reader.read(&mut buf) => break,
+            }
+        }
+
+        drop(rx);
+        events::prune();
+        debug!("Client unsubscribed from device {} events", device);
+    }
+
     /// Handling incoming client request.
-    /// If the request can be handled immediately, a Response will be sent
-    /// back to the client.
+    /// If the request can be handled immediately, the Response is returned
+    /// straight away.
     /// If the request needs background handling, it will be sent to
-    /// BackgroundProcess, and a thread will wait for a oneshot message
-    /// to be received indicating the response.  This thread will response
-    /// to the client, when that is received (assuming the client hasn't
-    /// disconnected first)
-    async fn handle_client_request(
-        &self,
-        stream: UnixStream,
-        request: Request,
-    ) -> Result<(), Error> {
+    /// BackgroundProcess, and the Response is returned once the background
+    /// response receiver has passed it back.
+    async fn handle_client_request(&self, request: Request) -> Result<Response, Error> {
         // Request fall into two categories:
         // * Those who need to be send to BackgroundProcess for processing
         // * Those who can be handled directly by Daemon
@@ -127,8 +328,17 @@ impl IpcServer {
             Mount { .. }
             | Unmount { .. }
             | BusReset { .. }
+            | ScanBus
+            | Metrics { .. }
+            | ListMounts
             | Identify { .. }
-            | GetStatus { .. } => {
+            | GetStatus { .. }
+            | ReadFile { .. }
+            | SetDeviceNumber { .. }
+            | RefreshDir { .. }
+            | MemoryRead { .. }
+            | MemoryWrite { .. }
+            | MemoryExecute { .. } => {
                 // Do any pre-validation of the request
                 let mountpoint_path = match request.clone() {
                     Mount {
@@ -136,17 +346,45 @@ impl IpcServer {
                         device,
                         dummy_formats,
                         bus_reset,
+                        read_write,
+                        mkdir,
+                        image,
                         ..
-                    } => Some(validate_mount_request(
-                        mountpoint,
-                        device,
-                        dummy_formats,
-                        bus_reset,
-                    )?),
+                    } => {
+                        // Create the mountpoint before validating it, and
+                        // remove it again if validation fails
+                        let created = create_mountpoint(Path::new(&mountpoint), mkdir)?;
+                        let path = validate_mount_request(
+                            &mountpoint,
+                            device,
+                            dummy_formats,
+                            bus_reset,
+                            read_write,
+                            image.as_deref(),
+                        )
+                        .inspect_err(|_| {
+                            if created {
+                                remove_created_mountpoint(Path::new(&mountpoint));
+                            }
+                        })?;
+                        Some((path, created))
+                    }
                     Unmount { mountpoint, device } => {
                         validate_unmount_request(&mountpoint, device)?;
                         None
                     }
+                    SetDeviceNumber { device, new_device } => {
+                        validate_new_device_number(device, new_device)?;
+                        None
+                    }
+                    MemoryRead { addr, len, .. } => {
+                        validate_memory_read(addr, len)?;
+                        None
+                    }
+                    MemoryWrite { addr, data, .. } => {
+                        validate_memory_write(addr, data.len())?;
+                        None
+                    }
                     _ => None,
                 };
 
@@ -157,19 +395,69 @@ impl IpcServer {
                         device,
                         dummy_formats,
                         bus_reset,
-                    } => OpType::Mount {
-                        device,
-                        mountpoint: mountpoint_path.unwrap(),
-                        dummy_formats,
-                        bus_reset,
-                    },
+                        auto_refresh_secs,
+                        read_write,
+                        safe_replace,
+                        verify_writes,
+                        interleave,
+                        mkdir: _,
+                        image,
+                    } => {
+                        let (mountpoint, created_mountpoint) = mountpoint_path.unwrap();
+                        OpType::Mount {
+                            device,
+                            mountpoint,
+                            dummy_formats,
+                            bus_reset,
+                            read_write,
+                            safe_replace,
+                            verify_writes,
+                            interleave,
+                            created_mountpoint,
+                            image,
+                            // Treat 0 as disabled
+                            auto_refresh: auto_refresh_secs
+                                .filter(|secs| *secs > 0)
+                                .map(Duration::from_secs),
+                        }
+                    }
                     Unmount { mountpoint, device } => OpType::Unmount {
                         device,
                         mountpoint: mountpoint.map(|s| s.into()),
                     },
                     BusReset => OpType::BusReset,
+                    ScanBus => OpType::ScanBus,
+                    Metrics { reset } => OpType::Metrics { reset },
+                    ListMounts => OpType::ListMounts,
                     Identify { device } => OpType::Identify { device },
                     GetStatus { device } => OpType::GetStatus { device },
+                    // There's no inode, as this isn't being read via a mount
+                    ReadFile { device, filename } => OpType::ReadFile {
+                        device,
+                        path: filename,
+                        inode: 0,
+                    },
+                    SetDeviceNumber { device, new_device } => {
+                        OpType::SetDeviceNumber { device, new_device }
+                    }
+                    RefreshDir { device, drive } => OpType::RefreshDir {
+                        device,
+                        drive_num: drive.unwrap_or(0),
+                    },
+                    // Validated above to fit in a single M-R
+                    MemoryRead { device, addr, len } => OpType::MemoryRead {
+                        device,
+                        addr,
+                        len: len as u8,
+                    },
+                    MemoryWrite { device, addr, data } => {
+                        OpType::MemoryWrite { device, addr, data }
+                    }
+                    MemoryExecute { device, addr } => OpType::MemoryExecute {
+                        device,
+                        addr,
+                        timeout: MEMORY_EXECUTE_TIMEOUT,
+                    },
                     _ => unreachable!(),
                 };
 
@@ -181,6 +469,25 @@ impl IpcServer {
                 Left(op)
             }
             Ping => Right(Response::Pong),
+            Health => Right(self.health.response().await),
+            Version => Right(Response::Version {
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                protocol: PROTOCOL_VERSION,
+            }),
+            SetLogLevel { level } => match LevelFilter::from_str(&level) {
+                Ok(level) => {
+                    info!("Changing log level to {}", level);
+                    set_log_level(level);
+                    Right(Response::LogLevel(get_log_level().to_string()))
+                }
+                Err(_) => Right(Response::Error {
+                    code: ERROR_CODE_VALIDATION,
+                    message: format!("Invalid log level: {}", level),
+                }),
+            },
+            GetLogLevel => Right(Response::LogLevel(get_log_level().to_string())),
+            // Handled by handle_connection, as it needs the connection
+            Subscribe { .. } => unreachable!(),
             Die => {
                 // Simulate a Ctrl-C, but after 250ms to give time for dying
                 // resonse to be sent
@@ -193,44 +500,65 @@ impl IpcServer {
             }
         };
 
-        // Split the stream, so we can move the writer to Operaton (to come)
-        // back on the response.  Or we keep it if we'll use it immediately.
-        let (_reader, mut writer) = stream.into_split();
-
         // Handle the response type
-        // - Left - send the message to background processor and spawn
-        //   thread to wait for response
-        // - Right - send the response
+        // - Left - send the message to background processor and wait for
+        //   the response to be passed back
+        // - Right - return the response
         match either {
             Left(mut op) => {
-                op.set_stream(writer)?;
+                let (reply_tx, reply_rx) = flume::bounded(1);
+                op.set_reply(reply_tx)?;
                 self.bg_proc_tx.try_send(op).map_err(|e| Error::Fs1541 {
                     message: "Failed to send message to background processor".to_string(),
                     error: Fs1541Error::Internal(e.to_string()),
+                })?;
+                reply_rx.recv_async().await.map_err(|e| Error::Fs1541 {
+                    message: "Failed to receive response from background processor".to_string(),
+                    error: Fs1541Error::Internal(e.to_string()),
                 })
             }
-            Right(rsp) => {
-                debug!("Sending response: {}", rsp);
-                Self::send_response(&mut writer, rsp).await
-            }
+            Right(rsp) => Ok(rsp),
         }
     }
 
-    pub async fn receive_request(&self, stream: &mut UnixStream) -> Result<Request, Error> {
-        let mut reader = BufReader::new(stream);
-        let mut request_data = String::new();
+    /// Reads the next request from the connection, returning None if the
+    /// client has closed it
+    async fn receive_request(reader: &mut OwnedReadHalf) -> Result<Option<Request>, Error> {
+        let mut prefix = [0u8; FRAME_LEN_SIZE];
+        match reader.read_exact(&mut prefix).await {
+            Ok(_) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => {
+                return Err(Error::Io {
+                    message: "Failed to read request length".to_string(),
+                    error: e.to_string(),
+                })
+            }
+        }
+
+        let len = frame_len(prefix);
+        if len > MAX_REQUEST_SIZE {
+            return Err(Error::Fs1541 {
+                message: "Request size exceeded limit".to_string(),
+                error: Fs1541Error::Validation(format!(
+                    "Request of {} bytes exceeded maximum size of {} bytes",
+                    len, MAX_REQUEST_SIZE
+                )),
+            });
+        }
 
+        let mut request_data = vec![0u8; len];
         reader
-            .read_line(&mut request_data)
+            .read_exact(&mut request_data)
             .await
             .map_err(|e| Error::Io {
-                message: "Failed to read request line".to_string(),
+                message: "Failed to read request".to_string(),
                 error: e.to_string(),
             })?;
 
-        serde_json::from_str(&request_data)
+        serde_json::from_slice(&request_data)
             .inspect(|req| debug!("Received request: {}", req))
-            .map(|req: Request| Ok(req))
+            .map(|req: Request| Ok(Some(req)))
             .inspect_err(|e| debug!("Failed to parse incoming request {}", e))
             .map_err(|e| Error::Serde {
                 message: "Failed to parse incoming request".to_string(),
@@ -239,30 +567,33 @@ impl IpcServer {
     }
 
     async fn setup_socket(&self) -> Result<UnixListener, Error> {
-        Self::remove_socket_if_exists().await;
-        UnixListener::bind(SOCKET_PATH).map_err(|e| Error::Io {
+        Self::remove_socket_if_exists(&self.socket_path).await;
+        UnixListener::bind(&self.socket_path).map_err(|e| Error::Io {
             message: "Failed to bind Unix socket".to_string(),
             error: e.to_string(),
         })
     }
 
-    async fn remove_socket_if_exists() {
-        if Path::new(SOCKET_PATH).exists() {
-            if let Err(e) = tokio::fs::remove_file(SOCKET_PATH).await {
+    async fn remove_socket_if_exists(socket_path: &Path) {
+        if socket_path.exists() {
+            if let Err(e) = tokio::fs::remove_file(socket_path).await {
                 warn!("Failed to remove socket during cleanup: {}", e);
             }
         }
     }
 
     async fn cleanup_socket(&self) {
-        Self::remove_socket_if_exists().await;
+        Self::remove_socket_if_exists(&self.socket_path).await;
     }
 
     async fn start_ipc_listener(&self) -> Result<JoinHandle<()>, Error> {
         self.ipc_server_run.store(true, Ordering::SeqCst);
         let listener = self.setup_socket().await?;
 
-        debug!("IPC server ready to accept connections on {}", SOCKET_PATH);
+        debug!(
+            "IPC server ready to accept connections on {}",
+            self.socket_path.display()
+        );
 
         // Create a clone of self for the spawned task
         let self_clone = self.clone();
@@ -281,19 +612,14 @@ impl IpcServer {
                     }
                     accept_result = listener.accept() => {
                         match accept_result {
-                            Ok((mut stream, addr)) => {
+                            Ok((stream, addr)) => {
                                 debug!("IPC server accepted new connection from {:?}", addr);
-                                // Receive the request from stream, then handle it
-                                match self_clone.receive_request(&mut stream).await {
-                                    Ok(req) => {
-                                        if let Err(e) = self_clone.handle_client_request(stream, req).await {
-                                            warn!("Error handling client request: {}", e);
-                                        }
-                                    }
-                                    Err(e) => {
-                                        debug!("Hit error receiving request from client {}", e);
-                                    }
-                                }
+                                // Handle the connection's requests in their
+                                // own task, so other clients aren't held up
+                                let connection = self_clone.clone();
+                                tokio::spawn(async move {
+                                    connection.handle_connection(stream).await;
+                                });
                             }
                             Err(e) => {
                                 warn!("Error accepting connection: {}", e);
@@ -357,15 +683,15 @@ impl IpcServer {
                         match recv_result {
                             Ok(mut resp) => {
                                 debug!("Received response from background processor {:?}", resp);
-                                if let Ok(mut stream) = resp.take_stream() {
+                                if let Ok(reply) = resp.take_reply() {
                                     let cli_resp = Response::from(OpResponseWrapper(Ok(resp)));
-                                    if let Err(e) = Self::send_response(&mut stream, cli_resp).await {
-                                        warn!("Failed to send response back to client {}", e);
+                                    if reply.send_async(cli_resp).await.is_err() {
+                                        warn!("Client connection closed before response could be sent");
                                     } else {
-                                        trace!("Successfully sent response back to client");
+                                        trace!("Successfully passed response back to client connection");
                                     }
                                 } else {
-                                    warn!("No stream on response - cannot send response to the client");
+                                    warn!("No reply on response - cannot send response to the client");
                                 }
                             }
                             Err(e) => {
@@ -407,18 +733,823 @@ impl From<OpResponseWrapper> for Response {
             Ok(op_response) => match op_response.rsp {
                 Ok(response_type) => match response_type {
                     OpResponseType::Mount() => Response::MountSuccess,
-                    OpResponseType::Unmount() => Response::UnmountSuccess,
-                    OpResponseType::BusReset() => Response::BusResetSuccess,
+                    OpResponseType::Unmount { not_mounted } => match not_mounted {
+                        Some(what) => Response::NotMounted(what),
+                        None => Response::UnmountSuccess,
+                    },
+                    OpResponseType::BusReset { reinitialized } => Response::BusResetSuccess {
+                        reinitialized_mounts: reinitialized,
+                    },
+                    OpResponseType::ScanBus { devices } => Response::BusScan(
+                        devices
+                            .into_iter()
+                            .map(|(device, info)| {
+                                (
+                                    device,
+                                    info.map(|info| DeviceIdentity {
+                                        device_type: info.device_type.as_str().to_string(),
+                                        description: info.description,
+                                    }),
+                                )
+                            })
+                            .collect(),
+                    ),
+                    OpResponseType::Metrics { metrics } => Response::Metrics(
+                        metrics
+                            .into_iter()
+                            .map(|(operation, metrics)| OperationMetrics {
+                                operation,
+                                count: metrics.count,
+                                errors: metrics.errors,
+                                min_ms: metrics.min.as_millis() as u64,
+                                max_ms: metrics.max.as_millis() as u64,
+                                avg_ms: metrics.avg().as_millis() as u64,
+                                avg_queued_ms: metrics.avg_queued().as_millis() as u64,
+                                bytes: metrics.bytes,
+                            })
+                            .collect(),
+                    ),
+                    OpResponseType::ListMounts { mounts } => Response::Mounts(mounts),
                     OpResponseType::Identify { info } => Response::Identified {
                         device_type: info.device_type.as_str().to_string(),
                         description: info.description,
                     },
-                    OpResponseType::GetStatus { status } => Response::GotStatus(status.to_string()),
-                    _ => Response::Error("Unsupported response type".to_string()),
+                    OpResponseType::GetStatus { status } => Response::GotStatusStructured {
+                        error_number: status.number,
+                        track: status.track,
+                        sector: status.sector,
+                        message: status.message,
+                    },
+                    OpResponseType::ReadFile {
+                        status, contents, ..
+                    } => {
+                        if status.is_ok() == CbmErrorNumberOk::Ok {
+                            Response::FileContents(contents)
+                        } else {
+                            let error = Fs1541Error::from(&status);
+                            Response::Error {
+                                code: error.code(),
+                                message: format!("Failed to read file: {}", error),
+                            }
+                        }
+                    }
+                    OpResponseType::SetDeviceNumber {
+                        device,
+                        new_device,
+                        status,
+                    } => {
+                        if status.is_ok() == CbmErrorNumberOk::Ok {
+                            Response::DeviceNumberSet { device, new_device }
+                        } else {
+                            let error = Fs1541Error::from(&status);
+                            Response::Error {
+                                code: error.code(),
+                                message: format!("Failed to set device number: {}", error),
+                            }
+                        }
+                    }
+                    OpResponseType::RefreshDir { device, drive_num } => Response::DirRefreshed {
+                        device,
+                        drive: drive_num,
+                    },
+                    OpResponseType::MemoryRead { device, addr, data } => {
+                        Response::MemoryContents { device, addr, data }
+                    }
+                    OpResponseType::MemoryWrite {
+                        device,
+                        addr,
+                        len,
+                        status,
+                    } => {
+                        if status.is_ok() == CbmErrorNumberOk::Ok {
+                            Response::MemoryWritten { device, addr, len }
+                        } else {
+                            let error = Fs1541Error::from(&status);
+                            Response::Error {
+                                code: error.code(),
+                                message: format!("Failed to write drive memory: {}", error),
+                            }
+                        }
+                    }
+                    OpResponseType::MemoryExecute {
+                        device,
+                        addr,
+                        status,
+                    } => {
+                        if status.is_ok() == CbmErrorNumberOk::Ok {
+                            Response::MemoryExecuted { device, addr }
+                        } else {
+                            let error = Fs1541Error::from(&status);
+                            Response::Error {
+                                code: error.code(),
+                                message: format!("Failed to execute drive code: {}", error),
+                            }
+                        }
+                    }
+                    _ => Response::Error {
+                        code: ERROR_CODE_INTERNAL,
+                        message: "Unsupported response type".to_string(),
+                    },
                 },
-                Err(e) => Response::Error(e.to_string()),
+                Err(e) => Response::from(e),
             },
-            Err(e) => Response::Error(e.to_string()),
+            Err(e) => Response::from(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::init_test_args;
+    use crate::backend::mock::MockBackend;
+    use crate::backend::CbmBackend;
+    use crate::bg::Proc;
+    use crate::drivemgr::DriveManager;
+    use fs1541::ipc::Event;
+    use fs1541::logging::{restore_log_level, save_log_level};
+    use rs1541::{CbmFileEntry, CbmFileType};
+    use std::collections::HashMap;
+    use tempfile::TempDir;
+    use tokio::sync::{Mutex, RwLock};
+
+    const TEST_IDLE_TIMEOUT: Duration = Duration::from_millis(200);
+
+    /// What handles the Operations the IpcServer sends to be processed
+    enum Processor {
+        /// Answers every Operation with the default (successful) response
+        /// for its OpType, so the IPC request/response plumbing can be
+        /// exercised on its own
+        Stub,
+
+        /// A real bg::Proc, driving the backend in place of the bus
+        Proc(Arc<Mutex<dyn CbmBackend>>),
+
+        /// Never answers, like a background processor stuck on a wedged bus
+        Stalled,
+    }
+
+    /// Runs an IpcServer on a temporary socket, with a background processor
+    /// handling its Operations, without a Cbm or any hardware attached
+    struct Harness {
+        dir: TempDir,
+        socket_path: PathBuf,
+        server: IpcServer,
+        handles: Vec<JoinHandle<()>>,
+    }
+
+    impl Harness {
+        async fn start() -> Self {
+            Self::start_with(Processor::Stub).await
+        }
+
+        async fn start_with(processor: Processor) -> Self {
+            let dir = TempDir::new().unwrap();
+            let socket_path = dir.path().join("1541fs-test.sock");
+
+            let (bg_proc_tx, bg_proc_rx) = flume::bounded::<Operation>(MAX_BG_RSP_CHANNELS);
+            let (bg_rsp_tx, bg_rsp_rx) = flume::bounded::<OpResponse>(MAX_BG_RSP_CHANNELS);
+            let bg_proc_tx = Arc::new(bg_proc_tx);
+            let bg_rsp_tx = Arc::new(bg_rsp_tx);
+
+            let mountpoints = Arc::new(RwLock::new(HashMap::new()));
+            let drive_mgr = match &processor {
+                Processor::Proc(cbm) => Some(DriveManager::new(cbm.clone())),
+                Processor::Stub | Processor::Stalled => None,
+            };
+            let bus_state = drive_mgr
+                .as_ref()
+                .map_or_else(|| Arc::new(BusState::new()), |mgr| mgr.bus_state());
+            let health = HealthState::new(mountpoints.clone(), bus_state);
+
+            let mut server =
+                IpcServer::new(Pid::this(), bg_proc_tx.clone(), bg_rsp_tx.clone(), health)
+                    .with_socket_path(socket_path.clone())
+                    .with_idle_timeout(TEST_IDLE_TIMEOUT);
+            let (bg_handle, ipc_handle) = server.start(bg_rsp_rx).await.unwrap();
+
+            let processor_handle = match processor {
+                Processor::Stub => tokio::spawn(async move {
+                    while let Ok(op) = bg_proc_rx.recv_async().await {
+                        let rsp = OpResponse::from(op);
+                        if bg_rsp_tx.send_async(rsp).await.is_err() {
+                            break;
+                        }
+                    }
+                }),
+                Processor::Proc(cbm) => {
+                    init_test_args();
+                    let mut proc = Proc::new(
+                        bg_proc_rx,
+                        bg_proc_tx,
+                        Arc::new(AtomicBool::new(false)),
+                        cbm,
+                        Arc::new(Mutex::new(drive_mgr.unwrap())),
+                        mountpoints,
+                    );
+                    tokio::spawn(async move { proc.run().await })
+                }
+                Processor::Stalled => tokio::spawn(async move {
+                    // Holds on to the operations, so they're never answered
+                    let mut ops = Vec::new();
+                    while let Ok(op) = bg_proc_rx.recv_async().await {
+                        ops.push(op);
+                    }
+                }),
+            };
+
+            Self {
+                dir,
+                socket_path,
+                server,
+                handles: vec![bg_handle, ipc_handle, processor_handle],
+            }
+        }
+
+        async fn send(&self, request: Request) -> Response {
+            let mut stream = UnixStream::connect(&self.socket_path).await.unwrap();
+            Self::send_on(&mut stream, request).await
+        }
+
+        /// Sends a request on an existing connection
+        async fn send_on(stream: &mut UnixStream, request: Request) -> Response {
+            stream
+                .write_all(&encode_frame(&request).unwrap())
+                .await
+                .unwrap();
+
+            let len = stream.read_u32().await.unwrap() as usize;
+            let mut response = vec![0u8; len];
+            stream.read_exact(&mut response).await.unwrap();
+            serde_json::from_slice(&response).unwrap()
+        }
+
+        async fn stop(self) {
+            self.server.stop_all();
+            for handle in self.handles {
+                handle.abort();
+                let _ = handle.await;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ping() {
+        let harness = Harness::start().await;
+        assert!(matches!(harness.send(Ping).await, Response::Pong));
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_scan_bus() {
+        let harness = Harness::start().await;
+        assert!(matches!(
+            harness.send(ScanBus).await,
+            Response::BusScan(devices) if devices.is_empty()
+        ));
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_health() {
+        let harness = Harness::start().await;
+        assert!(matches!(
+            harness.send(Health).await,
+            Response::Health {
+                active_mounts: 0,
+                last_bus_reset_secs: Some(_),
+                xum1541_open: Some(true),
+                ..
+            }
+        ));
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_health_bg_stalled() {
+        // Health is still answered when the background processor isn't
+        // answering, as when the bus is wedged
+        let harness = Harness::start_with(Processor::Stalled).await;
+        assert!(matches!(
+            harness.send(Health).await,
+            Response::Health { .. }
+        ));
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let harness = Harness::start().await;
+        assert!(matches!(
+            harness.send(Metrics { reset: true }).await,
+            Response::Metrics(metrics) if metrics.is_empty()
+        ));
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_list_mounts() {
+        let harness = Harness::start().await;
+        assert!(matches!(
+            harness.send(ListMounts).await,
+            Response::Mounts(mounts) if mounts.is_empty()
+        ));
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_oversized_request() {
+        let harness = Harness::start().await;
+
+        // The connection is dropped without the request being read
+        let mut stream = UnixStream::connect(&harness.socket_path).await.unwrap();
+        stream
+            .write_all(&(MAX_REQUEST_SIZE as u32 + 1).to_be_bytes())
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        assert!(response.is_empty());
+
+        // And later requests are still handled
+        assert!(matches!(harness.send(Ping).await, Response::Pong));
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_keep_alive() {
+        let harness = Harness::start().await;
+
+        // Several requests, handled directly and by the background
+        // processor, can be sent on one connection
+        let mut stream = UnixStream::connect(&harness.socket_path).await.unwrap();
+        assert!(matches!(
+            Harness::send_on(&mut stream, Ping).await,
+            Response::Pong
+        ));
+        assert!(matches!(
+            Harness::send_on(&mut stream, Health).await,
+            Response::Health { .. }
+        ));
+        assert!(matches!(
+            Harness::send_on(&mut stream, Ping).await,
+            Response::Pong
+        ));
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let harness = Harness::start().await;
+
+        // An idle connection is closed by the server
+        let mut stream = UnixStream::connect(&harness.socket_path).await.unwrap();
+        let mut response = Vec::new();
+        tokio::time::timeout(TEST_IDLE_TIMEOUT * 10, stream.read_to_end(&mut response))
+            .await
+            .expect("Idle connection wasn't closed")
+            .unwrap();
+        assert!(response.is_empty());
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_subscribe() {
+        let harness = Harness::start().await;
+
+        // Device 29 isn't used by any other tests, as subscribers are global
+        let mut stream = UnixStream::connect(&harness.socket_path).await.unwrap();
+        assert!(matches!(
+            Harness::send_on(&mut stream, Subscribe { device: 29 }).await,
+            Response::Subscribed
+        ));
+
+        // Published events for the device are pushed to the client
+        let event = Event::DiskChanged {
+            device: 29,
+            name: "games".into(),
+            id: "ab".into(),
+        };
+        events::publish(event.clone());
+        let len = stream.read_u32().await.unwrap() as usize;
+        let mut response = vec![0u8; len];
+        stream.read_exact(&mut response).await.unwrap();
+        match serde_json::from_slice(&response).unwrap() {
+            Response::Event(received) => assert_eq!(received, event),
+            rsp => panic!("Expected Event response, got {}", rsp),
+        }
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_version() {
+        let harness = Harness::start().await;
+        match harness.send(Version).await {
+            Response::Version { protocol, .. } => assert_eq!(protocol, PROTOCOL_VERSION),
+            rsp => panic!("Expected Version response, got {}", rsp),
+        }
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_log_level() {
+        let harness = Harness::start().await;
+
+        // The level is global, so put it back however the test ends
+        let _saved = scopeguard::guard(save_log_level(), restore_log_level);
+        match harness
+            .send(SetLogLevel {
+                level: "trace".into(),
+            })
+            .await
+        {
+            Response::LogLevel(level) => assert_eq!(level, "TRACE"),
+            rsp => panic!("Expected LogLevel response, got {}", rsp),
+        }
+        assert!(matches!(
+            harness.send(GetLogLevel).await,
+            Response::LogLevel(level) if level == "TRACE"
+        ));
+        assert!(matches!(
+            harness
+                .send(SetLogLevel {
+                    level: "verbose".into()
+                })
+                .await,
+            Response::Error {
+                code: ERROR_CODE_VALIDATION,
+                ..
+            }
+        ));
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_identify_and_get_status() {
+        let harness = Harness::start().await;
+        assert!(matches!(
+            harness.send(Identify { device: 8 }).await,
+            Response::Identified { .. }
+        ));
+        assert!(matches!(
+            harness.send(GetStatus { device: 8 }).await,
+            Response::GotStatusStructured { .. }
+        ));
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_set_device_number() {
+        let harness = Harness::start().await;
+        assert!(matches!(
+            harness
+                .send(SetDeviceNumber {
+                    device: 8,
+                    new_device: 9
+                })
+                .await,
+            Response::DeviceNumberSet {
+                device: 8,
+                new_device: 9
+            }
+        ));
+
+        // Invalid numbers are rejected before reaching the drive
+        for new_device in [8, 16] {
+            assert!(matches!(
+                harness
+                    .send(SetDeviceNumber {
+                        device: 8,
+                        new_device
+                    })
+                    .await,
+                Response::Error {
+                    code: ERROR_CODE_VALIDATION,
+                    ..
+                }
+            ));
+        }
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_refresh_dir() {
+        let harness = Harness::start().await;
+        assert!(matches!(
+            harness
+                .send(RefreshDir {
+                    device: 8,
+                    drive: None
+                })
+                .await,
+            Response::DirRefreshed {
+                device: 8,
+                drive: 0
+            }
+        ));
+        assert!(matches!(
+            harness
+                .send(RefreshDir {
+                    device: 8,
+                    drive: Some(1)
+                })
+                .await,
+            Response::DirRefreshed {
+                device: 8,
+                drive: 1
+            }
+        ));
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_mount_unmount_flow() {
+        let harness = Harness::start().await;
+        let mountpoint = harness.dir.path().join("mnt");
+        std::fs::create_dir(&mountpoint).unwrap();
+        let mountpoint = mountpoint.to_string_lossy().to_string();
+
+        let rsp = harness
+            .send(Mount {
+                mountpoint: mountpoint.clone(),
+                device: 8,
+                dummy_formats: false,
+                bus_reset: false,
+                auto_refresh_secs: None,
+                read_write: false,
+                safe_replace: false,
+                verify_writes: false,
+                interleave: None,
+                mkdir: false,
+                image: None,
+            })
+            .await;
+        assert!(matches!(rsp, Response::MountSuccess), "{}", rsp);
+
+        let rsp = harness
+            .send(Unmount {
+                mountpoint: Some(mountpoint),
+                device: None,
+            })
+            .await;
+        assert!(matches!(rsp, Response::UnmountSuccess), "{}", rsp);
+
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_read_file_open_name() {
+        let mut mock = MockBackend::new();
+        mock.dir_files.push(CbmFileEntry::ValidFile {
+            blocks: 1,
+            filename: "DATA".into(),
+            file_type: CbmFileType::SEQ,
+        });
+        mock.files.insert("DATA".into(), b"hello".to_vec());
+        mock.files.insert("GAME".into(), b"prg".to_vec());
+        let mock = Arc::new(Mutex::new(mock));
+        let harness = Harness::start_with(Processor::Proc(mock.clone())).await;
+
+        // The file's type is looked up, so a SEQ file is opened as one, and
+        // files not in the directory by the name given
+        for filename in ["DATA", "GAME"] {
+            let rsp = harness
+                .send(ReadFile {
+                    device: 8,
+                    filename: filename.into(),
+                })
+                .await;
+            assert!(matches!(rsp, Response::FileContents(_)), "{}", rsp);
+        }
+        assert_eq!(mock.lock().await.reads, vec!["DATA,s,r", "GAME"]);
+
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_memory_read_write() {
+        let mock = Arc::new(Mutex::new(MockBackend::new()));
+        let harness = Harness::start_with(Processor::Proc(mock.clone())).await;
+
+        // Written memory is read back, with the addresses sent low byte
+        // first
+        let data: Vec<u8> = (0..40).collect();
+        let rsp = harness
+            .send(MemoryWrite {
+                device: 8,
+                addr: 0x0500,
+                data: data.clone(),
+            })
+            .await;
+        assert!(
+            matches!(
+                rsp,
+                Response::MemoryWritten {
+                    device: 8,
+                    addr: 0x0500,
+                    len: 40
+                }
+            ),
+            "{}",
+            rsp
+        );
+        assert_eq!(mock.lock().await.commands.len(), 2);
+        let rsp = harness
+            .send(MemoryRead {
+                device: 8,
+                addr: 0x0500,
+                len: 40,
+            })
+            .await;
+        match rsp {
+            Response::MemoryContents {
+                device: 8,
+                addr: 0x0500,
+                data: read,
+            } => assert_eq!(read, data),
+            rsp => panic!("Expected MemoryContents response, got {}", rsp),
+        }
+
+        // Reads beyond what one M-R returns, and empty writes, are rejected
+        // before reaching the drive
+        for request in [
+            MemoryRead {
+                device: 8,
+                addr: 0x0500,
+                len: 256,
+            },
+            MemoryWrite {
+                device: 8,
+                addr: 0x0500,
+                data: Vec::new(),
+            },
+        ] {
+            assert!(matches!(
+                harness.send(request).await,
+                Response::Error {
+                    code: ERROR_CODE_VALIDATION,
+                    ..
+                }
+            ));
+        }
+        assert_eq!(mock.lock().await.commands.len(), 2);
+
+        // Code which returns is reported as executed
+        let rsp = harness
+            .send(MemoryExecute {
+                device: 8,
+                addr: 0x0500,
+            })
+            .await;
+        assert!(
+            matches!(
+                rsp,
+                Response::MemoryExecuted {
+                    device: 8,
+                    addr: 0x0500
+                }
+            ),
+            "{}",
+            rsp
+        );
+        assert_eq!(mock.lock().await.commands[2], "M-E\0\u{5}");
+
+        harness.stop().await;
+    }
+
+    // The mount's FUSE threads wait on the background processor, so it
+    // needs a runtime thread of its own.  Run with cargo test -- --ignored on
+    // a machine which can mount FUSE filesystems.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "needs /dev/fuse and permission to mount"]
+    async fn test_mount_read_directory_unmount() {
+        let cbm: Arc<Mutex<dyn CbmBackend>> = Arc::new(Mutex::new(MockBackend::new()));
+        let harness = Harness::start_with(Processor::Proc(cbm)).await;
+        let mountpoint = harness.dir.path().join("mnt");
+        std::fs::create_dir(&mountpoint).unwrap();
+        let mountpoint = mountpoint.to_string_lossy().to_string();
+
+        let rsp = harness
+            .send(Mount {
+                mountpoint: mountpoint.clone(),
+                device: 8,
+                dummy_formats: false,
+                bus_reset: false,
+                auto_refresh_secs: None,
+                read_write: false,
+                safe_replace: false,
+                verify_writes: false,
+                interleave: None,
+                mkdir: false,
+                image: None,
+            })
+            .await;
+        assert!(matches!(rsp, Response::MountSuccess), "{}", rsp);
+
+        // The directory is read from the backend by the processor
+        let rsp = harness
+            .send(RefreshDir {
+                device: 8,
+                drive: None,
+            })
+            .await;
+        assert!(
+            matches!(
+                rsp,
+                Response::DirRefreshed {
+                    device: 8,
+                    drive: 0
+                }
+            ),
+            "{}",
+            rsp
+        );
+
+        let rsp = harness
+            .send(Unmount {
+                mountpoint: Some(mountpoint),
+                device: None,
+            })
+            .await;
+        assert!(matches!(rsp, Response::UnmountSuccess), "{}", rsp);
+
+        harness.stop().await;
+    }
+
+    #[tokio::test]
+    async fn test_mount_mkdir() {
+        let harness = Harness::start().await;
+        let mount = |mountpoint: &Path, device| Mount {
+            mountpoint: mountpoint.to_string_lossy().to_string(),
+            device,
+            dummy_formats: false,
+            bus_reset: false,
+            auto_refresh_secs: None,
+            read_write: false,
+            safe_replace: false,
+            verify_writes: false,
+            interleave: None,
+            mkdir: true,
+            image: None,
+        };
+
+        // A missing mountpoint is created
+        let mountpoint = harness.dir.path().join("mnt");
+        let rsp = harness.send(mount(&mountpoint, 8)).await;
+        assert!(matches!(rsp, Response::MountSuccess), "{}", rsp);
+        assert!(mountpoint.is_dir());
+
+        // And removed again if the request fails validation
+        let mountpoint = harness.dir.path().join("bad");
+        let rsp = harness.send(mount(&mountpoint, 255)).await;
+        assert!(matches!(rsp, Response::Error { .. }), "{}", rsp);
+        assert!(!mountpoint.exists());
+
+        harness.stop().await;
+    }
+
+    /// Builds the client Response for a background processor response of
+    /// the given type
+    fn client_response(op_type: OpType, rsp: OpResponseType) -> Response {
+        let (tx, _rx) = flume::bounded::<OpResponse>(1);
+        let mut op_response = OpResponse::from(Operation::new(op_type, Arc::new(tx), None));
+        op_response.rsp = Ok(rsp);
+        Response::from(OpResponseWrapper(Ok(op_response)))
+    }
+
+    #[test]
+    fn test_unmount_not_mounted() {
+        let unmount = || OpType::Unmount {
+            device: Some(9),
+            mountpoint: None,
+        };
+
+        // Unmounting something which isn't mounted succeeds, but says so
+        match client_response(
+            unmount(),
+            OpResponseType::Unmount {
+                not_mounted: Some("device 9".to_string()),
+            },
+        ) {
+            Response::NotMounted(what) => assert_eq!(what, "device 9"),
+            rsp => panic!("Expected NotMounted response, got {}", rsp),
+        }
+        assert!(matches!(
+            client_response(unmount(), OpResponseType::Unmount { not_mounted: None }),
+            Response::UnmountSuccess
+        ));
+    }
+
+    #[test]
+    fn test_bus_reset_reinitialized() {
+        match client_response(
+            OpType::BusReset,
+            OpResponseType::BusReset { reinitialized: 2 },
+        ) {
+            Response::BusResetSuccess {
+                reinitialized_mounts,
+            } => assert_eq!(reinitialized_mounts, 2),
+            rsp => panic!("Expected BusResetSuccess response, got {}", rsp),
         }
     }
 }
@@ -1,16 +1,21 @@
 mod args;
+mod backend;
 mod bg;
 mod daemon;
 mod drivemgr;
+mod events;
 mod file;
 mod fusermount;
+mod image;
 mod ipc;
 mod mount;
 mod mountsvc;
 mod signal;
 
 use args::{log_args, Args};
+use backend::CbmBackend;
 use daemon::Daemon;
+use drivemgr::bus_reset_settle;
 use fs1541::error::{Error, Fs1541Error};
 use fs1541::ipc::DAEMON_PID_FILENAME;
 use fs1541::logging::init_logging;
@@ -144,7 +149,12 @@ async fn async_main(args: &Args) -> Result<(), Error> {
     // We do this after daemonizing so the PID used in syslog is the PID of
     // the daemon process, not the parent process that called daemonize()
     let pid = getpid();
-    init_logging(!args.std_logging, env!("CARGO_BIN_NAME").into());
+    init_logging(
+        !args.std_logging,
+        env!("CARGO_BIN_NAME").into(),
+        None,
+        args.json_logging,
+    );
     log_args(log::Level::Debug);
     info!("-------------- Starting --------------");
     if !args.foreground {
@@ -181,12 +191,23 @@ async fn async_main(args: &Args) -> Result<(), Error> {
         error: e,
     })?;
 
-    let shared_cbm = Arc::new(Mutex::new(cbm));
+    // Initializing the xum1541 resets the bus, so give the drives a chance to
+    // settle before we use it
+    bus_reset_settle().await;
+
+    let shared_cbm: Arc<Mutex<dyn CbmBackend>> = Arc::new(Mutex::new(cbm));
 
     // Now create the daemon object
     let daemon = Daemon::new(pid, shared_cbm)?;
     let shared_daemon = Arc::new(Mutex::new(daemon));
 
+    // Identify the drives on the bus up front, so mounts can skip it
+    if args.identify_at_startup {
+        locking_section!("Lock", "Daemon", {
+            shared_daemon.lock().await.identify_drives().await;
+        });
+    }
+
     // Create the Background Processor and IPC processor
     trace!("Create Background Processor");
     locking_section!("Lock", "Daemon", {
@@ -1,42 +1,152 @@
 use fs1541::error::{Error, Fs1541Error};
+use fs1541::ipc::{Event, MountInfo};
 use fs1541::validate::{validate_mountpoint, ValidationType};
-use rs1541::{validate_device, CbmFileEntry, DeviceValidation};
+use rs1541::{validate_device, CbmFileEntry, CbmFileType, DeviceValidation};
 use rs1541::{
-    Cbm, CbmDeviceInfo, CbmDirListing, CbmDriveUnit, CbmErrorNumber, CbmErrorNumberOk, CbmStatus,
+    CbmDeviceInfo, CbmDeviceType, CbmDirListing, CbmDriveUnit, CbmErrorNumber, CbmErrorNumberOk,
+    CbmStatus,
 };
 
 use crate::args::get_args;
-use crate::bg::{OpResponse, OpResponseType, OpType, Operation};
+use crate::backend::CbmBackend;
+use crate::bg::{OpOutcome, OpResponse, OpResponseType, OpType, Operation, WriteMode};
 use crate::drivemgr::DriveManager;
-use crate::file::{DiskInfo, DiskXattr, DriveXattr, FileCache, FileEntry, FileEntryType, XattrOps};
+use crate::events;
+use crate::file::{
+    bam_header, cbm_append_open_name, cbm_format_command, cbm_name_from_fuse, cbm_open_name,
+    cbm_read_open_name, cbm_scratch_command, cbm_write_open_name, d64_header, d64_image_geometry,
+    disk_header_matches, evict_file_caches, read_size_plausible, validate_cbm_filename,
+    ControlFilePurpose, D64Geometry, DiskInfo, DiskXattr, DriveXattr, FileCache, FileEntry,
+    FileEntryType, FuseFile, PartitionCommand, RestoreStatus, XattrOps, D64_BAM_SECTOR,
+    D64_BAM_TRACK, D64_BLOCK_SIZE,
+};
 use crate::locking_section;
 
-use fuser::{BackgroundSession, FileAttr, MountOption, FUSE_ROOT_ID};
+use fuser::{BackgroundSession, MountOption, Notifier, FUSE_ROOT_ID};
 use log::{debug, info, trace, warn};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::OsStr;
+use strum::IntoEnumIterator;
 
 use flume::{Receiver, Sender};
 use std::fmt;
+use std::os::unix::fs::DirBuilderExt;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread::JoinHandle;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::sync::{Mutex, RwLock};
 
 const NUM_MOUNT_RX_CHANNELS: usize = 2;
 
+// How often the auto-refresh thread checks whether it should exit
+const AUTO_REFRESH_CHECK_DUR: Duration = Duration::from_millis(100);
+
 // Reserve first 8 bits for disk directories (256 disk)
 const DISK_INO_SHIFT: u64 = 8;
 const FIRST_FILE_INO: u64 = 1u64 << DISK_INO_SHIFT;
 
-/// Cache for directory entries
-///
-/// Maintains a cache of directory entries for a mounted filesystem,
-/// tracking when it was last updated.
-#[derive(Debug, Clone)]
-pub struct DirectoryCache {
-    _entries: HashMap<String, FileAttr>,
-    _last_updated: std::time::SystemTime,
+// Each drive mechanism's directory gets an inode between the root and the
+// first file, which limits how many a unit can have
+const MAX_DISK_DRIVES: u8 = (FIRST_FILE_INO - FUSE_ROOT_ID - 1) as u8;
+
+/// Allocates inodes.  CBM files are keyed by drive number and FUSE name, so
+/// a file keeps its inode across directory re-reads, even if the order of
+/// the listing changes.  A file's inode is only freed once a listing no
+/// longer includes it, and freed inodes are reused oldest first.
+#[derive(Debug)]
+struct InodeAllocator {
+    files: HashMap<(u8, String), u64>,
+    free: VecDeque<u64>,
+    next_inode: u64,
+}
+
+impl Default for InodeAllocator {
+    fn default() -> Self {
+        Self {
+            files: HashMap::new(),
+            free: VecDeque::new(),
+            next_inode: FIRST_FILE_INO,
+        }
+    }
+}
+
+impl InodeAllocator {
+    /// Allocates an inode which isn't tied to a CBM file, such as for a
+    /// control file
+    fn allocate(&mut self) -> u64 {
+        self.free.pop_front().unwrap_or_else(|| {
+            let inode = self.next_inode;
+            self.next_inode += 1;
+            inode
+        })
+    }
+
+    /// Returns the inode of the CBM file, allocating one if it's new
+    fn file_inode(&mut self, drive_num: u8, name: &str) -> u64 {
+        if let Some(inode) = self.files.get(&(drive_num, name.to_string())) {
+            return *inode;
+        }
+        let inode = self.allocate();
+        self.files.insert((drive_num, name.to_string()), inode);
+        inode
+    }
+
+    /// Returns the drive number of the CBM file with the inode, if it is one
+    fn drive_num(&self, inode: u64) -> Option<u8> {
+        self.files
+            .iter()
+            .find_map(|((drive_num, _), file_inode)| (*file_inode == inode).then_some(*drive_num))
+    }
+
+    /// Frees the inodes of the drive's CBM files which aren't in names
+    fn release_missing(&mut self, drive_num: u8, names: &HashSet<&str>) {
+        let free = &mut self.free;
+        self.files.retain(|(drive, name), inode| {
+            let keep = *drive != drive_num || names.contains(name.as_str());
+            if !keep {
+                trace!("Freeing inode {inode} of {drive}/{name}");
+                free.push_back(*inode);
+            }
+            keep
+        });
+    }
+}
+
+/// Tracks which file each inode was last assigned to, and a generation
+/// number per inode, which is bumped whenever the inode is reassigned to a
+/// different file.  This allows NFS and other tools to detect stale handles.
+#[derive(Debug, Default)]
+struct InodeGenerations {
+    entries: HashMap<u64, (String, u64)>,
+}
+
+impl InodeGenerations {
+    /// Records that the inode is assigned to the file identified by owner,
+    /// returning the inode's generation number
+    fn assign(&mut self, inode: u64, owner: &str) -> u64 {
+        match self.entries.get_mut(&inode) {
+            Some((existing, generation)) => {
+                if existing != owner {
+                    debug!("Inode {inode} reassigned from {existing} to {owner}");
+                    *existing = owner.to_string();
+                    *generation += 1;
+                }
+                *generation
+            }
+            None => {
+                self.entries.insert(inode, (owner.to_string(), 0));
+                0
+            }
+        }
+    }
+
+    fn generation(&self, inode: u64) -> u64 {
+        self.entries
+            .get(&inode)
+            .map_or(0, |(_, generation)| *generation)
+    }
 }
 
 /// Represents a mounted filesystem
@@ -48,18 +158,28 @@ pub struct DirectoryCache {
 pub struct Mount {
     device_num: u8,
     mountpoint: PathBuf,
-    _dummy_formats: bool,
-    cbm: Arc<Mutex<Cbm>>,
+    dummy_formats: bool,
+    read_write: bool,
+    safe_replace: bool,
+    verify_writes: bool,
+    interleave: Option<u8>,
+    created_mountpoint: bool,
+    cbm: Arc<Mutex<dyn CbmBackend>>,
     drive_mgr: Arc<Mutex<DriveManager>>,
     drive_unit: Arc<RwLock<CbmDriveUnit>>,
     bg_proc_tx: Arc<Sender<Operation>>,
     bg_rsp_tx: Arc<Sender<OpResponse>>,
     bg_rsp_rx: Option<Receiver<OpResponse>>,
-    directory_cache: Arc<RwLock<DirectoryCache>>,
     fuser: Option<Arc<Mutex<BackgroundSession>>>,
-    next_inode: u64,
+    notifier: Option<Arc<Notifier>>,
+    invalidations: Vec<Invalidation>,
+    inodes: InodeAllocator,
+    inode_generations: InodeGenerations,
     shared_self: Option<Arc<parking_lot::RwLock<Mount>>>,
     bg_rsp_handle: Option<JoinHandle<()>>,
+    auto_refresh: Option<Duration>,
+    auto_refresh_run: Arc<AtomicBool>,
+    auto_refresh_handle: Option<JoinHandle<()>>,
     dir_outstanding: bool,
     drive_info: Option<CbmDeviceInfo>,
     drive_xattrs: Vec<DriveXattr>,
@@ -89,7 +209,13 @@ impl Mount {
         device_num: u8,
         mountpoint: P,
         dummy_formats: bool,
-        cbm: Arc<Mutex<Cbm>>,
+        read_write: bool,
+        safe_replace: bool,
+        verify_writes: bool,
+        interleave: Option<u8>,
+        created_mountpoint: bool,
+        auto_refresh: Option<Duration>,
+        cbm: Arc<Mutex<dyn CbmBackend>>,
         drive_mgr: Arc<Mutex<DriveManager>>,
         drive_unit: Arc<RwLock<CbmDriveUnit>>,
         bg_proc_tx: Arc<Sender<Operation>>,
@@ -100,28 +226,32 @@ impl Mount {
         // which is sync.
         let (tx, rx) = flume::bounded(NUM_MOUNT_RX_CHANNELS);
 
-        // Create directory cache
-        let dir_cache = Arc::new(RwLock::new(DirectoryCache {
-            _entries: HashMap::new(),
-            _last_updated: SystemTime::now(),
-        }));
-
         // Create Mount
         let mount = Ok(Self {
             device_num,
             mountpoint: mountpoint.as_ref().to_path_buf(),
-            _dummy_formats: dummy_formats,
+            dummy_formats,
+            read_write,
+            safe_replace,
+            verify_writes,
+            interleave,
+            created_mountpoint,
             cbm,
             drive_mgr,
             drive_unit,
             bg_proc_tx,
             bg_rsp_tx: Arc::new(tx),
             bg_rsp_rx: Some(rx),
-            directory_cache: dir_cache,
             fuser: None,
-            next_inode: FIRST_FILE_INO,
+            notifier: None,
+            invalidations: Vec::new(),
+            inodes: InodeAllocator::default(),
+            inode_generations: InodeGenerations::default(),
             shared_self: None,
             bg_rsp_handle: None,
+            auto_refresh,
+            auto_refresh_run: Arc::new(AtomicBool::new(false)),
+            auto_refresh_handle: None,
             dir_outstanding: false,
             drive_info: None,
             drive_xattrs: Vec::new(),
@@ -133,9 +263,7 @@ impl Mount {
 
     #[allow(dead_code)]
     fn allocate_inode(&mut self) -> u64 {
-        let inode = self.next_inode;
-        self.next_inode += 1;
-        inode
+        self.inodes.allocate()
     }
 
     pub fn get_device_num(&self) -> u8 {
@@ -146,10 +274,20 @@ impl Mount {
         &self.mountpoint
     }
 
+    /// Whether this mount was requested read-write.  If not, all writes are
+    /// rejected with EROFS.
+    pub fn read_write(&self) -> bool {
+        self.read_write
+    }
+
     pub fn fuser_mount_options(&self) -> Vec<MountOption> {
         // Build the FUSE options
         let mut options = Vec::new();
-        options.push(MountOption::RO);
+        options.push(if self.read_write {
+            MountOption::RW
+        } else {
+            MountOption::RO
+        });
         options.push(MountOption::NoSuid);
         options.push(MountOption::NoAtime);
         options.push(MountOption::Sync);
@@ -189,6 +327,16 @@ impl Mount {
             });
         }
 
+        // Check the drive is there before trying to init it, so a drive
+        // which is switched off gets a clear error
+        locking_section!("Lock", "Drive Manager", {
+            self.drive_mgr
+                .lock()
+                .await
+                .validate_drive(self.device_num)
+                .await?
+        });
+
         // Init the drive - this ensures that it is actually functional.  If the drive
         // can read a disk in one of the drives this returns true - so we kick off a
         // dir later
@@ -232,20 +380,7 @@ impl Mount {
         // If we don't succeed in initing (with perhaps an error 21) we will
         // the mount (and not bother mounting fuser)
 
-        // Construct errors to ignore in drive_init
-        // We don't need to provide Ok here - is won't be treated as an error
-        // anyway
-        let ignore = vec![
-            CbmErrorNumber::ReadErrorBlockHeaderNotFound,
-            CbmErrorNumber::ReadErrorNoSyncCharacter,
-            CbmErrorNumber::ReadErrorDataBlockNotPresent,
-            CbmErrorNumber::ReadErrorChecksumErrorInDataBlock,
-            CbmErrorNumber::ReadErrorByteDecodingError,
-            CbmErrorNumber::ReadErrorChecksumErrorInHeader,
-            CbmErrorNumber::DiskIdMismatch,
-            CbmErrorNumber::DosMismatch,
-            CbmErrorNumber::DriveNotReady,
-        ];
+        let ignore = init_ignored_errors();
 
         // Init the drive
         let result_vec = locking_section!("Lock", "Drive Manager", {
@@ -308,10 +443,35 @@ impl Mount {
         Ok(init_succeeded)
     }
 
+    /// Records the results of re-initializing the drive, for example after a
+    /// bus reset.  Whatever the results, the cached directories may now be
+    /// stale, so they are invalidated.
+    pub fn reinitialized(&mut self, results: Vec<Result<CbmStatus, Error>>) {
+        for (drive_num, result) in results.into_iter().enumerate() {
+            match result {
+                Ok(status) => self.update_last_status(&status),
+                Err(e) => warn!(
+                    "{} drive {} failed to re-initialize: {}",
+                    self, drive_num, e
+                ),
+            }
+        }
+        self.invalidate_all_dir_caches();
+    }
+
     fn update_last_status(&mut self, status: &CbmStatus) {
         // Get the time
         let now = SystemTime::now();
 
+        // Tell any subscribers if the status has changed
+        let status_str = status.to_string();
+        if self.last_status().map(|last| last.to_string()) != Some(status_str.clone()) {
+            events::publish(Event::Status {
+                device: self.device_num,
+                status: status_str,
+            });
+        }
+
         // Update the last status
         XattrOps::add_or_replace(
             &mut self.drive_xattrs,
@@ -331,6 +491,662 @@ impl Mount {
             );
             XattrOps::add_or_replace(&mut self.drive_xattrs, &DriveXattr::LastErrorTime(now));
         }
+
+        // Files are written to the unit's default drive, so that's the disk
+        // a write protect error is most likely to be for
+        if status.number == DOS_WRITE_PROTECT_ON {
+            self.update_write_protected(0, status);
+        }
+
+        self.update_status_file_sizes();
+    }
+
+    /// Returns the contents of those control files which report the drive's
+    /// status or identity, or None if this isn't one of them or there's no
+    /// status yet
+    pub fn control_file_status(&self, purpose: &ControlFilePurpose) -> Option<Vec<u8>> {
+        if matches!(purpose, ControlFilePurpose::GetDeviceInfo) {
+            return self.drive_info.as_ref().map(|info| {
+                format!(
+                    "Device: {}\nModel: {}\nDescription: {}\n",
+                    self.device_num,
+                    info.device_type.as_str(),
+                    info.description
+                )
+                .into_bytes()
+            });
+        }
+
+        self.drive_xattrs
+            .iter()
+            .find_map(|xattr| match (purpose, xattr) {
+                (
+                    ControlFilePurpose::GetCurDriveStatus
+                    | ControlFilePurpose::GetDriveStatus
+                    | ControlFilePurpose::GetLastDriveStatus,
+                    DriveXattr::LastStatus(status),
+                )
+                | (ControlFilePurpose::GetLastErrorStatus, DriveXattr::LastError(status)) => {
+                    Some(format!("{}\n", status).into_bytes())
+                }
+                _ => None,
+            })
+    }
+
+    /// Returns the JSON directory listing of the disk which the control file
+    /// with this inode belongs to, if it's a GetDirJson control file
+    pub fn control_file_dir_json(&self, inode: u64) -> Option<Vec<u8>> {
+        self.disk_info.iter().find_map(|disk_info| {
+            disk_info
+                .control_files
+                .iter()
+                .find(|file| file.inode() == inode)
+                .filter(|file| {
+                    matches!(
+                        &file.native,
+                        FileEntryType::ControlFile(control_file)
+                            if matches!(control_file.purpose(), ControlFilePurpose::GetDirJson)
+                    )
+                })
+                .and_then(|_| disk_info.dir_json())
+        })
+    }
+
+    /// Returns a summary of this mount, for listing the daemon's mounts
+    pub fn mount_info(&self) -> MountInfo {
+        let mut info = MountInfo {
+            device: self.device_num,
+            mountpoint: self.mountpoint.to_string_lossy().to_string(),
+            model: String::new(),
+            num_drives: 0,
+            mount_time_secs: None,
+        };
+        for xattr in self.drive_xattrs.iter() {
+            match xattr {
+                DriveXattr::Model(model) => info.model = model.clone(),
+                DriveXattr::NumDrives(num_drives) => info.num_drives = *num_drives,
+                DriveXattr::MountTime(time) => {
+                    info.mount_time_secs = time
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .ok()
+                        .map(|since| since.as_secs())
+                }
+                _ => (),
+            }
+        }
+        info
+    }
+
+    /// Returns the drive's last status, if there is one
+    pub fn last_status(&self) -> Option<&CbmStatus> {
+        self.drive_xattrs.iter().find_map(|xattr| match xattr {
+            DriveXattr::LastStatus(status) => Some(status),
+            _ => None,
+        })
+    }
+
+    /// Returns when the drive's status was last updated, if it ever has been
+    pub fn last_status_time(&self) -> Option<SystemTime> {
+        self.drive_xattrs.iter().find_map(|xattr| match xattr {
+            DriveXattr::LastStatusTime(time) => Some(*time),
+            _ => None,
+        })
+    }
+
+    /// The status control files' sizes must match their contents, or the
+    /// kernel won't read them
+    fn update_status_file_sizes(&mut self) {
+        let sizes: Vec<(ControlFilePurpose, u64)> = ControlFilePurpose::iter()
+            .filter_map(|purpose| {
+                self.control_file_status(&purpose)
+                    .map(|contents| (purpose, contents.len() as u64))
+            })
+            .collect();
+
+        for disk_info in self.disk_info.iter_mut() {
+            for file in disk_info.control_files.iter_mut() {
+                if let FileEntryType::ControlFile(control_file) = &file.native {
+                    if let Some((_, size)) = sizes.iter().find(|(purpose, _)| {
+                        std::mem::discriminant(purpose)
+                            == std::mem::discriminant(control_file.purpose())
+                    }) {
+                        file.fuse.size = *size;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Writes any data buffered for the file to the drive, via the BG
+    /// processor.  When the response is received the directory cache is
+    /// invalidated, so the file's new size is picked up.  Returns a channel
+    /// which receives the outcome of the write, if one was submitted.  Image
+    /// restores aren't waited for, as they report their progress via their
+    /// status file.
+    pub fn write_file_sync(&mut self, inode: u64) -> Result<Option<Receiver<OpOutcome>>, Error> {
+        let device = self.device_num;
+        let Some(file) = self.file_by_inode_mut(inode) else {
+            return Err(Error::Fs1541 {
+                message: "File not found".into(),
+                error: Fs1541Error::NoEntry(format!("Inode not found {}", inode)),
+            });
+        };
+
+        if let FileEntryType::ControlFile(control_file) = &file.native {
+            if control_file.is_image_restore() {
+                return self.restore_image_sync(inode).map(|_| None);
+            }
+        }
+
+        let (filename, file_type) = match &file.native {
+            FileEntryType::CbmFile(CbmFileEntry::ValidFile {
+                filename,
+                file_type,
+                ..
+            }) => (filename.clone(), file_type.clone()),
+            _ => return Ok(None),
+        };
+        let append = file.is_append();
+        let Some((data, overwrite)) = file.take_write_data() else {
+            trace!("No data to write for inode {inode}");
+            return Ok(None);
+        };
+
+        // The buffered data is discarded, rather than being left to be
+        // written on a later flush
+        let drive_num = self.get_drive_num_for_file(inode).unwrap_or(0);
+        if self.write_protected(drive_num) {
+            return Err(Error::Fs1541 {
+                message: "Disk is write protected".into(),
+                error: Fs1541Error::ReadOnly(format!("{} on device {}", filename, device)),
+            });
+        }
+
+        // Any existing file is scratched from the drive the file is on
+        let scratch =
+            (overwrite && self.safe_replace).then(|| cbm_scratch_command(drive_num, &filename));
+        if overwrite && !self.safe_replace {
+            if let Some(info) = self
+                .drive_info
+                .as_ref()
+                .filter(|info| save_replace_bug_affected(&info.device_type))
+            {
+                warn!(
+                    "Replacing {} using @: on a {}, which may corrupt the disk - mount with safe replace to avoid this",
+                    filename,
+                    info.device_type.as_str()
+                );
+            }
+        }
+        // Reading the file back returns the data it already had as well as
+        // what was appended, so appends aren't verified
+        let verify = (self.verify_writes && !append)
+            .then(|| cbm_read_open_name(&cbm_open_name(drive_num, &filename, false), &file_type));
+        let replace = overwrite && !self.safe_replace;
+        let (path, mode) = match (&file_type, append) {
+            (CbmFileType::SEQ, false) => {
+                (cbm_open_name(drive_num, &filename, replace), WriteMode::Seq)
+            }
+            (CbmFileType::SEQ, true) => (
+                cbm_open_name(drive_num, &filename, false),
+                WriteMode::SeqAppend,
+            ),
+            (_, true) => (
+                cbm_append_open_name(drive_num, &filename, &file_type).ok_or_else(|| {
+                    Error::Fs1541 {
+                        message: "Cannot append to file".into(),
+                        error: Fs1541Error::FileAccess(filename.clone()),
+                    }
+                })?,
+                WriteMode::Open,
+            ),
+            (_, false) => (
+                cbm_write_open_name(drive_num, &filename, &file_type, replace),
+                WriteMode::Open,
+            ),
+        };
+        debug!(
+            "Writing {} bytes to {path} ({mode:?}) on device {device}",
+            data.len()
+        );
+
+        let mut op = Operation::new(
+            OpType::WriteFile {
+                device,
+                drive_num,
+                path,
+                mode,
+                data,
+                scratch,
+                verify,
+                interleave: self.interleave_command(),
+            },
+            self.bg_rsp_tx.clone(),
+            None,
+        );
+        let waiter = op.waiter();
+        send_sync_to_bg_proc(self.bg_proc_tx.clone(), op).map(|_| Some(waiter))
+    }
+
+    /// Submits an operation to the BG processor to format the disk and write
+    /// the .d64 image buffered in the image restore control file to it.
+    /// Images of the wrong size are rejected before the disk is touched.  If
+    /// this mount was created with dummy formats, the restore is skipped and
+    /// reported as complete.
+    fn restore_image_sync(&mut self, inode: u64) -> Result<(), Error> {
+        let device = self.device_num;
+        let dummy_formats = self.dummy_formats;
+        let interleave = self.interleave_command();
+        let bg_proc_tx = self.bg_proc_tx.clone();
+        let bg_rsp_tx = self.bg_rsp_tx.clone();
+        let Some(file) = self.file_by_inode_mut(inode) else {
+            return Err(Error::Fs1541 {
+                message: "File not found".into(),
+                error: Fs1541Error::NoEntry(format!("Inode not found {}", inode)),
+            });
+        };
+        let Some((data, _)) = file.take_write_data() else {
+            trace!("No image to restore for inode {inode}");
+            return Ok(());
+        };
+
+        let geometry = d64_image_geometry(data.len()).inspect_err(|e| {
+            file.restore = Some((RestoreStatus::Failed(e.to_string()), Vec::new()))
+        })?;
+        let (name, id) = d64_header(&data);
+
+        // Restoring an image formats the disk, so is skipped in the same way
+        // as formats
+        if dummy_formats {
+            info!(
+                "Dummy formats enabled - not sending {} to device {} or writing {} image",
+                cbm_format_command(0, &name, &id),
+                device,
+                geometry
+            );
+            file.restore = Some((RestoreStatus::Complete, Vec::new()));
+            self.invalidate_all_dir_caches();
+            return Ok(());
+        }
+        info!("Restoring {geometry} disk image to device {device}");
+
+        let op = Operation::new(
+            OpType::WriteImage {
+                device,
+                inode,
+                name,
+                id,
+                geometry,
+                data,
+                interleave,
+            },
+            bg_rsp_tx,
+            None,
+        );
+        send_sync_to_bg_proc(bg_proc_tx, op)
+            .inspect(|_| file.restore = Some((RestoreStatus::InProgress, Vec::new())))
+    }
+
+    /// Submits an operation to the BG processor to read every block of the
+    /// disk, with the image dump control file's geometry, to populate its
+    /// cache
+    fn read_image_sync(&mut self, inode: u64, geometry: D64Geometry) -> Result<(), Error> {
+        let op = Operation::new(
+            OpType::ReadImage {
+                device: self.device_num,
+                inode,
+                geometry,
+            },
+            self.bg_rsp_tx.clone(),
+            None,
+        );
+        let bg_proc_tx = self.bg_proc_tx.clone();
+
+        let Some(file) = self.file_by_inode_mut(inode) else {
+            return Err(Error::Fs1541 {
+                message: "File not found".into(),
+                error: Fs1541Error::NoEntry(format!("Inode not found {}", inode)),
+            });
+        };
+
+        // Don't start another image read if one is already in progress
+        if file.cache.as_ref().is_some_and(|cache| {
+            !cache.is_fully_cached() && cache.failed_status().is_none() && !cache.is_cancelled()
+        }) {
+            debug!("Image read already in progress for {}", file.fuse.name);
+            return Ok(());
+        }
+
+        info!("Reading disk image for {}", file.fuse.name);
+        let cancel_token = op.cancel_token();
+        send_sync_to_bg_proc(bg_proc_tx, op)
+            .inspect(|_| file.cache = Some(FileCache::with_cancel_token(cancel_token)))
+    }
+
+    /// Formats the disk via the BG processor.  The directory cache is
+    /// invalidated when the response is received.  If this mount was created
+    /// with dummy formats, the format is skipped, but the cache is still
+    /// invalidated.
+    pub fn format_disk_sync(&mut self, name: String, id: String) -> Result<(), Error> {
+        if self.dummy_formats {
+            info!(
+                "Dummy formats enabled - not sending {} to device {}",
+                cbm_format_command(0, &name, &id),
+                self.device_num
+            );
+            self.invalidate_all_dir_caches();
+            return Ok(());
+        }
+
+        let op = Operation::new(
+            OpType::FormatDisk {
+                device: self.device_num,
+                name,
+                id,
+                interleave: self.interleave_command(),
+            },
+            self.bg_rsp_tx.clone(),
+            None,
+        );
+        send_sync_to_bg_proc(self.bg_proc_tx.clone(), op)
+    }
+
+    /// Returns the command to configure the drive with this mount's
+    /// interleave, if one was requested and the drive supports it
+    fn interleave_command(&self) -> Option<Vec<u8>> {
+        let interleave = self.interleave?;
+        let device_type = &self.drive_info.as_ref()?.device_type;
+        let command = interleave_command(device_type, interleave);
+        if command.is_none() {
+            debug!(
+                "Ignoring interleave {} for device {} - not supported by {}",
+                interleave,
+                self.device_num,
+                device_type.as_str()
+            );
+        }
+        command
+    }
+
+    /// Marks the directory listings as needing to be re-read, so the next
+    /// readdir picks up any changes
+    pub fn invalidate_all_dir_caches(&mut self) {
+        for drive_num in 0..self.disk_info.len() {
+            self.invalidate_dir_cache(drive_num as u8);
+        }
+    }
+
+    /// Marks one drive's cached directory listing as stale, so it is re-read
+    /// the next time it is accessed
+    pub fn invalidate_dir_cache(&mut self, drive_num: u8) {
+        if let Some(disk_info) = self.disk_info.get_mut(drive_num as usize) {
+            disk_info.disk_read_time = None;
+        }
+    }
+
+    /// Creates or selects a partition on a 1581 disk via the BG processor.
+    /// Other drives don't support partitions, so the command is rejected.
+    /// The directory cache is invalidated when the response is received, as
+    /// the selected partition's files are listed in place of the root's.
+    pub fn partition_sync(&mut self, command: PartitionCommand) -> Result<(), Error> {
+        match self.drive_info.as_ref().map(|info| &info.device_type) {
+            Some(CbmDeviceType::Cbm1581) => (),
+            device_type => {
+                return Err(Error::Fs1541 {
+                    message: "Partitions not supported".into(),
+                    error: Fs1541Error::Validation(format!(
+                        "Device {} is a {}, and only 1581 drives support partitions",
+                        self.device_num,
+                        device_type.map_or("unknown drive", |device_type| device_type.as_str())
+                    )),
+                })
+            }
+        }
+
+        let op = Operation::new(
+            OpType::Partition {
+                device: self.device_num,
+                commands: command.dos_commands(),
+            },
+            self.bg_rsp_tx.clone(),
+            None,
+        );
+        send_sync_to_bg_proc(self.bg_proc_tx.clone(), op)
+    }
+
+    /// Switches a 1571 between double-sided (1571) and single-sided (1541)
+    /// mode via the BG processor.  Other drives are rejected.  The mode is
+    /// recorded in the drive's xattrs when the response is received.
+    pub fn set_1571_mode_sync(&mut self, double_sided: bool) -> Result<(), Error> {
+        match self.drive_info.as_ref().map(|info| &info.device_type) {
+            Some(CbmDeviceType::Cbm1571) => (),
+            device_type => {
+                return Err(Error::Fs1541 {
+                    message: "1571 mode not supported".into(),
+                    error: Fs1541Error::Validation(format!(
+                        "Device {} is a {}, and only 1571 drives support switching mode",
+                        self.device_num,
+                        device_type.map_or("unknown drive", |device_type| device_type.as_str())
+                    )),
+                })
+            }
+        }
+
+        let op = Operation::new(
+            OpType::Set1571Mode {
+                device: self.device_num,
+                double_sided,
+            },
+            self.bg_rsp_tx.clone(),
+            None,
+        );
+        send_sync_to_bg_proc(self.bg_proc_tx.clone(), op)
+    }
+
+    /// Scratches a CBM file, identified by its FUSE name, via the BG
+    /// processor.  Control files can't be deleted.  The status is recorded,
+    /// and the drive's directory cache invalidated, when the response is
+    /// received.  Returns a channel which then receives the outcome.
+    pub fn delete_file_sync(
+        &mut self,
+        drive_num: u8,
+        name: &str,
+    ) -> Result<Receiver<OpOutcome>, Error> {
+        let Some(disk_info) = self.disk_info.get(drive_num as usize) else {
+            return Err(Error::Fs1541 {
+                message: "File not found".into(),
+                error: Fs1541Error::NoEntry(name.to_string()),
+            });
+        };
+        if disk_info.control_files.iter().any(|f| f.fuse.name == name) {
+            return Err(Error::Fs1541 {
+                message: "Cannot delete control file".into(),
+                error: Fs1541Error::NotPermitted(name.to_string()),
+            });
+        }
+        let Some(file) = disk_info.cbm_files.iter().find(|f| f.fuse.name == name) else {
+            return Err(Error::Fs1541 {
+                message: "File not found".into(),
+                error: Fs1541Error::NoEntry(name.to_string()),
+            });
+        };
+        let FileEntryType::CbmFile(CbmFileEntry::ValidFile { filename, .. }) = &file.native else {
+            return Err(Error::Fs1541 {
+                message: "Cannot delete file".into(),
+                error: Fs1541Error::FileAccess(name.to_string()),
+            });
+        };
+
+        // Wildcards would scratch other files too
+        if filename.contains(['*', '?']) {
+            return Err(Error::Fs1541 {
+                message: "Cannot delete file".into(),
+                error: Fs1541Error::Validation(format!(
+                    "Filename contains wildcard characters: {}",
+                    filename
+                )),
+            });
+        }
+
+        let mut op = Operation::new(
+            OpType::DeleteFile {
+                device: self.device_num,
+                drive_num,
+                filename: filename.clone(),
+            },
+            self.bg_rsp_tx.clone(),
+            None,
+        );
+        let waiter = op.waiter();
+        send_sync_to_bg_proc(self.bg_proc_tx.clone(), op).map(|_| waiter)
+    }
+
+    /// Renames a CBM file, identified by its FUSE name, via the BG processor.
+    /// The new name has any suffix for the file's type removed, and must be
+    /// a valid Commodore filename.  The status is recorded, and the drive's
+    /// directory cache invalidated, when the response is received.  Returns
+    /// a channel which then receives the outcome.
+    pub fn rename_file_sync(
+        &mut self,
+        drive_num: u8,
+        name: &str,
+        new_name: &str,
+    ) -> Result<Receiver<OpOutcome>, Error> {
+        let Some(file) = self
+            .disk_info
+            .get(drive_num as usize)
+            .and_then(|disk_info| disk_info.cbm_files.iter().find(|f| f.fuse.name == name))
+        else {
+            return Err(Error::Fs1541 {
+                message: "File not found".into(),
+                error: Fs1541Error::NoEntry(name.to_string()),
+            });
+        };
+        let FileEntryType::CbmFile(CbmFileEntry::ValidFile {
+            filename,
+            file_type,
+            ..
+        }) = &file.native
+        else {
+            return Err(Error::Fs1541 {
+                message: "Cannot rename file".into(),
+                error: Fs1541Error::FileAccess(name.to_string()),
+            });
+        };
+
+        let new = cbm_name_from_fuse(new_name, file_type);
+        validate_cbm_filename(&new)?;
+        let mut op = Operation::new(
+            OpType::RenameFile {
+                device: self.device_num,
+                drive_num,
+                old: filename.clone(),
+                new,
+            },
+            self.bg_rsp_tx.clone(),
+            None,
+        );
+        let waiter = op.waiter();
+        send_sync_to_bg_proc(self.bg_proc_tx.clone(), op).map(|_| waiter)
+    }
+
+    /// Creates a CBM file on the drive, with the type given by the FUSE
+    /// name's suffix, and opens it with the flags.  Nothing is written to the
+    /// disk until the file is flushed or released, so until then it only
+    /// exists in the mount.  Its inode is allocated under the drive and name,
+    /// so the file keeps it once it's in the drive's directory listing.
+    pub fn create_file(
+        &mut self,
+        drive_num: u8,
+        fuse_name: &str,
+        flags: i32,
+        max_write_len: usize,
+    ) -> Result<FileEntry, Error> {
+        let invalid = |reason: String| Error::Fs1541 {
+            message: format!("Cannot create {}", fuse_name),
+            error: Fs1541Error::Validation(reason),
+        };
+        let file_type = FuseFile::creatable_file_type(fuse_name)
+            .ok_or_else(|| invalid("Filename must end .prg, .seq or .usr".into()))?;
+        let filename = cbm_name_from_fuse(fuse_name, &file_type);
+        validate_cbm_filename(&filename)?;
+        if drive_num >= self.num_drives() {
+            return Err(Error::Fs1541 {
+                message: format!("Cannot create {}", fuse_name),
+                error: Fs1541Error::NoEntry(format!("Drive {}", drive_num)),
+            });
+        }
+
+        let entry = CbmFileEntry::ValidFile {
+            blocks: 0,
+            filename,
+            file_type,
+        };
+        let mut file = FileEntry::from_cbm_file_entry(&entry, 0)
+            .ok_or_else(|| invalid("Not a valid file".into()))?;
+
+        // The name must be the one the file will be listed under, or the
+        // kernel won't find it again
+        if file.fuse.name != fuse_name {
+            return Err(invalid(format!("Would be listed as {}", file.fuse.name)));
+        }
+
+        // A new file has nothing to replace or append to
+        file.open(flags & !(libc::O_TRUNC | libc::O_APPEND), max_write_len)?;
+
+        let inode = self.inodes.file_inode(drive_num, fuse_name);
+        file.set_inode(inode);
+        self.inode_generations
+            .assign(inode, &format!("{}/{}", drive_num, fuse_name));
+        self.disk_info[drive_num as usize]
+            .cbm_files
+            .push(file.clone());
+        Ok(file)
+    }
+
+    /// Sends a command to the drive via the BG processor.  The resulting
+    /// status is recorded when the response is received.
+    pub fn drive_command_sync(&mut self, command: String) -> Result<(), Error> {
+        let op = Operation::new(
+            OpType::DriveCommand {
+                device: self.device_num,
+                command,
+            },
+            self.bg_rsp_tx.clone(),
+            None,
+        );
+        send_sync_to_bg_proc(self.bg_proc_tx.clone(), op)
+    }
+
+    /// Resets the drive's DOS with UJ via the BG processor, without resetting
+    /// the bus.  The drive forgets its state, including open channels and any
+    /// selected partition, so the directory cache is invalidated when the
+    /// response is received.
+    pub fn soft_reset_sync(&mut self) -> Result<(), Error> {
+        info!("Soft resetting device {}", self.device_num);
+        let op = Operation::new(
+            OpType::SoftReset {
+                device: self.device_num,
+            },
+            self.bg_rsp_tx.clone(),
+            None,
+        );
+        send_sync_to_bg_proc(self.bg_proc_tx.clone(), op)
+    }
+
+    /// Reads the drive's current status via the BG processor.  The last
+    /// status is updated when the response is received.  Returns a channel
+    /// which then receives the status.
+    pub fn get_status_sync(&mut self) -> Result<Receiver<OpOutcome>, Error> {
+        let mut op = Operation::new(
+            OpType::GetStatus {
+                device: self.device_num,
+            },
+            self.bg_rsp_tx.clone(),
+            None,
+        );
+        let waiter = op.waiter();
+        send_sync_to_bg_proc(self.bg_proc_tx.clone(), op).map(|_| waiter)
     }
 
     #[allow(dead_code)]
@@ -347,13 +1163,14 @@ impl Mount {
         self.disk_info.clear();
         for ii in 0..self.num_drives() {
             trace!("Adding disk info for drive {ii}");
-            let mut disk_info = DiskInfo::new(ii);
+            let mut disk_info = DiskInfo::new(ii, get_args().info_files);
             if self.num_drives() > 1 {
                 disk_info.add_disk_dir();
             }
             self.disk_info.push(disk_info);
         }
         self.inode_disk_info();
+        self.update_status_file_sizes();
     }
 
     async fn retrieve_drive_info(&mut self) {
@@ -363,37 +1180,66 @@ impl Mount {
         });
     }
 
+    /// Returns the number of drive mechanisms the unit reports.  Units with
+    /// more than one get a sub-directory per drive.
     pub fn num_drives(&self) -> u8 {
         match &self.drive_info {
-            Some(info) => info.device_type.num_disk_drives(),
+            Some(info) => {
+                let num = info.device_type.num_disk_drives();
+                if num > MAX_DISK_DRIVES {
+                    warn!("Device reports {num} drives, only using {MAX_DISK_DRIVES}");
+                }
+                num.min(MAX_DISK_DRIVES)
+            }
             None => 0,
         }
     }
 
     fn inode_disk_info(&mut self) {
-        let mut next_inode = self.next_inode;
-
         for disk_info in self.disk_info.iter_mut() {
+            let drive_num = disk_info.drive_num;
             for file in disk_info.control_files.iter_mut() {
                 if file.inode() == 0 {
-                    file.set_inode(next_inode);
-                    next_inode += 1;
+                    file.set_inode(self.inodes.allocate());
                 }
             }
+
+            // CBM files get the same inode they had before the directory
+            // was re-read, and those which have gone free theirs
             for file in disk_info.cbm_files.iter_mut() {
-                if file.inode() == 0 {
-                    file.set_inode(next_inode);
-                    next_inode += 1;
-                }
+                file.set_inode(self.inodes.file_inode(drive_num, &file.fuse.name));
             }
+            let names: HashSet<&str> = disk_info
+                .cbm_files
+                .iter()
+                .map(|file| file.fuse.name.as_str())
+                .collect();
+            self.inodes.release_missing(drive_num, &names);
+
             if let Some(file) = disk_info.disk_dir.as_mut() {
                 if file.inode() == 0 {
-                    file.set_inode(Self::get_drive_ino(disk_info.drive_num));
+                    file.set_inode(Self::get_drive_ino(drive_num));
                 }
             }
+
+            // Record who owns each inode, bumping the generation of any
+            // which now belong to a different file
+            for file in disk_info
+                .control_files
+                .iter()
+                .chain(disk_info.cbm_files.iter())
+                .chain(disk_info.disk_dir.iter())
+            {
+                let owner = format!("{}/{}", drive_num, file.fuse.name);
+                self.inode_generations.assign(file.inode(), &owner);
+            }
         }
+    }
 
-        self.next_inode = next_inode;
+    /// Returns the generation number of the inode, for FUSE to use to
+    /// detect stale handles
+    pub fn inode_generation(&self, inode: u64) -> u64 {
+        self.inode_generations.generation(inode)
     }
 
     // This code is really unncessary - dropping Mount should cause fuser to
@@ -403,9 +1249,16 @@ impl Mount {
         // Setting fuser to None will cause the fuser BackgroundSession to
         // drop (as this is the only instance), in turn causing fuser to exit
         // for this mount
+        self.auto_refresh_run.store(false, Ordering::Relaxed);
+        self.auto_refresh_handle = None;
         self.bg_rsp_handle = None;
         self.shared_self = None;
         self.fuser = None;
+
+        // Only remove the mountpoint if we created it
+        if self.created_mountpoint {
+            remove_created_mountpoint(&self.mountpoint);
+        }
     }
 
     // We use the format CbmDeviceType_dev<num>
@@ -427,9 +1280,24 @@ impl Mount {
     }
 
     pub fn update_fuser(&mut self, fuser: BackgroundSession) {
+        if get_args().fuse_notify {
+            self.notifier = Some(Arc::new(fuser.notifier()));
+        }
         self.fuser = Some(Arc::new(Mutex::new(fuser)));
     }
 
+    /// Takes any kernel cache invalidations queued by directory re-reads,
+    /// to be sent once the Mount lock has been released
+    fn take_invalidations(&mut self) -> Option<(Arc<Notifier>, Vec<Invalidation>)> {
+        if self.invalidations.is_empty() {
+            return None;
+        }
+        let invalidations = std::mem::take(&mut self.invalidations);
+        self.notifier
+            .clone()
+            .map(|notifier| (notifier, invalidations))
+    }
+
     async fn do_dir(&mut self) {
         if !self.dir_outstanding {
             // Send a request off to the BG processor to read the directory (and
@@ -462,6 +1330,14 @@ impl Mount {
     /// when done
     pub fn do_dir_sync(&mut self, _drive_num: u8, force: bool) -> Result<(), Error> {
         if force || !self.dir_outstanding {
+            // The listing will replace the files, so any prefetches of the
+            // current ones are no longer needed
+            if get_args().prefetch {
+                if let Err(e) = self.cancel_cache_reads() {
+                    warn!("{} failed to cancel cache reads: {}", self, e);
+                }
+            }
+
             // Build the operation
             let op = Operation::new(
                 OpType::ReadDirectory {
@@ -480,6 +1356,54 @@ impl Mount {
         }
     }
 
+    /// Cancels any cache reads for this drive still queued in the BG
+    /// processor
+    fn cancel_cache_reads(&self) -> Result<(), Error> {
+        let op = Operation::new(
+            OpType::CancelDeviceCache {
+                device: self.device_num,
+            },
+            self.bg_rsp_tx.clone(),
+            None,
+        );
+        send_sync_to_bg_proc(self.bg_proc_tx.clone(), op)
+    }
+
+    /// Queues low priority cache reads of the disks' files, so they can be
+    /// served without waiting for the drive.  Files larger than the
+    /// configured threshold are skipped, as are any which would take the
+    /// file caches over their limit.
+    fn prefetch_files(&mut self) {
+        let args = get_args();
+        let mut budget = args
+            .max_file_cache_bytes
+            .saturating_sub(self.file_cache_bytes());
+        let files: Vec<(u64, usize)> = self
+            .disk_info
+            .iter()
+            .flat_map(|disk_info| disk_info.cbm_files.iter())
+            .filter(|file| {
+                file.cache.is_none()
+                    && matches!(
+                        file.native,
+                        FileEntryType::CbmFile(CbmFileEntry::ValidFile { .. })
+                    )
+            })
+            .map(|file| (file.inode(), file.fuse.size as usize))
+            .collect();
+
+        for (inode, size) in files {
+            if size > args.prefetch_max_file_bytes || size > budget {
+                trace!("Not prefetching {} byte file {}", size, inode);
+                continue;
+            }
+            budget -= size;
+            if let Err(e) = self.read_file_sync(inode, true) {
+                warn!("{} failed to prefetch file {}: {}", self, inode, e);
+            }
+        }
+    }
+
     pub fn set_shared_self(
         &mut self,
         shared_self: Arc<parking_lot::RwLock<Mount>>,
@@ -521,35 +1445,369 @@ impl Mount {
         // Get a runtime handle that can be moved into the thread
         let runtime = tokio::runtime::Handle::current();
 
-        // We have to spawn a regular thread here, not a tokio thread, because
-        // the shared_self object is locked with a parking_lot::RwLock
-        // rather than a tokio one, which means we can't pass into a tokio
-        // spawned thread.
-        // We need a parking_lot::RwLock for shared_self, because FuserMount
-        // also has a reference to it, and that is running in a regular
-        // thread (spawned by fuser), so we can't use a tokio RwLock there.
-        let join_handle = std::thread::spawn(move || {
-            // Create a blocking task in this thread that runs our async code
-            runtime
-                .block_on(async { Self::handle_bg_responses(shared_self, bg_rsp_rx, mountpoint) })
-        });
+        // We have to spawn a regular thread here, not a tokio thread, because
+        // the shared_self object is locked with a parking_lot::RwLock
+        // rather than a tokio one, which means we can't pass into a tokio
+        // spawned thread.
+        // We need a parking_lot::RwLock for shared_self, because FuserMount
+        // also has a reference to it, and that is running in a regular
+        // thread (spawned by fuser), so we can't use a tokio RwLock there.
+        let join_handle = std::thread::spawn(move || {
+            // Create a blocking task in this thread that runs our async code
+            runtime
+                .block_on(async { Self::handle_bg_responses(shared_self, bg_rsp_rx, mountpoint) })
+        });
+
+        self.bg_rsp_handle = Some(join_handle);
+
+        Ok(())
+    }
+
+    /// Creates a thread which, if auto-refresh was requested when mounting,
+    /// re-reads the directory periodically, so changes made to the disk by
+    /// another computer on the bus (such as a C64) are picked up without
+    /// waiting for the directory cache to expire.  If disk change polling is
+    /// configured, the same thread checks periodically whether the disk has
+    /// been swapped.  The thread exits when the mount is unmounted.
+    pub fn create_auto_refresh_thread(&mut self) -> Result<(), Error> {
+        let auto_refresh = self.auto_refresh;
+        let disk_change_poll = match get_args().disk_change_poll_secs {
+            0 => None,
+            _ if !self.disk_change_detectable() => {
+                debug!("{} can't detect disk changes on this drive", self);
+                None
+            }
+            secs => Some(Duration::from_secs(secs)),
+        };
+        if auto_refresh.is_none() && disk_change_poll.is_none() {
+            return Ok(());
+        }
+        let Some(shared_self) = self.shared_self.as_ref() else {
+            return Err(Error::Fs1541 {
+                message: "Cannot create Mount auto-refresh thread".into(),
+                error: Fs1541Error::Internal("Missing Mount shared self".into()),
+            });
+        };
+        if self.auto_refresh_handle.is_some() {
+            return Err(Error::Fs1541 {
+                message: "Cannot create Mount auto-refresh thread".into(),
+                error: Fs1541Error::Internal("Already exists".into()),
+            });
+        }
+
+        // Use a Weak reference, so this thread doesn't keep the Mount alive
+        let weak_self = Arc::downgrade(shared_self);
+        let run = self.auto_refresh_run.clone();
+        run.store(true, Ordering::Relaxed);
+        if let Some(interval) = auto_refresh {
+            info!(
+                "{} auto-refreshing directory every {}s",
+                self,
+                interval.as_secs()
+            );
+        }
+        if let Some(interval) = disk_change_poll {
+            info!(
+                "{} checking for disk changes every {}s",
+                self,
+                interval.as_secs()
+            );
+        }
+
+        // This is a regular thread, for the same reasons as the BG response
+        // thread - Mount is locked with a parking_lot::RwLock
+        let join_handle = std::thread::spawn(move || {
+            let mut last_refresh = Instant::now();
+            let mut last_poll = Instant::now();
+            while run.load(Ordering::Relaxed) {
+                std::thread::sleep(AUTO_REFRESH_CHECK_DUR);
+                let refresh_due = auto_refresh.is_some_and(|i| last_refresh.elapsed() >= i);
+                let poll_due = disk_change_poll.is_some_and(|i| last_poll.elapsed() >= i);
+                if !refresh_due && !poll_due {
+                    continue;
+                }
+
+                let Some(shared_self) = weak_self.upgrade() else {
+                    break;
+                };
+                locking_section!("Write", "Mount", {
+                    let mut mount = shared_self.write();
+
+                    // do_dir_sync won't send another request if there's one
+                    // outstanding, so requests can't pile up.  A refresh
+                    // also picks up a changed disk, so there's no need to
+                    // poll as well.
+                    if refresh_due {
+                        last_refresh = Instant::now();
+                        last_poll = Instant::now();
+                        trace!("{} auto-refreshing directory", mount);
+                        if let Err(e) = mount.do_dir_sync(0, false) {
+                            warn!("{} failed to auto-refresh directory: {}", mount, e);
+                        }
+                    } else if let Some(interval) = disk_change_poll {
+                        last_poll = Instant::now();
+                        if let Err(e) = mount.probe_disk_change_sync(interval) {
+                            warn!("{} failed to check for a disk change: {}", mount, e);
+                        }
+                    }
+                });
+            }
+            debug!("Auto-refresh thread exiting");
+        });
+
+        self.auto_refresh_handle = Some(join_handle);
+
+        Ok(())
+    }
+
+    /// Whether a changed disk can be detected by reading its BAM.  Only the
+    /// 1541's BAM location is known, so other drives aren't checked.
+    fn disk_change_detectable(&self) -> bool {
+        self.drive_info
+            .as_ref()
+            .is_some_and(|info| matches!(info.device_type, CbmDeviceType::Cbm1541))
+    }
+
+    /// Submits an operation to the BG processor to read the disk's BAM, so
+    /// its header can be compared with the cached directory's.  Nothing is
+    /// sent unless the directory has been read, and the drive has been idle
+    /// for at least `idle`, so the check doesn't slow down other access.
+    fn probe_disk_change_sync(&mut self, idle: Duration) -> Result<(), Error> {
+        if self.dir_outstanding
+            || !self
+                .disk_info
+                .first()
+                .is_some_and(|disk_info| disk_info.header.is_some())
+        {
+            return Ok(());
+        }
+        if self
+            .last_status_time()
+            .and_then(|time| time.elapsed().ok())
+            .is_some_and(|age| age < idle)
+        {
+            trace!("{} not idle, so not checking for a disk change", self);
+            return Ok(());
+        }
+
+        trace!("{} checking for a disk change", self);
+        let op = Operation::new(
+            OpType::ReadBlock {
+                device: self.device_num,
+                track: D64_BAM_TRACK,
+                sector: D64_BAM_SECTOR,
+                geometry: D64Geometry::Standard,
+            },
+            self.bg_rsp_tx.clone(),
+            None,
+        );
+        send_sync_to_bg_proc(self.bg_proc_tx.clone(), op)
+    }
+
+    /// Compares the header in the disk's BAM with the cached directory's.  If
+    /// they differ the disk has been changed, so the directory and file
+    /// caches are discarded, and the time of the change recorded.
+    fn check_disk_header(&mut self, bam: &[u8]) {
+        if bam.len() < D64_BLOCK_SIZE {
+            warn!("{} read short BAM block of {} bytes", self, bam.len());
+            return;
+        }
+        let Some(header) = self
+            .disk_info
+            .first()
+            .and_then(|disk_info| disk_info.header.as_ref())
+        else {
+            return;
+        };
+        let (cached_name, cached_id) = (header.name.clone(), header.id.clone());
+        let (name, id) = bam_header(bam);
+        if disk_header_matches(&cached_name, &cached_id, &name, &id) {
+            trace!("{} disk unchanged", self);
+            return;
+        }
+
+        info!(
+            "{} disk changed from {},{} to {},{}",
+            self, cached_name, cached_id, name, id
+        );
+        self.invalidate_all_dir_caches();
+        self.clear_write_protected();
+        for file in self
+            .disk_info
+            .iter_mut()
+            .flat_map(|disk_info| disk_info.cbm_files.iter_mut())
+        {
+            file.cache = None;
+        }
+        if let Err(e) = self.cancel_cache_reads() {
+            warn!("{} failed to cancel cache reads: {}", self, e);
+        }
+        XattrOps::add_or_replace(
+            &mut self.drive_xattrs,
+            &DriveXattr::LastDiskChange(SystemTime::now()),
+        );
+        events::publish(Event::DiskChanged {
+            device: self.device_num,
+            name,
+            id,
+        });
+    }
+
+    fn process_bg_response(shared_self: Arc<parking_lot::RwLock<Mount>>, rsp: OpResponseType) {
+        match rsp {
+            OpResponseType::GetStatus { status } => {
+                debug!("Got drive status {}", status);
+                locking_section!("Write", "Mount", {
+                    shared_self.write().update_last_status(&status);
+                });
+            }
+
+            OpResponseType::DriveCommand { status } => {
+                info!("Drive command completed with status {}", status);
+                locking_section!("Write", "Mount", {
+                    let mut mount = shared_self.write();
+                    mount.update_last_status(&status);
+                    // Such as initialising the drive after a disk has had its
+                    // write protect tab removed
+                    if status.is_ok() == CbmErrorNumberOk::Ok {
+                        mount.clear_write_protected();
+                    }
+                });
+            }
+
+            OpResponseType::WriteFile {
+                drive_num,
+                path,
+                status,
+                bytes_written,
+                verified,
+                ..
+            } => {
+                if status.is_ok() != CbmErrorNumberOk::Ok {
+                    warn!("Failed to write {path}: {status}");
+                } else if verified == Some(true) {
+                    info!("Wrote and verified {bytes_written} bytes to {path}");
+                } else {
+                    info!("Wrote {bytes_written} bytes to {path}");
+                }
+                locking_section!("Write", "Mount", {
+                    let mut mount = shared_self.write();
+                    mount.update_last_status(&status);
+                    mount.update_write_protected(drive_num, &status);
+                    mount.invalidate_all_dir_caches();
+                });
+            }
+
+            OpResponseType::ReadImage {
+                inode,
+                contents,
+                bad_sectors,
+                ..
+            } => {
+                locking_section!("Write", "Mount", {
+                    let mut mount = shared_self.write();
+                    let Some(file) = mount.file_by_inode_mut(inode) else {
+                        warn!("No file found for image response inode: {}", inode);
+                        return;
+                    };
+                    let cache = file.cache.get_or_insert_with(FileCache::new);
+                    cache.set_data_complete(&contents);
+                    cache.set_bad_sectors(bad_sectors);
+                    mount.enforce_file_cache_limit(inode);
+                });
+            }
+
+            OpResponseType::WriteImage {
+                inode,
+                status,
+                bad_sectors,
+                ..
+            } => {
+                let restore_status = if status.is_ok() != CbmErrorNumberOk::Ok {
+                    RestoreStatus::Failed(status.to_string())
+                } else if !bad_sectors.is_empty() {
+                    RestoreStatus::Failed(format!("{} sectors not written", bad_sectors.len()))
+                } else {
+                    RestoreStatus::Complete
+                };
+                info!("Image restore finished: {}", restore_status);
+                locking_section!("Write", "Mount", {
+                    let mut mount = shared_self.write();
+                    mount.update_last_status(&status);
+                    mount.update_write_protected(0, &status);
+                    mount.invalidate_all_dir_caches();
+                    match mount.file_by_inode_mut(inode) {
+                        Some(file) => file.restore = Some((restore_status, bad_sectors)),
+                        None => warn!("No file found for image restore inode: {}", inode),
+                    }
+                });
+            }
+
+            OpResponseType::RenameFile { drive_num, status }
+            | OpResponseType::DeleteFile { drive_num, status } => {
+                info!("File operation completed with status {}", status);
+                locking_section!("Write", "Mount", {
+                    let mut mount = shared_self.write();
+                    mount.update_last_status(&status);
+                    mount.update_write_protected(drive_num, &status);
+                    if status.is_ok() == CbmErrorNumberOk::Ok {
+                        mount.invalidate_dir_cache(drive_num);
+                    }
+                });
+            }
+
+            OpResponseType::SoftReset { status } => {
+                info!("Soft reset completed with status {}", status);
+                locking_section!("Write", "Mount", {
+                    let mut mount = shared_self.write();
+                    mount.update_last_status(&status);
+                    mount.invalidate_all_dir_caches();
+                });
+            }
 
-        self.bg_rsp_handle = Some(join_handle);
+            OpResponseType::Partition { status } => {
+                info!("Partition command completed with status {}", status);
+                locking_section!("Write", "Mount", {
+                    let mut mount = shared_self.write();
+                    mount.update_last_status(&status);
+                    mount.invalidate_all_dir_caches();
+                });
+            }
 
-        Ok(())
-    }
+            OpResponseType::Set1571Mode {
+                double_sided,
+                status,
+            } => {
+                info!(
+                    "Set 1571 mode double-sided {} completed with status {}",
+                    double_sided, status
+                );
+                locking_section!("Write", "Mount", {
+                    let mut mount = shared_self.write();
+                    mount.update_last_status(&status);
+                    if status.is_ok() == CbmErrorNumberOk::Ok {
+                        // The same disk reads differently in each mode
+                        XattrOps::add_or_replace(
+                            &mut mount.drive_xattrs,
+                            &DriveXattr::Mode1571(double_sided),
+                        );
+                        mount.invalidate_all_dir_caches();
+                    }
+                });
+            }
 
-    fn process_bg_response(shared_self: Arc<parking_lot::RwLock<Mount>>, response: OpResponse) {
-        let rsp = if let Err(e) = response.rsp {
-            warn!("Received BG processor Error response: {}", e);
-            return;
-        } else {
-            response.rsp.unwrap()
-        };
+            OpResponseType::FormatDisk { status } => {
+                info!("Format completed with status {}", status);
+                locking_section!("Write", "Mount", {
+                    let mut mount = shared_self.write();
+                    mount.update_last_status(&status);
+                    mount.update_write_protected(0, &status);
+                    mount.invalidate_all_dir_caches();
+                });
+            }
 
-        match rsp {
             OpResponseType::ReadDirectory { status, listings } => {
-                locking_section!("Write", "Mount", {
+                let invalidations = locking_section!("Write", "Mount", {
                     let mut guard = shared_self.write();
                     if !guard.dir_outstanding {
                         warn!("Received ReadDirectory listing when one wasn't outstanding");
@@ -557,7 +1815,34 @@ impl Mount {
                     guard.set_dir_outstanding(false);
                     guard.process_directory_listings(listings);
                     guard.update_last_status(&status);
+                    if get_args().prefetch {
+                        guard.prefetch_files();
+                    }
+                    guard.take_invalidations()
                 });
+
+                // Sent without the Mount lock held, as the kernel may be
+                // waiting on a FUSE request which needs it
+                if let Some((notifier, invalidations)) = invalidations {
+                    send_invalidations(&notifier, invalidations);
+                }
+            }
+
+            OpResponseType::ReadBlock {
+                track,
+                sector,
+                contents,
+                ..
+            } => {
+                if (track, sector) == (D64_BAM_TRACK, D64_BAM_SECTOR) {
+                    locking_section!("Write", "Mount", {
+                        shared_self.write().check_disk_header(&contents);
+                    });
+                }
+            }
+
+            OpResponseType::CancelDeviceCache { device } => {
+                debug!("Cancelled queued cache reads for device {}", device);
             }
 
             OpResponseType::ReadFile {
@@ -566,6 +1851,13 @@ impl Mount {
                 inode,
                 status,
                 contents,
+            }
+            | OpResponseType::ReadFileCache {
+                device: _,
+                path,
+                inode,
+                status,
+                contents,
             } => {
                 locking_section!("Write", "Mount", {
                     let mut mount = shared_self.write();
@@ -585,9 +1877,11 @@ impl Mount {
                             status, inode, path
                         );
 
-                        // Remove the cache, as we're clearly not going to be
-                        // able to read
-                        file.cache = None;
+                        // Record the failure, so the reader waiting for this
+                        // read gets the drive's error rather than timing out
+                        file.cache
+                            .get_or_insert_with(FileCache::new)
+                            .mark_failed(status);
                         return;
                     }
 
@@ -597,12 +1891,41 @@ impl Mount {
                         // We'll fall through to set the cache anyway
                     }
 
+                    // Check the amount of data read is consistent with the
+                    // directory entry's block count.  If not, the data is
+                    // still provided to whoever asked for it, but is marked
+                    // as suspect so it isn't served from the cache again.
+                    let args = get_args();
+                    let suspect = match &file.native {
+                        FileEntryType::CbmFile(CbmFileEntry::ValidFile { blocks, .. })
+                            if args.read_size_check =>
+                        {
+                            !read_size_plausible(
+                                *blocks,
+                                contents.len(),
+                                args.read_size_tolerance_blocks,
+                            )
+                        }
+                        _ => false,
+                    };
+                    if suspect {
+                        warn!(
+                            "Read {} bytes for inode: {inode} file: {path}, which is inconsistent with its directory entry - marking cache as suspect",
+                            contents.len()
+                        );
+                    }
+
                     trace!("Setting cache data length to {}", contents.len());
                     if file.cache.is_none() {
                         trace!("File cache didn't exist for inode {} file {}", inode, path);
                         file.cache = Some(FileCache::new());
                     }
-                    file.cache.as_mut().unwrap().set_data_complete(&contents);
+                    let cache = file.cache.as_mut().unwrap();
+                    cache.set_data_complete(&contents);
+                    if suspect {
+                        cache.mark_suspect();
+                    }
+                    mount.enforce_file_cache_limit(inode);
                 });
             }
 
@@ -619,7 +1942,29 @@ impl Mount {
     ) {
         loop {
             match rx.recv() {
-                Ok(response) => Self::process_bg_response(shared_self.clone(), response),
+                Ok(response) => {
+                    let (rsp, waiter) = response.into_parts();
+                    let outcome = match rsp {
+                        Ok(rsp) => {
+                            let status = rsp.status().cloned();
+                            Self::process_bg_response(shared_self.clone(), rsp);
+                            status.ok_or_else(|| Error::Fs1541 {
+                                message: "Operation has no status".into(),
+                                error: Fs1541Error::Internal("No status in response".into()),
+                            })
+                        }
+                        Err(e) => {
+                            warn!("Received BG processor Error response: {}", e);
+                            Err(e)
+                        }
+                    };
+
+                    // Only sent once the response has been processed, so the
+                    // waiter sees the Mount as updated by it
+                    if let Some(waiter) = waiter {
+                        let _ = waiter.send(outcome);
+                    }
+                }
                 Err(e) => {
                     warn!("BG processor response channel closed, exiting: {}", e);
                     break;
@@ -633,24 +1978,43 @@ impl Mount {
     }
 
     fn process_directory_listings(&mut self, listings: Vec<CbmDirListing>) {
+        let notify = self.notifier.is_some();
+        let mut before = Vec::new();
+
         for listing in listings {
             let drive_num = listing.header.drive_number as usize;
 
-            if drive_num > 1 {
-                warn!("Found drive number > 1 {drive_num}");
+            let Some(disk_info) = self.disk_info.get_mut(drive_num) else {
+                warn!(
+                    "Found drive number {drive_num}, but unit only has {} drives",
+                    self.disk_info.len()
+                );
                 continue; // Skip invalid drive numbers
-            }
+            };
 
-            let disk_info = &mut self.disk_info[drive_num];
+            if notify {
+                before.push((drive_num, dir_entries(disk_info)));
+            }
 
             // Note this leaves new file inodes as 0
             disk_info.update_from_dir_listing(&listing);
         }
 
-        // Must add non-zero inodes to those without 0 inodes
+        // Must give the new file entries their inodes
         self.inode_disk_info();
+
+        for (drive_num, before) in before {
+            let disk_info = &self.disk_info[drive_num];
+            let parent = match disk_info.disk_dir {
+                Some(_) => Self::get_drive_ino(drive_num as u8),
+                None => FUSE_ROOT_ID,
+            };
+            let after = dir_entries(disk_info);
+            self.invalidations
+                .extend(dir_invalidations(parent, &before, &after));
+        }
     }
-    fn file_by_inode_mut(&mut self, inode: u64) -> Option<&mut FileEntry> {
+    pub fn file_by_inode_mut(&mut self, inode: u64) -> Option<&mut FileEntry> {
         self.disk_info.iter_mut().find_map(|disk_info| {
             // Check control files
             if let Some(file) = disk_info
@@ -711,15 +2075,132 @@ impl Mount {
     }
 
     pub fn get_drive_ino(drive_num: u8) -> u64 {
+        debug_assert!(drive_num < MAX_DISK_DRIVES);
         drive_num as u64 + FUSE_ROOT_ID + 1
     }
 
-    pub fn disk_xattrs(&self, drive_num: u8) -> &Vec<DiskXattr> {
-        &self.disk_info[drive_num as usize].xattrs
+    /// Returns the disk's xattrs, including how long ago its directory
+    /// listing was cached
+    pub fn disk_xattrs(&self, drive_num: u8) -> Vec<DiskXattr> {
+        let disk_info = &self.disk_info[drive_num as usize];
+        let dir_age = disk_info
+            .disk_read_time
+            .and_then(|time| time.elapsed().ok())
+            .map(|age| age.as_secs());
+
+        let mut xattrs = disk_info.xattrs.clone();
+        xattrs.push(DiskXattr::DirAgeSecs(dir_age));
+        if let Some(write_protected) = disk_info.write_protected {
+            xattrs.push(DiskXattr::WriteProtected(write_protected));
+        }
+        xattrs
+    }
+
+    /// Whether the disk in the drive is known to be write protected, in
+    /// which case writes to it are rejected up front with EROFS, rather than
+    /// failing part way through
+    pub fn write_protected(&self, drive_num: u8) -> bool {
+        self.disk_info
+            .get(drive_num as usize)
+            .is_some_and(|disk_info| disk_info.write_protected == Some(true))
+    }
+
+    /// Records whether the disk is write protected, from the status returned
+    /// by an operation which wrote to it
+    fn update_write_protected(&mut self, drive_num: u8, status: &CbmStatus) {
+        let Some(write_protected) = write_protected_from_status(status.number) else {
+            return;
+        };
+        if let Some(disk_info) = self.disk_info.get_mut(drive_num as usize) {
+            if write_protected && disk_info.write_protected != Some(true) {
+                warn!("{} disk in drive {} is write protected", self, drive_num);
+            }
+            disk_info.write_protected = Some(write_protected);
+        }
+    }
+
+    /// Forgets whether the disks are write protected, for example because
+    /// they have been changed
+    fn clear_write_protected(&mut self) {
+        for disk_info in self.disk_info.iter_mut() {
+            disk_info.write_protected = None;
+        }
+    }
+
+    /// Returns the total and free blocks on the drive's disks, from their
+    /// cached directory listings.  Disks which haven't been read yet count
+    /// as zero.
+    pub fn block_counts(&self) -> (u64, u64) {
+        self.disk_info
+            .iter()
+            .fold((0, 0), |(total, free), disk_info| {
+                (
+                    total + disk_info.total_blocks.unwrap_or(0) as u64,
+                    free + disk_info.blocks_free.unwrap_or(0) as u64,
+                )
+            })
+    }
+
+    /// Returns the drive's xattrs, including the total size of the file
+    /// caches
+    pub fn drive_xattrs(&self) -> Vec<DriveXattr> {
+        let mut xattrs = self.drive_xattrs.clone();
+        xattrs.push(DriveXattr::FileCacheBytes(self.file_cache_bytes()));
+        xattrs
+    }
+
+    /// Returns the total size of the data cached for this mount's files
+    pub fn file_cache_bytes(&self) -> usize {
+        self.disk_info
+            .iter()
+            .flat_map(|disk_info| disk_info.control_files.iter().chain(&disk_info.cbm_files))
+            .filter_map(|file| file.cache.as_ref())
+            .map(|cache| cache.len())
+            .sum()
+    }
+
+    /// Evicts the least recently read file caches if the total cached
+    /// exceeds the configured maximum.  The cache for inode keep, which has
+    /// just been read, is retained.
+    fn enforce_file_cache_limit(&mut self, keep: u64) {
+        let mut files: Vec<&mut FileEntry> = self
+            .disk_info
+            .iter_mut()
+            .flat_map(|disk_info| {
+                disk_info
+                    .control_files
+                    .iter_mut()
+                    .chain(disk_info.cbm_files.iter_mut())
+            })
+            .collect();
+        let evicted = evict_file_caches(&mut files, get_args().max_file_cache_bytes, keep);
+        if evicted > 0 {
+            debug!("{} evicted {} bytes of file caches", self, evicted);
+        }
+    }
+
+    /// Checks that responses to background operations can still be
+    /// received, so callers waiting for them can give up straight away if
+    /// not
+    pub fn check_bg_liveness(&self) -> Result<(), Error> {
+        check_bg_liveness(self.bg_rsp_handle.as_ref(), &self.bg_proc_tx).map_err(|reason| {
+            Error::Fs1541 {
+                message: format!(
+                    "Mount {} cannot process operations",
+                    self.mountpoint.display()
+                ),
+                error: Fs1541Error::Operation(reason),
+            }
+        })
     }
 
-    pub fn drive_xattrs(&self) -> &Vec<DriveXattr> {
-        &self.drive_xattrs
+    /// Records that background processing has failed, so it is visible via
+    /// the drive's xattrs
+    pub fn set_bg_failed(&mut self, reason: &str) {
+        XattrOps::add_or_replace(
+            &mut self.drive_xattrs,
+            &DriveXattr::BgStatus(format!("failed: {}", reason)),
+        );
     }
 
     pub fn disk_info(&self) -> &Vec<DiskInfo> {
@@ -744,6 +2225,23 @@ impl Mount {
         }
     }
 
+    /// Returns the drive whose files are in the directory with this inode -
+    /// the root for a single drive unit, or the drive's own directory for
+    /// multi-drive units
+    pub fn get_drive_num_for_dir(&self, inode: u64) -> Option<u8> {
+        match self.num_drives() {
+            1 if inode == FUSE_ROOT_ID => Some(0),
+            num if num > 1 => self.get_drive_num_by_inode(inode),
+            _ => None,
+        }
+    }
+
+    /// Returns the drive number of the CBM file with the inode, from the key
+    /// its inode was allocated under
+    pub fn get_drive_num_for_file(&self, inode: u64) -> Option<u8> {
+        self.inodes.drive_num(inode)
+    }
+
     pub fn get_drive_num_by_inode(&self, inode: u64) -> Option<u8> {
         self.file_by_inode(inode)
             .and_then(|file_entry| match file_entry.native {
@@ -788,12 +2286,21 @@ impl Mount {
     pub fn read_file_sync(&mut self, inode: u64, cache: bool) -> Result<(), Error> {
         trace!("Mount::read_file_sync");
 
+        // The image dump control file's contents are read from the drive
+        if let Some(FileEntryType::ControlFile(control_file)) =
+            self.file_by_inode(inode).map(|file| &file.native)
+        {
+            if let Some(geometry) = control_file.image_geometry() {
+                return self.read_image_sync(inode, geometry);
+            }
+        }
+
         // Extract some values before we access self mutably.
         let device_num = self.device_num;
         let bg_rsp_tx = self.bg_rsp_tx.clone();
         let bg_proc_tx = self.bg_proc_tx.clone();
 
-        let _drive = self.get_drive_num_from_inode(inode).unwrap_or_else(|| {
+        let drive_num = self.get_drive_num_from_inode(inode).unwrap_or_else(|| {
             warn!("Failed to get drive number from file, using 0");
             0
         });
@@ -807,10 +2314,16 @@ impl Mount {
             })?,
         };
 
-        // Get the filename (includes ensuring we have the proper type of file)
+        // Get the filename (includes ensuring we have the proper type of
+        // file), including the drive it's on and any suffix needed to open
+        // this type of file
         let filename = match &file.native {
             FileEntryType::CbmFile(cbm_file) => match cbm_file {
-                CbmFileEntry::ValidFile { filename, .. } => filename.clone(),
+                CbmFileEntry::ValidFile {
+                    filename,
+                    file_type,
+                    ..
+                } => cbm_read_open_name(&cbm_open_name(drive_num, filename, false), file_type),
                 CbmFileEntry::InvalidFile { .. } => {
                     return Err(Error::Fs1541 {
                         message: "Shouldn't be asking bg processor to read an invalid CBM file"
@@ -837,7 +2350,11 @@ impl Mount {
         // either leave it (if we've been asked to do a cache read, so it
         // continues with the same priority), or drop it, and do another one
         if let Some(file_cache) = &file.cache {
-            if file_cache.is_fully_cached() {
+            if file_cache.is_fully_cached()
+                || file_cache.is_suspect()
+                || file_cache.failed_status().is_some()
+                || file_cache.is_cancelled()
+            {
                 debug!(
                     "Dropping existing file cache for {} {}",
                     inode, file.fuse.name
@@ -874,9 +2391,120 @@ impl Mount {
             }
         };
         let op = Operation::new(op_type, bg_rsp_tx, None);
+        let cancel_token = op.cancel_token();
 
         // Send it
-        send_sync_to_bg_proc(bg_proc_tx, op).inspect(|_| file.cache = Some(FileCache::new()))
+        send_sync_to_bg_proc(bg_proc_tx, op)
+            .inspect(|_| file.cache = Some(FileCache::with_cancel_token(cancel_token)))
+    }
+}
+
+/// The errors to ignore when initializing a drive, which mean there's no
+/// disk, or one which can't be read, in one of its drives.  We don't need to
+/// provide Ok here - it won't be treated as an error anyway.
+pub fn init_ignored_errors() -> Vec<CbmErrorNumber> {
+    vec![
+        CbmErrorNumber::ReadErrorBlockHeaderNotFound,
+        CbmErrorNumber::ReadErrorNoSyncCharacter,
+        CbmErrorNumber::ReadErrorDataBlockNotPresent,
+        CbmErrorNumber::ReadErrorChecksumErrorInDataBlock,
+        CbmErrorNumber::ReadErrorByteDecodingError,
+        CbmErrorNumber::ReadErrorChecksumErrorInHeader,
+        CbmErrorNumber::DiskIdMismatch,
+        CbmErrorNumber::DosMismatch,
+        CbmErrorNumber::DriveNotReady,
+    ]
+}
+
+/// DOS error number reported when writing to a write protected disk
+const DOS_WRITE_PROTECT_ON: u8 = 26;
+
+/// Whether a disk is write protected, based on the DOS error number returned
+/// by an operation which wrote to it.  None if the status doesn't tell us.
+fn write_protected_from_status(number: u8) -> Option<bool> {
+    match number {
+        DOS_WRITE_PROTECT_ON => Some(true),
+        // OK and FILES SCRATCHED
+        0 | 1 => Some(false),
+        _ => None,
+    }
+}
+
+/// Whether the drive's DOS has the save-with-replace bug, where replacing a
+/// file using @: can corrupt the disk
+fn save_replace_bug_affected(device_type: &CbmDeviceType) -> bool {
+    matches!(device_type, CbmDeviceType::Cbm1541)
+}
+
+/// Returns the command to set the sector interleave used when the drive
+/// writes files, or None if the drive's DOS doesn't support changing it.
+/// The 1541 keeps it in zero page at $69, while the 1571 and 1581 have a
+/// U0>S command for it.
+fn interleave_command(device_type: &CbmDeviceType, interleave: u8) -> Option<Vec<u8>> {
+    match device_type {
+        CbmDeviceType::Cbm1541 => {
+            let mut command = b"M-W".to_vec();
+            command.extend_from_slice(&[0x69, 0x00, 1, interleave]);
+            Some(command)
+        }
+        CbmDeviceType::Cbm1571 | CbmDeviceType::Cbm1581 => {
+            let mut command = b"U0>S".to_vec();
+            command.push(interleave);
+            Some(command)
+        }
+        _ => None,
+    }
+}
+
+/// Re-reads the directory in one of the mount's drives, returning once the
+/// new listing has been read, or with a timeout error.  As in
+/// FuserMount::wait_for_dir_refresh, the Mount is only locked briefly to
+/// check whether the listing has been read, and not while sleeping.
+///
+/// The read is requested on a blocking thread, as this may be called from
+/// the BG processor, which must stay free to receive it.
+pub async fn refresh_dir(
+    mount: Arc<parking_lot::RwLock<Mount>>,
+    drive_num: u8,
+    timeout: Duration,
+    interval: Duration,
+) -> Result<(), Error> {
+    let started = Instant::now();
+    let request_mount = mount.clone();
+    tokio::task::spawn_blocking(move || {
+        locking_section!("Write", "Mount", {
+            let mut mount = request_mount.write();
+            if drive_num >= mount.num_drives() {
+                return Err(Error::Fs1541 {
+                    message: "Invalid drive number".into(),
+                    error: Fs1541Error::Validation(format!("{} has no drive {}", mount, drive_num)),
+                });
+            }
+            mount.do_dir_sync(drive_num, false)
+        })
+    })
+    .await
+    .map_err(|e| Error::Fs1541 {
+        message: "Failed to request directory re-read".into(),
+        error: Fs1541Error::Internal(e.to_string()),
+    })??;
+
+    loop {
+        let read = locking_section!("Read", "Mount", {
+            let mount = mount.read();
+            mount.check_bg_liveness()?;
+            !mount.should_refresh_dir(drive_num, started.elapsed())
+        });
+        if read {
+            return Ok(());
+        }
+        if started.elapsed() >= timeout {
+            return Err(Error::Fs1541 {
+                message: "Directory re-read timed out".into(),
+                error: Fs1541Error::Timeout(format!("Drive {} directory", drive_num), timeout),
+            });
+        }
+        tokio::time::sleep(interval).await;
     }
 }
 
@@ -896,11 +2524,43 @@ fn send_sync_to_bg_proc(bg_proc_tx: Arc<Sender<Operation>>, op: Operation) -> Re
     }
 }
 
+/// Creates the mountpoint if mkdir was requested and it doesn't already exist.
+/// Returns whether it was created, so the caller knows whether to remove it
+/// again later.  Relative paths are left for validation to reject.
+pub fn create_mountpoint(path: &Path, mkdir: bool) -> Result<bool, Error> {
+    if !mkdir || !path.is_absolute() || path.exists() {
+        return Ok(false);
+    }
+
+    std::fs::DirBuilder::new()
+        .mode(0o755)
+        .create(path)
+        .map_err(|e| Error::Io {
+            message: format!("Failed to create mountpoint {}", path.display()),
+            error: e.to_string(),
+        })?;
+    info!("Created mountpoint {}", path.display());
+
+    Ok(true)
+}
+
+/// Removes a mountpoint we created.  Uses remove_dir, so will never remove a
+/// directory which has had something put in it since.
+pub fn remove_created_mountpoint(path: &Path) {
+    match std::fs::remove_dir(path) {
+        Ok(()) => info!("Removed mountpoint {}", path.display()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => warn!("Failed to remove mountpoint {}: {}", path.display(), e),
+    }
+}
+
 pub fn validate_mount_request<P: AsRef<Path>>(
     mountpoint: P,
     device: u8,
     dummy_formats: bool,
     bus_reset: bool,
+    read_write: bool,
+    image: Option<&Path>,
 ) -> Result<PathBuf, Error> {
     // If validation OK, assert that we got given the same device number - it
     // shouldn't change if it was validate, as we are doing Required
@@ -929,6 +2589,23 @@ pub fn validate_mount_request<P: AsRef<Path>>(
     if bus_reset {
         debug!("Bus reset requested")
     };
+    if read_write {
+        debug!("Read-write mount requested")
+    };
+
+    // Disk images are served read-only
+    if let Some(image) = image {
+        if read_write {
+            return Err(Error::Fs1541 {
+                message: "Validation failure".to_string(),
+                error: Fs1541Error::Validation(format!(
+                    "Disk image {} can only be mounted read-only",
+                    image.display()
+                )),
+            });
+        }
+        debug!("Mount of disk image {} requested", image.display());
+    }
 
     Ok(rpath)
 }
@@ -977,3 +2654,452 @@ pub fn validate_unmount_request<P: AsRef<Path>>(
 
     Ok(())
 }
+
+/// Returns a description of the problem if the background response thread
+/// has exited (for example because it panicked), or the background
+/// processor has stopped receiving operations.  A response thread which
+/// hasn't been started yet isn't a problem.
+fn check_bg_liveness(
+    rsp_handle: Option<&JoinHandle<()>>,
+    bg_proc_tx: &Sender<Operation>,
+) -> Result<(), String> {
+    if rsp_handle.is_some_and(|handle| handle.is_finished()) {
+        Err("Background response thread has exited".to_string())
+    } else if bg_proc_tx.is_disconnected() {
+        Err("Background processor is not running".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+/// A kernel cache entry made stale by a directory re-read
+#[derive(Debug, Clone, PartialEq)]
+enum Invalidation {
+    Inode(u64),
+    Entry { parent: u64, name: String },
+}
+
+/// Returns the (name, inode, size) of each CBM file on the disk
+fn dir_entries(disk_info: &DiskInfo) -> Vec<(String, u64, u64)> {
+    disk_info
+        .cbm_files
+        .iter()
+        .map(|file| (file.fuse.name.clone(), file.inode(), file.fuse.size))
+        .collect()
+}
+
+/// Works out which kernel cache entries need invalidating after a
+/// directory's contents changed from before to after.  Names which have gone
+/// or now refer to a different inode have their dentry and old inode
+/// invalidated, files whose size changed have their inode invalidated, and
+/// the directory itself is invalidated if anything changed at all.
+fn dir_invalidations(
+    parent: u64,
+    before: &[(String, u64, u64)],
+    after: &[(String, u64, u64)],
+) -> Vec<Invalidation> {
+    let mut invalidations = Vec::new();
+
+    for (name, inode, size) in before {
+        match after.iter().find(|(n, _, _)| n == name) {
+            Some((_, new_inode, _)) if new_inode != inode => {
+                invalidations.push(Invalidation::Entry {
+                    parent,
+                    name: name.clone(),
+                });
+                invalidations.push(Invalidation::Inode(*inode));
+            }
+            Some((_, _, new_size)) if new_size != size => {
+                invalidations.push(Invalidation::Inode(*inode));
+            }
+            Some(_) => (),
+            None => {
+                invalidations.push(Invalidation::Entry {
+                    parent,
+                    name: name.clone(),
+                });
+                invalidations.push(Invalidation::Inode(*inode));
+            }
+        }
+    }
+
+    let added = after
+        .iter()
+        .any(|(name, _, _)| !before.iter().any(|(n, _, _)| n == name));
+    if added || !invalidations.is_empty() {
+        invalidations.push(Invalidation::Inode(parent));
+    }
+
+    invalidations
+}
+
+/// Tells the kernel to drop the given cached entries.  Failures are only
+/// logged, as the kernel may simply not have had the entry cached.
+fn send_invalidations(notifier: &Notifier, invalidations: Vec<Invalidation>) {
+    for invalidation in invalidations {
+        let result = match &invalidation {
+            Invalidation::Inode(ino) => notifier.inval_inode(*ino, 0, 0),
+            Invalidation::Entry { parent, name } => notifier.inval_entry(*parent, OsStr::new(name)),
+        };
+        match result {
+            Ok(()) => trace!("Sent invalidation {invalidation:?}"),
+            Err(e) => debug!("Failed to send invalidation {invalidation:?}: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::init_test_args;
+    use crate::backend::mock::MockBackend;
+    use crate::file::d64_offset;
+    use crate::image::CbmImageBackend;
+
+    #[test]
+    fn test_save_replace_bug_affected() {
+        assert!(save_replace_bug_affected(&CbmDeviceType::Cbm1541));
+        assert!(!save_replace_bug_affected(&CbmDeviceType::Cbm1581));
+    }
+
+    #[test]
+    fn test_write_protected_from_status() {
+        assert_eq!(write_protected_from_status(26), Some(true));
+        assert_eq!(write_protected_from_status(0), Some(false));
+        assert_eq!(write_protected_from_status(1), Some(false));
+        assert_eq!(write_protected_from_status(62), None);
+        assert_eq!(write_protected_from_status(73), None);
+    }
+
+    #[test]
+    fn test_interleave_command() {
+        assert_eq!(
+            interleave_command(&CbmDeviceType::Cbm1541, 6),
+            Some(b"M-W\x69\x00\x01\x06".to_vec())
+        );
+        assert_eq!(
+            interleave_command(&CbmDeviceType::Cbm1571, 6),
+            Some(b"U0>S\x06".to_vec())
+        );
+        assert_eq!(
+            interleave_command(&CbmDeviceType::Cbm1581, 1),
+            Some(b"U0>S\x01".to_vec())
+        );
+        assert!(interleave_command(&CbmDeviceType::Cbm8250, 6).is_none());
+    }
+
+    #[test]
+    fn test_inode_allocator() {
+        let mut inodes = InodeAllocator::default();
+        let listing = |inodes: &mut InodeAllocator, drive_num: u8, names: &[&str]| {
+            let allocated: Vec<u64> = names
+                .iter()
+                .map(|name| inodes.file_inode(drive_num, name))
+                .collect();
+            inodes.release_missing(drive_num, &names.iter().copied().collect());
+            allocated
+        };
+
+        // Files keep their inodes when the listing's order changes
+        let first = listing(&mut inodes, 0, &["a.prg", "b.prg"]);
+        assert_eq!(first, vec![FIRST_FILE_INO, FIRST_FILE_INO + 1]);
+        let swapped = listing(&mut inodes, 0, &["b.prg", "a.prg"]);
+        assert_eq!(swapped, vec![first[1], first[0]]);
+
+        // The same name on another drive is a different file, and re-reading
+        // one drive doesn't affect the other's
+        let other = listing(&mut inodes, 1, &["a.prg"]);
+        assert_eq!(other, vec![FIRST_FILE_INO + 2]);
+
+        // Inodes are only reused once their file has gone
+        assert_eq!(listing(&mut inodes, 0, &["a.prg"]), vec![first[0]]);
+        assert_eq!(
+            listing(&mut inodes, 0, &["a.prg", "c.prg"]),
+            vec![first[0], first[1]]
+        );
+        assert_eq!(inodes.file_inode(1, "a.prg"), other[0]);
+        assert_eq!(inodes.allocate(), FIRST_FILE_INO + 3);
+
+        // Each file's drive comes from the key its inode was allocated under
+        assert_eq!(inodes.drive_num(first[0]), Some(0));
+        assert_eq!(inodes.drive_num(other[0]), Some(1));
+        assert_eq!(inodes.drive_num(FIRST_FILE_INO + 3), None);
+    }
+
+    #[test]
+    fn test_inode_generations() {
+        let mut generations = InodeGenerations::default();
+        assert_eq!(generations.generation(2), 0);
+
+        // Reassigning an inode to the same file leaves its generation alone
+        assert_eq!(generations.assign(2, "0/file1.prg"), 0);
+        assert_eq!(generations.assign(2, "0/file1.prg"), 0);
+        assert_eq!(generations.assign(3, "0/file2.prg"), 0);
+
+        // Reusing it for a different file bumps it
+        assert_eq!(generations.assign(2, "0/file3.prg"), 1);
+        assert_eq!(generations.generation(2), 1);
+        assert_eq!(generations.assign(2, "0/file1.prg"), 2);
+
+        // Other inodes are unaffected
+        assert_eq!(generations.generation(3), 0);
+    }
+
+    #[test]
+    fn test_drive_inos() {
+        // Every drive's directory inode is unique, and below the file inodes
+        let inos: Vec<u64> = (0..MAX_DISK_DRIVES).map(Mount::get_drive_ino).collect();
+        assert_eq!(inos[0], FUSE_ROOT_ID + 1);
+        assert!(inos.windows(2).all(|pair| pair[1] == pair[0] + 1));
+        assert!(*inos.last().unwrap() < FIRST_FILE_INO);
+    }
+
+    #[test]
+    fn test_bg_liveness() {
+        let (tx, rx) = flume::bounded::<Operation>(1);
+
+        // Not yet started, and running, response threads are fine
+        assert!(check_bg_liveness(None, &tx).is_ok());
+        let (stop_tx, stop_rx) = flume::bounded::<()>(1);
+        let running = std::thread::spawn(move || {
+            let _ = stop_rx.recv();
+        });
+        assert!(check_bg_liveness(Some(&running), &tx).is_ok());
+        stop_tx.send(()).unwrap();
+        running.join().unwrap();
+
+        // A response handler which has died is detected
+        let dead = std::thread::spawn(|| panic!("Simulated response handler failure"));
+        while !dead.is_finished() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(check_bg_liveness(Some(&dead), &tx).is_err());
+
+        // As is the background processor going away
+        drop(rx);
+        assert!(check_bg_liveness(None, &tx).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_soft_reset() {
+        init_test_args();
+
+        // A device no other test subscribes to
+        let device = 27;
+        let cbm: Arc<Mutex<dyn CbmBackend>> = Arc::new(Mutex::new(MockBackend::new()));
+        let drive_mgr = Arc::new(Mutex::new(DriveManager::new(cbm.clone())));
+        let drive_unit = drive_mgr.lock().await.add_drive(device).await.unwrap();
+        let (tx, rx) = flume::unbounded();
+        let mut mount = Mount::new(
+            device,
+            "/tmp/soft_reset",
+            false,
+            false,
+            false,
+            false,
+            None,
+            false,
+            None,
+            cbm,
+            drive_mgr,
+            drive_unit,
+            Arc::new(tx),
+        )
+        .unwrap();
+        mount.mount().await.unwrap();
+        mount.disk_info[0].disk_read_time = Some(SystemTime::now());
+
+        // The directory is still cached while the reset is queued
+        mount.soft_reset_sync().unwrap();
+        assert!(mount.disk_info[0].disk_read_time.is_some());
+        assert!(rx
+            .try_iter()
+            .any(|op| matches!(op.op_type, OpType::SoftReset { device: 27 })));
+
+        // And only invalidated once the drive has reset
+        let mount = Arc::new(parking_lot::RwLock::new(mount));
+        Mount::process_bg_response(
+            mount.clone(),
+            OpResponseType::SoftReset {
+                status: CbmStatus::default(),
+            },
+        );
+        assert!(mount.read().disk_info[0].disk_read_time.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_image_seq_read() {
+        init_test_args();
+
+        // An image holding a one block SEQ file
+        let data = b"hello\r";
+        let mut image = vec![0; D64Geometry::Standard.image_size()];
+        let dir = d64_offset(D64_BAM_TRACK, 1);
+        image[dir + 1] = 0xff;
+        image[dir + 2] = 0x81;
+        image[dir + 3..dir + 5].copy_from_slice(&[17, 0]);
+        image[dir + 5..dir + 21].fill(0xa0);
+        image[dir + 5..dir + 10].copy_from_slice(b"NOTES");
+        image[dir + 30] = 1;
+        let block = d64_offset(17, 0);
+        image[block + 1] = data.len() as u8 + 1;
+        image[block + 2..block + 2 + data.len()].copy_from_slice(data);
+
+        // A device no other test subscribes to
+        let device = 25;
+        let cbm: Arc<Mutex<dyn CbmBackend>> = Arc::new(Mutex::new(
+            CbmImageBackend::new("notes.d64", image).unwrap(),
+        ));
+        let drive_mgr = Arc::new(Mutex::new(DriveManager::new(cbm.clone())));
+        let drive_unit = drive_mgr
+            .lock()
+            .await
+            .add_image_drive(device, cbm.clone())
+            .await
+            .unwrap();
+        let (tx, rx) = flume::unbounded();
+        let mut mount = Mount::new(
+            device,
+            "/tmp/image_seq",
+            false,
+            false,
+            false,
+            false,
+            None,
+            false,
+            None,
+            cbm.clone(),
+            drive_mgr,
+            drive_unit.clone(),
+            Arc::new(tx),
+        )
+        .unwrap();
+        mount.mount().await.unwrap();
+        let (listings, _) = cbm.lock().await.dir(&*drive_unit.read().await).unwrap();
+        mount.process_directory_listings(listings);
+
+        // The mount opens the file with its type, which the image ignores
+        let inode = mount
+            .get_drive_files(0)
+            .iter()
+            .find(|file| file.fuse.name == "notes.seq")
+            .unwrap()
+            .inode();
+        mount.read_file_sync(inode, false).unwrap();
+        let path = rx
+            .try_iter()
+            .find_map(|op| match op.op_type {
+                OpType::ReadFile { path, .. } => Some(path),
+                _ => None,
+            })
+            .unwrap();
+        assert!(path.ends_with(",s,r"), "{}", path);
+        let (contents, _) = cbm
+            .lock()
+            .await
+            .read_file(&*drive_unit.read().await, &path, &AtomicBool::new(false))
+            .unwrap();
+        assert_eq!(contents, data);
+    }
+
+    #[test]
+    fn test_dir_invalidations() {
+        let entry = |name: &str, inode, size| (name.to_string(), inode, size);
+        let before = vec![entry("a.prg", 10, 254), entry("b.prg", 11, 508)];
+
+        // Nothing changed
+        assert!(dir_invalidations(2, &before, &before).is_empty());
+
+        // A file was added
+        let after = vec![
+            entry("a.prg", 10, 254),
+            entry("b.prg", 11, 508),
+            entry("c.prg", 12, 254),
+        ];
+        assert_eq!(
+            dir_invalidations(2, &before, &after),
+            vec![Invalidation::Inode(2)]
+        );
+
+        // A file was removed, another changed size
+        let after = vec![entry("a.prg", 10, 762)];
+        assert_eq!(
+            dir_invalidations(2, &before, &after),
+            vec![
+                Invalidation::Inode(10),
+                Invalidation::Entry {
+                    parent: 2,
+                    name: "b.prg".to_string()
+                },
+                Invalidation::Inode(11),
+                Invalidation::Inode(2),
+            ]
+        );
+
+        // A file was re-read with a new inode
+        let after = vec![entry("a.prg", 13, 254), entry("b.prg", 11, 508)];
+        assert_eq!(
+            dir_invalidations(2, &before, &after),
+            vec![
+                Invalidation::Entry {
+                    parent: 2,
+                    name: "a.prg".to_string()
+                },
+                Invalidation::Inode(10),
+                Invalidation::Inode(2),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_create_mountpoint() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("mnt");
+
+        // Only created if requested
+        assert!(!create_mountpoint(&path, false).unwrap());
+        assert!(!path.exists());
+        assert!(create_mountpoint(&path, true).unwrap());
+        assert!(path.is_dir());
+
+        // An existing directory isn't reported as created
+        assert!(!create_mountpoint(&path, true).unwrap());
+
+        // Relative paths are left for validation to reject
+        assert!(!create_mountpoint(Path::new("relative"), true).unwrap());
+
+        remove_created_mountpoint(&path);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_remove_created_mountpoint_not_empty() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("mnt");
+        std::fs::create_dir(&path).unwrap();
+        std::fs::write(path.join("file"), b"data").unwrap();
+
+        // A directory with something in it is left alone
+        remove_created_mountpoint(&path);
+        assert!(path.join("file").exists());
+
+        // As is a missing one
+        remove_created_mountpoint(&dir.path().join("missing"));
+    }
+    #[test]
+    fn test_validate_mount_request_image() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let image = Path::new("/tmp/disk.d64");
+
+        // Disk images can only be mounted read-only
+        assert!(validate_mount_request(dir.path(), 8, false, false, false, Some(image)).is_ok());
+        assert!(matches!(
+            validate_mount_request(dir.path(), 8, false, false, true, Some(image)),
+            Err(Error::Fs1541 {
+                error: Fs1541Error::Validation(_),
+                ..
+            })
+        ));
+        assert!(validate_mount_request(dir.path(), 8, false, false, true, None).is_ok());
+    }
+}
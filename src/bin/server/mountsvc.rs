@@ -1,33 +1,43 @@
+use crate::args::get_args;
+use crate::backend::CbmBackend;
 use crate::bg::Operation;
 use crate::drivemgr::DriveManager;
+use crate::events;
 use crate::fusermount::FuserMount;
+use crate::image::CbmImageBackend;
 use crate::locking_section;
-use crate::mount::Mount;
+use crate::mount::{init_ignored_errors, Mount};
 
 use fs1541::error::{Error, Fs1541Error};
-use rs1541::Cbm;
+use fs1541::ipc::{Event, MountInfo};
 
 use flume::Sender;
 use log::{debug, info, trace, warn};
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::{Mutex, RwLock};
 
 /// Service that sits above DeviceManager and Mount to manage lifecycle of
 /// CbmDeviceUnit and Mount objects - as Mount lifecycle operations require
 /// locking DriveManager, and hence we must not call into these Mount
 /// operations with DriveManager locked (so can't do it from DriveManager)
+///
+/// Each mount costs a FUSE session thread (spawned by fuser), a background
+/// response thread (see Mount::create_bg_response_thread), plus the memory
+/// for its directory listing and file caches.  The number of concurrent
+/// mounts is capped by the max_mounts argument to bound this.
 #[derive(Debug)]
 pub struct MountService {
-    cbm: Arc<Mutex<Cbm>>,
+    cbm: Arc<Mutex<dyn CbmBackend>>,
     drive_mgr: Arc<Mutex<DriveManager>>,
     mountpoints: Arc<RwLock<HashMap<PathBuf, Arc<parking_lot::RwLock<Mount>>>>>,
 }
 
 impl MountService {
     pub fn new(
-        cbm: Arc<Mutex<Cbm>>,
+        cbm: Arc<Mutex<dyn CbmBackend>>,
         drive_mgr: Arc<Mutex<DriveManager>>,
         mountpoints: Arc<RwLock<HashMap<PathBuf, Arc<parking_lot::RwLock<Mount>>>>>,
     ) -> Self {
@@ -43,20 +53,73 @@ impl MountService {
         device_number: u8,
         mountpoint: P,
         dummy_formats: bool,
+        read_write: bool,
+        safe_replace: bool,
+        verify_writes: bool,
+        interleave: Option<u8>,
+        created_mountpoint: bool,
+        auto_refresh: Option<Duration>,
+        image: Option<PathBuf>,
         sender: Arc<Sender<Operation>>,
     ) -> Result<(), Error> {
+        // Check we haven't hit the maximum number of mounts, before touching
+        // the drive.  This is checked again when the mount is added, with
+        // mountpoints locked, so concurrent mounts can't exceed it.
+        check_mount_limit(self.num_mounts().await, get_args().max_mounts)?;
+
+        // Check neither the device nor the mountpoint is already mounted
+        if self.find_by_device(device_number).await.is_ok() {
+            return Err(Error::Fs1541 {
+                message: "Cannot mount".to_string(),
+                error: Fs1541Error::Operation(format!("Device {} already mounted", device_number)),
+            });
+        }
+        if self.find_by_path(&mountpoint).await.is_ok() {
+            return Err(Error::Fs1541 {
+                message: "Cannot mount".to_string(),
+                error: Fs1541Error::Operation(format!(
+                    "Already have mount at {}",
+                    mountpoint.as_ref().to_string_lossy()
+                )),
+            });
+        }
+
         // Create a CbmDriveUnit for this mount. Will fail if already exists.
-        let drive_unit = locking_section!("Lock", "Drive Manager", {
-            let drive_mgr = self.drive_mgr.lock().await;
-            drive_mgr.add_drive(device_number).await?
-        });
+        // A mount of a disk image is served by the image, rather than the
+        // bus.
+        let (cbm, drive_unit) = match image {
+            Some(image) => {
+                let image: Arc<Mutex<dyn CbmBackend>> =
+                    Arc::new(Mutex::new(CbmImageBackend::open(image)?));
+                let drive_unit = locking_section!("Lock", "Drive Manager", {
+                    let drive_mgr = self.drive_mgr.lock().await;
+                    drive_mgr
+                        .add_image_drive(device_number, image.clone())
+                        .await?
+                });
+                (image, drive_unit)
+            }
+            None => {
+                let drive_unit = locking_section!("Lock", "Drive Manager", {
+                    let drive_mgr = self.drive_mgr.lock().await;
+                    drive_mgr.add_drive(device_number).await?
+                });
+                (self.cbm.clone(), drive_unit)
+            }
+        };
 
         // Create a Mount
         let mut mount = Mount::new(
             device_number,
             mountpoint.as_ref().to_path_buf(),
             dummy_formats,
-            self.cbm.clone(),
+            read_write,
+            safe_replace,
+            verify_writes,
+            interleave,
+            created_mountpoint,
+            auto_refresh,
+            cbm,
             self.drive_mgr.clone(),
             drive_unit,
             sender,
@@ -70,6 +133,10 @@ impl MountService {
                 if let Err(_remove_err) = drive_mgr.remove_drive(device_number).await {
                     warn!("Failed to cleanup failed mount: {}", e);
                 }
+
+                // The drive may not be the type we cached - it may have
+                // been swapped - so identify it afresh next time
+                drive_mgr.invalidate_identity(device_number).await;
                 return Err(e);
             });
         } else {
@@ -101,9 +168,11 @@ impl MountService {
             mount.update_fuser(fuser);
             mount.set_shared_self(shared_mount.clone())?;
             mount.create_bg_response_thread()?;
+            mount.create_auto_refresh_thread()?;
         });
 
         // Finally, add it to the mountpoints HashMap
+        let mountpoint_str = mountpoint.as_ref().to_string_lossy().to_string();
         if let Err(e) = self
             .add_mount_to_mountpoints(shared_mount.clone(), mountpoint, device_number)
             .await
@@ -123,6 +192,10 @@ impl MountService {
             });
             Err(e)
         } else {
+            events::publish(Event::Mounted {
+                device: device_number,
+                mountpoint: mountpoint_str,
+            });
             Ok(())
         }
     }
@@ -133,9 +206,11 @@ impl MountService {
         mountpoint: P,
         device_number: u8,
     ) -> Result<(), Error> {
-        // Now it's mounted, add it to the mountpoints HashMap
+        // Now it's mounted, add it to the mountpoints HashMap, if there's
+        // still room
         locking_section!("Lock", "Mountpoints", {
             let mut mps = self.mountpoints.write().await;
+            check_mount_limit(mps.len(), get_args().max_mounts)?;
             match mps.insert(mountpoint.as_ref().to_path_buf(), mount) {
                 None => Ok(()), // No previous value, success
                 Some(_) => {
@@ -162,57 +237,88 @@ impl MountService {
         })
     }
 
-    pub async fn get_mount<P: AsRef<Path>>(
+    /// Returns the number of current mounts
+    pub async fn num_mounts(&self) -> usize {
+        locking_section!("Lock", "Mountpoints", {
+            self.mountpoints.read().await.len()
+        })
+    }
+
+    /// Returns a summary of each current mount, ordered by device number
+    pub async fn mount_infos(&self) -> Vec<MountInfo> {
+        let mut infos: Vec<MountInfo> = locking_section!("Lock", "Mountpoints", {
+            let mps = self.mountpoints.read().await;
+            mps.values()
+                .map(|mps_mount| {
+                    locking_section!("Read", "Mount", { mps_mount.read().mount_info() })
+                })
+                .collect()
+        });
+        infos.sort_by_key(|info| info.device);
+        infos
+    }
+
+    /// Returns the mount at the given mountpoint, or a not mounted error
+    pub async fn find_by_path<P: AsRef<Path>>(
         &self,
         mountpoint: P,
     ) -> Result<Arc<parking_lot::RwLock<Mount>>, Error> {
-        trace!("Getting mount {}", mountpoint.as_ref().to_string_lossy());
+        trace!("Finding mount {}", mountpoint.as_ref().to_string_lossy());
         locking_section!("Lock", "Mountpoints", {
             let mountpoints = self.mountpoints.read().await;
             mountpoints
                 .get(mountpoint.as_ref())
                 .cloned() // Clone the Arc if it exists
-                .ok_or(Error::Fs1541 {
-                    message: format!(
-                        "Mountpoint {} not found",
-                        mountpoint.as_ref().to_string_lossy()
-                    ),
-                    error: Fs1541Error::Operation(String::from("Mountpoint not found")),
+                .ok_or_else(|| {
+                    not_mounted(format!("path {}", mountpoint.as_ref().to_string_lossy()))
                 })
         })
     }
 
-    pub async fn get_mount_from_device_num(
+    /// Returns the mount for the given device, or a not mounted error
+    pub async fn find_by_device(
         &self,
         device_number: u8,
     ) -> Result<Arc<parking_lot::RwLock<Mount>>, Error> {
-        let mount = locking_section!("Lock", "Mountpoints", {
+        trace!("Finding mount for device {}", device_number);
+        locking_section!("Lock", "Mountpoints", {
             let mps = self.mountpoints.read().await;
-            for (_path, mps_mount) in mps.iter() {
-                let mount_match = locking_section!("Lock", "Mount", {
-                    let mps_mount_guard = mps_mount.read();
-                    if mps_mount_guard.get_device_num() == device_number {
-                        debug!(
-                            "Found matching mount {} at device {}",
-                            mps_mount_guard.get_mountpoint().to_string_lossy(),
-                            device_number
-                        );
-                        Some(mps_mount.clone())
-                    } else {
-                        None
-                    }
-                });
-                if let Some(mount) = mount_match {
-                    return Ok(mount);
-                }
-            }
-            Err(Error::Fs1541 {
-                message: format!("Device {} not found", device_number),
-                error: Fs1541Error::Operation(String::from("Device not found")),
-            })
-        });
+            mps.values()
+                .find(|mps_mount| {
+                    locking_section!("Read", "Mount", {
+                        mps_mount.read().get_device_num() == device_number
+                    })
+                })
+                .cloned()
+                .ok_or_else(|| not_mounted(format!("device {}", device_number)))
+        })
+    }
 
-        mount
+    /// Returns the mount identified by either its device number or its
+    /// mountpoint, or a not mounted error
+    async fn find_mount<P: AsRef<Path>>(
+        &self,
+        device_number: Option<u8>,
+        mountpoint: Option<P>,
+    ) -> Result<Arc<parking_lot::RwLock<Mount>>, Error> {
+        match (device_number, mountpoint) {
+            (Some(device_number), _) => self.find_by_device(device_number).await,
+            (None, Some(mountpoint)) => self.find_by_path(mountpoint).await,
+            (None, None) => Err(not_mounted("no device or mountpoint")),
+        }
+    }
+
+    /// Returns the device number of the mount identified by either its
+    /// device number or its mountpoint, or a not mounted error
+    pub async fn find_device_number<P: AsRef<Path>>(
+        &self,
+        device_number: Option<u8>,
+        mountpoint: Option<P>,
+    ) -> Result<u8, Error> {
+        let mount = self.find_mount(device_number, mountpoint).await?;
+        Ok(locking_section!("Read", "Mount", {
+            mount.read().get_device_num()
+        }))
     }
 
     /// The force option is used by cleanup() in order to make the unmount
@@ -230,41 +336,10 @@ impl MountService {
         assert!(mountpoint.is_some() || device_number.is_some());
         assert!(device_number.is_none() || mountpoint.is_none());
 
-        // Try and get the mountpoint first
-        let mount = {
-            if let Some(mountpoint) = mountpoint {
-                match self.get_mount(mountpoint.as_ref()).await {
-                    Ok(mount) => Some(mount),
-                    Err(_) => None,
-                }
-            } else {
-                None
-            }
-        };
-
-        // Now get the device number
-        let device_number = if device_number.is_none() {
-            match mount.clone() {
-                Some(mount) => {
-                    locking_section!("Lock", "Mount", {
-                        let mount_guard = mount.read();
-                        Some(mount_guard.get_device_num())
-                    })
-                }
-                None => unreachable!(),
-            }
-        } else {
-            device_number
-        };
-        let device_number = device_number.unwrap();
-
-        // We now have the device number, but we may still not have the Mount,
-        // but given the device_number we can find it the old fashioned way
-        let mount = if mount.is_none() {
-            self.get_mount_from_device_num(device_number).await?
-        } else {
-            mount.unwrap()
-        };
+        // Find the Mount, and from that the device number if we weren't
+        // given it
+        let mount = self.find_mount(device_number, mountpoint).await?;
+        let device_number = locking_section!("Read", "Mount", { mount.read().get_device_num() });
 
         // Now we have a device_number and mount, as u8 and Arc<Mutex<Mount>>.
         // Unmount the drive
@@ -289,13 +364,18 @@ impl MountService {
         });
 
         // Now remove it
-        locking_section!("Lock", "Mountpoints", {
+        let mountpoint = locking_section!("Lock", "Mountpoints", {
             let mut mps = self.mountpoints.write().await;
             let mount_guard = mount.read();
             match mps.remove(mount_guard.get_mountpoint()) {
                 Some(_) => (), // Successfully removed
                 None => unreachable!(),
             }
+            mount_guard.get_mountpoint().to_string_lossy().to_string()
+        });
+        events::publish(Event::Unmounted {
+            device: device_number,
+            mountpoint,
         });
 
         // Nothing else to do - as we've removed the Mount from mountpoints
@@ -303,6 +383,42 @@ impl MountService {
         Ok(())
     }
 
+    /// Re-initializes the drives of all mounts, which lose their state when
+    /// the bus is reset, and marks their directories as needing to be
+    /// re-read.  Returns the number of mounts re-initialized.
+    pub async fn reinit_all(&self) -> usize {
+        let mounts: Vec<Arc<parking_lot::RwLock<Mount>>> =
+            locking_section!("Lock", "Mountpoints", {
+                self.mountpoints.read().await.values().cloned().collect()
+            });
+
+        let mut count = 0;
+        for mount in mounts {
+            let device_number =
+                locking_section!("Read", "Mount", { mount.read().get_device_num() });
+            let result = locking_section!("Lock", "Drive Manager", {
+                self.drive_mgr
+                    .lock()
+                    .await
+                    .init_drive(device_number, &init_ignored_errors())
+                    .await
+            });
+            match result {
+                Ok(results) => {
+                    locking_section!("Write", "Mount", {
+                        mount.write().reinitialized(results);
+                    });
+                    count += 1;
+                }
+                Err(e) => warn!(
+                    "Failed to re-initialize device {} after bus reset: {}",
+                    device_number, e
+                ),
+            }
+        }
+        count
+    }
+
     pub async fn cleanup(&self) {
         trace!("Starting cleanup of all mounts");
 
@@ -349,3 +465,110 @@ impl MountService {
         );
     }
 }
+
+/// The error returned when a mount can't be found
+fn not_mounted<S: std::fmt::Display>(what: S) -> Error {
+    Error::Fs1541 {
+        message: format!("No mount found for {}", what),
+        error: Fs1541Error::NotMounted(what.to_string()),
+    }
+}
+
+/// Checks whether another mount can be added, given the current number of
+/// mounts and the configured maximum
+fn check_mount_limit(num_mounts: usize, max_mounts: usize) -> Result<(), Error> {
+    if num_mounts >= max_mounts {
+        Err(Error::Fs1541 {
+            message: "Cannot mount".to_string(),
+            error: Fs1541Error::LimitReached(format!(
+                "Maximum number of mounts ({}) reached",
+                max_mounts
+            )),
+        })
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::init_test_args;
+    use crate::backend::mock::MockBackend;
+
+    #[test]
+    fn test_mount_limit() {
+        assert!(check_mount_limit(0, 2).is_ok());
+        assert!(check_mount_limit(1, 2).is_ok());
+        match check_mount_limit(2, 2) {
+            Err(Error::Fs1541 {
+                error: Fs1541Error::LimitReached(_),
+                ..
+            }) => (),
+            other => panic!("Expected Fs1541Error::LimitReached, got {:?}", other),
+        }
+        assert!(check_mount_limit(0, 0).is_err());
+    }
+
+    #[test]
+    fn test_not_mounted() {
+        let e = not_mounted("device 9");
+        match &e {
+            Error::Fs1541 {
+                error: Fs1541Error::NotMounted(what),
+                ..
+            } => assert_eq!(what, "device 9"),
+            other => panic!("Expected Fs1541Error::NotMounted, got {:?}", other),
+        }
+        assert_eq!(e.to_fuse_reply_error(), libc::EINVAL);
+    }
+
+    #[tokio::test]
+    async fn test_reinit_all() {
+        init_test_args();
+
+        // A device no other test subscribes to
+        let device = 26;
+        let mock = Arc::new(Mutex::new(MockBackend::new()));
+        let cbm: Arc<Mutex<dyn CbmBackend>> = mock.clone();
+        let drive_mgr = Arc::new(Mutex::new(DriveManager::new(cbm.clone())));
+        let mountpoints = Arc::new(RwLock::new(HashMap::new()));
+        let mount_svc = MountService::new(cbm.clone(), drive_mgr.clone(), mountpoints.clone());
+
+        // With nothing mounted, there's nothing to re-initialize
+        assert_eq!(mount_svc.reinit_all().await, 0);
+        assert!(mock.lock().await.inits.is_empty());
+
+        let drive_unit = drive_mgr.lock().await.add_drive(device).await.unwrap();
+        let (tx, _rx) = flume::unbounded();
+        let mut mount = Mount::new(
+            device,
+            "/tmp/reinit_all",
+            false,
+            false,
+            false,
+            false,
+            None,
+            false,
+            None,
+            cbm,
+            drive_mgr,
+            drive_unit,
+            Arc::new(tx),
+        )
+        .unwrap();
+        mount.mount().await.unwrap();
+        mountpoints.write().await.insert(
+            PathBuf::from("/tmp/reinit_all"),
+            Arc::new(parking_lot::RwLock::new(mount)),
+        );
+
+        // The mounted drive is initialized again, on top of when it was
+        // mounted
+        let inits = mock.lock().await.inits.len();
+        assert_eq!(mount_svc.reinit_all().await, 1);
+        let mock = mock.lock().await;
+        assert_eq!(mock.inits.len(), inits + 1);
+        assert_eq!(mock.inits.last(), Some(&device));
+    }
+}
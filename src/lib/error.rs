@@ -1,4 +1,5 @@
 /// Contains [`fs1541`] error types
+use rs1541::CbmStatus;
 use rs1541::Error as Rs1541Error;
 use serde::{Deserialize, Serialize};
 use thiserror::Error as ThisError;
@@ -81,9 +82,75 @@ pub enum Fs1541Error {
     #[error("File is not a directory: {0}")]
     IsNotDir(String),
 
+    /// Operation not permitted on this file
+    #[error("Operation not permitted: {0}")]
+    NotPermitted(String),
+
     /// No entry (e.g. file or directory)
     #[error("No (filesystem) entry: {0}")]
     NoEntry(String),
+
+    /// No space left to hold the data
+    #[error("No space: {0}")]
+    NoSpace(String),
+
+    /// A configured resource limit has been reached
+    #[error("Limit reached: {0}")]
+    LimitReached(String),
+
+    /// Not mounted
+    #[error("Not mounted: {0}")]
+    NotMounted(String),
+
+    /// The drive reported a DOS error.  Contains the DOS error number and the
+    /// full status string.
+    #[error("Drive DOS error {0}: {1}")]
+    Dos(u8, String),
+}
+
+/// Codes identifying the category of an error, returned to IPC clients in
+/// Response::Error in the style of JSON-RPC, so they can act on errors
+/// without parsing the message.  These are part of the IPC protocol, so
+/// existing values mustn't change.
+///
+/// An error which doesn't fit any other category
+pub const ERROR_CODE_GENERAL: u32 = 1;
+/// An invalid request, argument or configuration
+pub const ERROR_CODE_VALIDATION: u32 = 2;
+/// An operation timed out, or was aged out before it ran
+pub const ERROR_CODE_TIMEOUT: u32 = 3;
+/// A file, mount or other entry doesn't exist
+pub const ERROR_CODE_NOT_FOUND: u32 = 4;
+/// Communication with the device failed - the XUM1541, USB or the bus
+pub const ERROR_CODE_DEVICE: u32 = 5;
+/// The drive reported a DOS error
+pub const ERROR_CODE_DRIVE: u32 = 6;
+/// The operation isn't permitted, such as writing a read only file
+pub const ERROR_CODE_PERMISSION: u32 = 7;
+/// There's no space left on the disk
+pub const ERROR_CODE_NO_SPACE: u32 = 8;
+/// A configured limit has been reached
+pub const ERROR_CODE_LIMIT: u32 = 9;
+/// The operation was cancelled
+pub const ERROR_CODE_CANCELLED: u32 = 10;
+/// An IO error within 1541fs, such as on the IPC socket
+pub const ERROR_CODE_IO: u32 = 11;
+/// An internal error, suggesting a bug in 1541fs
+pub const ERROR_CODE_INTERNAL: u32 = 12;
+
+/// Broad classification of where an error originated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// The drive itself reported an error via its DOS status channel - the
+    /// bus and USB transport worked fine
+    Drive,
+
+    /// Communication with the drive failed - the XUM1541, USB or IEC/IEEE-488
+    /// bus
+    Transport,
+
+    /// An error within 1541fs itself, such as invalid input or a timeout
+    Host,
 }
 
 impl Error {
@@ -93,6 +160,62 @@ impl Error {
             _ => libc::EIO,
         }
     }
+
+    /// Returns this error's code, for returning to IPC clients
+    pub fn code(&self) -> u32 {
+        match self {
+            Error::Rs1541 { .. } => ERROR_CODE_DEVICE,
+            Error::Io { .. } => ERROR_CODE_IO,
+            Error::Serde { .. } => ERROR_CODE_VALIDATION,
+            Error::Fs1541 { error, .. } => error.code(),
+        }
+    }
+
+    /// Returns whether this error was reported by the drive, or was a failure
+    /// to communicate with it, or neither
+    pub fn class(&self) -> ErrorClass {
+        match self {
+            Error::Rs1541 { .. } => ErrorClass::Transport,
+            Error::Fs1541 {
+                error: Fs1541Error::Dos(_, _),
+                ..
+            } => ErrorClass::Drive,
+            Error::Io { .. } | Error::Serde { .. } | Error::Fs1541 { .. } => ErrorClass::Host,
+        }
+    }
+}
+
+impl From<&CbmStatus> for Fs1541Error {
+    fn from(status: &CbmStatus) -> Self {
+        Fs1541Error::Dos(status.number, status.to_string())
+    }
+}
+
+/// Maps a DOS error number reported by a drive to the most appropriate errno
+/// to return to FUSE.  Numbers below 20 aren't errors, so map to 0.
+pub fn dos_error_to_errno(number: u8) -> i32 {
+    match number {
+        0..=19 => 0,
+        26 => libc::EROFS,            // Write protect on
+        30..=34 | 39 => libc::EINVAL, // Syntax errors
+        51 => libc::EOVERFLOW,        // Overflow in record
+        52 => libc::EFBIG,            // File too large
+        60 | 70 => libc::EBUSY,       // Write file open, no channel
+        61 => libc::EBADF,            // File not open
+        62 => libc::ENOENT,           // File not found
+        63 => libc::EEXIST,           // File exists
+        64 | 66 | 67 => libc::EINVAL, // File type mismatch, illegal T/S
+        65 | 72 => libc::ENOSPC,      // No block, disk full
+        74 => libc::ENOMEDIUM,        // Drive not ready
+        _ => libc::EIO,               // Read/write errors and the rest
+    }
+}
+
+/// Whether a DOS error reported by a drive may not recur if the operation is
+/// retried - read errors (20-24, 27 and 29) and drive not ready (74).  Write
+/// protect, syntax and other errors will fail again, so aren't transient.
+pub fn dos_error_transient(number: u8) -> bool {
+    matches!(number, 20..=24 | 27 | 29 | 74)
 }
 
 impl Fs1541Error {
@@ -111,7 +234,193 @@ impl Fs1541Error {
             Fs1541Error::FileAccess(_) => libc::EACCES,
             Fs1541Error::IsDir(_) => libc::EISDIR,
             Fs1541Error::IsNotDir(_) => libc::ENOTDIR,
+            Fs1541Error::NotPermitted(_) => libc::EPERM,
             Fs1541Error::NoEntry(_) => libc::ENOENT,
+            Fs1541Error::NoSpace(_) => libc::ENOSPC,
+            Fs1541Error::LimitReached(_) => libc::EMFILE,
+            Fs1541Error::NotMounted(_) => libc::EINVAL,
+            // Whatever the number, the drive's status was treated as an
+            // error, so it must not be reported as success
+            Fs1541Error::Dos(number, _) => match dos_error_to_errno(*number) {
+                0 => libc::EIO,
+                errno => errno,
+            },
+        }
+    }
+
+    /// Returns this error's code, for returning to IPC clients
+    pub fn code(&self) -> u32 {
+        match self {
+            Fs1541Error::Operation(_) => ERROR_CODE_GENERAL,
+            Fs1541Error::Configuration(_) => ERROR_CODE_VALIDATION,
+            Fs1541Error::Validation(_) => ERROR_CODE_VALIDATION,
+            Fs1541Error::AgedOut(_) => ERROR_CODE_TIMEOUT,
+            Fs1541Error::Internal(_) => ERROR_CODE_INTERNAL,
+            Fs1541Error::Timeout(_, _) => ERROR_CODE_TIMEOUT,
+            Fs1541Error::Cancelled(_) => ERROR_CODE_CANCELLED,
+            Fs1541Error::ReadOnly(_) => ERROR_CODE_PERMISSION,
+            Fs1541Error::WriteOnly(_) => ERROR_CODE_PERMISSION,
+            Fs1541Error::ReadOrWriteOnly(_) => ERROR_CODE_PERMISSION,
+            Fs1541Error::FileAccess(_) => ERROR_CODE_PERMISSION,
+            Fs1541Error::IsDir(_) => ERROR_CODE_VALIDATION,
+            Fs1541Error::IsNotDir(_) => ERROR_CODE_VALIDATION,
+            Fs1541Error::NotPermitted(_) => ERROR_CODE_PERMISSION,
+            Fs1541Error::NoEntry(_) => ERROR_CODE_NOT_FOUND,
+            Fs1541Error::NoSpace(_) => ERROR_CODE_NO_SPACE,
+            Fs1541Error::LimitReached(_) => ERROR_CODE_LIMIT,
+            Fs1541Error::NotMounted(_) => ERROR_CODE_NOT_FOUND,
+            Fs1541Error::Dos(_, _) => ERROR_CODE_DRIVE,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dos_error(number: u8) -> Error {
+        Error::Fs1541 {
+            message: "Drive reported an error".into(),
+            error: Fs1541Error::Dos(number, format!("{},TEST,00,00", number)),
+        }
+    }
+
+    #[test]
+    fn test_dos_error_to_errno() {
+        // Every error number a CBM drive's DOS reports
+        let table = [
+            (0, 0),                // OK
+            (1, 0),                // Files scratched
+            (20, libc::EIO),       // Read error (block header not found)
+            (21, libc::EIO),       // Read error (no sync character)
+            (22, libc::EIO),       // Read error (data block not present)
+            (23, libc::EIO),       // Read error (checksum error in data block)
+            (24, libc::EIO),       // Read error (byte decoding error)
+            (25, libc::EIO),       // Write error (write-verify error)
+            (26, libc::EROFS),     // Write protect on
+            (27, libc::EIO),       // Read error (checksum error in header)
+            (28, libc::EIO),       // Write error (long data block)
+            (29, libc::EIO),       // Disk ID mismatch
+            (30, libc::EINVAL),    // Syntax error (general syntax)
+            (31, libc::EINVAL),    // Syntax error (invalid command)
+            (32, libc::EINVAL),    // Syntax error (long line)
+            (33, libc::EINVAL),    // Syntax error (invalid file name)
+            (34, libc::EINVAL),    // Syntax error (no file given)
+            (39, libc::EINVAL),    // Syntax error (invalid command)
+            (50, libc::EIO),       // Record not present
+            (51, libc::EOVERFLOW), // Overflow in record
+            (52, libc::EFBIG),     // File too large
+            (60, libc::EBUSY),     // Write file open
+            (61, libc::EBADF),     // File not open
+            (62, libc::ENOENT),    // File not found
+            (63, libc::EEXIST),    // File exists
+            (64, libc::EINVAL),    // File type mismatch
+            (65, libc::ENOSPC),    // No block
+            (66, libc::EINVAL),    // Illegal track and sector
+            (67, libc::EINVAL),    // Illegal system track or sector
+            (70, libc::EBUSY),     // No channel
+            (71, libc::EIO),       // Directory error
+            (72, libc::ENOSPC),    // Disk full
+            (73, libc::EIO),       // DOS mismatch
+            (74, libc::ENOMEDIUM), // Drive not ready
+        ];
+        for (number, errno) in table {
+            assert_eq!(dos_error_to_errno(number), errno, "DOS error {}", number);
+        }
+    }
+
+    #[test]
+    fn test_dos_error_transient() {
+        assert!(!dos_error_transient(0));
+        assert!(dos_error_transient(21));
+        assert!(dos_error_transient(23));
+        assert!(dos_error_transient(74));
+        assert!(!dos_error_transient(26));
+        assert!(!dos_error_transient(31));
+        assert!(!dos_error_transient(62));
+    }
+
+    #[test]
+    fn test_dos_error_fuse_reply() {
+        assert_eq!(dos_error(62).to_fuse_reply_error(), libc::ENOENT);
+        assert_eq!(dos_error(72).to_fuse_reply_error(), libc::ENOSPC);
+        assert_eq!(dos_error(26).to_fuse_reply_error(), libc::EROFS);
+        assert_eq!(dos_error(0).to_fuse_reply_error(), libc::EIO);
+        assert_eq!(dos_error(1).to_fuse_reply_error(), libc::EIO);
+    }
+
+    #[test]
+    fn test_error_code() {
+        let s = || "test".to_string();
+        let table = [
+            (Fs1541Error::Operation(s()), ERROR_CODE_GENERAL),
+            (Fs1541Error::Configuration(s()), ERROR_CODE_VALIDATION),
+            (Fs1541Error::Validation(s()), ERROR_CODE_VALIDATION),
+            (Fs1541Error::AgedOut(s()), ERROR_CODE_TIMEOUT),
+            (Fs1541Error::Internal(s()), ERROR_CODE_INTERNAL),
+            (
+                Fs1541Error::Timeout(s(), std::time::Duration::ZERO),
+                ERROR_CODE_TIMEOUT,
+            ),
+            (Fs1541Error::Cancelled(s()), ERROR_CODE_CANCELLED),
+            (Fs1541Error::ReadOnly(s()), ERROR_CODE_PERMISSION),
+            (Fs1541Error::WriteOnly(s()), ERROR_CODE_PERMISSION),
+            (Fs1541Error::ReadOrWriteOnly(s()), ERROR_CODE_PERMISSION),
+            (Fs1541Error::FileAccess(s()), ERROR_CODE_PERMISSION),
+            (Fs1541Error::IsDir(s()), ERROR_CODE_VALIDATION),
+            (Fs1541Error::IsNotDir(s()), ERROR_CODE_VALIDATION),
+            (Fs1541Error::NotPermitted(s()), ERROR_CODE_PERMISSION),
+            (Fs1541Error::NoEntry(s()), ERROR_CODE_NOT_FOUND),
+            (Fs1541Error::NoSpace(s()), ERROR_CODE_NO_SPACE),
+            (Fs1541Error::LimitReached(s()), ERROR_CODE_LIMIT),
+            (Fs1541Error::NotMounted(s()), ERROR_CODE_NOT_FOUND),
+            (Fs1541Error::Dos(62, s()), ERROR_CODE_DRIVE),
+        ];
+        for (error, code) in table {
+            let name = error.to_string();
+            let error = Error::Fs1541 {
+                message: "Failed".into(),
+                error,
+            };
+            assert_eq!(error.code(), code, "{}", name);
         }
+
+        assert_eq!(
+            Error::Io {
+                message: "Failed".into(),
+                error: s(),
+            }
+            .code(),
+            ERROR_CODE_IO
+        );
+        assert_eq!(
+            Error::Serde {
+                message: "Failed".into(),
+                error: s(),
+            }
+            .code(),
+            ERROR_CODE_VALIDATION
+        );
+    }
+
+    #[test]
+    fn test_error_class() {
+        assert_eq!(dos_error(62).class(), ErrorClass::Drive);
+        assert_eq!(
+            Error::Io {
+                message: "Failed".into(),
+                error: "broken pipe".into(),
+            }
+            .class(),
+            ErrorClass::Host
+        );
+        assert_eq!(
+            Error::Fs1541 {
+                message: "Failed".into(),
+                error: Fs1541Error::Timeout("Timed out".into(), std::time::Duration::ZERO),
+            }
+            .class(),
+            ErrorClass::Host
+        );
     }
 }
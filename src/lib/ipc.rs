@@ -1,9 +1,46 @@
-use serde::{Deserialize, Serialize};
+use crate::error::Error;
+use serde::{Deserialize, Deserializer, Serialize};
 use std::fmt;
+use std::path::PathBuf;
 pub const SOCKET_PATH: &str = "/tmp/1541fs.sock";
 pub const DAEMON_PNAME: &str = "1541fsd";
 pub const DAEMON_PID_FILENAME: &str = "/tmp/1541d.pid";
 
+/// Version of the IPC protocol spoken between the client and daemon.  Bump
+/// this whenever a change is made to Request or Response which means an older
+/// client or daemon can't interoperate with a newer one.
+pub const PROTOCOL_VERSION: u32 = 21;
+
+/// Maximum size of a request the daemon will accept
+pub const MAX_REQUEST_SIZE: usize = 64 * 1024; // 64KB limit
+
+/// Maximum size of a response the client will accept, for most requests
+pub const MAX_RESPONSE_SIZE: usize = 1024 * 1024; // 1MB limit
+
+/// Maximum size of a response the client will accept for bulk requests,
+/// such as reading a file.  JSON encoding of file contents is ~4x the size
+/// of the raw data, so this allows for files comfortably larger than any
+/// CBM disk.
+pub const MAX_BULK_RESPONSE_SIZE: usize = 64 * 1024 * 1024; // 64MB limit
+
+/// Size of the big-endian length prefix sent before each JSON encoded
+/// Request and Response
+pub const FRAME_LEN_SIZE: usize = 4;
+
+/// Serializes a Request or Response as JSON, prefixed with its length
+pub fn encode_frame<T: Serialize>(message: &T) -> Result<Vec<u8>, serde_json::Error> {
+    let payload = serde_json::to_vec(message)?;
+    let mut frame = Vec::with_capacity(FRAME_LEN_SIZE + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+/// Returns the length of the JSON payload which follows a length prefix
+pub fn frame_len(prefix: [u8; FRAME_LEN_SIZE]) -> usize {
+    u32::from_be_bytes(prefix) as usize
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Request {
     Mount {
@@ -11,6 +48,31 @@ pub enum Request {
         device: u8,
         dummy_formats: bool,
         bus_reset: bool,
+        // Defaulted so older clients, which don't send it, are still
+        // understood
+        #[serde(default)]
+        auto_refresh_secs: Option<u64>,
+        // Read-only unless explicitly requested
+        #[serde(default)]
+        read_write: bool,
+        // Replace files by scratching them first, rather than using @:
+        #[serde(default)]
+        safe_replace: bool,
+        // Read files back after writing them, to check they were written
+        // correctly
+        #[serde(default)]
+        verify_writes: bool,
+        // Sector interleave to configure the drive with before writing, if
+        // the drive supports it
+        #[serde(default)]
+        interleave: Option<u8>,
+        // Create the mountpoint if it doesn't exist, and remove it again on
+        // unmount
+        #[serde(default)]
+        mkdir: bool,
+        // Serve the mount from this .d64 disk image, rather than the drive
+        #[serde(default)]
+        image: Option<PathBuf>,
     },
     Unmount {
         // Either mountpoint or device can be sent
@@ -18,14 +80,77 @@ pub enum Request {
         device: Option<u8>,
     },
     BusReset,
+    ScanBus,
     Ping,
+    Health,
+    Metrics {
+        // Clear the metrics after reporting them
+        #[serde(default)]
+        reset: bool,
+    },
     Die,
+    ListMounts,
     Identify {
         device: u8,
     },
     GetStatus {
         device: u8,
     },
+    Version,
+    SetLogLevel {
+        level: String,
+    },
+    GetLogLevel,
+    ReadFile {
+        device: u8,
+        filename: String,
+    },
+    // Events for the device are sent, as Response::Event, until the client
+    // closes the connection
+    Subscribe {
+        device: u8,
+    },
+    // Changes the drive's device number until it is reset or powered off
+    SetDeviceNumber {
+        device: u8,
+        new_device: u8,
+    },
+    // Re-reads the directory of the mounted device, responding once the new
+    // listing has been read.  Drive defaults to 0.
+    RefreshDir {
+        device: u8,
+        #[serde(default)]
+        drive: Option<u8>,
+    },
+    // Reads up to 255 bytes of the drive's memory using M-R
+    MemoryRead {
+        device: u8,
+        addr: u16,
+        len: usize,
+    },
+    // Writes to the drive's memory using M-W
+    MemoryWrite {
+        device: u8,
+        addr: u16,
+        data: Vec<u8>,
+    },
+    // Runs the code in the drive's memory using M-E, responding once the
+    // drive answers again
+    MemoryExecute {
+        device: u8,
+        addr: u16,
+    },
+}
+
+impl Request {
+    /// The maximum size of response the client should accept for this
+    /// request
+    pub fn max_response_size(&self) -> usize {
+        match self {
+            Request::ReadFile { .. } => MAX_BULK_RESPONSE_SIZE,
+            _ => MAX_RESPONSE_SIZE,
+        }
+    }
 }
 
 impl fmt::Display for Request {
@@ -36,11 +161,35 @@ impl fmt::Display for Request {
                 device,
                 dummy_formats,
                 bus_reset,
+                auto_refresh_secs,
+                read_write,
+                safe_replace,
+                verify_writes,
+                interleave,
+                mkdir,
+                image,
             } => {
                 write!(
                     f,
-                    "Mount request: device {} at '{}' (dummy formats: {}, bus reset: {})",
-                    device, mountpoint, dummy_formats, bus_reset
+                    "Mount request: device {} at '{}' (dummy formats: {}, bus reset: {}, auto refresh: {}, read-write: {}, safe replace: {}, verify writes: {}, interleave: {}, mkdir: {}, image: {})",
+                    device,
+                    mountpoint,
+                    dummy_formats,
+                    bus_reset,
+                    auto_refresh_secs
+                        .map(|secs| format!("{}s", secs))
+                        .unwrap_or_else(|| "off".to_string()),
+                    read_write,
+                    safe_replace,
+                    verify_writes,
+                    interleave
+                        .map(|interleave| interleave.to_string())
+                        .unwrap_or_else(|| "default".to_string()),
+                    mkdir,
+                    image
+                        .as_ref()
+                        .map(|image| image.display().to_string())
+                        .unwrap_or_else(|| "none".to_string())
                 )
             }
             Request::Unmount { mountpoint, device } => match (mountpoint, device) {
@@ -52,10 +201,148 @@ impl fmt::Display for Request {
                 (None, None) => write!(f, "Unmount request: no target specified"),
             },
             Request::BusReset => write!(f, "Bus reset request"),
+            Request::ScanBus => write!(f, "Scan bus request"),
             Request::Ping => write!(f, "Ping request"),
+            Request::Health => write!(f, "Health request"),
+            Request::Metrics { reset } => write!(f, "Metrics request (reset: {})", reset),
             Request::Die => write!(f, "Shutdown request"),
+            Request::ListMounts => write!(f, "List mounts request"),
             Request::Identify { device } => write!(f, "Identify request: device {}", device),
             Request::GetStatus { device } => write!(f, "Get status request: device {}", device),
+            Request::Version => write!(f, "Version request"),
+            Request::SetLogLevel { level } => write!(f, "Set log level request: {}", level),
+            Request::GetLogLevel => write!(f, "Get log level request"),
+            Request::ReadFile { device, filename } => {
+                write!(
+                    f,
+                    "Read file request: device {} file '{}'",
+                    device, filename
+                )
+            }
+            Request::Subscribe { device } => write!(f, "Subscribe request: device {}", device),
+            Request::SetDeviceNumber { device, new_device } => write!(
+                f,
+                "Set device number request: device {} to {}",
+                device, new_device
+            ),
+            Request::RefreshDir { device, drive } => write!(
+                f,
+                "Refresh directory request: device {} drive {}",
+                device,
+                drive.unwrap_or(0)
+            ),
+            Request::MemoryRead { device, addr, len } => write!(
+                f,
+                "Memory read request: device {} {} bytes from ${:04X}",
+                device, len, addr
+            ),
+            Request::MemoryWrite { device, addr, data } => write!(
+                f,
+                "Memory write request: device {} {} bytes to ${:04X}",
+                device,
+                data.len(),
+                addr
+            ),
+            Request::MemoryExecute { device, addr } => write!(
+                f,
+                "Memory execute request: device {} code at ${:04X}",
+                device, addr
+            ),
+        }
+    }
+}
+
+/// The identity of a device found on the bus
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DeviceIdentity {
+    pub device_type: String,
+    pub description: String,
+}
+
+/// Timings and throughput of one type of background operation, since the
+/// daemon started or its metrics were last reset.  Times are measured from
+/// when the operation was taken off the queue, so queueing time is reported
+/// separately.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OperationMetrics {
+    pub operation: String,
+    pub count: u64,
+    pub errors: u64,
+    pub min_ms: u64,
+    pub max_ms: u64,
+    pub avg_ms: u64,
+    pub avg_queued_ms: u64,
+    pub bytes: u64,
+}
+
+/// A summary of one of the daemon's current mounts
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MountInfo {
+    pub device: u8,
+    pub mountpoint: String,
+    pub model: String,
+    pub num_drives: u8,
+    // Seconds since the Unix epoch
+    pub mount_time_secs: Option<u64>,
+}
+
+/// Something which happened to a device, sent to clients which have
+/// subscribed to it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Event {
+    Status {
+        device: u8,
+        status: String,
+    },
+    DiskChanged {
+        device: u8,
+        name: String,
+        id: String,
+    },
+    Mounted {
+        device: u8,
+        mountpoint: String,
+    },
+    Unmounted {
+        device: u8,
+        mountpoint: String,
+    },
+    // The xum1541 was reset over USB and reopened.  The device's mount
+    // carries on, but the drive was reset along with the bus.
+    Reconnected {
+        device: u8,
+    },
+}
+
+impl Event {
+    /// The device this event happened to
+    pub fn device(&self) -> u8 {
+        match self {
+            Event::Status { device, .. }
+            | Event::DiskChanged { device, .. }
+            | Event::Mounted { device, .. }
+            | Event::Unmounted { device, .. }
+            | Event::Reconnected { device } => *device,
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::Status { device, status } => write!(f, "Device {} status: {}", device, status),
+            Event::DiskChanged { device, name, id } => {
+                write!(f, "Device {} disk changed to {},{}", device, name, id)
+            }
+            Event::Mounted { device, mountpoint } => {
+                write!(f, "Device {} mounted at '{}'", device, mountpoint)
+            }
+            Event::Unmounted { device, mountpoint } => {
+                write!(f, "Device {} unmounted from '{}'", device, mountpoint)
+            }
+            Event::Reconnected { device } => {
+                write!(f, "Device {} reconnected after USB reset", device)
+            }
         }
     }
 }
@@ -64,25 +351,135 @@ impl fmt::Display for Request {
 pub enum Response {
     MountSuccess,
     UnmountSuccess,
-    BusResetSuccess,
-    Error(String),
+    // An unmount found nothing mounted for the device or mountpoint given,
+    // so there was nothing to do
+    NotMounted(String),
+    BusResetSuccess {
+        // Number of mounts whose drives were re-initialized after the reset
+        reinitialized_mounts: usize,
+    },
+    // Every device number scanned, with the identity of those which
+    // responded
+    BusScan(Vec<(u8, Option<DeviceIdentity>)>),
+    // The code identifies the category of error - see the ERROR_CODE_
+    // constants in error.rs.  It defaults to 0 (unknown) if missing,
+    // including from daemons which sent the message on its own.
+    #[serde(deserialize_with = "deserialize_error")]
+    Error {
+        code: u32,
+        message: String,
+    },
     Pong,
+    Health {
+        uptime_secs: u64,
+        active_mounts: usize,
+        // None if the bus hasn't been reset since the daemon started
+        last_bus_reset_secs: Option<u64>,
+        // Whether the XUM1541's handle is open.  None from daemons which
+        // don't report it.
+        #[serde(default)]
+        xum1541_open: Option<bool>,
+    },
+    Metrics(Vec<OperationMetrics>),
+    Mounts(Vec<MountInfo>),
+    Subscribed,
+    Event(Event),
     Dying,
     Identified {
         device_type: String,
         description: String,
     },
+    #[deprecated(note = "Use GotStatusStructured, which the daemon returns for GetStatus")]
     GotStatus(String),
+    GotStatusStructured {
+        error_number: u8,
+        track: u8,
+        sector: u8,
+        message: String,
+    },
+    Version {
+        version: String,
+        protocol: u32,
+    },
+    LogLevel(String),
+    FileContents(Vec<u8>),
+    DeviceNumberSet {
+        device: u8,
+        new_device: u8,
+    },
+    DirRefreshed {
+        device: u8,
+        drive: u8,
+    },
+    MemoryContents {
+        device: u8,
+        addr: u16,
+        data: Vec<u8>,
+    },
+    MemoryWritten {
+        device: u8,
+        addr: u16,
+        len: usize,
+    },
+    MemoryExecuted {
+        device: u8,
+        addr: u16,
+    },
 }
 
 impl fmt::Display for Response {
+    #[allow(deprecated)]
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Response::MountSuccess => write!(f, "Mount successful"),
             Response::UnmountSuccess => write!(f, "Unmount successful"),
-            Response::BusResetSuccess => write!(f, "Bus reset successful"),
-            Response::Error(msg) => write!(f, "Error: {}", msg),
+            Response::NotMounted(what) => write!(f, "No mount found for {}", what),
+            Response::BusResetSuccess {
+                reinitialized_mounts,
+            } => write!(
+                f,
+                "Bus reset successful, {} mounts re-initialized",
+                reinitialized_mounts
+            ),
+            Response::BusScan(devices) => write!(
+                f,
+                "Bus scan: {} of {} devices responded",
+                devices
+                    .iter()
+                    .filter(|(_, identity)| identity.is_some())
+                    .count(),
+                devices.len()
+            ),
+            Response::Error { code, message } => write!(f, "Error {}: {}", code, message),
             Response::Pong => write!(f, "Pong"),
+            Response::Health {
+                uptime_secs,
+                active_mounts,
+                last_bus_reset_secs,
+                xum1541_open,
+            } => write!(
+                f,
+                "Health: up {}s, {} active mounts, last bus reset {}, XUM1541 {}",
+                uptime_secs,
+                active_mounts,
+                last_bus_reset_secs
+                    .map(|secs| format!("{}s ago", secs))
+                    .unwrap_or_else(|| "never".to_string()),
+                match xum1541_open {
+                    Some(true) => "open",
+                    Some(false) => "closed",
+                    None => "unknown",
+                }
+            ),
+            Response::Metrics(metrics) => write!(
+                f,
+                "Metrics: {} operations of {} types",
+                metrics.iter().map(|m| m.count).sum::<u64>(),
+                metrics.len()
+            ),
+            Response::Mounts(mounts) => write!(f, "Mounts: {} active", mounts.len()),
+            Response::Subscribed => write!(f, "Subscribed"),
+            Response::Event(event) => write!(f, "Event: {}", event),
             Response::Dying => write!(f, "Shutting down"),
             Response::Identified {
                 device_type,
@@ -91,6 +488,126 @@ impl fmt::Display for Response {
                 write!(f, "Device identified: {} ({})", device_type, description)
             }
             Response::GotStatus(status) => write!(f, "Status: {}", status),
+            Response::GotStatusStructured {
+                error_number,
+                track,
+                sector,
+                message,
+            } => write!(
+                f,
+                "Status: {:02},{},{:02},{:02}",
+                error_number, message, track, sector
+            ),
+            Response::Version { version, protocol } => {
+                write!(f, "Version: {} (protocol {})", version, protocol)
+            }
+            Response::LogLevel(level) => write!(f, "Log level: {}", level),
+            Response::FileContents(contents) => {
+                write!(f, "File contents: {} bytes", contents.len())
+            }
+            Response::DeviceNumberSet { device, new_device } => {
+                write!(f, "Device {} is now device {}", device, new_device)
+            }
+            Response::DirRefreshed { device, drive } => {
+                write!(f, "Device {} drive {} directory refreshed", device, drive)
+            }
+            Response::MemoryContents { device, addr, data } => write!(
+                f,
+                "Device {} memory: {} bytes from ${:04X}",
+                device,
+                data.len(),
+                addr
+            ),
+            Response::MemoryWritten { device, addr, len } => write!(
+                f,
+                "Device {} memory: {} bytes written to ${:04X}",
+                device, len, addr
+            ),
+            Response::MemoryExecuted { device, addr } => {
+                write!(f, "Device {} code at ${:04X} executed", device, addr)
+            }
+        }
+    }
+}
+
+/// Deserializes Response::Error's fields from either its current shape, or
+/// the bare message string sent before errors had codes
+fn deserialize_error<'de, D>(deserializer: D) -> Result<(u32, String), D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum ErrorFields {
+        Message(String),
+        Coded {
+            #[serde(default)]
+            code: u32,
+            message: String,
+        },
+    }
+
+    Ok(match ErrorFields::deserialize(deserializer)? {
+        ErrorFields::Message(message) => (0, message),
+        ErrorFields::Coded { code, message } => (code, message),
+    })
+}
+
+impl From<Error> for Response {
+    fn from(error: Error) -> Self {
+        Response::Error {
+            code: error.code(),
+            message: error.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_response_shapes() {
+        let error = |json: &str| match serde_json::from_str::<Response>(json).unwrap() {
+            Response::Error { code, message } => (code, message),
+            rsp => panic!("Expected Error response, got {}", rsp),
+        };
+
+        // The message on its own, as sent before errors had codes
+        assert_eq!(error(r#"{"Error":"failed"}"#), (0, "failed".to_string()));
+
+        // With a code, or without one
+        assert_eq!(
+            error(r#"{"Error":{"code":3,"message":"failed"}}"#),
+            (3, "failed".to_string())
+        );
+        assert_eq!(
+            error(r#"{"Error":{"message":"failed"}}"#),
+            (0, "failed".to_string())
+        );
+
+        // Errors are still sent with their code
+        let json = serde_json::to_string(&Response::Error {
+            code: 3,
+            message: "failed".to_string(),
+        })
+        .unwrap();
+        assert_eq!(json, r#"{"Error":{"code":3,"message":"failed"}}"#);
+        assert_eq!(error(&json), (3, "failed".to_string()));
+    }
+
+    #[test]
+    fn test_health_without_xum1541_open() {
+        // As sent by daemons from before the XUM1541 handle was reported
+        let json = r#"{"Health":{"uptime_secs":5,"active_mounts":1,"last_bus_reset_secs":null}}"#;
+        match serde_json::from_str::<Response>(json).unwrap() {
+            Response::Health {
+                uptime_secs: 5,
+                active_mounts: 1,
+                last_bus_reset_secs: None,
+                xum1541_open,
+            } => assert_eq!(xum1541_open, None),
+            rsp => panic!("Expected Health response, got {}", rsp),
         }
     }
 }
@@ -1,6 +1,136 @@
-use env_logger::{Builder, Target};
+use env_logger::{Builder, Logger, Target};
+use log::{LevelFilter, Log, Metadata, Record};
+use std::future::Future;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
 use syslog::{BasicLogger, Facility, Formatter3164};
 
+/// Context included in JSON log records, identifying the device and
+/// operation being handled when the record was logged
+#[derive(Debug, Clone)]
+pub struct LogContext {
+    pub device: Option<u8>,
+    pub operation: String,
+}
+
+tokio::task_local! {
+    static LOG_CONTEXT: LogContext;
+}
+
+/// Runs the future with the given context, which is added to any JSON log
+/// records it logs
+pub async fn with_log_context<F: Future>(context: LogContext, f: F) -> F::Output {
+    LOG_CONTEXT.scope(context, f).await
+}
+
+/// Set once the log level has been changed at runtime, after which the
+/// global max level is used instead of the filter configured at startup
+static LEVEL_OVERRIDDEN: AtomicBool = AtomicBool::new(false);
+
+/// Wraps the stdout logger so that the level can be changed at runtime.
+///
+/// Until the level is changed, the logger configured at startup (including
+/// any module directives from RUST_LOG) is used.  Once changed, all modules
+/// are logged at the new level.  The syslog logger doesn't filter itself, so
+/// doesn't need this - changing the global max level is sufficient.
+struct SwitchableLogger {
+    configured: Logger,
+    unfiltered: Logger,
+}
+
+impl Log for SwitchableLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        if LEVEL_OVERRIDDEN.load(Ordering::Relaxed) {
+            metadata.level() <= log::max_level()
+        } else {
+            self.configured.enabled(metadata)
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if LEVEL_OVERRIDDEN.load(Ordering::Relaxed) {
+            self.unfiltered.log(record);
+        } else {
+            self.configured.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.configured.flush();
+        self.unfiltered.flush();
+    }
+}
+
+fn stdout_builder(json: bool) -> Builder {
+    let mut builder = Builder::new();
+    builder
+        .format_target(false) // Don't include target in messages
+        .format_timestamp(None) // Don't include timestamp
+        .target(Target::Stdout); // Log to stdout instead of stderr
+    if json {
+        builder.format(|buf, record| writeln!(buf, "{}", json_record(record)));
+    }
+    builder
+}
+
+/// Returns the log record as a JSON object, one of which is logged per line
+/// when JSON logging is enabled, for log collectors to parse
+fn json_record(record: &Record) -> serde_json::Value {
+    let mut value = serde_json::json!({
+        "timestamp": chrono::Local::now().to_rfc3339(),
+        "level": record.level().as_str(),
+        "pid": std::process::id(),
+        "target": record.target(),
+        "message": record.args().to_string(),
+    });
+    if let Ok(context) = LOG_CONTEXT.try_with(|context| context.clone()) {
+        if let Some(device) = context.device {
+            value["device"] = device.into();
+        }
+        value["operation"] = context.operation.into();
+    }
+    value
+}
+
+/// Change the log level at runtime.  Applies to all modules, overriding any
+/// RUST_LOG module directives.
+pub fn set_log_level(level: LevelFilter) {
+    LEVEL_OVERRIDDEN.store(true, Ordering::Relaxed);
+    log::set_max_level(level);
+}
+
+/// Get the current log level
+pub fn get_log_level() -> LevelFilter {
+    log::max_level()
+}
+
+/// The log level, and whether it has been changed at runtime, as returned by
+/// save_log_level()
+#[derive(Debug, Clone, Copy)]
+pub struct SavedLogLevel {
+    level: LevelFilter,
+    overridden: bool,
+}
+
+/// Returns the current log level, so it can be put back by
+/// restore_log_level() after being changed
+pub fn save_log_level() -> SavedLogLevel {
+    SavedLogLevel {
+        level: log::max_level(),
+        overridden: LEVEL_OVERRIDDEN.load(Ordering::Relaxed),
+    }
+}
+
+/// Puts back a log level returned by save_log_level(), including whether
+/// the filter configured at startup is used
+pub fn restore_log_level(saved: SavedLogLevel) {
+    log::set_max_level(saved.level);
+    LEVEL_OVERRIDDEN.store(saved.overridden, Ordering::Relaxed);
+}
+
 /// Initialize logging
 ///
 /// # Arguments
@@ -8,7 +138,13 @@ use syslog::{BasicLogger, Facility, Formatter3164};
 /// * `damon` - whether this process is will as a daemon (in which case
 ///             this function will set up syslog loggin)
 /// * `name` - the name to use in logging (only used if daemonize is true)
-pub fn init_logging(daemon: bool, name: String) {
+/// * `level` - the level to log at.  If None, the level is taken from
+///             RUST_LOG (if set), otherwise this overrides RUST_LOG's
+///             default level
+/// * `json` - whether to log each record as a JSON object, rather than as
+///            text.  Only used when logging to stdout - syslog has its own
+///            format
+pub fn init_logging(daemon: bool, name: String, level: Option<LevelFilter>, json: bool) {
     let mut syslog_ok: bool = false;
     if daemon {
         // Initialize syslog logger
@@ -26,10 +162,12 @@ pub fn init_logging(daemon: bool, name: String) {
             }
             Ok(logger) => {
                 log::set_boxed_logger(Box::new(BasicLogger::new(logger))).unwrap();
-                let level = env_logger::Builder::new()
-                    .parse_default_env()
-                    .build()
-                    .filter();
+                let level = level.unwrap_or_else(|| {
+                    env_logger::Builder::new()
+                        .parse_default_env()
+                        .build()
+                        .filter()
+                });
                 log::set_max_level(level);
                 syslog_ok = true;
             }
@@ -38,11 +176,60 @@ pub fn init_logging(daemon: bool, name: String) {
 
     if !syslog_ok {
         // Initialize env_logger instead of syslog
-        Builder::new()
-            .format_target(false) // Don't include target in messages
-            .format_timestamp(None) // Don't include timestamp
-            .target(Target::Stdout) // Log to stdout instead of stderr
-            .parse_default_env() // Use RUST_LOG level if present
-            .init();
+        let mut builder = stdout_builder(json);
+        builder.parse_default_env(); // Use RUST_LOG level if present
+        if let Some(level) = level {
+            builder.filter_level(level);
+        }
+        let configured = builder.build();
+        let unfiltered = stdout_builder(json)
+            .filter_level(LevelFilter::Trace)
+            .build();
+
+        let level = configured.filter();
+        log::set_boxed_logger(Box::new(SwitchableLogger {
+            configured,
+            unfiltered,
+        }))
+        .unwrap();
+        log::set_max_level(level);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_record() {
+        let value = json_record(
+            &Record::builder()
+                .args(format_args!("hello"))
+                .level(log::Level::Info)
+                .target("1541fsd::bg")
+                .build(),
+        );
+        assert_eq!(value["message"], "hello");
+        assert_eq!(value["level"], "INFO");
+        assert_eq!(value["target"], "1541fsd::bg");
+        assert_eq!(value["pid"], std::process::id());
+        assert!(value.get("device").is_none());
+        assert!(value.get("operation").is_none());
+
+        // Records logged within a context include it
+        let context = LogContext {
+            device: Some(8),
+            operation: "ReadFile".to_string(),
+        };
+        let value = LOG_CONTEXT.sync_scope(context, || {
+            json_record(
+                &Record::builder()
+                    .args(format_args!("reading"))
+                    .level(log::Level::Debug)
+                    .build(),
+            )
+        });
+        assert_eq!(value["device"], 8);
+        assert_eq!(value["operation"], "ReadFile");
     }
 }
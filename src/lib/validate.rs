@@ -1,10 +1,18 @@
 use crate::error::{Error, Fs1541Error};
 
 use log::debug;
+use std::ffi::OsStr;
 use std::fs;
+use std::ops::RangeInclusive;
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 
+/// The device numbers a drive can be given in software
+pub const SOFT_DEVICE_NUMS: RangeInclusive<u8> = 8..=15;
+
+/// The most bytes a drive returns in response to a single M-R command
+pub const MAX_MEMORY_READ_LEN: usize = 255;
+
 #[derive(Debug, PartialEq)]
 pub enum ValidationType {
     Mount,
@@ -125,3 +133,183 @@ fn has_write_permission<P: AsRef<Path>>(path: P) -> bool {
         Err(_) => false,
     }
 }
+
+/// Validates a filename received from the kernel (via FUSE) before it is used
+/// to look up a file, or is passed on to rs1541.  The name must be valid
+/// UTF-8, and must not contain NUL or other control bytes - a NUL in
+/// particular must never reach the PETSCII conversion or the underlying
+/// open call, as the filename is passed on as a C string.
+///
+/// Returns an error which maps to ENOENT for non UTF-8 names (which can't
+/// match any file we expose) and EINVAL for names containing control bytes.
+pub fn validate_fuse_filename(name: &OsStr) -> Result<&str, Error> {
+    let name = name.to_str().ok_or_else(|| Error::Fs1541 {
+        message: "Filename validation failed".into(),
+        error: Fs1541Error::NoEntry(format!("Filename {:?} is not valid UTF-8", name)),
+    })?;
+
+    if name.is_empty() {
+        return Err(Error::Fs1541 {
+            message: "Filename validation failed".into(),
+            error: Fs1541Error::Validation("Filename is empty".into()),
+        });
+    }
+
+    if let Some(c) = name.chars().find(|c| c.is_control()) {
+        return Err(Error::Fs1541 {
+            message: "Filename validation failed".into(),
+            error: Fs1541Error::Validation(format!(
+                "Filename {:?} contains control character 0x{:02x}",
+                name, c as u32
+            )),
+        });
+    }
+
+    Ok(name)
+}
+
+/// Validates a request to change a drive's device number in software.  The
+/// new number must be one of SOFT_DEVICE_NUMS, and differ from the current
+/// one.
+pub fn validate_new_device_number(device: u8, new_device: u8) -> Result<(), Error> {
+    if !SOFT_DEVICE_NUMS.contains(&new_device) {
+        return Err(Error::Fs1541 {
+            message: "Device number validation failed".into(),
+            error: Fs1541Error::Validation(format!(
+                "New device number {} must be between {} and {}",
+                new_device,
+                SOFT_DEVICE_NUMS.start(),
+                SOFT_DEVICE_NUMS.end()
+            )),
+        });
+    }
+    if new_device == device {
+        return Err(Error::Fs1541 {
+            message: "Device number validation failed".into(),
+            error: Fs1541Error::Validation(format!("Device is already number {}", device)),
+        });
+    }
+    Ok(())
+}
+
+/// Validates a request to read len bytes of a drive's memory from addr.  A
+/// read is limited to what a single M-R command returns.
+pub fn validate_memory_read(addr: u16, len: usize) -> Result<(), Error> {
+    if len > MAX_MEMORY_READ_LEN {
+        return Err(Error::Fs1541 {
+            message: "Memory read validation failed".into(),
+            error: Fs1541Error::Validation(format!(
+                "Can't read {} bytes - at most {} can be read at once",
+                len, MAX_MEMORY_READ_LEN
+            )),
+        });
+    }
+    validate_memory_range("read", addr, len)
+}
+
+/// Validates a request to write len bytes to a drive's memory at addr
+pub fn validate_memory_write(addr: u16, len: usize) -> Result<(), Error> {
+    validate_memory_range("write", addr, len)
+}
+
+/// Checks the range is non-empty, and doesn't run past the end of the
+/// drive's 64KB address space
+fn validate_memory_range(what: &str, addr: u16, len: usize) -> Result<(), Error> {
+    let invalid = |detail: String| Error::Fs1541 {
+        message: format!("Memory {} validation failed", what),
+        error: Fs1541Error::Validation(detail),
+    };
+    if len == 0 {
+        return Err(invalid(format!("Nothing to {}", what)));
+    }
+    if addr as usize + len > 0x10000 {
+        return Err(invalid(format!(
+            "Can't {} {} bytes at ${:04X} - past the end of memory",
+            what, len, addr
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::ffi::OsStrExt;
+
+    fn validate_bytes(bytes: &[u8]) -> Result<&str, Error> {
+        validate_fuse_filename(OsStr::from_bytes(bytes))
+    }
+
+    #[test]
+    fn test_valid_filenames() {
+        assert_eq!(validate_bytes(b"hello.prg").unwrap(), "hello.prg");
+        assert_eq!(validate_bytes(b"a b c.seq").unwrap(), "a b c.seq");
+        assert_eq!(
+            validate_bytes(b".exec_command.rw").unwrap(),
+            ".exec_command.rw"
+        );
+    }
+
+    #[test]
+    fn test_nul_rejected() {
+        for bytes in [&b"\0"[..], b"foo\0", b"\0foo", b"fo\0o.prg", b"foo.prg\0\0"] {
+            let err = validate_bytes(bytes).unwrap_err();
+            assert_eq!(err.to_fuse_reply_error(), libc::EINVAL, "{:?}", bytes);
+        }
+    }
+
+    #[test]
+    fn test_control_bytes_rejected() {
+        for bytes in [
+            &b"foo\n"[..],
+            b"\rfoo",
+            b"fo\x1bo",
+            b"foo\x7f",
+            b"\x01\x02\x03",
+            "foo\u{85}".as_bytes(),
+        ] {
+            let err = validate_bytes(bytes).unwrap_err();
+            assert_eq!(err.to_fuse_reply_error(), libc::EINVAL, "{:?}", bytes);
+        }
+    }
+
+    #[test]
+    fn test_invalid_utf8_rejected() {
+        for bytes in [&b"\xff"[..], b"foo\xc3", b"\xed\xa0\x80", b"\x80\0"] {
+            let err = validate_bytes(bytes).unwrap_err();
+            assert_eq!(err.to_fuse_reply_error(), libc::ENOENT, "{:?}", bytes);
+        }
+    }
+
+    #[test]
+    fn test_empty_rejected() {
+        let err = validate_bytes(b"").unwrap_err();
+        assert_eq!(err.to_fuse_reply_error(), libc::EINVAL);
+    }
+
+    #[test]
+    fn test_new_device_number() {
+        assert!(validate_new_device_number(8, 9).is_ok());
+        assert!(validate_new_device_number(9, 8).is_ok());
+        assert!(validate_new_device_number(8, 15).is_ok());
+        assert!(validate_new_device_number(8, 8).is_err());
+        assert!(validate_new_device_number(8, 7).is_err());
+        assert!(validate_new_device_number(8, 16).is_err());
+    }
+
+    #[test]
+    fn test_memory_ranges() {
+        assert!(validate_memory_read(0x0300, 1).is_ok());
+        assert!(validate_memory_read(0x0300, MAX_MEMORY_READ_LEN).is_ok());
+        assert!(validate_memory_read(0xffff, 1).is_ok());
+        assert!(validate_memory_read(0x0300, 0).is_err());
+        assert!(validate_memory_read(0x0300, MAX_MEMORY_READ_LEN + 1).is_err());
+        assert!(validate_memory_read(0xff80, 0x81).is_err());
+
+        // Writes are split into as many M-W commands as they need
+        assert!(validate_memory_write(0x0500, 1024).is_ok());
+        assert!(validate_memory_write(0x0000, 0x10000).is_ok());
+        assert!(validate_memory_write(0x0500, 0).is_err());
+        assert!(validate_memory_write(0x0001, 0x10000).is_err());
+    }
+}